@@ -0,0 +1,48 @@
+//! Cost of finding a connection by its quad, which every received segment
+//! pays: the standard library's SipHash against the keyed `QuadState`.
+//! Run with `cargo bench --bench quad_lookup`.
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+use tcprs::{QuadState, Tcp4Tuple};
+
+const CONNECTIONS: u32 = 10_000;
+const LOOKUPS: u32 = 10_000_000;
+
+fn quad(i: u32) -> Tcp4Tuple {
+    Tcp4Tuple {
+        src: (
+            Ipv4Addr::from_bits(0x0a00_0000 | i >> 8),
+            32768 + (i & 0xff) as u16,
+        ),
+        dst: (Ipv4Addr::new(192, 168, 0, 2), 80),
+    }
+}
+
+fn bench<S: BuildHasher + Default>(name: &str) {
+    let table: HashMap<Tcp4Tuple, u32, S> = (0..CONNECTIONS).map(|i| (quad(i), i)).collect();
+    let quads: Vec<Tcp4Tuple> = (0..CONNECTIONS).map(quad).collect();
+    let start = Instant::now();
+    let mut found = 0u64;
+    for i in 0..LOOKUPS {
+        found += table
+            .get(black_box(&quads[(i % CONNECTIONS) as usize]))
+            .map_or(0, |&i| u64::from(i));
+    }
+    black_box(found);
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>6.1} ns/lookup",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(LOOKUPS)
+    );
+}
+
+fn main() {
+    bench::<std::collections::hash_map::RandomState>("siphash");
+    bench::<QuadState>("quad-state");
+}
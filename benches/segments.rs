@@ -0,0 +1,205 @@
+//! Cost of the per-segment paths of a connection: receiving in-order data,
+//! sending data and taking its ACK, and a connection's whole life from SYN
+//! to CLOSED. The peer is simulated and the segments of the connection go
+//! out through a `MemoryDevice`, as the interface sends them through the
+//! tun device. Run with `cargo bench --bench segments`.
+
+use std::cell::Cell;
+use std::hint::black_box;
+use std::io::IoSlice;
+use std::net::Ipv4Addr;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+
+use tcprs::{Action, Config, Connection, Device, DropStats, MemoryDevice};
+
+const LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 80);
+const REMOTE: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 2), 40000);
+/// Initial sequence numbers of the peer and of the connection
+const PEER_ISS: u32 = 1000;
+const ISS: u32 = 5000;
+/// Payload of a full segment on Ethernet
+const SEGMENT: usize = 1460;
+
+#[derive(Clone, Copy)]
+struct Flags {
+    syn: bool,
+    ack: bool,
+    fin: bool,
+}
+
+const SYN: Flags = Flags {
+    syn: true,
+    ack: false,
+    fin: false,
+};
+const ACK: Flags = Flags {
+    syn: false,
+    ack: true,
+    fin: false,
+};
+const FIN_ACK: Flags = Flags {
+    syn: false,
+    ack: true,
+    fin: true,
+};
+
+/// A segment from the peer, which always has room for more
+fn segment(flags: Flags, seq: u32, ack: u32, data: &[u8]) -> Vec<u8> {
+    let mut tcp = TcpHeader::new(REMOTE.1, LOCAL.1, seq, u16::MAX);
+    tcp.syn = flags.syn;
+    tcp.ack = flags.ack;
+    tcp.fin = flags.fin;
+    tcp.acknowledgment_number = ack;
+    let ip = Ipv4Header::new(
+        (tcp.header_len() + data.len()) as u16,
+        64,
+        IpNumber::TCP,
+        REMOTE.0.octets(),
+        LOCAL.0.octets(),
+    )
+    .unwrap();
+    tcp.checksum = tcp.calc_checksum_ipv4(&ip, data).unwrap();
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    tcp.write(&mut packet).unwrap();
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn parse(packet: &[u8]) -> (Ipv4HeaderSlice<'_>, TcpHeaderSlice<'_>, &[u8]) {
+    let ip = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let rest = &packet[ip.slice().len()..];
+    let tcp = TcpHeaderSlice::from_slice(rest).unwrap();
+    let data = &rest[tcp.slice().len()..];
+    (ip, tcp, data)
+}
+
+/// A connection and the device its segments leave through
+struct Endpoint {
+    config: Config,
+    conn: Connection,
+    device: MemoryDevice,
+    drops: DropStats,
+}
+
+impl Endpoint {
+    /// A connection accepted from the peer's SYN
+    fn accept() -> Self {
+        let config = Config::default();
+        let syn = segment(SYN, PEER_ISS, 0, &[]);
+        let (ip, tcp, data) = parse(&syn);
+        let conn = Connection::accept_with_iss(&config, ip, tcp, data, ISS).unwrap();
+        let mut endpoint = Self {
+            config,
+            conn,
+            device: MemoryDevice::new(),
+            drops: DropStats::default(),
+        };
+        endpoint.transmit();
+        endpoint
+    }
+
+    /// A connection that completed the handshake
+    fn established() -> Self {
+        let mut endpoint = Self::accept();
+        endpoint.deliver(&segment(ACK, PEER_ISS + 1, ISS + 1, &[]));
+        endpoint
+    }
+
+    fn deliver(&mut self, packet: &[u8]) {
+        let (ip, tcp, data) = parse(packet);
+        self.conn.on_packet(&mut self.drops, ip, tcp, data).unwrap();
+        self.transmit();
+    }
+
+    /// Send what the connection queued and hand the buffers back
+    fn transmit(&mut self) {
+        for action in self.conn.take_actions() {
+            if let Action::Transmit(packet) = action {
+                let bufs = [
+                    IoSlice::new(packet.headers()),
+                    IoSlice::new(packet.payload()),
+                ];
+                self.device.send_vectored(&bufs).unwrap();
+                self.config.buffers.give(packet.into_payload());
+            }
+        }
+        black_box(self.device.take_sent());
+    }
+}
+
+fn receive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("receive");
+    group.throughput(Throughput::Bytes(SEGMENT as u64));
+    let mut endpoint = Endpoint::established();
+    let seq = Cell::new(PEER_ISS + 1);
+    let payload = [b'x'; SEGMENT];
+    group.bench_function("in_order_segment", |b| {
+        b.iter_batched(
+            || {
+                let packet = segment(ACK, seq.get(), ISS + 1, &payload);
+                seq.set(seq.get().wrapping_add(SEGMENT as u32));
+                packet
+            },
+            |packet| {
+                endpoint.deliver(&packet);
+                // The application reads everything right away
+                let unread = endpoint.conn.ingress().len();
+                endpoint.conn.consume(unread).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn send(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send");
+    group.throughput(Throughput::Bytes(SEGMENT as u64));
+    let mut endpoint = Endpoint::established();
+    let acked = Cell::new(ISS + 1);
+    let payload = [b'x'; SEGMENT];
+    group.bench_function("segment_and_ack", |b| {
+        b.iter_batched(
+            || {
+                acked.set(acked.get().wrapping_add(SEGMENT as u32));
+                segment(ACK, PEER_ISS + 1, acked.get(), &[])
+            },
+            |ack| {
+                endpoint.conn.queue(&payload);
+                endpoint.conn.push();
+                endpoint.conn.on_timer().unwrap();
+                endpoint.transmit();
+                endpoint.deliver(&ack);
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn lifetime(c: &mut Criterion) {
+    let packets = [
+        segment(ACK, PEER_ISS + 1, ISS + 1, &[]),
+        segment(FIN_ACK, PEER_ISS + 1, ISS + 1, &[]),
+        segment(ACK, PEER_ISS + 2, ISS + 2, &[]),
+    ];
+    c.bench_function("connection/setup_teardown", |b| {
+        b.iter(|| {
+            let mut endpoint = Endpoint::accept();
+            endpoint.deliver(&packets[0]);
+            // The peer closes first, then the application
+            endpoint.deliver(&packets[1]);
+            endpoint.conn.close().unwrap();
+            endpoint.conn.on_timer().unwrap();
+            endpoint.transmit();
+            endpoint.deliver(&packets[2]);
+            assert!(endpoint.conn.is_closed());
+        })
+    });
+}
+
+criterion_group!(benches, receive, send, lifetime);
+criterion_main!(benches);
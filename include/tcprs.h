@@ -0,0 +1,51 @@
+/* C bindings of the tcp-rs socket API. Build the library with
+ *
+ *     cargo rustc --release --lib --features cdylib --crate-type cdylib
+ *
+ * and link against target/release/libtcprs.so. Functions returning a handle
+ * return NULL on failure, read and write return -1; errno tells why.
+ */
+#ifndef TCPRS_H
+#define TCPRS_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct tcprs_interface tcprs_interface;
+typedef struct tcprs_listener tcprs_listener;
+typedef struct tcprs_stream tcprs_stream;
+
+/* Attach to the tun device `name`, or the default device if NULL */
+tcprs_interface *tcprs_open(const char *name);
+/* Release the interface, after closing its listeners and streams */
+void tcprs_free(tcprs_interface *iface);
+
+/* Listen for connections on `port` */
+tcprs_listener *tcprs_bind(tcprs_interface *iface, uint16_t port);
+/* Wait for the next connection */
+tcprs_stream *tcprs_accept(tcprs_listener *listener);
+/* Stop listening */
+void tcprs_unbind(tcprs_listener *listener);
+/* Descriptor that polls readable while a connection waits to be accepted */
+int tcprs_listener_fd(const tcprs_listener *listener);
+
+/* Read up to `len` bytes; 0 once the peer closed its side */
+ssize_t tcprs_read(tcprs_stream *stream, void *buf, size_t len);
+/* Queue up to `len` bytes for sending */
+ssize_t tcprs_write(tcprs_stream *stream, const void *buf, size_t len);
+/* Descriptor that polls readable while a read wouldn't block, or once a
+ * write that failed with EAGAIN can be retried. Owned by the stream. */
+int tcprs_stream_fd(const tcprs_stream *stream);
+/* Close the connection and release the stream */
+void tcprs_close(tcprs_stream *stream);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
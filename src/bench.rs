@@ -0,0 +1,160 @@
+//! End-to-end benchmarks of the stack against a peer, in the spirit of
+//! iperf: bulk transfers measuring goodput and ping-pong exchanges
+//! measuring round-trip latency. The stack runs one end of an accepted
+//! connection; the peer either sinks or sources data, or echoes what it
+//! receives for `ping_pong()`.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{ConnectionEvent, Event, TcpStream};
+
+/// Size of the writes of a bulk transfer
+const CHUNK: usize = 64 * 1024;
+
+/// Counts retransmissions from the events of an interface, see
+/// `InterfaceBuilder::on_event()`. Clones share the count.
+#[derive(Debug, Clone, Default)]
+pub struct RetransmitCounter(Arc<AtomicU64>);
+
+impl RetransmitCounter {
+    pub fn observe(&self, event: &Event) {
+        if let ConnectionEvent::Retransmission { .. } = event.kind {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Outcome of a bulk transfer
+#[derive(Debug, Clone)]
+pub struct BulkReport {
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub retransmissions: u64,
+}
+
+impl BulkReport {
+    /// Bits of data delivered per second
+    pub fn goodput(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+impl fmt::Display for BulkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {:.3}s, {:.2} Mbit/s, {} retransmissions",
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.goodput() / 1e6,
+            self.retransmissions
+        )
+    }
+}
+
+/// Outcome of a ping-pong exchange
+#[derive(Debug, Clone)]
+pub struct LatencyReport {
+    /// Size of the messages
+    pub size: usize,
+    /// Round-trip times, shortest first
+    pub samples: Vec<Duration>,
+    pub retransmissions: u64,
+}
+
+impl LatencyReport {
+    /// The round-trip time `p` percent of the samples don't exceed
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.clamp(1, self.samples.len()) - 1]
+    }
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} round trips of {} bytes: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}, {} retransmissions",
+            self.samples.len(),
+            self.size,
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0),
+            self.retransmissions
+        )
+    }
+}
+
+/// Send data for `duration`, then close and wait for the peer to close in
+/// turn, so that the goodput counts delivered data only
+pub fn send(
+    stream: &mut TcpStream,
+    duration: Duration,
+    retransmits: &RetransmitCounter,
+) -> io::Result<BulkReport> {
+    let before = retransmits.get();
+    let chunk = vec![0u8; CHUNK];
+    let started = Instant::now();
+    let mut bytes = 0;
+    while started.elapsed() < duration {
+        stream.write_all(&chunk)?;
+        bytes += chunk.len() as u64;
+    }
+    stream.shutdown(std::net::Shutdown::Write)?;
+    io::copy(stream, &mut io::sink())?;
+    Ok(BulkReport {
+        bytes,
+        elapsed: started.elapsed(),
+        retransmissions: retransmits.get() - before,
+    })
+}
+
+/// Receive data until the peer closes
+pub fn receive(stream: &mut TcpStream, retransmits: &RetransmitCounter) -> io::Result<BulkReport> {
+    let before = retransmits.get();
+    let started = Instant::now();
+    let bytes = io::copy(stream, &mut io::sink())?;
+    Ok(BulkReport {
+        bytes,
+        elapsed: started.elapsed(),
+        retransmissions: retransmits.get() - before,
+    })
+}
+
+/// Send `count` messages of `size` bytes one after the other, each once the
+/// peer echoed the previous one back, timing every round trip
+pub fn ping_pong(
+    stream: &mut TcpStream,
+    size: usize,
+    count: usize,
+    retransmits: &RetransmitCounter,
+) -> io::Result<LatencyReport> {
+    let before = retransmits.get();
+    let ping = vec![0u8; size];
+    let mut pong = vec![0u8; size];
+    let mut samples = Vec::with_capacity(count);
+    for _ in 0..count {
+        let sent = Instant::now();
+        stream.write_all(&ping)?;
+        stream.read_exact(&mut pong)?;
+        samples.push(sent.elapsed());
+    }
+    samples.sort();
+    Ok(LatencyReport {
+        size,
+        samples,
+        retransmissions: retransmits.get() - before,
+    })
+}
@@ -0,0 +1,597 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Write};
+use std::mem;
+use std::os::fd::{FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::tcp::ratelimit::{RateLimit, TokenBucket};
+use crate::tcp::time::Clock;
+
+/// Packets a shaper holds back before it starts dropping them
+const SHAPER_QUEUE: usize = 1024;
+
+/// Packet information the kernel puts in front of every packet unless the
+/// device is opened without it: two bytes of flags and the EtherType of the
+/// packet, here IPv4
+const PACKET_INFO: [u8; 4] = [0, 0, 0x08, 0x00];
+
+/// `_IOR('T', 210, unsigned int)`: the name and flags of a tun descriptor
+const TUNGETIFF: libc::c_ulong = 0x8004_54d2;
+
+/// A device carrying raw IPv4 packets to and from the stack
+pub trait Device {
+    /// Transmit one packet
+    fn send(&self, packet: &[u8]) -> io::Result<usize>;
+
+    /// Transmit one packet made of `bufs`, e.g. headers and a payload kept
+    /// apart. Devices that can, like `Tun`, hand the pieces to the kernel
+    /// as they are; the default joins them and calls `send()`.
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.send(&join(bufs))
+    }
+
+    /// Receive one packet into `buf`, returning its length
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// The pieces of a packet in one buffer
+fn join(bufs: &[IoSlice<'_>]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+    for buf in bufs {
+        packet.extend_from_slice(buf);
+    }
+    packet
+}
+
+impl Device for tun_tap::Iface {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        tun_tap::Iface::recv(self, buf)
+    }
+}
+
+/// A tun device that can be opened again under its name after it failed,
+/// e.g. because it was deleted
+#[derive(Debug)]
+pub struct Tun {
+    name: String,
+    non_blocking: bool,
+    // Packets carry the packet information prefix
+    packet_info: bool,
+    file: RwLock<Option<File>>,
+}
+
+impl Tun {
+    pub fn open(name: &str, non_blocking: bool, packet_info: bool) -> io::Result<Self> {
+        let (name, file) = Self::create(name, non_blocking, packet_info)?;
+        Ok(Self {
+            name,
+            non_blocking,
+            packet_info,
+            file: RwLock::new(Some(file)),
+        })
+    }
+
+    /// Take over a tun device opened by someone else, e.g. a privileged
+    /// helper. Its name is asked from the kernel, which can't tell whether
+    /// it has packet information: `IFF_NO_PI` shares its bit with the
+    /// `IFF_NOFILTER` reported alongside.
+    pub fn from_fd(fd: OwnedFd, non_blocking: bool, packet_info: bool) -> io::Result<Self> {
+        let mut req: libc::ifreq = unsafe { mem::zeroed() };
+        if unsafe { libc::ioctl(fd.as_raw_fd(), TUNGETIFF, &mut req) } < 0 {
+            let error = io::Error::last_os_error();
+            return Err(match error.raw_os_error() {
+                Some(libc::ENOTTY | libc::EINVAL | libc::EBADFD) => {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Not a tun device")
+                }
+                _ => error,
+            });
+        }
+        let flags = i32::from(unsafe { req.ifr_ifru.ifru_flags });
+        if flags & (libc::IFF_TUN | libc::IFF_TAP) != libc::IFF_TUN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a tun device",
+            ));
+        }
+        let name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) };
+        let file = File::from(fd);
+        set_non_blocking(&file, non_blocking)?;
+        Ok(Self {
+            name: name.to_string_lossy().into_owned(),
+            non_blocking,
+            packet_info,
+            file: RwLock::new(Some(file)),
+        })
+    }
+
+    fn create(name: &str, non_blocking: bool, packet_info: bool) -> io::Result<(String, File)> {
+        let iface = if packet_info {
+            tun_tap::Iface::new(name, tun_tap::Mode::Tun)?
+        } else {
+            tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tun)?
+        };
+        let name = iface.name().to_string();
+        let file = unsafe { File::from_raw_fd(iface.into_raw_fd()) };
+        set_non_blocking(&file, non_blocking)?;
+        Ok((name, file))
+    }
+
+    /// Name of the device, with any `%d` in the requested one filled in
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The MTU of the device, which `ip link set mtu` may change at any time
+    pub fn mtu(&self) -> io::Result<usize> {
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = unsafe { OwnedFd::from_raw_fd(sock) };
+        let mut req: libc::ifreq = unsafe { mem::zeroed() };
+        // The name is at most 15 bytes, leaving the NUL
+        for (dst, &src) in req.ifr_name.iter_mut().zip(self.name.as_bytes()) {
+            *dst = src as libc::c_char;
+        }
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFMTU, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { req.ifr_ifru.ifru_mtu } as usize)
+    }
+
+    /// Close the device and open it again. A device that isn't persistent
+    /// is created anew, without its addresses and routes.
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut file = self.file.write().unwrap();
+        // A device only takes one descriptor, so the old one goes first
+        drop(file.take());
+        let (_, reopened) = Self::create(&self.name, self.non_blocking, self.packet_info)?;
+        *file = Some(reopened);
+        Ok(())
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&File) -> io::Result<T>) -> io::Result<T> {
+        match &*self.file.read().unwrap() {
+            Some(file) => f(file),
+            None => Err(io::Error::from_raw_os_error(libc::ENODEV)),
+        }
+    }
+}
+
+fn set_non_blocking(file: &File, non_blocking: bool) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if non_blocking {
+        flags | libc::O_NONBLOCK
+    } else {
+        flags & !libc::O_NONBLOCK
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Device for Tun {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(packet)])
+    }
+
+    /// One writev of the pieces, a tun device taking every write as one
+    /// packet
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        if !self.packet_info {
+            return self.with(|mut file| file.write_vectored(bufs));
+        }
+        // The prefix goes out in the same write, without copying the packet
+        let mut iov = Vec::with_capacity(bufs.len() + 1);
+        iov.push(IoSlice::new(&PACKET_INFO));
+        iov.extend_from_slice(bufs);
+        let n = self.with(|mut file| file.write_vectored(&iov))?;
+        Ok(n.saturating_sub(PACKET_INFO.len()))
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.packet_info {
+            return self.with(|mut file| file.read(buf));
+        }
+        // The prefix is read apart from the packet and dropped
+        let mut info = [0u8; PACKET_INFO.len()];
+        let mut iov = [
+            libc::iovec {
+                iov_base: info.as_mut_ptr() as *mut libc::c_void,
+                iov_len: info.len(),
+            },
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            },
+        ];
+        let n = self.with(|file| {
+            match unsafe { libc::readv(file.as_raw_fd(), iov.as_mut_ptr(), iov.len() as i32) } {
+                n if n < 0 => Err(io::Error::last_os_error()),
+                n => Ok(n as usize),
+            }
+        })?;
+        Ok(n.saturating_sub(PACKET_INFO.len()))
+    }
+}
+
+/// The current descriptor, which changes when the device is reopened
+impl AsRawFd for Tun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(-1, |file| file.as_raw_fd())
+    }
+}
+
+/// Device keeping packets in memory: packets sent by the stack are queued
+/// for inspection and packets to receive are injected by the caller. Used
+/// to exercise the protocol without a tun device.
+#[derive(Debug, Default)]
+pub struct MemoryDevice {
+    sent: Mutex<VecDeque<Vec<u8>>>,
+    incoming: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl MemoryDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a packet for the stack to receive
+    pub fn inject(&self, packet: Vec<u8>) {
+        self.incoming.lock().unwrap().push_back(packet);
+    }
+
+    /// Take the packets sent by the stack so far, oldest first
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        self.sent.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Device for MemoryDevice {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.sent.lock().unwrap().push_back(packet.to_vec());
+        Ok(packet.len())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .incoming
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let len = std::cmp::min(packet.len(), buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+}
+
+/// Limits the rate at which packets leave through a device, in bytes per
+/// second. Packets over the limit are queued and released by `release`,
+/// or dropped once the queue is full, like a token bucket filter qdisc.
+#[derive(Debug)]
+pub struct Shaper<D> {
+    inner: D,
+    state: Option<Mutex<ShaperState>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct ShaperState {
+    bucket: TokenBucket,
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl ShaperState {
+    /// Send queued packets for which there are tokens
+    fn release(&mut self, inner: &dyn Device, now: Instant) -> io::Result<()> {
+        while let Some(packet) = self.queue.front() {
+            if !self.admits(packet.len(), now) {
+                break;
+            }
+            let packet = self.queue.pop_front().unwrap();
+            self.bucket.take(packet.len() as u32);
+            inner.send(&packet)?;
+        }
+        Ok(())
+    }
+
+    /// Packets bigger than the burst go out once the bucket is full
+    fn admits(&mut self, len: usize, now: Instant) -> bool {
+        let len = u32::try_from(len).unwrap_or(u32::MAX);
+        self.bucket.available(now) >= len.min(self.bucket.limit().burst)
+    }
+}
+
+impl<D: Device> Shaper<D> {
+    /// Shape the traffic sent through `inner` to `limit` bytes, or pass it
+    /// through unchanged if `None`, refilling the bucket by `clock`
+    pub fn new(inner: D, limit: Option<RateLimit>, clock: Arc<dyn Clock>) -> Self {
+        let state = limit.map(|limit| {
+            Mutex::new(ShaperState {
+                bucket: TokenBucket::new(limit, clock.now()),
+                queue: VecDeque::new(),
+            })
+        });
+        Self {
+            inner,
+            state,
+            clock,
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Send the queued packets that the rate allows by now
+    pub fn release(&self) -> io::Result<()> {
+        match &self.state {
+            Some(state) => state.lock().unwrap().release(&self.inner, self.clock.now()),
+            None => Ok(()),
+        }
+    }
+
+    /// When the next queued packet may be released
+    pub fn release_at(&self) -> Option<Instant> {
+        let state = self.state.as_ref()?.lock().unwrap();
+        let len = state.queue.front()?.len();
+        Some(
+            state
+                .bucket
+                .ready_at(u32::try_from(len).unwrap_or(u32::MAX)),
+        )
+    }
+}
+
+impl<D: Device> Device for Shaper<D> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        let Some(state) = &self.state else {
+            return self.inner.send(packet);
+        };
+        let mut state = state.lock().unwrap();
+        let now = self.clock.now();
+        // Keep the packets in order
+        state.release(&self.inner, now)?;
+        if state.queue.is_empty() && state.admits(packet.len(), now) {
+            state.bucket.take(packet.len() as u32);
+            return self.inner.send(packet);
+        }
+        if state.queue.len() < SHAPER_QUEUE {
+            state.queue.push_back(packet.to_vec());
+        }
+        Ok(packet.len())
+    }
+
+    /// Kept in pieces unless the packet is shaped, which queues it whole
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match &self.state {
+            None => self.inner.send_vectored(bufs),
+            Some(_) => self.send(&join(bufs)),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+}
+
+impl<D: AsRawFd> AsRawFd for Shaper<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Impairments applied to the packets sent through an `Impaired` device,
+/// modeled after netem. Probabilities are between 0 and 1.
+#[derive(Debug, Clone)]
+pub struct Impairments {
+    /// Probability that a packet is dropped
+    pub loss: f64,
+    /// Probability that a packet is sent twice
+    pub duplicate: f64,
+    /// Probability that a bit of a packet is flipped
+    pub corrupt: f64,
+    /// Time every packet is held back
+    pub delay: Duration,
+    /// Random extra delay of up to this much, which reorders packets
+    pub jitter: Duration,
+    /// Probability that a packet skips the delay and overtakes the packets
+    /// held back
+    pub reorder: f64,
+    /// Seed of the random choices, so runs can be repeated
+    pub seed: u64,
+}
+
+impl Impairments {
+    /// Packets go through untouched and right away
+    fn is_none(&self) -> bool {
+        self.loss == 0.0
+            && self.duplicate == 0.0
+            && self.corrupt == 0.0
+            && self.delay.is_zero()
+            && self.jitter.is_zero()
+    }
+}
+
+impl Default for Impairments {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            reorder: 0.0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+/// xorshift64* generator: not for cryptography, but cheap and repeatable
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// True with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        // 53 random bits make a uniform float in [0, 1)
+        let sample = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        p > 0.0 && sample < p
+    }
+
+    /// A number in `0..n`
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next() % n
+        }
+    }
+}
+
+/// Applies `Impairments` to the packets sent through a device, so loss
+/// recovery and congestion control can be exercised without netem. Packets
+/// held back are sent by `release`. Received packets are not impaired.
+#[derive(Debug)]
+pub struct Impaired<D> {
+    inner: D,
+    impairments: Impairments,
+    state: Mutex<ImpairedState>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct ImpairedState {
+    rng: Rng,
+    /// packets held back by release time; the counter keeps the order of
+    /// packets with the same release time
+    delayed: BTreeMap<(Instant, u64), Vec<u8>>,
+    counter: u64,
+}
+
+impl<D: Device> Impaired<D> {
+    /// Impair the packets sent through `inner`, holding them back by `clock`
+    pub fn new(inner: D, impairments: Impairments, clock: Arc<dyn Clock>) -> Self {
+        let state = Mutex::new(ImpairedState {
+            rng: Rng::new(impairments.seed),
+            delayed: BTreeMap::new(),
+            counter: 0,
+        });
+        Self {
+            inner,
+            impairments,
+            state,
+            clock,
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Send the held back packets that are due
+    pub fn release(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.release_due(&mut state, self.clock.now())
+    }
+
+    /// When the next held back packet is due
+    pub fn release_at(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        state.delayed.keys().next().map(|(at, _)| *at)
+    }
+
+    fn release_due(&self, state: &mut ImpairedState, now: Instant) -> io::Result<()> {
+        while let Some(entry) = state.delayed.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            self.inner.send(&entry.remove())?;
+        }
+        Ok(())
+    }
+
+    /// Send or hold back one copy of a packet
+    fn impair(&self, state: &mut ImpairedState, packet: &[u8], now: Instant) -> io::Result<()> {
+        let imp = &self.impairments;
+        let mut packet = packet.to_vec();
+        if !packet.is_empty() && state.rng.chance(imp.corrupt) {
+            let bit = state.rng.below(packet.len() as u64 * 8);
+            packet[(bit / 8) as usize] ^= 1 << (bit % 8);
+        }
+        let jitter = Duration::from_nanos(state.rng.below(imp.jitter.as_nanos() as u64 + 1));
+        let delay = imp.delay + jitter;
+        if delay.is_zero() || state.rng.chance(imp.reorder) {
+            self.inner.send(&packet)?;
+        } else {
+            state.counter += 1;
+            let key = (now + delay, state.counter);
+            state.delayed.insert(key, packet);
+        }
+        Ok(())
+    }
+}
+
+impl<D: Device> Device for Impaired<D> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let now = self.clock.now();
+        self.release_due(&mut state, now)?;
+        if state.rng.chance(self.impairments.loss) {
+            return Ok(packet.len());
+        }
+        self.impair(&mut state, packet, now)?;
+        if state.rng.chance(self.impairments.duplicate) {
+            self.impair(&mut state, packet, now)?;
+        }
+        Ok(packet.len())
+    }
+
+    /// Kept in pieces unless the packet is impaired
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        if self.impairments.is_none() {
+            return self.inner.send_vectored(bufs);
+        }
+        self.send(&join(bufs))
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+}
+
+impl<D: AsRawFd> AsRawFd for Impaired<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
@@ -0,0 +1,303 @@
+//! C bindings of the socket API, so programs in other languages can link
+//! against the stack. Handles are opaque pointers owned by the caller and
+//! released with the matching release function. Functions returning a
+//! handle return NULL on failure, read and write -1; either way `errno`
+//! tells what went wrong. See `include/tcprs.h`.
+
+use std::ffi::{c_char, CStr};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use crate::interface::{Interface, TcpListener, TcpStream};
+
+/// Report `e` through errno
+fn set_errno(e: &io::Error) {
+    let errno = e.raw_os_error().unwrap_or(match e.kind() {
+        io::ErrorKind::WouldBlock => libc::EAGAIN,
+        io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+        io::ErrorKind::ConnectionReset => libc::ECONNRESET,
+        io::ErrorKind::ConnectionAborted => libc::ECONNABORTED,
+        io::ErrorKind::ConnectionRefused => libc::ECONNREFUSED,
+        io::ErrorKind::AddrInUse => libc::EADDRINUSE,
+        io::ErrorKind::BrokenPipe => libc::EPIPE,
+        io::ErrorKind::NotConnected => libc::ENOTCONN,
+        io::ErrorKind::InvalidInput => libc::EINVAL,
+        _ => libc::EIO,
+    });
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Box up the result as a handle, or report the error and return NULL
+fn into_handle<T>(result: io::Result<T>) -> *mut T {
+    match result {
+        Ok(t) => Box::into_raw(Box::new(t)),
+        Err(e) => {
+            set_errno(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Turn a byte count into the return value of read and write
+fn into_ssize(result: io::Result<usize>) -> isize {
+    match result {
+        Ok(n) => n as isize,
+        Err(e) => {
+            set_errno(&e);
+            -1
+        }
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Null handle")
+}
+
+/// Attach to the tun device `name`, or the default device if NULL
+///
+/// # Safety
+/// `name` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_open(name: *const c_char) -> *mut Interface {
+    let builder = Interface::builder();
+    let builder = if name.is_null() {
+        builder
+    } else {
+        match unsafe { CStr::from_ptr(name) }.to_str() {
+            Ok(name) => builder.name(name),
+            Err(_) => {
+                set_errno(&io::Error::new(io::ErrorKind::InvalidInput, "Bad name"));
+                return std::ptr::null_mut();
+            }
+        }
+    };
+    into_handle(builder.build())
+}
+
+/// Stop processing packets and release the interface. Listeners and streams
+/// must be closed first.
+///
+/// # Safety
+/// `iface` must be NULL or a handle returned by `tcprs_open`.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_free(iface: *mut Interface) {
+    if !iface.is_null() {
+        drop(unsafe { Box::from_raw(iface) });
+    }
+}
+
+/// Listen for connections on `port`
+///
+/// # Safety
+/// `iface` must be a handle returned by `tcprs_open`.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_bind(iface: *mut Interface, port: u16) -> *mut TcpListener {
+    match unsafe { iface.as_mut() } {
+        Some(iface) => into_handle(iface.bind(port)),
+        None => into_handle(Err(invalid())),
+    }
+}
+
+/// Wait for the next connection on the listener
+///
+/// # Safety
+/// `listener` must be a handle returned by `tcprs_bind`.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_accept(listener: *mut TcpListener) -> *mut TcpStream {
+    match unsafe { listener.as_mut() } {
+        Some(listener) => into_handle(listener.accept()),
+        None => into_handle(Err(invalid())),
+    }
+}
+
+/// Stop listening
+///
+/// # Safety
+/// `listener` must be NULL or a handle returned by `tcprs_bind`.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_unbind(listener: *mut TcpListener) {
+    if !listener.is_null() {
+        drop(unsafe { Box::from_raw(listener) });
+    }
+}
+
+/// Descriptor that is readable while a connection is waiting to be
+/// accepted, or -1
+///
+/// # Safety
+/// `listener` must be a handle returned by `tcprs_bind`.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_listener_fd(listener: *const TcpListener) -> i32 {
+    match unsafe { listener.as_ref() } {
+        Some(listener) => listener.as_raw_fd(),
+        None => {
+            set_errno(&invalid());
+            -1
+        }
+    }
+}
+
+/// Descriptor that is readable while a read wouldn't block, or once a
+/// write that failed with EAGAIN can be retried; -1 on failure
+///
+/// # Safety
+/// `stream` must be a handle returned by `tcprs_accept`.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_stream_fd(stream: *const TcpStream) -> i32 {
+    match unsafe { stream.as_ref() } {
+        Some(stream) => stream.as_raw_fd(),
+        None => {
+            set_errno(&invalid());
+            -1
+        }
+    }
+}
+
+/// Read up to `len` bytes into `buf`. Returns the number of bytes read, 0
+/// once the peer closed its side, or -1.
+///
+/// # Safety
+/// `stream` must be a handle returned by `tcprs_accept` and `buf` valid for
+/// writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_read(stream: *mut TcpStream, buf: *mut u8, len: usize) -> isize {
+    match unsafe { stream.as_mut() } {
+        Some(_) if buf.is_null() && len > 0 => into_ssize(Err(invalid())),
+        Some(_) if len == 0 => 0,
+        Some(stream) => {
+            let buf = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+            into_ssize(stream.read(buf))
+        }
+        None => into_ssize(Err(invalid())),
+    }
+}
+
+/// Queue up to `len` bytes from `buf` for sending. Returns the number of
+/// bytes queued or -1.
+///
+/// # Safety
+/// `stream` must be a handle returned by `tcprs_accept` and `buf` valid for
+/// reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_write(stream: *mut TcpStream, buf: *const u8, len: usize) -> isize {
+    match unsafe { stream.as_mut() } {
+        Some(_) if buf.is_null() && len > 0 => into_ssize(Err(invalid())),
+        Some(_) if len == 0 => 0,
+        Some(stream) => {
+            let buf = unsafe { std::slice::from_raw_parts(buf, len) };
+            into_ssize(stream.write(buf))
+        }
+        None => into_ssize(Err(invalid())),
+    }
+}
+
+/// Close the connection and release the stream. Data queued before is
+/// still delivered, while unread data makes the connection reset.
+///
+/// # Safety
+/// `stream` must be NULL or a handle returned by `tcprs_accept`; it can't
+/// be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn tcprs_close(stream: *mut TcpStream) {
+    if !stream.is_null() {
+        drop(unsafe { Box::from_raw(stream) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::testing::{self, NetNs, TestBed};
+
+    fn errno() -> i32 {
+        io::Error::last_os_error().raw_os_error().unwrap()
+    }
+
+    #[test]
+    fn null_handles_are_rejected() {
+        unsafe {
+            assert!(tcprs_bind(std::ptr::null_mut(), 80).is_null());
+            assert_eq!(errno(), libc::EINVAL);
+            assert!(tcprs_accept(std::ptr::null_mut()).is_null());
+            assert_eq!(errno(), libc::EINVAL);
+            assert_eq!(tcprs_listener_fd(std::ptr::null()), -1);
+            assert_eq!(tcprs_stream_fd(std::ptr::null()), -1);
+            let mut buf = [0; 4];
+            assert_eq!(tcprs_read(std::ptr::null_mut(), buf.as_mut_ptr(), 4), -1);
+            assert_eq!(tcprs_write(std::ptr::null_mut(), buf.as_ptr(), 4), -1);
+            assert_eq!(errno(), libc::EINVAL);
+            // Releasing NULL is a no-op
+            tcprs_close(std::ptr::null_mut());
+            tcprs_unbind(std::ptr::null_mut());
+            tcprs_free(std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn names_must_be_utf8() {
+        let name = c"tun\xff";
+        assert!(unsafe { tcprs_open(name.as_ptr()) }.is_null());
+        assert_eq!(errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn echo() {
+        let ns = match NetNs::enter() {
+            Ok(ns) => ns,
+            Err(e) if testing::unavailable(&e) => {
+                eprintln!("skipping: {}", e);
+                return;
+            }
+            Err(e) => panic!("entering a namespace: {}", e),
+        };
+        let iface = unsafe { tcprs_open(c"tun0".as_ptr()) };
+        if iface.is_null() {
+            let e = io::Error::last_os_error();
+            assert!(testing::unavailable(&e), "opening the device: {}", e);
+            eprintln!("skipping: {}", e);
+            return;
+        }
+        unsafe { &*iface }
+            .set_address(TestBed::LINK_ADDR, TestBed::PREFIX_LEN)
+            .expect("address");
+
+        let port = 8000;
+        let listener = unsafe { tcprs_bind(iface, port) };
+        assert!(!listener.is_null(), "bind");
+        assert!(unsafe { tcprs_listener_fd(listener) } >= 0);
+        let client = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut stream = TestBed::connect(port)?;
+            stream.write_all(b"hello")?;
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed)?;
+            Ok(echoed)
+        });
+
+        let stream = unsafe { tcprs_accept(listener) };
+        assert!(!stream.is_null(), "accept");
+        assert!(unsafe { tcprs_stream_fd(stream) } >= 0);
+        let mut buf = [0; 5];
+        let mut read = 0;
+        while read < buf.len() {
+            let n = unsafe { tcprs_read(stream, buf[read..].as_mut_ptr(), buf.len() - read) };
+            assert!(n > 0, "read: {}", io::Error::last_os_error());
+            read += n as usize;
+        }
+        assert_eq!(&buf, b"hello");
+        assert_eq!(unsafe { tcprs_write(stream, buf.as_ptr(), buf.len()) }, 5);
+        unsafe {
+            tcprs_close(stream);
+            tcprs_unbind(listener);
+        }
+
+        let echoed = client
+            .join()
+            .expect("client panicked")
+            .expect("client failed");
+        assert_eq!(echoed, b"hello");
+        unsafe { tcprs_free(iface) };
+        drop(ns);
+    }
+}
@@ -0,0 +1,3925 @@
+//! The interface: the tun device, the thread processing its packets and the
+//! blocking socket-like API on top of the protocol core.
+
+use bitflags::bitflags;
+use etherparse::{IpNumber, Ipv4HeaderSlice, TcpHeaderSlice};
+use std::{
+    any::Any,
+    collections::{hash_map, HashMap, HashSet},
+    hash::BuildHasher,
+    io::{self, IoSlice},
+    net::{Ipv4Addr, SocketAddrV4},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+    thread, time,
+};
+
+use nix::errno::Errno;
+use nix::poll;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, BorrowedFd, OwnedFd};
+
+use crate::device::{Device, Impaired, Impairments, Shaper, Tun};
+use crate::netlink;
+use crate::readiness::{ReadinessFd, Waker};
+use crate::tcp::{
+    action::{Action, Packet},
+    checkpoint::SavedConnection,
+    config::{Config, MemoryLimits, MemoryPressure, Threshold, Watermarks},
+    congestion::CongestionAlgorithm,
+    connection::{Connection, Tcp4Tuple},
+    cwnd_trace::CwndTrace,
+    drops::{DropReason, DropStats},
+    event::Event,
+    hash::QuadState,
+    history::{Direction, SegmentHistory},
+    pool::BufferPool,
+    ratelimit::{RateLimit, SynLimiter},
+    recording::{Record, Recording},
+    snapshot::TcbSnapshot,
+    state::{Available, State},
+    time::Clock,
+    transitions::TransitionLog,
+};
+use crate::threads::{self, ThreadOptions};
+
+mod backlog;
+#[cfg(feature = "control")]
+mod control;
+mod faults;
+mod table;
+
+use self::backlog::AcceptQueue;
+use self::faults::Faults;
+pub use self::faults::{Fault, FaultHandle, FaultRule, SegmentMatcher};
+use self::table::{ConnectionId, ConnectionTable, LimitKey};
+
+// The smallest MTU of IPv4, RFC 791, and the largest packet
+const MIN_MTU: usize = 68;
+const MAX_MTU: usize = u16::MAX as usize;
+// How often the MTU of the device is checked for changes
+const MTU_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+const DEFAULT_IFACE_NAME: &str = "tun0";
+// How much of a file `send_file` reads at a time
+const SEND_FILE_CHUNK: usize = 16 * 1024;
+// Validated packets the receive thread may queue for the protocol thread
+const RX_QUEUE_LEN: usize = 256;
+
+/// Type for handling interface requests
+type InterfaceHandle = Arc<InterfaceManager>;
+
+/// Callback invoked with the local and remote address of a connection whose
+/// retransmissions crossed the R1 threshold, so that routes or the path MTU
+/// can be re-evaluated. It runs on the packet processing thread with the
+/// listeners locked and must not call back into the interface.
+pub type RetransmitHook = Box<dyn Fn(SocketAddrV4, SocketAddrV4) + Send>;
+
+/// Handler receiving connection lifecycle events. It runs on the packet
+/// processing thread without the connection table locked, so it may use the
+/// interface, but blocking in it holds up packet processing. Forwarding the
+/// events to a channel keeps it short.
+pub type EventHandler = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// Handler receiving the failures and recoveries of the device. It runs on
+/// the receive thread, which doesn't receive packets meanwhile.
+pub type DeviceHandler = Box<dyn Fn(&DeviceEvent) + Send + Sync>;
+
+/// Something that happened to the device, see `InterfaceBuilder::on_device_event()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// Receiving failed with a recoverable error; the device is reopened
+    /// after `backoff`
+    Failed {
+        kind: io::ErrorKind,
+        attempt: u32,
+        backoff: time::Duration,
+    },
+    /// The device was reopened and its address and routes configured again
+    Reopened,
+}
+
+/// How the receive thread recovers from device errors like the device
+/// being deleted, see `InterfaceBuilder::device_retry()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceRetry {
+    /// Attempts at reopening the device before the packet loop fails
+    pub attempts: u32,
+    /// Wait before the first attempt, doubled after every failed one
+    pub backoff: time::Duration,
+    /// Longest wait between attempts
+    pub max_backoff: time::Duration,
+}
+
+impl Default for DeviceRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff: time::Duration::from_millis(100),
+            max_backoff: time::Duration::from_secs(2),
+        }
+    }
+}
+
+/// What happens to a received segment, as decided by a `PacketFilter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Process the segment as usual
+    Accept,
+    /// Discard the segment silently
+    Drop,
+    /// Discard the segment and answer it with a reset, aborting the
+    /// connection it belongs to
+    Reset,
+}
+
+/// Inspects every received segment before the stack processes it, for
+/// firewalling, logging or tampering with experiments. It runs on the packet
+/// processing thread with the connection table locked and must not call
+/// back into the interface.
+pub trait PacketFilter: Send + Sync {
+    /// Decide on a segment whose checksum was verified
+    fn filter(&self, ip: &Ipv4HeaderSlice, tcp: &TcpHeaderSlice, data: &[u8]) -> Verdict;
+}
+
+/// State shared by the threads of the stack. The listeners are locked
+/// before a shard whenever both are needed, and shards in the order of
+/// their workers.
+struct InterfaceManager {
+    manager: Mutex<ConnectionManager>,
+    pending_var: Condvar,
+    // Connections of each protocol worker, see `worker_of()`
+    shards: Vec<Mutex<Shard>>,
+    // Bytes held by the connections of each worker as of its last run of
+    // the timers
+    buffered: Vec<AtomicUsize>,
+    // Set when the packet loop is to stop
+    terminate: AtomicBool,
+    // Kind and description of the error that stopped the packet loop
+    failure: OnceLock<(io::ErrorKind, String)>,
+    // Settings the workers read without locking the listeners
+    clock: Arc<dyn Clock>,
+    orphan_timeout: time::Duration,
+    max_orphans: usize,
+    memory_limits: Option<MemoryLimits>,
+    nic: Shaper<Impaired<Tun>>,
+    // MTU of the device, the size of the packets received
+    mtu: AtomicUsize,
+    // Address and routes of the device, reverted when the interface is
+    // dropped
+    link: Mutex<netlink::LinkSetup>,
+    device_retry: Option<DeviceRetry>,
+    device_handler: Option<DeviceHandler>,
+    // Wake up the protocol workers to recompute their deadlines, one each
+    wakers: Vec<Waker>,
+    // Picks the worker of a connection from its quad
+    steering: QuadState,
+    // Segments to send once the connection table is unlocked
+    outbox: Outbox,
+    event_handler: Option<EventHandler>,
+    packet_filter: Option<Box<dyn PacketFilter>>,
+    // Faults injected by tests
+    faults: Faults,
+}
+
+impl InterfaceManager {
+    /// Lock the listeners. A panic of the packet loop while it held the
+    /// lock doesn't poison it for the application, which learns about the
+    /// failure from `InterfaceManager::failure` instead.
+    fn lock(&self) -> MutexGuard<'_, ConnectionManager> {
+        self.manager.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the connections of `worker`, unpoisoned like `lock()`
+    fn shard(&self, worker: usize) -> MutexGuard<'_, Shard> {
+        self.shards[worker]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the connections of the worker running the connection of `quad`
+    fn shard_of(&self, quad: &Tcp4Tuple) -> MutexGuard<'_, Shard> {
+        self.shard(self.worker_of(quad))
+    }
+
+    /// Whether the packet loop died, see `check_running()`
+    fn failed(&self) -> bool {
+        self.failure.get().is_some()
+    }
+
+    /// Fails once the packet loop died, for calls that would otherwise wait
+    /// for it or queue data it never sends
+    fn check_running(&self) -> io::Result<()> {
+        match self.failure.get() {
+            Some((_, message)) => Err(io::Error::other(format!("Packet loop failed: {}", message))),
+            None => Ok(()),
+        }
+    }
+
+    /// Record why a loop of the stack stopped, stop the others and wake up
+    /// every blocked call so it fails instead of waiting forever
+    fn fail(&self, error: &io::Error) {
+        eprintln!("Packet loop failed: {}", error);
+        self.terminate.store(true, Ordering::Relaxed);
+        let _ = self.failure.set((error.kind(), error.to_string()));
+        // Blocked calls checked for the failure with their lock held, so
+        // they are waiting by the time it is taken here
+        self.lock().signal_readiness(true);
+        for worker in 0..self.shards.len() {
+            let shard = self.shard(worker);
+            shard.signal_readiness(true);
+            for vars in shard.stream_vars.values() {
+                vars.notify(Available::all());
+            }
+        }
+        self.wake();
+        self.pending_var.notify_all();
+    }
+
+    /// When the connections of `worker` or the device next need attention.
+    /// The first worker also follows the MTU of the device.
+    fn poll_at(&self, worker: usize) -> Option<time::Instant> {
+        let mtu_check_at = match worker {
+            0 => self.lock().mtu_check_at,
+            _ => None,
+        };
+        [
+            self.shard(worker).poll_at(self.orphan_timeout),
+            mtu_check_at,
+            self.nic.release_at(),
+            self.nic.inner().release_at(),
+            self.faults.release_at(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Have the packet loop run the timers again, after the application
+    /// queued data or changed a timer
+    fn wake(&self) {
+        for waker in &self.wakers {
+            waker.wake();
+        }
+    }
+
+    /// The protocol worker running the connection of `quad`
+    fn worker_of(&self, quad: &Tcp4Tuple) -> usize {
+        match self.wakers.len() {
+            1 => 0,
+            n => (self.steering.hash_one(quad) % n as u64) as usize,
+        }
+    }
+
+    /// Send the segments queued by connections. Must be called without the
+    /// connection table locked.
+    fn flush(&self) {
+        self.outbox.flush(&self.nic, &self.faults, &*self.clock);
+    }
+
+    /// Receive packets of up to `mtu` bytes and send no larger ones, on
+    /// established connections too
+    fn set_mtu(&self, cm: &mut ConnectionManager, mtu: usize) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+        cm.config.mtu = mtu;
+        for worker in 0..self.shards.len() {
+            for conn in self.shard(worker).connections.values_mut() {
+                conn.set_mtu(mtu);
+            }
+        }
+    }
+
+    /// Follow changes of the device's MTU, checking it once in a while
+    fn check_mtu(&self, now: time::Instant) {
+        let mut cm = self.lock();
+        if cm.mtu_check_at.is_some_and(|at| at > now) {
+            return;
+        }
+        cm.mtu_check_at = Some(now + MTU_CHECK_INTERVAL);
+        // The device may be away while it is reopened
+        if let Ok(mtu) = self.nic.inner().inner().mtu() {
+            if mtu != cm.config.mtu {
+                self.set_mtu(&mut cm, mtu);
+            }
+        }
+    }
+
+    /// Which memory limits the connections of all workers are over, as of
+    /// their last run of the timers
+    fn memory_pressure(&self) -> MemoryPressure {
+        let buffered = self.buffered.iter().map(|b| b.load(Ordering::Relaxed));
+        self.memory_limits.map_or(MemoryPressure::Normal, |limits| {
+            limits.pressure(buffered.sum())
+        })
+    }
+
+    /// Compare the memory the connections of all workers hold to the limits
+    /// and tell the connections of `worker` when the pressure changed
+    fn update_memory_pressure(&self, worker: usize, shard: &mut Shard) {
+        if self.memory_limits.is_none() {
+            return;
+        }
+        let buffered = shard.connections.values().map(Connection::buffered).sum();
+        self.buffered[worker].store(buffered, Ordering::Relaxed);
+        let pressure = self.memory_pressure();
+        if pressure != shard.memory_pressure {
+            shard.memory_pressure = pressure;
+            for conn in shard.connections.values_mut() {
+                let _ = conn.set_memory_pressure(pressure);
+                transmit(&self.outbox, conn);
+            }
+        }
+    }
+
+    fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            buffered_bytes: (0..self.shards.len())
+                .map(|worker| {
+                    let shard = self.shard(worker);
+                    shard
+                        .connections
+                        .values()
+                        .map(Connection::buffered)
+                        .sum::<usize>()
+                })
+                .sum(),
+            pressure: self.memory_pressure(),
+            limits: self.memory_limits,
+        }
+    }
+
+    fn orphan_stats(&self) -> OrphanStats {
+        (0..self.shards.len()).fold(OrphanStats::default(), |stats, worker| {
+            let shard = self.shard(worker);
+            shard
+                .connections
+                .values()
+                .filter(|conn| conn.orphaned_since().is_some())
+                .fold(stats, |stats, conn| OrphanStats {
+                    count: stats.count + 1,
+                    buffered_bytes: stats.buffered_bytes + conn.buffered(),
+                })
+        })
+    }
+
+    /// Segments discarded by the listeners and by the connections of every
+    /// worker
+    fn drop_stats(&self) -> DropStats {
+        let mut drops = self.lock().drops.clone();
+        for worker in 0..self.shards.len() {
+            drops.merge(&self.shard(worker).drops);
+        }
+        drops
+    }
+
+    /// Would a connection from `peer` to the listener on `addr` go beyond
+    /// its connection limits. Locks every shard, so none may be locked.
+    fn over_connection_limits(
+        &self,
+        cm: &ConnectionManager,
+        addr: SocketAddrV4,
+        peer: Ipv4Addr,
+    ) -> bool {
+        let Some(listener) = cm.listeners.get(&addr) else {
+            return false;
+        };
+        let limits = listener.limits;
+        if limits.total.is_none() && limits.per_peer.is_none() {
+            return false;
+        }
+        let key = LimitKey {
+            listener: addr,
+            network: network(peer, limits.per_peer_prefix),
+        };
+        let (mut total, mut from_peer) = (0, 0);
+        for worker in 0..self.shards.len() {
+            let (listener, network) = self.shard(worker).connections.limit_counts(&key);
+            total += listener;
+            from_peer += network;
+        }
+        limits.total.is_some_and(|max| total >= max)
+            || limits.per_peer.is_some_and(|max| from_peer >= max)
+    }
+
+    /// Tell the device handler about `event`
+    fn report(&self, event: &DeviceEvent) {
+        if let Some(handler) = &self.device_handler {
+            handler(event);
+        }
+    }
+
+    /// Deliver events to the handler. Must be called without the connection
+    /// table locked.
+    fn dispatch(&self, events: Vec<Event>) {
+        if let Some(handler) = &self.event_handler {
+            for event in &events {
+                handler(event);
+            }
+        }
+    }
+}
+
+/// Condition variables the blocked calls on a stream wait on, so that a
+/// change of a connection only wakes up the threads using it
+#[derive(Debug, Default)]
+struct StreamVars {
+    receive: Condvar,
+    send: Condvar,
+}
+
+impl StreamVars {
+    /// Wake up the readers if `avail` has `READ` and the writers if it has
+    /// `WRITE`
+    fn notify(&self, avail: Available) {
+        if avail.contains(Available::READ) {
+            self.receive.notify_all();
+        }
+        if avail.contains(Available::WRITE) {
+            self.send.notify_all();
+        }
+    }
+}
+
+/// The buffer of a `read()` blocked on an empty receive queue, which the
+/// packet loop fills with arriving data instead of queueing it
+struct DirectBuffer {
+    ptr: *mut u8,
+    len: usize,
+    filled: usize,
+}
+
+// The buffer is only touched with the connection table locked, while the
+// reader it belongs to waits for the table
+unsafe impl Send for DirectBuffer {}
+
+impl DirectBuffer {
+    /// The part of the buffer left to fill
+    ///
+    /// # Safety
+    ///
+    /// The reader must still be waiting, with the buffer registered.
+    unsafe fn rest(&mut self) -> &mut [u8] {
+        std::slice::from_raw_parts_mut(self.ptr.add(self.filled), self.len - self.filled)
+    }
+}
+
+/// Progress of the data written to a stream, see `TcpStream::deliveries()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    /// Data bytes the peer acknowledged so far
+    pub acked: u64,
+    /// Bytes acknowledged per second since the previous report
+    pub rate: u64,
+}
+
+/// Sends a stream's `Delivery` reports as ACKs take data off its send queue
+struct DeliveryReporter {
+    tx: mpsc::Sender<Delivery>,
+    acked: u64,
+    at: time::Instant,
+}
+
+impl DeliveryReporter {
+    /// Report `acked` if the peer acknowledged more since the last report.
+    /// Returns false once the receiver is gone.
+    fn report(&mut self, acked: u64, now: time::Instant) -> bool {
+        if acked == self.acked {
+            return true;
+        }
+        let elapsed = now.saturating_duration_since(self.at).as_secs_f64();
+        let rate = ((acked - self.acked) as f64 / elapsed.max(1e-6)) as u64;
+        self.acked = acked;
+        self.at = now;
+        self.tx.send(Delivery { acked, rate }).is_ok()
+    }
+}
+
+/// Take the events recorded on a connection
+fn events_of(quad: &Tcp4Tuple, conn: &mut Connection) -> Vec<Event> {
+    conn.take_events()
+        .into_iter()
+        .map(|kind| Event {
+            local: quad.local(),
+            remote: quad.remote(),
+            kind,
+        })
+        .collect()
+}
+
+/// Segments waiting to be sent on the device. They are queued with the
+/// connection table locked and sent once it is released, so a slow device
+/// doesn't hold up every other stream.
+#[derive(Debug)]
+struct Outbox {
+    queue: Mutex<Vec<Packet>>,
+    // Held while sending, which keeps segments in the order they were queued
+    sending: Mutex<()>,
+    // Where the segments sent go back to
+    buffers: &'static dyn BufferPool,
+}
+
+impl Outbox {
+    fn new(buffers: &'static dyn BufferPool) -> Self {
+        Self {
+            queue: Mutex::default(),
+            sending: Mutex::default(),
+            buffers,
+        }
+    }
+
+    fn push(&self, packet: Packet) {
+        self.queue.lock().unwrap().push(packet);
+    }
+
+    fn flush(&self, nic: &dyn Device, faults: &Faults, clock: &dyn Clock) {
+        let _sending = self.sending.lock().unwrap();
+        loop {
+            let packets = std::mem::take(&mut *self.queue.lock().unwrap());
+            if packets.is_empty() {
+                return;
+            }
+            // Without faults to apply, the headers and the payload of a
+            // segment go out in one write without joining them
+            if faults.is_empty() {
+                for packet in packets {
+                    let bufs = [
+                        IoSlice::new(packet.headers()),
+                        IoSlice::new(packet.payload()),
+                    ];
+                    if let Err(e) = nic.send_vectored(&bufs) {
+                        eprintln!("Error sending segment: {:?}", e);
+                    }
+                    self.buffers.give(packet.into_payload());
+                }
+                continue;
+            }
+            let now = clock.now();
+            for packet in packets {
+                faults.apply(Direction::Sent, packet.into_vec(), now, |packet| {
+                    if let Err(e) = nic.send(&packet) {
+                        eprintln!("Error sending segment: {:?}", e);
+                    }
+                    self.buffers.give(packet);
+                });
+            }
+        }
+    }
+}
+
+/// Queue the segments a connection wants to send
+fn transmit(outbox: &Outbox, conn: &mut Connection) {
+    for action in conn.take_actions() {
+        if let Action::Transmit(packet) = action {
+            outbox.push(packet);
+        }
+    }
+}
+
+/// Answer a segment that doesn't belong to a connection with a reset
+fn refuse(
+    outbox: &Outbox,
+    config: &Config,
+    ip: &Ipv4HeaderSlice,
+    tcp: &TcpHeaderSlice,
+    data: &[u8],
+) {
+    match Connection::reset_unknown(config, ip, tcp, data) {
+        Ok(Some(rst)) => outbox.push(rst),
+        Ok(None) => {}
+        Err(e) => eprintln!("Error refusing connection: {:?}", e),
+    }
+}
+
+/// Predicate deciding whether a remote address may connect to a listener.
+/// It runs on the packet processing thread with the connection table locked
+/// and must not call back into the interface.
+pub type PeerFilter = Box<dyn Fn(Ipv4Addr) -> bool + Send>;
+
+/// What a listener does with connection requests it refuses, while paused
+/// or because of the peer's address
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PauseMode {
+    /// Silently drop the SYN so the peer retries later
+    #[default]
+    Drop,
+    /// Refuse the connection with a reset
+    Reset,
+}
+
+/// Caps on the connections of a listener, checked when a SYN arrives.
+/// Connections in TIME-WAIT don't count.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    /// Connections on the listener; `None` is unlimited
+    pub total: Option<usize>,
+    /// Connections from the peers in one prefix of `per_peer_prefix` bits;
+    /// `None` is unlimited
+    pub per_peer: Option<usize>,
+    /// Length of the prefixes peers are counted by, 32 for single addresses
+    pub per_peer_prefix: u8,
+    /// What happens to the SYNs beyond a limit
+    pub excess: PauseMode,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            total: None,
+            per_peer: None,
+            per_peer_prefix: 32,
+            excess: PauseMode::Drop,
+        }
+    }
+}
+
+/// SYNs a listener refused because its accept queue was full, see
+/// `TcpListener::set_backlog()`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BacklogStats {
+    /// SYNs dropped so the peer retries
+    pub dropped: u64,
+    /// SYNs answered with a reset
+    pub reset: u64,
+}
+
+/// Whether the packet loop of an interface is alive, see `Interface::health()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Health {
+    /// Processing packets, or waiting to be polled
+    Running,
+    /// Stopped by `Interface::checkpoint()`
+    Stopped,
+    /// Stopped by an error or a panic, see `Interface::last_error()`.
+    /// Blocked and later calls on streams and listeners fail.
+    Failed,
+}
+
+/// Listeners and connections handed over by `Interface::checkpoint`, to
+/// carry on with in a restarted stack with `Interface::restore`. Listener
+/// settings like filters and timeouts are not included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    /// Ports listened on, on every address
+    pub listeners: Vec<u16>,
+    /// Addresses listened on, for listeners bound to a specific address
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bound: Vec<SocketAddrV4>,
+    /// Connections waiting to be accepted
+    pub pending: Vec<SavedConnection>,
+    /// Connections owned by streams
+    pub accepted: Vec<SavedConnection>,
+}
+
+/// Listeners and streams recreated from a `Checkpoint`
+pub struct Restored {
+    pub listeners: Vec<TcpListener>,
+    pub streams: Vec<TcpStream>,
+}
+
+/// State kept for a port that accepts connections
+#[derive(Default)]
+struct Listener {
+    // Connections waiting to be accepted
+    pending: AcceptQueue,
+    // Set while new connection requests are refused
+    paused: Option<PauseMode>,
+    // Idle timeout of the connections accepted on the port
+    idle_timeout: Option<time::Duration>,
+    // Record the inputs of the connections accepted on the port
+    record: bool,
+    // Connections opened per remote address
+    syn_limiter: SynLimiter,
+    // Prefixes peers must be in, if any
+    allowed: Vec<(Ipv4Addr, u8)>,
+    // Prefixes peers must not be in
+    denied: Vec<(Ipv4Addr, u8)>,
+    // Additional check of the peer's address
+    peer_filter: Option<PeerFilter>,
+    // What happens to the requests of peers that are not admitted
+    rejected: PauseMode,
+    // Caps on the connections of the listener
+    limits: ConnectionLimits,
+    // Most connections waiting to be accepted, and what happens to the
+    // SYNs beyond
+    backlog: Option<usize>,
+    backlog_full: PauseMode,
+    backlog_stats: BacklogStats,
+    // Readable while connections are waiting to be accepted
+    readiness: Option<Arc<ReadinessFd>>,
+}
+
+impl Listener {
+    /// May `peer` open a connection
+    fn admits(&self, peer: Ipv4Addr) -> bool {
+        (self.allowed.is_empty() || self.allowed.iter().any(|p| in_prefix(peer, *p)))
+            && !self.denied.iter().any(|p| in_prefix(peer, *p))
+            && self.peer_filter.as_ref().is_none_or(|filter| filter(peer))
+    }
+}
+
+/// Is `addr` in `net/prefix_len`
+fn in_prefix(addr: Ipv4Addr, (net, prefix_len): (Ipv4Addr, u8)) -> bool {
+    network(addr, prefix_len) == network(net, prefix_len)
+}
+
+/// The network of `prefix_len` bits that `addr` is in
+fn network(addr: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix_len.min(32)))
+        .unwrap_or(0);
+    Ipv4Addr::from(u32::from(addr) & mask)
+}
+
+/// struct for managing the listeners and the admission of new connections,
+/// shared by the protocol workers
+#[derive(Default)]
+pub struct ConnectionManager {
+    // Addresses on which connections are accepted; an unspecified IP
+    // address stands for any
+    listeners: HashMap<SocketAddrV4, Listener>,
+    // When to look at the MTU of the device again
+    mtu_check_at: Option<time::Instant>,
+    // Tunables for new connections
+    config: Config,
+    // Called when a connection crosses R1
+    retransmit_hook: Option<RetransmitHook>,
+    // Segments discarded before they reached a connection, by reason
+    drops: DropStats,
+}
+
+/// The connections one protocol worker runs and the streams on them. Each
+/// worker has its own, so workers and the streams of their connections
+/// don't wait for each other.
+#[derive(Default)]
+struct Shard {
+    // Accepted connections
+    connections: ConnectionTable,
+    // Events of connections that were removed before they were dispatched
+    events: Vec<Event>,
+    // Segments of the connections discarded, by reason
+    drops: DropStats,
+    // Descriptors signaling the readiness of the streams
+    readiness: HashMap<Tcp4Tuple, Arc<ReadinessFd>, QuadState>,
+    // What blocked calls on the streams wait on
+    stream_vars: HashMap<Tcp4Tuple, Arc<StreamVars>, QuadState>,
+    // Buffers of the reads waiting for data
+    readers: HashMap<Tcp4Tuple, DirectBuffer, QuadState>,
+    // Where the deliveries of the streams are reported
+    deliveries: HashMap<Tcp4Tuple, DeliveryReporter, QuadState>,
+    // Which memory limits the connections were last told they are over
+    memory_pressure: MemoryPressure,
+}
+
+/// Selects connections for `Interface::for_each_connection()` and
+/// `Interface::abort_connections()`. Criteria
+/// left unset match every connection.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionFilter {
+    /// Connections in this state
+    pub state: Option<State>,
+    /// Connections on this local port
+    pub local_port: Option<u16>,
+    /// Connections to or from this peer address
+    pub remote: Option<Ipv4Addr>,
+    /// Connections that have been idle for at least this long
+    pub idle_for: Option<time::Duration>,
+}
+
+impl ConnectionFilter {
+    fn matches(&self, conn: &Connection) -> bool {
+        self.state.is_none_or(|state| conn.state == state)
+            && self
+                .local_port
+                .is_none_or(|port| conn.local().port() == port)
+            && self.remote.is_none_or(|addr| *conn.remote().ip() == addr)
+            && self.idle_for.is_none_or(|idle| conn.idle_for() >= idle)
+    }
+}
+
+/// Memory held in the queues of all connections and how it compares to
+/// `InterfaceBuilder::memory_limits()`
+#[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MemoryStats {
+    /// Bytes held in the send and receive buffers of all connections
+    pub buffered_bytes: usize,
+    /// Pressure as of the last run of the timers
+    pub pressure: MemoryPressure,
+    pub limits: Option<MemoryLimits>,
+}
+
+/// Resources held by orphaned connections: connections whose `TcpStream`
+/// was dropped while they were still closing
+#[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct OrphanStats {
+    /// Number of orphaned connections
+    pub count: usize,
+    /// Bytes held in the send and receive buffers of orphaned connections
+    pub buffered_bytes: usize,
+}
+
+impl ConnectionManager {
+    /// The listener a connection request to `local` goes to: the one bound
+    /// to that very address, else the one bound to any address on the port,
+    /// else those bound to port 0 of the address and of any address
+    /// What a connection on `quad` counts against: the limits of the
+    /// listener it belongs to
+    fn limit_key(&self, quad: &Tcp4Tuple) -> Option<LimitKey> {
+        let listener = self.listener_addr(quad.local())?;
+        let prefix_len = self.listeners.get(&listener)?.limits.per_peer_prefix;
+        Some(LimitKey {
+            listener,
+            network: network(*quad.remote().ip(), prefix_len),
+        })
+    }
+
+    fn listener_addr(&self, local: SocketAddrV4) -> Option<SocketAddrV4> {
+        let any = Ipv4Addr::UNSPECIFIED;
+        [
+            local,
+            SocketAddrV4::new(any, local.port()),
+            SocketAddrV4::new(*local.ip(), 0),
+            SocketAddrV4::new(any, 0),
+        ]
+        .into_iter()
+        .find(|addr| self.listeners.contains_key(addr))
+    }
+
+    /// Bring the readiness descriptors of the listeners up to date: set
+    /// while connections are waiting to be accepted, or once the packet
+    /// loop `failed`
+    fn signal_readiness(&self, failed: bool) {
+        for listener in self.listeners.values() {
+            if let Some(fd) = &listener.readiness {
+                fd.set(failed || !listener.pending.is_empty());
+            }
+        }
+    }
+
+    /// Take connections that were given up on out of the accept queues
+    fn forget_pending(&mut self, quads: &HashSet<Tcp4Tuple>) {
+        for listener in self.listeners.values_mut() {
+            listener.pending.retain(|quad| !quads.contains(quad));
+        }
+    }
+}
+
+impl Shard {
+    /// Remove a connection, keeping its undelivered events
+    fn remove(&mut self, quad: &Tcp4Tuple) -> Option<Connection> {
+        let mut conn = self.connections.remove(quad)?;
+        self.events.extend(events_of(quad, &mut conn));
+        // Ends the reports
+        self.deliveries.remove(quad);
+        Some(conn)
+    }
+
+    /// Bring the readiness descriptors of the streams up to date. A
+    /// stream's is set while a read wouldn't block, or once a write that
+    /// failed with `WouldBlock` can be retried.
+    fn signal_readiness(&self, failed: bool) {
+        // Every call fails once the packet loop died
+        for (quad, fd) in &self.readiness {
+            fd.set(
+                failed
+                    || self.connections.get(quad).is_none_or(|conn| {
+                        let ready = conn.readiness();
+                        ready.contains(Available::READ)
+                            || (conn.write_blocked && ready.contains(Available::WRITE))
+                    }),
+            );
+        }
+    }
+
+    /// Take the events recorded on all connections
+    fn take_events(&mut self) -> Vec<Event> {
+        let mut events = std::mem::take(&mut self.events);
+        for (quad, conn) in self.connections.iter_mut() {
+            events.extend(events_of(quad, conn));
+        }
+        events
+    }
+
+    /// Remove orphans that have finished closing, and reset the ones that
+    /// have been lingering for longer than `timeout`
+    fn reap_orphans(&mut self, outbox: &Outbox, timeout: time::Duration, now: time::Instant) {
+        let events = &mut self.events;
+        self.connections
+            .retain(|quad, conn| match conn.orphaned_since() {
+                None => true,
+                Some(_) if conn.is_closed() => {
+                    events.extend(events_of(quad, conn));
+                    false
+                }
+                Some(since) if now.saturating_duration_since(since) > timeout => {
+                    eprintln!("Reaping orphaned connection {:?}", quad);
+                    let _ = conn.reset();
+                    transmit(outbox, conn);
+                    events.extend(events_of(quad, conn));
+                    false
+                }
+                Some(_) => true,
+            });
+    }
+
+    /// Drop connections that never completed the handshake and were given
+    /// up on before the application accepted them: closed ones no stream
+    /// owns that are not orphans. Returns them to take them out of the
+    /// accept queues.
+    fn reap_embryonic(&mut self) -> HashSet<Tcp4Tuple> {
+        let streams = &self.stream_vars;
+        let events = &mut self.events;
+        let mut reaped = HashSet::new();
+        self.connections.retain(|quad, conn| {
+            let failed =
+                conn.is_closed() && conn.orphaned_since().is_none() && !streams.contains_key(quad);
+            if failed {
+                eprintln!("Handshake timed out {:?}", quad);
+                events.extend(events_of(quad, conn));
+                reaped.insert(quad.clone());
+            }
+            !failed
+        });
+        reaped
+    }
+
+    /// Earliest time a connection needs its timers run, or an orphan is due
+    /// to be reset after `orphan_timeout`
+    fn poll_at(&self, orphan_timeout: time::Duration) -> Option<time::Instant> {
+        self.connections
+            .values()
+            .flat_map(|conn| {
+                [
+                    conn.poll_at(),
+                    conn.orphaned_since().map(|t| t + orphan_timeout),
+                ]
+            })
+            .flatten()
+            .min()
+    }
+}
+
+/// Struct that acts as an interface to the tcp implementation
+/// Essentially, it interfaces to the thread that manages tcp connections
+/// and an interface handle (to connection manager) that keeps track of
+/// the connections
+pub struct Interface {
+    ih: Option<InterfaceHandle>,
+    jh: Option<thread::JoinHandle<io::Result<()>>>,
+    // Server answering diagnostic queries
+    #[cfg(feature = "control")]
+    control: Option<control::ControlSocket>,
+}
+
+/// Builder for configuring an `Interface` before it starts processing packets
+pub struct InterfaceBuilder {
+    name: String,
+    address: Option<(Ipv4Addr, u8)>,
+    routes: Vec<(Ipv4Addr, u8)>,
+    mtu: Option<usize>,
+    packet_info: bool,
+    device_fd: Option<OwnedFd>,
+    config: Config,
+    retransmit_hook: Option<RetransmitHook>,
+    event_handler: Option<EventHandler>,
+    packet_filter: Option<Box<dyn PacketFilter>>,
+    egress_rate_limit: Option<RateLimit>,
+    impairments: Impairments,
+    background: bool,
+    protocol_workers: usize,
+    threads: StackThreads,
+    device_retry: Option<DeviceRetry>,
+    device_handler: Option<DeviceHandler>,
+    #[cfg(feature = "control")]
+    control_socket: Option<std::path::PathBuf>,
+}
+
+/// Run the stack in threads: a receive thread that reads packets from the
+/// device and validates them, and protocol workers that run the
+/// connections, each those whose quad steers them to it. The handle of the
+/// first worker is returned; it stops the others once it is done. A bounded
+/// channel connects the receive thread to every worker, so a burst on the
+/// device is taken off it while the workers are busy. The threads are named
+/// after the device unless `threads` name them, workers after the first
+/// with their number appended.
+fn spawn_packet_loop(
+    ih: &InterfaceHandle,
+    device: &str,
+    threads: &StackThreads,
+) -> io::Result<thread::JoinHandle<io::Result<()>>> {
+    let (txs, mut rxs): (Vec<_>, Vec<_>) = (0..ih.wakers.len())
+        .map(|_| mpsc::sync_channel(RX_QUEUE_LEN))
+        .unzip();
+    let stop = Arc::new(Waker::new()?);
+    let receiver = {
+        let ih = ih.clone();
+        let stop = stop.clone();
+        threads::spawn(&threads.receive, format!("{}-rx", device), move || {
+            supervise(&ih, || rx_loop(&ih, &stop, &txs))
+        })?
+    };
+    let first_rx = rxs.remove(0);
+    let mut workers = Vec::new();
+    for (i, rx) in rxs.into_iter().enumerate() {
+        let worker = i + 1;
+        let mut options = threads.protocol.clone();
+        options.name = options.name.map(|name| format!("{}{}", name, worker));
+        let ih_worker = ih.clone();
+        let spawned = threads::spawn(&options, format!("{}-tcp{}", device, worker), move || {
+            supervise(&ih_worker, || protocol_loop(&ih_worker, worker, &rx))
+        });
+        match spawned {
+            Ok(jh) => workers.push(jh),
+            Err(e) => {
+                // The error of spawning is the one that matters
+                let _ = stop_workers(ih, &stop, receiver, workers);
+                return Err(e);
+            }
+        }
+    }
+    let ih = ih.clone();
+    let stop_receiver = stop.clone();
+    let ih_first = ih.clone();
+    threads::spawn(&threads.protocol, format!("{}-tcp", device), move || {
+        let result = supervise(&ih, || protocol_loop(&ih, 0, &first_rx));
+        drop(first_rx);
+        result.and(stop_workers(&ih, &stop, receiver, workers))
+    })
+    .inspect_err(|_| {
+        // The other threads are left to stop on their own
+        ih_first.terminate.store(true, Ordering::Relaxed);
+        ih_first.wake();
+        stop_receiver.wake();
+    })
+}
+
+/// Stop the receive thread and the protocol workers and wait for them,
+/// returning the first error they stopped with
+fn stop_workers(
+    ih: &InterfaceManager,
+    stop: &Waker,
+    receiver: thread::JoinHandle<io::Result<()>>,
+    workers: Vec<thread::JoinHandle<io::Result<()>>>,
+) -> io::Result<()> {
+    ih.terminate.store(true, Ordering::Relaxed);
+    ih.wake();
+    stop.wake();
+    let mut result = Ok(());
+    for jh in workers.into_iter().chain([receiver]) {
+        let stopped = jh.join().unwrap_or_else(|payload| Err(panicked(payload)));
+        result = result.and(stopped);
+    }
+    result
+}
+
+/// Run a loop of the stack, turning a panic into an error, and record why
+/// it failed
+fn supervise(ih: &InterfaceManager, f: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+    let result =
+        panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| Err(panicked(payload)));
+    if let Err(e) = &result {
+        ih.fail(e);
+    }
+    result
+}
+
+/// The error standing for a panic with `payload`
+fn panicked(payload: Box<dyn Any + Send>) -> io::Error {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".to_string());
+    io::Error::other(format!("Panicked: {}", message))
+}
+
+/// Options of the threads running the stack
+#[derive(Debug, Clone, Default)]
+struct StackThreads {
+    protocol: ThreadOptions,
+    receive: ThreadOptions,
+}
+
+/// Read packets from the device until `stop` is woken up, and hand the ones
+/// with valid headers to the protocol worker of their connection
+fn rx_loop(
+    ih: &InterfaceManager,
+    stop: &Waker,
+    txs: &[mpsc::SyncSender<Vec<u8>>],
+) -> io::Result<()> {
+    let nic = &ih.nic;
+    let buffers = ih.outbox.buffers;
+    loop {
+        let nic_fd = unsafe { BorrowedFd::borrow_raw(nic.as_raw_fd()) };
+        let stop_fd = unsafe { BorrowedFd::borrow_raw(stop.as_raw_fd()) };
+        let mut pfd = [
+            poll::PollFd::new(nic_fd, poll::PollFlags::POLLIN),
+            poll::PollFd::new(stop_fd, poll::PollFlags::POLLIN),
+        ];
+        match poll::poll(&mut pfd[..], poll::PollTimeout::NONE) {
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+            Ok(_) => {}
+        }
+        if pfd[1]
+            .revents()
+            .is_some_and(|r| r.contains(poll::PollFlags::POLLIN))
+        {
+            return Ok(());
+        }
+        let mut packet = buffers.take();
+        packet.resize(ih.mtu.load(Ordering::Relaxed), 0);
+        let nbytes = match nic.recv(&mut packet[..]) {
+            Ok(nbytes) => nbytes,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) =>
+            {
+                buffers.give(packet);
+                continue;
+            }
+            Err(e) => {
+                buffers.give(packet);
+                recover(ih, stop, e)?;
+                continue;
+            }
+        };
+        packet.truncate(nbytes);
+        let mut stopped = false;
+        ih.faults
+            .apply(Direction::Received, packet, ih.clock.now(), |packet| {
+                stopped |= !forward(ih, txs, packet);
+            });
+        if stopped {
+            return Ok(());
+        }
+    }
+}
+
+/// Hand a received packet with valid headers to the protocol worker of its
+/// connection. False once the worker stopped.
+fn forward(ih: &InterfaceManager, txs: &[mpsc::SyncSender<Vec<u8>>], packet: Vec<u8>) -> bool {
+    let worker = match validate(&packet) {
+        Ok((ip, tcp, _)) => ih.worker_of(&Tcp4Tuple {
+            src: (ip.source_addr(), tcp.source_port()),
+            dst: (ip.destination_addr(), tcp.destination_port()),
+        }),
+        Err(reason) => {
+            ih.lock().drops.record(reason);
+            ih.outbox.buffers.give(packet);
+            return true;
+        }
+    };
+    // Blocks while the queue is full, leaving packets to the device
+    if txs[worker].send(packet).is_err() {
+        return false;
+    }
+    ih.wakers[worker].wake();
+    true
+}
+
+/// Whether reopening the device may cure `error`
+fn recoverable(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EIO | libc::EBADFD | libc::ENODEV | libc::ENXIO | libc::ENETDOWN)
+    )
+}
+
+/// Reopen the device after a recoverable error and configure its link
+/// again, waiting longer after every failed attempt. Returns early if `stop`
+/// is woken up, and fails with the last error once the attempts are used up
+/// or right away for errors reopening doesn't cure.
+fn recover(ih: &InterfaceManager, stop: &Waker, error: io::Error) -> io::Result<()> {
+    let Some(retry) = ih.device_retry.filter(|_| recoverable(&error)) else {
+        return Err(error);
+    };
+    let tun = ih.nic.inner().inner();
+    let mut error = error;
+    let mut backoff = retry.backoff;
+    for attempt in 1..=retry.attempts {
+        eprintln!(
+            "Device {} failed: {}, reopening it in {:?}",
+            tun.name(),
+            error,
+            backoff
+        );
+        ih.report(&DeviceEvent::Failed {
+            kind: error.kind(),
+            attempt,
+            backoff,
+        });
+        let stop_fd = unsafe { BorrowedFd::borrow_raw(stop.as_raw_fd()) };
+        let mut pfd = [poll::PollFd::new(stop_fd, poll::PollFlags::POLLIN)];
+        let timeout = u16::try_from(backoff.as_millis()).unwrap_or(u16::MAX);
+        if poll::poll(&mut pfd[..], poll::PollTimeout::from(timeout)).is_ok_and(|n| n > 0) {
+            return Ok(());
+        }
+        match tun
+            .reopen()
+            .and_then(|()| ih.link.lock().unwrap().apply(tun.name()))
+        {
+            Ok(()) => {
+                eprintln!("Device {} reopened", tun.name());
+                ih.report(&DeviceEvent::Reopened);
+                return Ok(());
+            }
+            Err(e) => error = e,
+        }
+        backoff = (backoff * 2).min(retry.max_backoff);
+    }
+    Err(error)
+}
+
+/// Sleep until the receive thread queued packets for `worker`, the earliest
+/// timer of its connections is due or the loop is woken up, then process
+/// what is due
+fn protocol_loop(
+    ih: &InterfaceManager,
+    worker: usize,
+    rx: &mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    let clock = ih.clock.clone();
+
+    loop {
+        if ih.terminate.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        // Round up so a timer isn't polled for repeatedly before it is due
+        let timeout = ih.poll_at(worker).map(|at| {
+            let wait = at.saturating_duration_since(clock.now());
+            u16::try_from(wait.as_micros().div_ceil(1000)).unwrap_or(u16::MAX)
+        });
+        let waker = &ih.wakers[worker];
+        let waker_fd = unsafe { BorrowedFd::borrow_raw(waker.as_raw_fd()) };
+        let mut pfd = [poll::PollFd::new(waker_fd, poll::PollFlags::POLLIN)];
+        match poll::poll(&mut pfd[..], poll::PollTimeout::from(timeout)) {
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+            Ok(_) => {}
+        }
+        if pfd[0]
+            .revents()
+            .is_some_and(|r| r.contains(poll::PollFlags::POLLIN))
+        {
+            waker.drain();
+        }
+        for packet in rx.try_iter().take(RX_QUEUE_LEN) {
+            // The receive thread validated the packet already
+            if let Ok((ip, tcp, data)) = parse(&packet) {
+                handle_segment(ih, ip, tcp, data);
+                ih.flush();
+            }
+            ih.outbox.buffers.give(packet);
+        }
+        on_tick(ih, worker, clock.now());
+    }
+}
+
+/// Run the timers of the connections of `worker` due at `now` and reap the
+/// connections that are done
+fn on_tick(ih: &InterfaceManager, worker: usize, now: time::Instant) {
+    let nic = &ih.nic;
+    if let Err(e) = nic.release().and_then(|_| nic.inner().release()) {
+        eprintln!("Error sending segment: {:?}", e);
+    }
+    release_delayed(ih);
+    if worker == 0 {
+        ih.check_mtu(now);
+    }
+    let mut guard = ih.shard(worker);
+    let shard = &mut *guard;
+    ih.update_memory_pressure(worker, shard);
+    let mut crossed = Vec::new();
+    for (quad, conn) in shard.connections.iter_mut() {
+        // Data ready to go is due at the connection's current time, which
+        // is later than `now`
+        if conn.poll_at().is_none_or(|at| at > now.max(ih.clock.now())) {
+            continue;
+        }
+        if let Ok(avail) = conn.on_timer() {
+            if let Some(vars) = shard.stream_vars.get(quad) {
+                vars.notify(avail);
+            }
+        }
+        transmit(&ih.outbox, conn);
+        if conn.take_r1_crossed() {
+            crossed.push(quad.clone());
+        }
+    }
+    let embryonic = shard.reap_embryonic();
+    shard.reap_orphans(&ih.outbox, ih.orphan_timeout, ih.clock.now());
+    shard.signal_readiness(ih.failed());
+    let events = shard.take_events();
+    drop(guard);
+    if !crossed.is_empty() || !embryonic.is_empty() {
+        let mut cm = ih.lock();
+        if let Some(hook) = &cm.retransmit_hook {
+            for quad in &crossed {
+                hook(quad.local(), quad.remote());
+            }
+        }
+        cm.forget_pending(&embryonic);
+        cm.signal_readiness(ih.failed());
+    }
+    ih.flush();
+    ih.dispatch(events);
+}
+
+/// Send and process the segments a fault held back that are due by now
+fn release_delayed(ih: &InterfaceManager) {
+    for (direction, packet) in ih.faults.take_due(ih.clock.now()) {
+        match direction {
+            Direction::Sent => {
+                if let Err(e) = ih.nic.send(&packet) {
+                    eprintln!("Error sending segment: {:?}", e);
+                }
+            }
+            Direction::Received => process_packet(ih, &packet),
+        }
+        ih.outbox.buffers.give(packet);
+    }
+}
+
+/// Process a packet received on the device and send the answers
+fn process_packet(ih: &InterfaceManager, buf: &[u8]) {
+    match validate(buf) {
+        Ok((ip, tcp, data)) => handle_segment(ih, ip, tcp, data),
+        Err(reason) => ih.lock().drops.record(reason),
+    }
+    ih.flush();
+}
+
+/// Check the headers of a packet received on the device and split it into
+/// them and the payload
+fn parse(buf: &[u8]) -> Result<(Ipv4HeaderSlice<'_>, TcpHeaderSlice<'_>, &[u8]), DropReason> {
+    let nbytes = buf.len();
+    if buf.first().map(|b| b >> 4) != Some(4) {
+        return Err(DropReason::NotIpv4); // ignore non-ip
+    }
+    let ip = Ipv4HeaderSlice::from_slice(buf).map_err(|e| {
+        eprintln!("Ignoring packet. len:{} Err: {}", nbytes, e);
+        DropReason::MalformedIp
+    })?;
+    if ip.protocol() != IpNumber::TCP {
+        return Err(DropReason::NotTcp); // ignore non-tcp
+    }
+    let ip_len = ip.slice().len();
+    let tcp = TcpHeaderSlice::from_slice(&buf[ip_len..]).map_err(|e| {
+        eprintln!("Ignoring packet. len:{} Err: {}", nbytes, e);
+        DropReason::MalformedTcp
+    })?;
+    let data = &buf[ip_len + tcp.slice().len()..];
+    Ok((ip, tcp, data))
+}
+
+/// `parse()` followed by verifying the checksum, the work that doesn't
+/// need the connection table
+fn validate(buf: &[u8]) -> Result<(Ipv4HeaderSlice<'_>, TcpHeaderSlice<'_>, &[u8]), DropReason> {
+    let (ip, tcp, data) = parse(buf)?;
+    if tcp.calc_checksum_ipv4(&ip, data).ok() != Some(tcp.checksum()) {
+        return Err(DropReason::BadChecksum);
+    }
+    Ok((ip, tcp, data))
+}
+
+/// Process a valid segment. Segments of existing connections only need
+/// the shard of their worker; connection requests also need the listeners.
+fn handle_segment(ih: &InterfaceManager, ip: Ipv4HeaderSlice, tcp: TcpHeaderSlice, data: &[u8]) {
+    let outbox = &ih.outbox;
+    let src = ip.source_addr();
+    let dst = ip.destination_addr();
+    let srcp = tcp.source_port();
+    let dstp = tcp.destination_port();
+
+    let quad = Tcp4Tuple {
+        src: (src, srcp),
+        dst: (dst, dstp),
+    };
+    let worker = ih.worker_of(&quad);
+    let mut shard_guard = ih.shard(worker);
+    let shard = &mut *shard_guard;
+
+    let verdict = ih
+        .packet_filter
+        .as_ref()
+        .map_or(Verdict::Accept, |filter| filter.filter(&ip, &tcp, data));
+    if verdict != Verdict::Accept {
+        shard.drops.record(DropReason::Filtered);
+    }
+    match verdict {
+        Verdict::Accept => {}
+        Verdict::Drop => return,
+        Verdict::Reset => {
+            match shard.connections.get_mut(&quad) {
+                Some(conn) => {
+                    let _ = conn.reset();
+                    transmit(outbox, conn);
+                    let events = events_of(&quad, conn);
+                    if let Some(vars) = shard.stream_vars.get(&quad) {
+                        vars.notify(Available::all());
+                    }
+                    drop(shard_guard);
+                    ih.dispatch(events);
+                }
+                None => {
+                    drop(shard_guard);
+                    refuse(outbox, &ih.lock().config, &ip, &tcp, data);
+                }
+            }
+            return;
+        }
+    }
+
+    // A SYN of a new incarnation may end TIME-WAIT early, which depends on
+    // the listeners
+    let reincarnates =
+        |conn: &Connection| conn.orphaned_since().is_some() && conn.accepts_new_incarnation(&tcp);
+    if shard
+        .connections
+        .get(&quad)
+        .is_some_and(|conn| !reincarnates(conn))
+    {
+        on_segment(ih, shard_guard, &quad, ip, tcp, data);
+        return;
+    }
+
+    // Connection requests need the listeners, which are locked first
+    drop(shard_guard);
+    let mut cm_guard = ih.lock();
+    // Trick to borrow a mutable reference to the underlying connection manager
+    // instead of just a reference to the outer mutex guard
+    let cm = &mut *cm_guard;
+    let addr = cm.listener_addr(quad.local());
+    let over_limits = tcp.syn()
+        && !tcp.ack()
+        && addr.is_some_and(|addr| ih.over_connection_limits(cm, addr, src));
+    let mut shard_guard = ih.shard(worker);
+    let shard = &mut *shard_guard;
+
+    // A SYN of a new incarnation ends TIME-WAIT early, once
+    // the application let go of the old connection
+    let reuse_iss = shard
+        .connections
+        .get(&quad)
+        .filter(|conn| reincarnates(conn) && addr.is_some())
+        .map(Connection::next_iss);
+    if reuse_iss.is_some() {
+        shard.remove(&quad);
+    }
+    if shard.connections.get(&quad).is_some() {
+        drop(cm_guard);
+        on_segment(ih, shard_guard, &quad, ip, tcp, data);
+        return;
+    }
+
+    let listener = addr.and_then(|addr| cm.listeners.get_mut(&addr));
+    let Some(listener) = listener else {
+        cm.drops.record(DropReason::NoListener);
+        return;
+    };
+    let refusal = if listener.paused.is_some() {
+        cm.drops.record(DropReason::ListenerPaused);
+        listener.paused
+    } else if !listener.admits(src) {
+        cm.drops.record(DropReason::PeerRejected);
+        Some(listener.rejected)
+    } else if over_limits {
+        cm.drops.record(DropReason::ConnectionLimit);
+        Some(listener.limits.excess)
+    } else if tcp.syn()
+        && !tcp.ack()
+        && listener
+            .backlog
+            .is_some_and(|max| listener.pending.len() >= max)
+    {
+        cm.drops.record(DropReason::BacklogFull);
+        match listener.backlog_full {
+            PauseMode::Drop => listener.backlog_stats.dropped += 1,
+            PauseMode::Reset => listener.backlog_stats.reset += 1,
+        }
+        Some(listener.backlog_full)
+    } else {
+        None
+    };
+    match refusal {
+        Some(PauseMode::Drop) => return,
+        Some(PauseMode::Reset) => {
+            refuse(outbox, &cm.config, &ip, &tcp, data);
+            return;
+        }
+        None => {}
+    }
+    if tcp.syn() && !tcp.ack() && ih.memory_pressure() == MemoryPressure::Hard {
+        cm.drops.record(DropReason::MemoryPressure);
+        return;
+    }
+    if tcp.syn() && !tcp.ack() && !listener.syn_limiter.allow(src, ih.clock.now()) {
+        cm.drops.record(DropReason::SynRateLimited);
+        return;
+    }
+    let config = (listener.record != cm.config.record).then(|| Config {
+        record: listener.record,
+        ..cm.config.clone()
+    });
+    let config = config.as_ref().unwrap_or(&cm.config);
+    let accepted = match reuse_iss {
+        Some(iss) => Connection::accept_with_iss(config, ip, tcp, data, iss),
+        None => Connection::accept(config, ip, tcp, data),
+    };
+    match accepted {
+        Ok(mut c) => {
+            c.set_idle_timeout(listener.idle_timeout);
+            let _ = c.set_memory_pressure(shard.memory_pressure);
+            transmit(outbox, &mut c);
+            let limits = addr.map(|addr| LimitKey {
+                listener: addr,
+                network: network(src, listener.limits.per_peer_prefix),
+            });
+            shard.connections.insert(quad.clone(), c, limits);
+            listener.pending.push(quad);
+            drop(shard_guard);
+            cm.signal_readiness(ih.failed());
+            // Release the lock so the woken threads can use the lock
+            drop(cm_guard);
+            // Notify all waiting threads
+            ih.pending_var.notify_all();
+        }
+        Err(e) => {
+            cm.drops.record(DropReason::NotSyn);
+            eprintln!("Error accepting connection: {:?}", e);
+        }
+    }
+}
+
+/// Process a segment of the existing connection of `quad`, in the locked
+/// shard of its worker
+fn on_segment(
+    ih: &InterfaceManager,
+    mut shard_guard: MutexGuard<'_, Shard>,
+    quad: &Tcp4Tuple,
+    ip: Ipv4HeaderSlice,
+    tcp: TcpHeaderSlice,
+    data: &[u8],
+) {
+    let shard = &mut *shard_guard;
+    let Some(conn) = shard.connections.get_mut(quad) else {
+        return;
+    };
+    let result = match shard.readers.get_mut(quad) {
+        Some(reader) => {
+            // Safety: a registered reader waits until it takes its
+            // buffer back, which needs the shard we hold
+            let buf = unsafe { reader.rest() };
+            conn.on_packet_into(&mut shard.drops, ip, tcp, data, buf)
+                .map(|(avail, n)| {
+                    reader.filled += n;
+                    if n > 0 {
+                        avail | Available::READ
+                    } else {
+                        avail
+                    }
+                })
+        }
+        None => conn.on_packet(&mut shard.drops, ip, tcp, data),
+    };
+    transmit(&ih.outbox, conn);
+    match result {
+        Ok(avail) => {
+            let events = events_of(quad, conn);
+            if let Some(vars) = shard.stream_vars.get(quad) {
+                vars.notify(avail);
+            }
+            if let Some(reporter) = shard.deliveries.get_mut(quad) {
+                if !reporter.report(conn.bytes_acked(), ih.clock.now()) {
+                    shard.deliveries.remove(quad);
+                }
+            }
+            drop(shard_guard);
+            ih.dispatch(events);
+        }
+        Err(e) => {
+            eprintln!("Error processing packet: {:?}", e);
+        }
+    }
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_IFACE_NAME.to_string(),
+            address: None,
+            routes: Vec::new(),
+            mtu: None,
+            packet_info: false,
+            device_fd: None,
+            config: Config::default(),
+            retransmit_hook: None,
+            event_handler: None,
+            packet_filter: None,
+            egress_rate_limit: None,
+            impairments: Impairments::default(),
+            background: true,
+            protocol_workers: 1,
+            threads: StackThreads::default(),
+            device_retry: Some(DeviceRetry::default()),
+            device_handler: None,
+            #[cfg(feature = "control")]
+            control_socket: None,
+        }
+    }
+}
+
+impl InterfaceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer diagnostic queries on a Unix domain socket at `path`: the
+    /// connections, counters and TCB snapshots, in JSON. A socket left at
+    /// `path` is replaced, and removed when the interface is dropped.
+    #[cfg(feature = "control")]
+    pub fn control_socket(mut self, path: impl AsRef<std::path::Path>) -> Self {
+        self.control_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Name of the tun device to attach to
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Open the device with packet information, the 4-byte prefix of flags
+    /// and protocol in front of every packet, for environments that only
+    /// provide such devices. Off by default.
+    pub fn packet_info(mut self, enable: bool) -> Self {
+        self.packet_info = enable;
+        self
+    }
+
+    /// Use the tun device open at `fd` instead of opening one by name, so
+    /// a privileged helper or systemd can open it for a process without
+    /// CAP_NET_ADMIN. Its name is taken from the device; set
+    /// `packet_info()` if it was opened with packet information. Leave the
+    /// address, routes and MTU unset unless the process may configure the
+    /// link.
+    pub fn device_fd(mut self, fd: OwnedFd) -> Self {
+        self.device_fd = Some(fd);
+        self
+    }
+
+    /// Assign `addr/prefix_len` to the device and bring the link up when the
+    /// interface is created. The address is removed and the link brought down
+    /// again when the interface is dropped.
+    pub fn address(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        self.address = Some((addr, prefix_len));
+        self
+    }
+
+    /// Install a route to `dst/prefix_len` through the device when the
+    /// interface is created, so the host can reach the stack without running
+    /// `ip route add` by hand. Use a prefix length of 32 for a host route.
+    /// The link is brought up for the routes even without an `address()`.
+    /// Routes are removed again when the interface is dropped.
+    pub fn route(mut self, dst: Ipv4Addr, prefix_len: u8) -> Self {
+        self.routes.push((dst, prefix_len));
+        self
+    }
+
+    /// How long a connection that has sent its FIN and had it acknowledged
+    /// waits for the peer's FIN before it is closed (FIN-WAIT-2 timeout).
+    /// Defaults to 60 seconds; `None` waits forever.
+    pub fn fin_wait2_timeout(mut self, timeout: Option<time::Duration>) -> Self {
+        self.config.fin_wait2_timeout = timeout;
+        self
+    }
+
+    /// Reset connections that go this long without receiving a segment or
+    /// sending data, independent of keepalive. Defaults to `None`, which
+    /// keeps idle connections forever. Can be overridden per listener.
+    pub fn idle_timeout(mut self, timeout: Option<time::Duration>) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// Let the User Timeout advertised by the peer (RFC 5482) raise the
+    /// local one. Disabled by default, when the peer's value is ignored.
+    /// Can be changed per connection with
+    /// `TcpStream::set_adopt_user_timeout()`.
+    pub fn adopt_user_timeout(mut self, enable: bool) -> Self {
+        self.config.adopt_user_timeout = enable;
+        self
+    }
+
+    /// Limit how many connections a single remote address may open on each
+    /// listener. SYNs beyond the limit are dropped and counted as
+    /// `DropReason::SynRateLimited`. Defaults to `None`, unlimited.
+    pub fn syn_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.config.syn_rate_limit = limit;
+        self
+    }
+
+    /// Limit the bytes the send and receive queues of all connections hold.
+    /// Over the soft limit the queues stop growing and receive windows open
+    /// by half as much; over the hard limit windows stop opening and SYNs
+    /// are dropped as `DropReason::MemoryPressure`. Defaults to `None`,
+    /// unlimited.
+    pub fn memory_limits(mut self, limits: Option<MemoryLimits>) -> Self {
+        self.config.memory_limits = limits;
+        self
+    }
+
+    /// Send a reset to the peer when the FIN-WAIT-2 timeout expires
+    pub fn fin_wait2_reset(mut self, reset: bool) -> Self {
+        self.config.fin_wait2_reset = reset;
+        self
+    }
+
+    /// How many times an unanswered SYN-ACK is retransmitted before the
+    /// embryonic connection is dropped. Defaults to 5.
+    pub fn synack_retries(mut self, retries: u32) -> Self {
+        self.config.synack_retries = retries;
+        self
+    }
+
+    /// Number of retransmissions of the same data after which a soft error is
+    /// recorded on the connection and the retransmission hook is called (R1).
+    /// Defaults to 3 retransmissions.
+    pub fn r1_retransmissions(mut self, count: u32) -> Self {
+        self.config.r1 = Threshold::Retransmissions(count);
+        self
+    }
+
+    /// Like `r1_retransmissions`, but measured as the time spent
+    /// retransmitting without progress
+    pub fn r1_timeout(mut self, timeout: time::Duration) -> Self {
+        self.config.r1 = Threshold::Time(timeout);
+        self
+    }
+
+    /// Number of retransmissions of the same data after which the connection
+    /// is aborted and reads and writes fail with `TimedOut` (R2)
+    pub fn r2_retransmissions(mut self, count: u32) -> Self {
+        self.config.r2 = Threshold::Retransmissions(count);
+        self
+    }
+
+    /// Like `r2_retransmissions`, but measured as the time spent
+    /// retransmitting without progress. Defaults to 100 seconds.
+    pub fn r2_timeout(mut self, timeout: time::Duration) -> Self {
+        self.config.r2 = Threshold::Time(timeout);
+        self
+    }
+
+    /// Register a callback for connections crossing the R1 threshold
+    pub fn on_retransmit_threshold(
+        mut self,
+        hook: impl Fn(SocketAddrV4, SocketAddrV4) + Send + 'static,
+    ) -> Self {
+        self.retransmit_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Register a handler for connection lifecycle events: handshakes
+    /// completing, state transitions, retransmissions, resets received
+    /// and connections closing. See `EventHandler`.
+    pub fn on_event(mut self, handler: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        self.event_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Register a handler for the device failing and being reopened. See
+    /// `DeviceHandler`.
+    pub fn on_device_event(
+        mut self,
+        handler: impl Fn(&DeviceEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.device_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Register a filter that decides on every received segment before the
+    /// stack processes it. See `PacketFilter`.
+    pub fn packet_filter(mut self, filter: impl PacketFilter + 'static) -> Self {
+        self.packet_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Limit the rate at which the whole stack sends to the device, to stay
+    /// below the link rate. Segments over the rate are queued, and dropped
+    /// once too many are. Defaults to `None`, unlimited.
+    pub fn egress_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.egress_rate_limit = limit;
+        self
+    }
+
+    /// Clock the connections read the time from, instead of the system's
+    /// monotonic clock. A `ManualClock` makes timers fire only when the
+    /// clock is advanced, for deterministic tests.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    /// Pool the packets sent and received are kept in, instead of the
+    /// process-wide `PACKETS`
+    pub fn buffer_pool(mut self, buffers: &'static dyn BufferPool) -> Self {
+        self.config.buffers = buffers;
+        self
+    }
+
+    /// Impair the packets the stack sends, to test how connections cope with
+    /// a bad network. Impairments apply after the egress rate limit.
+    pub fn impairments(mut self, impairments: Impairments) -> Self {
+        self.impairments = impairments;
+        self
+    }
+
+    /// Set the MTU of the device. By default the device's MTU is left
+    /// alone; either way the stack sizes its packets after it, and follows
+    /// changes made with `ip link set mtu`.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Time to live of the packets sent. Defaults to 64.
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.config.ttl = ttl;
+        self
+    }
+
+    /// Initial receive buffer size for new connections, from which the
+    /// advertised window is derived. Defaults to 64 KiB.
+    pub fn recv_buffer(mut self, size: usize) -> Self {
+        self.config.recv_buffer = size;
+        self
+    }
+
+    /// Limit for receive buffer auto-tuning, which also sets the window
+    /// scale announced to peers. Defaults to 6 MiB. Without window scaling
+    /// the advertised window can't exceed 65535 bytes.
+    pub fn recv_buffer_max(mut self, max: usize) -> Self {
+        self.config.recv_buffer_max = max;
+        self
+    }
+
+    /// Scale the windows of connections whose peer offers it (RFC 7323
+    /// Section 2), so they can grow past 65535 bytes. Enabled by default.
+    pub fn window_scaling(mut self, enable: bool) -> Self {
+        self.config.window_scaling = enable;
+        self
+    }
+
+    /// Grow receive buffers with the bandwidth-delay product measured on each
+    /// connection, so fast links aren't throttled by the initial buffer size.
+    /// Enabled by default.
+    pub fn recv_buffer_autotune(mut self, autotune: bool) -> Self {
+        self.config.recv_buffer_autotune = autotune;
+        self
+    }
+
+    /// Detect spurious retransmission timeouts with F-RTO (RFC 5682) and undo
+    /// the congestion window reduction they caused. Enabled by default.
+    pub fn frto(mut self, enable: bool) -> Self {
+        self.config.frto = enable;
+        self
+    }
+
+    /// Send a tail loss probe (RFC 8985) when the end of a burst goes
+    /// unacknowledged for about two round trips, so that losing the last
+    /// segments is detected before the retransmission timeout. Enabled by
+    /// default.
+    pub fn tail_loss_probe(mut self, enable: bool) -> Self {
+        self.config.tlp = enable;
+        self
+    }
+
+    /// Detect lost segments with RACK (RFC 8985): a segment counts as lost
+    /// once a segment sent after it was acknowledged and a reordering window
+    /// passed, so losses are repaired without waiting for the retransmission
+    /// timeout. Enabled by default.
+    pub fn rack(mut self, enable: bool) -> Self {
+        self.config.rack = enable;
+        self
+    }
+
+    /// Pace transmissions: release the congestion window evenly over the
+    /// round trip time instead of in one burst, which reduces losses on
+    /// paths with shallow buffers. Disabled by default.
+    pub fn pacing(mut self, enable: bool) -> Self {
+        self.config.pacing = enable;
+        self
+    }
+
+    /// Congestion control algorithm for new connections. Defaults to Reno;
+    /// LEDBAT suits background transfers that should yield to other traffic.
+    pub fn congestion_control(mut self, algorithm: CongestionAlgorithm) -> Self {
+        self.config.congestion = algorithm;
+        self
+    }
+
+    /// Log every segment sent and received on new connections as a
+    /// tcpdump-like line on stderr. Can be switched per connection with
+    /// `TcpStream::set_trace()`. Disabled by default.
+    pub fn trace(mut self, enable: bool) -> Self {
+        self.config.trace = enable;
+        self
+    }
+
+    /// Record the inputs of new connections: the segments they receive,
+    /// their timers and the calls of the application. See
+    /// `TcpStream::recording()`. Disabled by default.
+    pub fn record(mut self, enable: bool) -> Self {
+        self.config.record = enable;
+        self
+    }
+
+    /// Record the state transitions of new connections, see
+    /// `TcpStream::transitions()`. Can be switched per connection with
+    /// `TcpStream::set_record_transitions()`. Disabled by default.
+    pub fn record_transitions(mut self, enable: bool) -> Self {
+        self.config.record_transitions = enable;
+        self
+    }
+
+    /// Record the congestion window of new connections whenever their
+    /// congestion controller acts, see `TcpStream::cwnd_trace()`. Can be
+    /// switched per connection with `TcpStream::set_record_cwnd()`.
+    /// Disabled by default.
+    pub fn record_cwnd(mut self, enable: bool) -> Self {
+        self.config.record_cwnd = enable;
+        self
+    }
+
+    /// Keep the last `capacity` segments of new connections, dumped to
+    /// stderr when a connection aborts, see `TcpStream::segment_history()`.
+    /// Can be changed per connection with `TcpStream::set_segment_history()`.
+    /// 0, the default, keeps none.
+    pub fn segment_history(mut self, capacity: usize) -> Self {
+        self.config.segment_history = capacity;
+        self
+    }
+
+    /// Initial congestion window for new connections, in segments.
+    /// Defaults to 10 segments (RFC 6928).
+    pub fn initial_window(mut self, segments: usize) -> Self {
+        self.config.initial_window = segments;
+        self
+    }
+
+    /// Default send queue watermarks for new connections: writes block once
+    /// `high` bytes are queued and resume when the queue drained to `low`.
+    /// Defaults to 512 and 1024 bytes.
+    pub fn write_watermarks(mut self, low: usize, high: usize) -> Self {
+        self.config.send_watermarks = Watermarks { low, high };
+        self
+    }
+
+    /// Limit for send buffer auto-tuning
+    pub fn send_buffer_max(mut self, max: usize) -> Self {
+        self.config.send_buffer_max = max;
+        self
+    }
+
+    /// Grow the send queue of each connection with its congestion window, so
+    /// a bulk sender can keep the pipe full. The watermarks configured with
+    /// `write_watermarks` are the starting point. Enabled by default.
+    pub fn send_buffer_autotune(mut self, autotune: bool) -> Self {
+        self.config.send_buffer_autotune = autotune;
+        self
+    }
+
+    /// Maximum number of orphaned connections, i.e. connections still
+    /// closing after their stream was dropped. Orphans beyond the limit are
+    /// reset immediately.
+    pub fn max_orphans(mut self, max: usize) -> Self {
+        self.config.max_orphans = max;
+        self
+    }
+
+    /// How long an orphaned connection may linger before it is reset
+    pub fn orphan_timeout(mut self, timeout: time::Duration) -> Self {
+        self.config.orphan_timeout = timeout;
+        self
+    }
+
+    /// Process packets and timers on background threads, one receiving and
+    /// one running the connections. Enabled by default; when disabled the
+    /// caller drives the interface with `Interface::poll()` from its own
+    /// loop.
+    pub fn background_thread(mut self, enable: bool) -> Self {
+        self.background = enable;
+        self
+    }
+
+    /// Reopen the device when receiving from it fails with an error that
+    /// reopening may cure, like the device being deleted or an I/O error,
+    /// and configure its address and routes again. Connections carry on.
+    /// `None` fails the packet loop on the first error instead. Only the
+    /// background thread recovers; `Interface::poll()` returns the error.
+    pub fn device_retry(mut self, retry: Option<DeviceRetry>) -> Self {
+        self.device_retry = retry;
+        self
+    }
+
+    /// Run the connections on `count` protocol workers instead of one, for
+    /// many connections on a multicore machine. A hash of its quad steers
+    /// every connection to a worker, which processes its segments and runs
+    /// its timers, so segments of a connection are processed in order. Every
+    /// worker keeps its connections in a table of its own, so workers only
+    /// share the listeners, locked to admit new connections, and send
+    /// segments and run the event handler concurrently. Needs the
+    /// background thread.
+    pub fn protocol_workers(mut self, count: usize) -> Self {
+        self.protocol_workers = count;
+        self
+    }
+
+    /// Name, pin or prioritize the thread that runs the connections and
+    /// their timers, named after the device with `-tcp` by default. The
+    /// options apply to every protocol worker, see `protocol_workers()`.
+    /// `build()` fails if the options can't be applied.
+    pub fn protocol_thread(mut self, options: ThreadOptions) -> Self {
+        self.threads.protocol = options;
+        self
+    }
+
+    /// Name, pin or prioritize the thread that receives packets from the
+    /// device, named after it with `-rx` by default
+    pub fn receive_thread(mut self, options: ThreadOptions) -> Self {
+        self.threads.receive = options;
+        self
+    }
+
+    pub fn build(mut self) -> io::Result<Interface> {
+        if self.config.initial_window == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero initial window",
+            ));
+        }
+        if self.config.recv_buffer == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero receive buffer",
+            ));
+        }
+        if self.config.ttl == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Zero TTL"));
+        }
+        if self.protocol_workers == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero protocol workers",
+            ));
+        }
+        if self.protocol_workers > 1 && !self.background {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Protocol workers need the background thread",
+            ));
+        }
+        if let Some(mtu) = self.mtu {
+            if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid MTU"));
+            }
+        }
+        if self
+            .config
+            .memory_limits
+            .is_some_and(|limits| limits.soft > limits.hard)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Soft memory limit above the hard limit",
+            ));
+        }
+        let Watermarks { low, high } = self.config.send_watermarks;
+        if high == 0 || low > high {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid watermarks",
+            ));
+        }
+
+        let nic = match self.device_fd.take() {
+            Some(fd) => Tun::from_fd(fd, !self.background, self.packet_info)?,
+            None => Tun::open(&self.name, !self.background, self.packet_info)?,
+        };
+        let device = nic.name().to_string();
+
+        // Configure the link before any packets can be exchanged over it
+        let mut link =
+            netlink::LinkSetup::new(self.address, self.routes, self.mtu.map(|mtu| mtu as u32));
+        link.apply(&device)?;
+        // Packets are sized after the device, whose MTU `mtu()` set
+        let mtu = nic.mtu()?;
+        self.config.mtu = mtu;
+
+        let buffers = self.config.buffers;
+        let clock = self.config.clock.clone();
+        let ih: InterfaceHandle = Arc::new(InterfaceManager {
+            shards: (0..self.protocol_workers)
+                .map(|_| Mutex::default())
+                .collect(),
+            buffered: (0..self.protocol_workers)
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            terminate: AtomicBool::new(false),
+            failure: OnceLock::new(),
+            clock: clock.clone(),
+            orphan_timeout: self.config.orphan_timeout,
+            max_orphans: self.config.max_orphans,
+            memory_limits: self.config.memory_limits,
+            manager: Mutex::new(ConnectionManager {
+                config: self.config,
+                retransmit_hook: self.retransmit_hook,
+                ..Default::default()
+            }),
+            pending_var: Condvar::new(),
+            nic: Shaper::new(
+                Impaired::new(nic, self.impairments, clock.clone()),
+                self.egress_rate_limit,
+                clock,
+            ),
+            mtu: AtomicUsize::new(mtu),
+            link: Mutex::new(link),
+            device_retry: self.device_retry,
+            device_handler: self.device_handler,
+            wakers: (0..self.protocol_workers)
+                .map(|_| Waker::new())
+                .collect::<io::Result<_>>()?,
+            steering: QuadState::default(),
+            outbox: Outbox::new(buffers),
+            event_handler: self.event_handler,
+            packet_filter: self.packet_filter,
+            faults: Faults::default(),
+        });
+
+        // create a new thread and move the connection manager into the thread
+
+        #[cfg(feature = "control")]
+        let control = match &self.control_socket {
+            Some(path) => Some(control::ControlSocket::serve(path, ih.clone())?),
+            None => None,
+        };
+
+        let jh = if self.background {
+            match spawn_packet_loop(&ih, &device, &self.threads) {
+                Ok(jh) => Some(jh),
+                Err(e) => {
+                    ih.terminate.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(Interface {
+            ih: Some(ih),
+            jh,
+            #[cfg(feature = "control")]
+            control,
+        })
+    }
+}
+
+impl Interface {
+    pub fn new() -> io::Result<Self> {
+        InterfaceBuilder::default().build()
+    }
+
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder::default()
+    }
+
+    /// Run on the tun device open at `fd`, see
+    /// `InterfaceBuilder::device_fd()`
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        InterfaceBuilder::default().device_fd(fd).build()
+    }
+    /// Whether the packet loop still runs
+    pub fn health(&self) -> Health {
+        let ih = self.ih.as_ref().unwrap();
+        if ih.failed() {
+            Health::Failed
+        } else if ih.terminate.load(Ordering::Relaxed) {
+            Health::Stopped
+        } else {
+            Health::Running
+        }
+    }
+
+    /// The error or panic that stopped the packet loop
+    pub fn last_error(&self) -> Option<io::Error> {
+        let (kind, message) = self.ih.as_ref().unwrap().failure.get()?;
+        Some(io::Error::new(*kind, message.clone()))
+    }
+
+    /// Number of received segments that were discarded, by reason
+    pub fn drop_stats(&self) -> DropStats {
+        self.ih.as_ref().unwrap().drop_stats()
+    }
+
+    /// Bytes held in the queues of all connections and the memory pressure
+    /// they put on the interface
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.ih.as_ref().unwrap().memory_stats()
+    }
+
+    /// Number of orphaned connections and the buffer space they hold
+    pub fn orphan_stats(&self) -> OrphanStats {
+        self.ih.as_ref().unwrap().orphan_stats()
+    }
+
+    /// For tests: drop, delay, duplicate or corrupt the segments `rule`
+    /// picks. Sent segments are hit before the egress rate limit and the
+    /// impairments, received ones before their checksum is verified. The
+    /// rule stays until `clear_faults()`.
+    pub fn inject_fault(&self, rule: FaultRule) -> FaultHandle {
+        let ih = self.ih.as_ref().unwrap();
+        let handle = ih.faults.inject(rule);
+        // Delays need the timers recomputed
+        ih.wake();
+        handle
+    }
+
+    /// Remove the injected faults. Segments they delayed still go through.
+    pub fn clear_faults(&self) {
+        self.ih.as_ref().unwrap().faults.clear();
+    }
+
+    /// Change the MTU of the device and of every connection, established
+    /// ones included
+    pub fn set_mtu(&self, mtu: usize) -> io::Result<()> {
+        if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid MTU"));
+        }
+        let ih = self.ih.as_ref().unwrap();
+        let tun = ih.nic.inner().inner();
+        ih.link.lock().unwrap().set_mtu(tun.name(), mtu as u32)?;
+        ih.set_mtu(&mut ih.lock(), mtu);
+        Ok(())
+    }
+
+    /// Change the time to live of the packets of every connection,
+    /// established ones included, and of resets
+    pub fn set_ttl(&self, ttl: u8) -> io::Result<()> {
+        if ttl == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Zero TTL"));
+        }
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.lock();
+        cm.config.ttl = ttl;
+        for worker in 0..ih.shards.len() {
+            for conn in ih.shard(worker).connections.values_mut() {
+                conn.set_ttl(ttl);
+            }
+        }
+        Ok(())
+    }
+
+    /// Initial receive buffer of the connections accepted from now on, see
+    /// `InterfaceBuilder::recv_buffer()`
+    pub fn set_recv_buffer(&self, size: usize) -> io::Result<()> {
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero receive buffer",
+            ));
+        }
+        self.ih.as_ref().unwrap().lock().config.recv_buffer = size;
+        Ok(())
+    }
+
+    /// Send queue watermarks of the connections accepted from now on, see
+    /// `TcpStream::set_write_watermarks()` for existing ones
+    pub fn set_write_watermarks(&self, low: usize, high: usize) -> io::Result<()> {
+        if high == 0 || low > high {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid watermarks",
+            ));
+        }
+        self.ih.as_ref().unwrap().lock().config.send_watermarks = Watermarks { low, high };
+        Ok(())
+    }
+
+    /// Replace the address assigned with `InterfaceBuilder::address()`, or
+    /// assign one. The link stays up and keeps its routes, so connections
+    /// to the old address carry on as far as the routes allow.
+    pub fn set_address(&self, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        let ih = self.ih.as_ref().unwrap();
+        let tun = ih.nic.inner().inner();
+        ih.link
+            .lock()
+            .unwrap()
+            .set_address(tun.name(), Some((addr, prefix_len)))
+    }
+
+    /// Call `f` on every connection matching `filter`, orphaned ones
+    /// included, e.g. to list them. `f` runs with the connection table
+    /// locked and must not call back into the interface.
+    pub fn for_each_connection(&self, filter: &ConnectionFilter, mut f: impl FnMut(&Connection)) {
+        let ih = self.ih.as_ref().unwrap();
+        for worker in 0..ih.shards.len() {
+            let shard = ih.shard(worker);
+            for conn in shard.connections.values() {
+                if filter.matches(conn) {
+                    f(conn);
+                }
+            }
+        }
+    }
+
+    /// Reset every connection matching `filter`, orphaned ones included.
+    /// Returns how many were reset.
+    pub fn abort_connections(&self, filter: &ConnectionFilter) -> usize {
+        let ih = self.ih.as_ref().unwrap();
+        let mut events = Vec::new();
+        let mut aborted = 0;
+        for worker in 0..ih.shards.len() {
+            let mut guard = ih.shard(worker);
+            let shard = &mut *guard;
+            for (quad, conn) in shard.connections.iter_mut() {
+                if filter.matches(conn) && !conn.is_closed() {
+                    let _ = conn.reset();
+                    aborted += 1;
+                    transmit(&ih.outbox, conn);
+                    if let Some(vars) = shard.stream_vars.get(quad) {
+                        vars.notify(Available::all());
+                    }
+                }
+            }
+            shard.signal_readiness(ih.failed());
+            events.extend(shard.take_events());
+        }
+        ih.flush();
+        ih.wake();
+        ih.dispatch(events);
+        aborted
+    }
+
+    /// Hand over the listeners and open connections, e.g. to a new process
+    /// that restarts the stack. The connections are taken out of this
+    /// interface without telling the peers, and the packet loop stops, so
+    /// the interface and its streams can be dropped afterwards. Orphaned
+    /// connections and data buffered in a stream by `BufRead` are lost.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.lock();
+        ih.terminate.store(true, Ordering::Relaxed);
+        ih.wake();
+        let pending: HashSet<Tcp4Tuple> = cm
+            .listeners
+            .values_mut()
+            .flat_map(|listener| listener.pending.drain())
+            .collect();
+        let mut checkpoint = Checkpoint {
+            listeners: cm
+                .listeners
+                .keys()
+                .filter(|addr| addr.ip().is_unspecified())
+                .map(SocketAddrV4::port)
+                .collect(),
+            bound: cm
+                .listeners
+                .keys()
+                .filter(|addr| !addr.ip().is_unspecified())
+                .copied()
+                .collect(),
+            ..Default::default()
+        };
+        let drained = (0..ih.shards.len()).flat_map(|worker| ih.shard(worker).connections.drain());
+        for (quad, conn) in drained {
+            if conn.orphaned_since().is_some() {
+                continue;
+            }
+            let Some(saved) = conn.save() else {
+                continue;
+            };
+            if pending.contains(&quad) {
+                checkpoint.pending.push(saved);
+            } else {
+                checkpoint.accepted.push(saved);
+            }
+        }
+        checkpoint
+    }
+
+    /// Carry on with the listeners and connections of a checkpoint taken by
+    /// another interface on the same address. Unacknowledged data is sent
+    /// again.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> io::Result<Restored> {
+        let listeners = checkpoint
+            .listeners
+            .iter()
+            .map(|&port| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+            .chain(checkpoint.bound.iter().copied())
+            .map(|addr| self.bind_to(addr))
+            .collect::<io::Result<Vec<_>>>()?;
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.lock();
+        let mut streams = Vec::new();
+        for (saved, pending) in checkpoint
+            .pending
+            .iter()
+            .map(|saved| (saved, true))
+            .chain(checkpoint.accepted.iter().map(|saved| (saved, false)))
+        {
+            let mut conn = Connection::restore(&cm.config, saved)?;
+            transmit(&ih.outbox, &mut conn);
+            let quad = Tcp4Tuple {
+                src: (*saved.remote.ip(), saved.remote.port()),
+                dst: (*saved.local.ip(), saved.local.port()),
+            };
+            let mut shard = ih.shard_of(&quad);
+            shard
+                .connections
+                .insert(quad.clone(), conn, cm.limit_key(&quad));
+            let listener = cm
+                .listener_addr(saved.local)
+                .and_then(|addr| cm.listeners.get_mut(&addr));
+            match listener {
+                Some(listener) if pending => {
+                    listener.pending.push(quad);
+                }
+                _ => {
+                    streams.push(TcpStream::new(ih, &mut shard, quad)?);
+                    shard.signal_readiness(ih.failed());
+                }
+            }
+        }
+        cm.signal_readiness(ih.failed());
+        drop(cm);
+        ih.flush();
+        ih.wake();
+        ih.pending_var.notify_all();
+        Ok(Restored { listeners, streams })
+    }
+
+    /// Process the packets waiting on the device and run the timers due at
+    /// `now`. Returns when the interface next needs to be polled, if ever;
+    /// arriving packets also need a poll, so wait for the device to become
+    /// readable as well. Only available without the background thread, see
+    /// `InterfaceBuilder::background_thread()`. Blocking calls on streams
+    /// and listeners only return once another thread polls the interface.
+    pub fn poll(&self, now: time::Instant) -> io::Result<Option<time::Instant>> {
+        if self.jh.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Interface is driven by a background thread",
+            ));
+        }
+        let ih = self.ih.as_ref().unwrap();
+        let buffers = ih.outbox.buffers;
+        loop {
+            let mut packet = buffers.take();
+            packet.resize(ih.mtu.load(Ordering::Relaxed), 0);
+            match ih.nic.recv(&mut packet[..]) {
+                Ok(nbytes) => {
+                    packet.truncate(nbytes);
+                    ih.faults.apply(Direction::Received, packet, now, |packet| {
+                        process_packet(ih, &packet);
+                        buffers.give(packet);
+                    });
+                }
+                Err(e) => {
+                    buffers.give(packet);
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        break;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        on_tick(ih, 0, now);
+        Ok(ih.poll_at(0))
+    }
+
+    /// Listen on `port` of every address of the interface. Port 0 accepts
+    /// the connections to every port nothing else listens on, e.g. for a
+    /// transparent proxy, which finds the destination the peer asked for
+    /// with `TcpStream::local_addr()`.
+    pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+        self.bind_to(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+    }
+
+    /// Listen on one address only. Connection requests go to the listener
+    /// bound to their destination address if there is one, and to the one
+    /// bound to every address on the port otherwise. Port 0 takes the other
+    /// ports as with `bind()`. Binding an address and port that are
+    /// listened on already fails with `AddrInUse`.
+    pub fn bind_to(&mut self, addr: SocketAddrV4) -> io::Result<TcpListener> {
+        if addr.ip().is_broadcast() || addr.ip().is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "Can't listen on a broadcast or multicast address",
+            ));
+        }
+        let mut cm = self.ih.as_mut().unwrap().lock();
+        let idle_timeout = cm.config.idle_timeout;
+        let record = cm.config.record;
+        let syn_limiter = SynLimiter::new(cm.config.syn_rate_limit);
+        let readiness = Arc::new(ReadinessFd::new()?);
+        match cm.listeners.entry(addr) {
+            hash_map::Entry::Vacant(v) => {
+                v.insert(Listener {
+                    idle_timeout,
+                    record,
+                    syn_limiter,
+                    readiness: Some(readiness.clone()),
+                    ..Default::default()
+                });
+            }
+            hash_map::Entry::Occupied(_o) => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "Port in use"));
+            }
+        }
+        // Start accepting SYN packets on 'addr'
+        drop(cm);
+        Ok(TcpListener {
+            ih: self.ih.as_mut().unwrap().clone(),
+            addr,
+            readiness,
+        })
+    }
+}
+
+/// The device's descriptor, to wait for packets in poll mode
+impl AsRawFd for Interface {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.ih.as_ref().unwrap().nic.as_raw_fd()
+    }
+}
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        let ih = self.ih.take().unwrap();
+        ih.terminate.store(true, Ordering::Relaxed);
+        ih.wake();
+        // A failure of the packet loop was recorded and reported already
+        if let Some(jh) = self.jh.take() {
+            let _ = jh.join();
+        }
+        #[cfg(feature = "control")]
+        drop(self.control.take());
+        // Revert the link configuration only after the packet loop is gone
+        ih.link.lock().unwrap().revert();
+    }
+}
+
+/// Accepts connections on a port. Dropping it resets the connections
+/// waiting to be accepted; accepted streams are not affected.
+pub struct TcpListener {
+    ih: InterfaceHandle,
+    addr: SocketAddrV4,
+    readiness: Arc<ReadinessFd>,
+}
+
+impl TcpListener {
+    /// The address listened on; an unspecified IP address stands for any
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    pub fn accept(&mut self) -> io::Result<TcpStream> {
+        let mut cm = self.ih.lock();
+        loop {
+            while let Some(quad) = cm
+                .listeners
+                .get_mut(&self.addr)
+                .expect("Port closed while listener is active")
+                .pending
+                .pop()
+            {
+                let mut shard = self.ih.shard_of(&quad);
+                // The handshake may have been given up on since
+                if shard.connections.id(&quad).is_none() {
+                    continue;
+                }
+                let stream = TcpStream::new(&self.ih, &mut shard, quad);
+                shard.signal_readiness(self.ih.failed());
+                drop(shard);
+                cm.signal_readiness(self.ih.failed());
+                return stream;
+            }
+            cm.signal_readiness(self.ih.failed());
+            self.ih.check_running()?;
+            // Block for connections
+            cm = self
+                .ih
+                .pending_var
+                .wait(cm)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Accept a connection together with up to `max` bytes of the first
+    /// data it received, waiting up to `timeout` for some to arrive. The
+    /// bytes are peeked and stay queued in the stream, so a protocol router
+    /// can dispatch the connection by them and the handler still reads them.
+    /// Fewer bytes come back if no more arrived together, and none if the
+    /// peer sent nothing in time or closed without sending.
+    pub fn accept_with_data(
+        &mut self,
+        max: usize,
+        timeout: time::Duration,
+    ) -> io::Result<(TcpStream, Vec<u8>)> {
+        let deadline = self.ih.clock.now() + timeout;
+        let stream = self.accept()?;
+        let data = stream.peek_until(max, deadline)?;
+        Ok((stream, data))
+    }
+
+    /// Stop accepting new connections on the port without unbinding it.
+    /// Connection requests are dropped or refused with a reset, depending on
+    /// `mode`, until `resume()` is called. Established connections and the
+    /// ones already waiting to be accepted are not affected.
+    pub fn pause(&self, mode: PauseMode) {
+        let mut cm = self.ih.lock();
+        cm.listeners
+            .get_mut(&self.addr)
+            .expect("Port closed while listener is active")
+            .paused = Some(mode);
+    }
+
+    /// Start accepting new connections again after `pause()`
+    pub fn resume(&self) {
+        let mut cm = self.ih.lock();
+        cm.listeners
+            .get_mut(&self.addr)
+            .expect("Port closed while listener is active")
+            .paused = None;
+    }
+
+    /// Idle timeout of the connections accepted from now on, overriding the
+    /// interface's; `None` keeps them forever
+    pub fn set_idle_timeout(&self, timeout: Option<time::Duration>) {
+        let mut cm = self.ih.lock();
+        cm.listeners
+            .get_mut(&self.addr)
+            .expect("Port closed while listener is active")
+            .idle_timeout = timeout;
+    }
+
+    /// Record the inputs of the connections accepted from now on, see
+    /// `TcpStream::recording()`
+    pub fn set_record(&self, enable: bool) {
+        self.with_listener(|listener| listener.record = enable);
+    }
+
+    /// Only accept connections from peers in `addr/prefix_len`. Once a
+    /// prefix is allowed, peers outside of all allowed prefixes are refused.
+    pub fn allow(&self, addr: Ipv4Addr, prefix_len: u8) {
+        self.with_listener(|listener| listener.allowed.push((addr, prefix_len)));
+    }
+
+    /// Refuse connections from peers in `addr/prefix_len`, even if they are
+    /// in an allowed prefix
+    pub fn deny(&self, addr: Ipv4Addr, prefix_len: u8) {
+        self.with_listener(|listener| listener.denied.push((addr, prefix_len)));
+    }
+
+    /// Refuse connections from peers for which `filter` returns false, on
+    /// top of the allowed and denied prefixes. `None` removes the filter.
+    pub fn set_peer_filter(&self, filter: Option<PeerFilter>) {
+        self.with_listener(|listener| listener.peer_filter = filter);
+    }
+
+    /// Whether requests of refused peers are dropped (the default) or
+    /// answered with a reset. They never take a slot in the accept queue.
+    pub fn reject_with(&self, mode: PauseMode) {
+        self.with_listener(|listener| listener.rejected = mode);
+    }
+
+    /// Cap the connections on this listener, in total and per peer, e.g.
+    /// against a single host opening many. Unlimited by default.
+    pub fn set_connection_limits(&self, limits: ConnectionLimits) {
+        let mut cm = self.ih.lock();
+        cm.listeners
+            .get_mut(&self.addr)
+            .expect("Port closed while listener is active")
+            .limits = limits;
+        // Peers are counted by the new prefix from now on
+        for worker in 0..self.ih.shards.len() {
+            self.ih
+                .shard(worker)
+                .connections
+                .recount(self.addr, |peer| network(peer, limits.per_peer_prefix));
+        }
+    }
+
+    /// Limit the connections waiting to be accepted to `max`, handshakes
+    /// in progress included; `None`, the default, is unlimited. SYNs that
+    /// find the queue full are dropped, for the peer to retry, or answered
+    /// with a reset, as `when_full` says.
+    pub fn set_backlog(&self, max: Option<usize>, when_full: PauseMode) {
+        self.with_listener(|listener| {
+            listener.backlog = max;
+            listener.backlog_full = when_full;
+        });
+    }
+
+    /// SYNs refused so far because the accept queue was full
+    pub fn backlog_stats(&self) -> BacklogStats {
+        let mut stats = BacklogStats::default();
+        self.with_listener(|listener| stats = listener.backlog_stats);
+        stats
+    }
+
+    fn with_listener(&self, f: impl FnOnce(&mut Listener)) {
+        let mut cm = self.ih.lock();
+        f(cm.listeners
+            .get_mut(&self.addr)
+            .expect("Port closed while listener is active"));
+    }
+
+    /// Limit how many connections a single remote address may open on this
+    /// listener, overriding the interface's; `None` is unlimited
+    pub fn set_syn_rate_limit(&self, limit: Option<RateLimit>) {
+        let mut cm = self.ih.lock();
+        cm.listeners
+            .get_mut(&self.addr)
+            .expect("Port closed while listener is active")
+            .syn_limiter
+            .set_limit(limit);
+    }
+
+    /// A connection is waiting to be accepted, so `accept` won't block
+    pub fn has_pending(&self) -> bool {
+        let cm = self.ih.lock();
+        !cm.listeners
+            .get(&self.addr)
+            .expect("Port closed while listener is active")
+            .pending
+            .is_empty()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        let cm = self.ih.lock();
+        cm.listeners
+            .get(&self.addr)
+            .expect("Port closed while listener is active")
+            .paused
+            .is_some()
+    }
+}
+
+/// A descriptor that is readable while connections are waiting to be
+/// accepted, to wait on the listener with poll or epoll
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.readiness.as_raw_fd()
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut cm = self.ih.lock();
+        let mut listener = cm
+            .listeners
+            .remove(&self.addr)
+            .expect("Failed to remove port listener");
+
+        // Connections nobody accepted are refused with a reset; the ones
+        // handed out by `accept()` are owned by their streams and live on
+        let mut events = Vec::new();
+        for quad in listener.pending.drain() {
+            eprintln!("Terminating {:?}", quad);
+            let mut shard = self.ih.shard_of(&quad);
+            if let Some(conn) = shard.connections.get_mut(&quad) {
+                let _ = conn.reset();
+                transmit(&self.ih.outbox, conn);
+            }
+            shard.remove(&quad);
+            events.append(&mut shard.events);
+        }
+        drop(cm);
+        self.ih.flush();
+        self.ih.dispatch(events);
+    }
+}
+
+pub struct TcpStream {
+    ih: InterfaceHandle,
+    quad: Tcp4Tuple,
+    // Protocol worker running the connection, whose shard holds it
+    worker: usize,
+    // Slot of the connection in the table
+    id: ConnectionId,
+    // Data taken from the receive queue by `fill_buf`, and how much of it
+    // was consumed
+    buffered: Vec<u8>,
+    consumed: usize,
+    readiness: Arc<ReadinessFd>,
+    vars: Arc<StreamVars>,
+}
+
+/// Copy as much of `head` followed by `tail` into `buf` as fits
+fn copy_from(head: &[u8], tail: &[u8], buf: &mut [u8]) -> usize {
+    let hread = std::cmp::min(buf.len(), head.len());
+    buf[..hread].copy_from_slice(&head[..hread]);
+    let tread = std::cmp::min(buf.len() - hread, tail.len());
+    buf[hread..hread + tread].copy_from_slice(&tail[..tread]);
+    hread + tread
+}
+
+bitflags! {
+    /// Flags of `TcpStream::recv_with_flags()` and `send_with_flags()`,
+    /// named after their POSIX counterparts
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MsgFlags: u8 {
+        /// Return received data without removing it from the queue
+        const PEEK = 0b0001;
+        /// Block until the whole buffer was filled, the peer closed its side
+        /// or an error occurred
+        const WAITALL = 0b0010;
+        /// Fail with `WouldBlock` instead of blocking
+        const DONTWAIT = 0b0100;
+        /// Urgent data, which is not supported
+        const OOB = 0b1000;
+    }
+}
+
+impl TcpStream {
+    /// A stream on the connection `quad`, with its readiness descriptor and
+    /// condition variables registered in `shard`, the shard of its worker
+    fn new(ih: &InterfaceHandle, shard: &mut Shard, quad: Tcp4Tuple) -> io::Result<Self> {
+        let id = shard
+            .connections
+            .id(&quad)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+        let readiness = Arc::new(ReadinessFd::new()?);
+        shard.readiness.insert(quad.clone(), readiness.clone());
+        let vars = Arc::new(StreamVars::default());
+        shard.stream_vars.insert(quad.clone(), vars.clone());
+        Ok(TcpStream {
+            ih: ih.clone(),
+            worker: ih.worker_of(&quad),
+            quad,
+            id,
+            buffered: Vec::new(),
+            consumed: 0,
+            readiness,
+            vars,
+        })
+    }
+
+    /// Lock the shard holding the connection
+    fn shard(&self) -> MutexGuard<'_, Shard> {
+        self.ih.shard(self.worker)
+    }
+
+    /// Have the worker running the connection run its timers again
+    fn wake(&self) {
+        self.ih.wakers[self.worker].wake();
+    }
+
+    /// Block until enough was received for a read of `len` bytes or the peer
+    /// closed its side, then hand the head and the tail of the receive queue
+    /// to `take`, which returns how many bytes it took from them. Enough is
+    /// `len` bytes with `WAITALL`, else the receive low watermark up to
+    /// `len`. `PEEK` leaves them queued and `DONTWAIT` takes what is there,
+    /// failing with `WouldBlock` if nothing is, instead of blocking.
+    ///
+    /// While a read of any amount waits for an empty queue, the packet loop
+    /// copies arriving data to `direct`, `len` bytes the caller may write
+    /// to, and the read returns what it copied.
+    fn read_with(
+        &self,
+        flags: MsgFlags,
+        len: usize,
+        direct: Option<*mut u8>,
+        mut take: impl FnMut(&[u8], &[u8]) -> usize,
+    ) -> io::Result<usize> {
+        let mut cm = self.shard();
+        loop {
+            let conn = cm
+                .connections
+                .by_id_mut(self.id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+            if let Some(kind) = conn.error {
+                return Err(io::Error::from(kind));
+            }
+
+            if conn.is_recv_closed() && conn.ingress.is_empty() {
+                // No more data to read
+                return Ok(0);
+            }
+
+            let min = if flags.contains(MsgFlags::WAITALL) {
+                len
+            } else if flags.contains(MsgFlags::DONTWAIT) {
+                1
+            } else {
+                conn.recv_lowat.min(len)
+            };
+            // Peeking can't free any space, so wait for no more than the
+            // receive buffer holds
+            let min = min.clamp(1, std::cmp::max(conn.recv_buffer_size(), 1));
+            if conn.ingress.len() >= min || (conn.is_recv_closed() && !conn.ingress.is_empty()) {
+                let (head, tail) = conn.ingress.as_slices();
+                let nread = take(head, tail);
+                if !flags.contains(MsgFlags::PEEK) {
+                    drop(conn.ingress.drain(..nread));
+                    conn.record_with(|| Record::Consume(nread));
+                    let _ = conn.on_read();
+                    transmit(&self.ih.outbox, conn);
+                    cm.signal_readiness(self.ih.failed());
+                    // The worker tells the connections once the memory
+                    // freed relieves the pressure
+                    if self.ih.memory_pressure() != MemoryPressure::Normal {
+                        self.wake();
+                    }
+                }
+                drop(cm);
+                self.ih.flush();
+                return Ok(nread);
+            }
+
+            self.ih.check_running()?;
+            if flags.contains(MsgFlags::DONTWAIT) {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            if let Some(ptr) = direct.filter(|_| min == 1) {
+                let reader = DirectBuffer {
+                    ptr,
+                    len,
+                    filled: 0,
+                };
+                cm.readers.insert(self.quad.clone(), reader);
+            }
+            cm = self
+                .vars
+                .receive
+                .wait(cm)
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(reader) = cm.readers.remove(&self.quad) {
+                if reader.filled > 0 {
+                    return Ok(reader.filled);
+                }
+            }
+        }
+    }
+
+    /// Up to `max` bytes from the head of the receive queue, leaving them
+    /// queued, once any data is there or the peer closed, or nothing once
+    /// `deadline` passes
+    fn peek_until(&self, max: usize, deadline: time::Instant) -> io::Result<Vec<u8>> {
+        let mut cm = self.shard();
+        loop {
+            let conn = cm
+                .connections
+                .by_id_mut(self.id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+            if let Some(kind) = conn.error {
+                return Err(io::Error::from(kind));
+            }
+            if !conn.ingress.is_empty() || conn.is_recv_closed() {
+                let (head, tail) = conn.ingress.as_slices();
+                let mut data = vec![0; max.min(conn.ingress.len())];
+                copy_from(head, tail, &mut data);
+                return Ok(data);
+            }
+            self.ih.check_running()?;
+            let now = self.ih.clock.now();
+            if now >= deadline {
+                return Ok(Vec::new());
+            }
+            cm = self
+                .vars
+                .receive
+                .wait_timeout(cm, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Queue as much of `buf` as the send queue takes, blocking while it is
+    /// full unless `DONTWAIT` is set
+    fn write_with(&self, flags: MsgFlags, buf: &[u8]) -> io::Result<usize> {
+        let mut cm = self.shard();
+        loop {
+            self.ih.check_running()?;
+            let conn = cm
+                .connections
+                .by_id_mut(self.id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+            if let Some(kind) = conn.error {
+                return Err(io::Error::from(kind));
+            }
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            let Watermarks { low, high } = conn.watermarks;
+            if conn.write_blocked && conn.unacked.len() <= low {
+                conn.write_blocked = false;
+            }
+            if !conn.write_blocked && conn.unacked.len() < high {
+                let nwrite = std::cmp::min(buf.len(), high - conn.unacked.len());
+                conn.unacked.extend(&mut buf[..nwrite].iter());
+                conn.record_with(|| Record::Queue(buf[..nwrite].to_vec()));
+                conn.push();
+                cm.signal_readiness(self.ih.failed());
+                self.wake();
+                return Ok(nwrite);
+            }
+
+            // Block until the send queue drained to the low watermark
+            conn.write_blocked = true;
+            if flags.contains(MsgFlags::DONTWAIT) {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            cm = self
+                .vars
+                .send
+                .wait(cm)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl io::Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed < self.buffered.len() {
+            let nread = io::Read::read(&mut &self.buffered[self.consumed..], buf)?;
+            io::BufRead::consume(self, nread);
+            return Ok(nread);
+        }
+        // The packet loop and `take` both write through `ptr`, one at a
+        // time as they need the connection table for it
+        let (ptr, len) = (buf.as_mut_ptr(), buf.len());
+        self.read_with(MsgFlags::empty(), len, Some(ptr), |head, tail| {
+            copy_from(head, tail, unsafe {
+                std::slice::from_raw_parts_mut(ptr, len)
+            })
+        })
+    }
+
+    /// Takes the whole receive queue every time data arrives, instead of
+    /// locking the connection table for every chunk of a buffer
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        buf.extend_from_slice(&self.buffered[self.consumed..]);
+        self.consumed = self.buffered.len();
+        loop {
+            let nread = self.read_with(MsgFlags::empty(), usize::MAX, None, |head, tail| {
+                buf.extend_from_slice(head);
+                buf.extend_from_slice(tail);
+                head.len() + tail.len()
+            })?;
+            if nread == 0 {
+                return Ok(buf.len() - start);
+            }
+        }
+    }
+
+    fn read_to_string(&mut self, buf: &mut String) -> io::Result<usize> {
+        let mut bytes = Vec::new();
+        self.read_to_end(&mut bytes)?;
+        let s = String::from_utf8(bytes).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )
+        })?;
+        buf.push_str(&s);
+        Ok(s.len())
+    }
+
+    /// Like `read`, but copies straight into the uninitialized part of the
+    /// buffer instead of requiring it to be zeroed first
+    #[cfg(feature = "nightly")]
+    fn read_buf(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        if self.consumed < self.buffered.len() {
+            let unread = &self.buffered[self.consumed..];
+            let nread = std::cmp::min(cursor.capacity(), unread.len());
+            cursor.append(&unread[..nread]);
+            io::BufRead::consume(self, nread);
+            return Ok(());
+        }
+        self.read_with(MsgFlags::empty(), cursor.capacity(), None, |head, tail| {
+            let hread = std::cmp::min(cursor.capacity(), head.len());
+            cursor.append(&head[..hread]);
+            let tread = std::cmp::min(cursor.capacity(), tail.len());
+            cursor.append(&tail[..tread]);
+            hread + tread
+        })?;
+        Ok(())
+    }
+}
+
+/// Line-oriented reading without a `BufReader`: `fill_buf` takes the
+/// contiguous head of the receive queue into the stream
+impl io::BufRead for TcpStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.consumed == self.buffered.len() {
+            let mut buffered = std::mem::take(&mut self.buffered);
+            buffered.clear();
+            self.consumed = 0;
+            let result = self.read_with(MsgFlags::empty(), usize::MAX, None, |head, _tail| {
+                buffered.extend_from_slice(head);
+                head.len()
+            });
+            self.buffered = buffered;
+            result?;
+        }
+        Ok(&self.buffered[self.consumed..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed = std::cmp::min(self.consumed + amt, self.buffered.len());
+    }
+}
+
+impl io::Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_with(MsgFlags::empty(), buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        if let Some(kind) = conn.error {
+            return Err(io::Error::from(kind));
+        }
+
+        conn.push();
+        self.wake();
+        if conn.unacked.is_empty() {
+            return Ok(());
+        }
+        // TODO: block
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "Too much data to write",
+        ))
+    }
+}
+
+impl TcpStream {
+    /// Our end of the connection: the address the peer connected to, which
+    /// for a stream accepted on a port 0 listener is the one it asked for
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.quad.local()
+    }
+
+    /// The peer's end of the connection
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.quad.remote()
+    }
+
+    pub fn shutdown(&self, _how: std::net::Shutdown) -> io::Result<()> {
+        // TODO: Send FIN
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.close()?;
+        self.wake();
+        Ok(())
+    }
+
+    /// Receive like `read()`, with the semantics of the POSIX `recv()`
+    /// flags: `PEEK` leaves the data queued, `WAITALL` fills the whole
+    /// buffer unless the connection closes first and `DONTWAIT` fails with
+    /// `WouldBlock` where a read would block. Urgent data (`OOB`) is not
+    /// supported.
+    pub fn recv_with_flags(&mut self, buf: &mut [u8], flags: MsgFlags) -> io::Result<usize> {
+        if flags.contains(MsgFlags::OOB) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Urgent data is not supported",
+            ));
+        }
+        if flags.contains(MsgFlags::WAITALL) && !flags.contains(MsgFlags::PEEK) {
+            let mut nread = 0;
+            while nread < buf.len() {
+                match self.recv_with_flags(&mut buf[nread..], flags - MsgFlags::WAITALL) {
+                    Ok(0) => break,
+                    Ok(n) => nread += n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock && nread > 0 => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(nread);
+        }
+        if self.consumed < self.buffered.len() {
+            let nread = copy_from(&self.buffered[self.consumed..], &[], buf);
+            if !flags.contains(MsgFlags::PEEK) {
+                io::BufRead::consume(self, nread);
+            }
+            return Ok(nread);
+        }
+        self.read_with(flags, buf.len(), None, |head, tail| {
+            copy_from(head, tail, buf)
+        })
+    }
+
+    /// Send like `write()`. Only `DONTWAIT` applies, failing with
+    /// `WouldBlock` where a write would block; urgent data (`OOB`) is not
+    /// supported.
+    pub fn send_with_flags(&mut self, buf: &[u8], flags: MsgFlags) -> io::Result<usize> {
+        if flags.contains(MsgFlags::OOB) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Urgent data is not supported",
+            ));
+        }
+        if flags.intersects(MsgFlags::PEEK | MsgFlags::WAITALL) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Flag only applies to receiving",
+            ));
+        }
+        self.write_with(flags, buf)
+    }
+
+    /// Send the bytes of `file` in `range`, reading them as the send queue
+    /// drains, so a large file is paced by the window and congestion window
+    /// instead of being buffered up front. The file's cursor is left alone.
+    /// Returns the number of bytes queued, which is short of the range only
+    /// if the file ends before it.
+    pub fn send_file(&mut self, file: &std::fs::File, range: Range<u64>) -> io::Result<u64> {
+        let mut chunk = vec![0; SEND_FILE_CHUNK];
+        let mut pos = range.start;
+        while pos < range.end {
+            let len = std::cmp::min(chunk.len() as u64, range.end - pos) as usize;
+            let nread = match file.read_at(&mut chunk[..len], pos) {
+                Ok(0) => break,
+                Ok(nread) => nread,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            io::Write::write_all(self, &chunk[..nread])?;
+            pos += nread as u64;
+        }
+        Ok(pos - range.start)
+    }
+
+    /// Abort the connection immediately: a reset is sent to the peer and any
+    /// data still queued in either direction is discarded. Subsequent reads
+    /// and writes fail with `ConnectionAborted`.
+    pub fn reset(&self) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        let reset = conn.reset();
+        transmit(&self.ih.outbox, conn);
+        drop(cm);
+        self.ih.flush();
+        reset?;
+        // Wake up readers and writers blocked on the connection
+        self.vars.notify(Available::all());
+        Ok(())
+    }
+
+    /// Override the initial congestion window (in segments) configured on
+    /// the interface. Fails once data has been sent on the connection.
+    pub fn set_initial_window(&self, segments: usize) -> io::Result<()> {
+        if segments == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero initial window",
+            ));
+        }
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_initial_window(segments)
+    }
+
+    /// Switch the connection to another congestion control algorithm. The
+    /// new algorithm starts from the current congestion window.
+    pub fn set_congestion_control(&self, algorithm: CongestionAlgorithm) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_congestion_control(algorithm);
+        Ok(())
+    }
+
+    /// Log the segments sent and received on the connection as tcpdump-like
+    /// lines on stderr
+    pub fn set_trace(&self, enable: bool) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_trace(enable);
+        Ok(())
+    }
+
+    /// The inputs recorded on the connection since it was opened, if it was
+    /// accepted with recording enabled. Saved with `Recording::encode()`,
+    /// they can be fed into a fresh connection with a `Replayer` to
+    /// reproduce what happened. Options changed on the stream, e.g. the
+    /// user timeout, are not recorded.
+    pub fn recording(&self) -> io::Result<Option<Recording>> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.recording().cloned())
+    }
+
+    /// Record the state transitions of the connection from now on, or stop
+    /// and forget the ones recorded
+    pub fn set_record_transitions(&self, enable: bool) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_record_transitions(enable);
+        Ok(())
+    }
+
+    /// The state transitions recorded on the connection, each with the
+    /// segment that triggered it, if recording is enabled. The log can be
+    /// exported as a Graphviz or Mermaid diagram.
+    pub fn transitions(&self) -> io::Result<Option<TransitionLog>> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.transitions().cloned())
+    }
+
+    /// Record the congestion window whenever the congestion controller
+    /// acts from now on, or stop and forget the samples
+    pub fn set_record_cwnd(&self, enable: bool) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_record_cwnd(enable);
+        Ok(())
+    }
+
+    /// The congestion window, slow start threshold, bytes in flight and
+    /// congestion state of the connection over time, if they are recorded.
+    /// `CwndTrace::to_csv()` and `to_json()` export them for plotting.
+    pub fn cwnd_trace(&self) -> io::Result<Option<CwndTrace>> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.cwnd_trace().cloned())
+    }
+
+    /// Keep the last `capacity` segments sent and received from now on, or
+    /// with 0 stop and forget the ones kept
+    pub fn set_segment_history(&self, capacity: usize) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_segment_history(capacity);
+        Ok(())
+    }
+
+    /// The last segments sent and received on the connection, oldest
+    /// first, if they are kept
+    pub fn segment_history(&self) -> io::Result<Option<SegmentHistory>> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.segment_history().cloned())
+    }
+
+    /// Structured view of the connection's TCB: state, sequence spaces,
+    /// timers and queue lengths. Its `Display` output is meant for bug
+    /// reports and logs.
+    pub fn debug_snapshot(&self) -> io::Result<TcbSnapshot> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.snapshot())
+    }
+
+    /// Current size of the receive buffer, which grows with auto-tuning
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.recv_buffer_size())
+    }
+
+    /// Set the receive low watermark, as SO_RCVLOWAT: blocking reads wait
+    /// until `bytes` were received, or as many as the read asks for if
+    /// fewer, and the stream only polls readable then. The end of the
+    /// stream and errors end the wait early. 0 counts as 1, the default.
+    pub fn set_recv_lowat(&self, bytes: usize) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.recv_lowat = bytes.max(1);
+        cm.signal_readiness(self.ih.failed());
+        drop(cm);
+        // Blocked readers may have enough with a lower watermark
+        self.vars.receive.notify_all();
+        Ok(())
+    }
+
+    /// The receive low watermark, see `set_recv_lowat()`
+    pub fn recv_lowat(&self) -> io::Result<usize> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.recv_lowat)
+    }
+
+    /// Set the send queue watermarks: writes block once `high` bytes are
+    /// queued and resume when the queue drained down to `low` bytes, so a
+    /// producer is paced by how fast the peer acknowledges data. This turns
+    /// off send buffer auto-tuning for the connection.
+    pub fn set_write_watermarks(&self, low: usize, high: usize) -> io::Result<()> {
+        if high == 0 || low > high {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid watermarks",
+            ));
+        }
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.watermarks = Watermarks { low, high };
+        conn.watermarks_locked = true;
+        drop(cm);
+        // Blocked writers may be able to proceed with the new watermarks
+        self.vars.send.notify_all();
+        Ok(())
+    }
+
+    /// Bytes written and not acknowledged by the peer yet, sent or not
+    pub fn bytes_unacked(&self) -> io::Result<usize> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.unacked.len())
+    }
+
+    /// Report the progress of sending: from now on, every ACK that takes
+    /// data off the send queue sends a `Delivery` with the bytes
+    /// acknowledged so far and the rate since the previous report. The
+    /// channel ends when the connection goes away; asking again replaces it.
+    pub fn deliveries(&self) -> io::Result<mpsc::Receiver<Delivery>> {
+        let mut cm = self.shard();
+        let now = self.ih.clock.now();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        let (tx, rx) = mpsc::channel();
+        let reporter = DeliveryReporter {
+            tx,
+            acked: conn.bytes_acked(),
+            at: now,
+        };
+        cm.deliveries.insert(self.quad.clone(), reporter);
+        Ok(rx)
+    }
+
+    /// Send what is queued and block until the peer acknowledged all of it,
+    /// failing with `TimedOut` if it hasn't by `deadline`, a time of the
+    /// interface's clock. The data stays queued then and is still
+    /// retransmitted.
+    pub fn flush_deadline(&self, deadline: time::Instant) -> io::Result<()> {
+        let mut cm = self.shard();
+        let mut pushed = false;
+        loop {
+            let conn = cm
+                .connections
+                .by_id_mut(self.id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+            if let Some(kind) = conn.error {
+                return Err(io::Error::from(kind));
+            }
+            if conn.unacked.is_empty() {
+                return Ok(());
+            }
+            if !pushed {
+                conn.push();
+                self.wake();
+                pushed = true;
+            }
+            self.ih.check_running()?;
+            let now = self.ih.clock.now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Data not acknowledged in time",
+                ));
+            }
+            // Writers are only woken up at the low watermark, so check the
+            // send queue regularly
+            cm = self
+                .vars
+                .send
+                .wait_timeout(cm, (deadline - now).min(time::Duration::from_millis(10)))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// A read wouldn't block: there is data, the peer closed its side or the
+    /// connection failed
+    pub fn is_read_ready(&self) -> bool {
+        self.consumed < self.buffered.len() || self.readiness().contains(Available::READ)
+    }
+
+    /// A write wouldn't block: there is room in the send queue or the
+    /// connection failed
+    pub fn is_write_ready(&self) -> bool {
+        self.readiness().contains(Available::WRITE)
+    }
+
+    fn readiness(&self) -> Available {
+        if self.ih.failed() {
+            return Available::all();
+        }
+        let cm = self.shard();
+        // Operations on a connection that is gone fail right away
+        cm.connections
+            .by_id(self.id)
+            .map_or(Available::all(), |conn| conn.readiness())
+    }
+
+    /// Take the soft error recorded on the connection, e.g. `TimedOut` after
+    /// data had to be retransmitted R1 times. The connection keeps running.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.take_error().map(io::Error::from))
+    }
+
+    /// Set the TCP user timeout (RFC 5482): how long transmitted data may
+    /// remain unacknowledged before the connection is aborted and reads and
+    /// writes fail with `TimedOut`. The timeout is also advertised to the
+    /// peer. A timeout advertised by the peer is only taken into account
+    /// once enabled with `set_adopt_user_timeout()`.
+    pub fn set_user_timeout(&self, timeout: time::Duration) -> io::Result<()> {
+        if timeout.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero user timeout",
+            ));
+        }
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_user_timeout(timeout);
+        self.wake();
+        Ok(())
+    }
+
+    /// Let the user timeout advertised by the peer raise the local one
+    /// (RFC 5482 Section 3)
+    pub fn set_adopt_user_timeout(&self, enable: bool) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_adopt_user_timeout(enable);
+        self.wake();
+        Ok(())
+    }
+
+    /// The user timeout currently in effect for the connection
+    pub fn user_timeout(&self) -> io::Result<Option<time::Duration>> {
+        let cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        Ok(conn.user_timeout())
+    }
+
+    /// Cap the rate at which the connection sends new data at
+    /// `bytes_per_sec`, independent of congestion control, e.g. for
+    /// background transfers. `None` removes the cap.
+    pub fn set_rate_limit(&self, bytes_per_sec: Option<u64>) -> io::Result<()> {
+        if bytes_per_sec == Some(0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Zero rate"));
+        }
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_rate_limit(bytes_per_sec);
+        self.wake();
+        Ok(())
+    }
+
+    /// Change the idle timeout inherited from the listener: the connection
+    /// is reset and reads and writes fail with `TimedOut` once it goes
+    /// `timeout` without activity. `None` exempts the connection.
+    pub fn set_idle_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        let mut cm = self.shard();
+
+        let conn = cm
+            .connections
+            .by_id_mut(self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))?;
+
+        conn.set_idle_timeout(timeout);
+        self.wake();
+        Ok(())
+    }
+}
+
+/// Move data between two connections of the same interface in both
+/// directions until neither peer has more to send, as a proxy does. Data
+/// goes straight from one connection's receive queue into the other's send
+/// queue; once a peer closes its side, the other connection is shut down
+/// for writing. Returns the bytes copied from `a` to `b` and from `b` to `a`.
+pub fn copy_bidirectional(a: &mut TcpStream, b: &mut TcpStream) -> io::Result<(u64, u64)> {
+    if !Arc::ptr_eq(&a.ih, &b.ih) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Streams belong to different interfaces",
+        ));
+    }
+    // Data `fill_buf` already took from the receive queues goes first
+    let copied = [write_unread(a, b)?, write_unread(b, a)?];
+
+    let ih = a.ih.clone();
+    // Blocked on both connections at once, the copy waits on condition
+    // variables both of them notify
+    let vars = Arc::new(StreamVars::default());
+    for stream in [&*a, &*b] {
+        stream
+            .shard()
+            .stream_vars
+            .insert(stream.quad.clone(), vars.clone());
+    }
+    let result = copy_both(&ih, [(a.worker, a.id), (b.worker, b.id)], &vars, copied);
+    for stream in [a, b] {
+        stream
+            .shard()
+            .stream_vars
+            .insert(stream.quad.clone(), stream.vars.clone());
+    }
+    result
+}
+
+fn copy_both(
+    ih: &InterfaceManager,
+    [(wa, a), (wb, b)]: [(usize, ConnectionId); 2],
+    vars: &StreamVars,
+    mut copied: [u64; 2],
+) -> io::Result<(u64, u64)> {
+    let mut done = [false; 2];
+    // Connections of two workers need both shards, locked in the order of
+    // the workers. The copy waits on the first one only, so data arriving
+    // on the other may wait for the next regular wake-up.
+    let mut first = ih.shard(wa.min(wb));
+    loop {
+        let mut second = (wa != wb).then(|| ih.shard(wa.max(wb)));
+        let pair = match second.as_deref_mut() {
+            None => first.connections.pair_mut(a, b),
+            Some(second) => {
+                let (sa, sb) = if wa < wb {
+                    (&mut *first, second)
+                } else {
+                    (second, &mut *first)
+                };
+                sa.connections.by_id_mut(a).zip(sb.connections.by_id_mut(b))
+            }
+        };
+        let Some((ca, cb)) = pair else {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Connection closed",
+            ));
+        };
+        let mut moved = false;
+        for i in 0..2 {
+            let (src, dst) = if i == 0 {
+                (&mut *ca, &mut *cb)
+            } else {
+                (&mut *cb, &mut *ca)
+            };
+            if !done[i] {
+                let (n, eof) = splice(&ih.outbox, src, dst)?;
+                copied[i] += n as u64;
+                done[i] = eof;
+                moved |= n > 0 || eof;
+            }
+        }
+        drop(second);
+        if moved {
+            // The data and FINs queued wait for the packet loop
+            ih.wakers[wa].wake();
+            ih.wakers[wb].wake();
+        }
+        if done == [true; 2] {
+            return Ok((copied[0], copied[1]));
+        }
+        if !moved {
+            ih.check_running()?;
+            // Data arriving wakes up the receive condition and the send
+            // queues draining the send one; wake up regularly for the latter
+            first = vars
+                .receive
+                .wait_timeout(first, time::Duration::from_millis(10))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+fn write_unread(src: &mut TcpStream, dst: &mut TcpStream) -> io::Result<u64> {
+    let unread = src.buffered[src.consumed..].to_vec();
+    io::BufRead::consume(src, unread.len());
+    io::Write::write_all(dst, &unread)?;
+    Ok(unread.len() as u64)
+}
+
+/// Move as much of `src`'s received data as `dst`'s send queue takes, and
+/// shut `dst` down for writing once `src` has nothing more to deliver.
+/// Returns the bytes moved and whether the direction is done.
+fn splice(
+    outbox: &Outbox,
+    src: &mut Connection,
+    dst: &mut Connection,
+) -> io::Result<(usize, bool)> {
+    if let Some(kind) = src.error.or(dst.error) {
+        return Err(io::Error::from(kind));
+    }
+    let room = dst.watermarks.high.saturating_sub(dst.unacked.len());
+    let n = std::cmp::min(room, src.ingress.len());
+    dst.record_with(|| Record::Queue(src.ingress.range(..n).copied().collect()));
+    src.record_with(|| Record::Consume(n));
+    dst.unacked.extend(src.ingress.drain(..n));
+    if n > 0 {
+        dst.push();
+        let _ = src.on_read();
+        transmit(outbox, src);
+    }
+    if src.is_recv_closed() && src.ingress.is_empty() {
+        dst.close()?;
+        return Ok((n, true));
+    }
+    Ok((n, false))
+}
+
+/// A descriptor to wait on the stream with poll or epoll. It is readable
+/// while a read wouldn't block, and after a write failed with `WouldBlock`
+/// once the write can be retried. Data buffered by `fill_buf` doesn't count.
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.readiness.as_raw_fd()
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut shard_guard = self.shard();
+        let shard = &mut *shard_guard;
+        shard.readiness.remove(&self.quad);
+        shard.stream_vars.remove(&self.quad);
+        let Some(conn) = shard.connections.by_id_mut(self.id) else {
+            return;
+        };
+
+        if conn.is_closed() {
+            shard.remove(&self.quad);
+            return;
+        }
+        if !conn.ingress.is_empty() || self.consumed < self.buffered.len() {
+            // Unread data would be lost: tell the peer by resetting the
+            // connection rather than closing it gracefully (RFC 2525 2.17)
+            let _ = conn.reset();
+            transmit(&self.ih.outbox, conn);
+            shard.remove(&self.quad);
+            drop(shard_guard);
+            self.ih.flush();
+            return;
+        }
+
+        // Send FIN once the queued data went out and let the connection
+        // finish closing in the background
+        let _ = conn.close();
+        conn.orphan();
+        self.wake();
+        // The orphans of every worker count, which needs their shards
+        drop(shard_guard);
+
+        if self.ih.orphan_stats().count > self.ih.max_orphans {
+            eprintln!("Too many orphaned connections, resetting {:?}", self.quad);
+            let mut shard = self.shard();
+            if let Some(conn) = shard.connections.by_id_mut(self.id) {
+                let _ = conn.reset();
+                transmit(&self.ih.outbox, conn);
+                shard.remove(&self.quad);
+            }
+            drop(shard);
+            self.ih.flush();
+        }
+    }
+}
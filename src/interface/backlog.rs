@@ -0,0 +1,58 @@
+//! The connections of a listener waiting to be accepted, in the order
+//! their SYNs arrived. A set of the queued quads keeps a connection from
+//! being queued twice, e.g. when its SYN was retransmitted while the SYN,ACK
+//! was in flight, which would hand it out to two accepts.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::tcp::connection::Tcp4Tuple;
+use crate::tcp::hash::QuadState;
+
+#[derive(Default)]
+pub(super) struct AcceptQueue {
+    order: VecDeque<Tcp4Tuple>,
+    queued: HashSet<Tcp4Tuple, QuadState>,
+}
+
+impl AcceptQueue {
+    /// Queue `quad` unless it is queued already
+    pub fn push(&mut self, quad: Tcp4Tuple) -> bool {
+        if !self.queued.insert(quad.clone()) {
+            return false;
+        }
+        self.order.push_back(quad);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Tcp4Tuple> {
+        let quad = self.order.pop_front()?;
+        self.queued.remove(&quad);
+        Some(quad)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Keep the quads for which `f` returns true
+    pub fn retain(&mut self, mut f: impl FnMut(&Tcp4Tuple) -> bool) {
+        let queued = &mut self.queued;
+        self.order.retain(|quad| {
+            let keep = f(quad);
+            if !keep {
+                queued.remove(quad);
+            }
+            keep
+        });
+    }
+
+    /// Take all quads out of the queue, oldest first
+    pub fn drain(&mut self) -> impl Iterator<Item = Tcp4Tuple> + '_ {
+        self.queued.clear();
+        self.order.drain(..)
+    }
+}
@@ -0,0 +1,240 @@
+//! Control socket: a Unix domain socket answering diagnostic queries about a
+//! running interface, one line of JSON per command. Commands:
+//!
+//! - `connections`: local and remote address, state and queues of every
+//!   connection
+//! - `stats`: listening ports, orphaned connections, memory pressure and
+//!   drop counters
+//! - `connection <local> <remote>`: the TCB snapshot of one connection
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddrV4;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::{fs, thread, time};
+
+use nix::poll;
+use serde::Serialize;
+
+use super::{InterfaceHandle, InterfaceManager, MemoryStats, OrphanStats};
+use crate::tcp::{connection::Tcp4Tuple, snapshot::TcbSnapshot, state::State};
+
+/// How often the server checks whether the interface went away
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// How long a reply may wait for the client to read earlier ones
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct ConnectionInfo {
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    state: State,
+    idle_for: time::Duration,
+    orphaned: bool,
+    ingress: usize,
+    unacked: usize,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    connections: usize,
+    listeners: Vec<u16>,
+    orphans: OrphanStats,
+    memory: MemoryStats,
+    drops: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Serialize)]
+struct Details {
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    snapshot: TcbSnapshot,
+}
+
+#[derive(Serialize)]
+struct Failure<'a> {
+    error: &'a str,
+}
+
+/// The control socket's server thread. Dropping it waits for the thread,
+/// which stops once the interface terminates, and removes the socket.
+pub(super) struct ControlSocket {
+    path: PathBuf,
+    jh: Option<thread::JoinHandle<()>>,
+}
+
+impl ControlSocket {
+    /// Listen on `path`, replacing a socket left behind by an earlier run.
+    /// Only the owner may connect, since the answers tell about every
+    /// connection.
+    pub(super) fn serve(path: &Path, ih: InterfaceHandle) -> io::Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        let jh = thread::spawn(move || {
+            if let Err(e) = accept_loop(&listener, &ih) {
+                eprintln!("Control socket failed: {:?}", e);
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+            jh: Some(jh),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Some(jh) = self.jh.take() {
+            let _ = jh.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn terminated(ih: &InterfaceManager) -> bool {
+    ih.terminate.load(Ordering::Relaxed)
+}
+
+/// Serve every client on a thread of its own until the interface
+/// terminates, so a client that stays connected doesn't lock out others
+fn accept_loop(listener: &UnixListener, ih: &InterfaceHandle) -> io::Result<()> {
+    let mut clients: Vec<thread::JoinHandle<()>> = Vec::new();
+    while !terminated(ih) {
+        clients.retain(|client| !client.is_finished());
+        let mut pfd = [poll::PollFd::new(listener.as_fd(), poll::PollFlags::POLLIN)];
+        if poll::poll(&mut pfd[..], POLL_INTERVAL.as_millis() as u16)? == 0 {
+            continue;
+        }
+        match listener.accept() {
+            Ok((client, _)) => {
+                let ih = ih.clone();
+                clients.push(thread::spawn(move || {
+                    if let Err(e) = serve_client(client, &ih) {
+                        eprintln!("Control client failed: {:?}", e);
+                    }
+                }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    // Clients notice within a poll interval
+    for client in clients {
+        let _ = client.join();
+    }
+    Ok(())
+}
+
+/// Answer the client's commands until it hangs up
+fn serve_client(client: UnixStream, ih: &InterfaceManager) -> io::Result<()> {
+    client.set_nonblocking(false)?;
+    client.set_read_timeout(Some(POLL_INTERVAL))?;
+    client.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut writer = client.try_clone()?;
+    let mut reader = BufReader::new(client);
+    let mut line = String::new();
+    loop {
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {
+                let reply = answer(ih, line.trim());
+                writeln!(writer, "{}", reply)?;
+                line.clear();
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if terminated(ih) {
+                    return Ok(());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn answer(ih: &InterfaceManager, command: &str) -> String {
+    let mut words = command.split_whitespace();
+    let reply = match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("connections"), None, ..) => serde_json::to_string(&connections(ih)),
+        (Some("stats"), None, ..) => serde_json::to_string(&stats(ih)),
+        (Some("connection"), Some(local), Some(remote), None) => {
+            match (local.parse(), remote.parse()) {
+                (Ok(local), Ok(remote)) => match details(ih, local, remote) {
+                    Some(details) => serde_json::to_string(&details),
+                    None => serde_json::to_string(&Failure {
+                        error: "no such connection",
+                    }),
+                },
+                _ => serde_json::to_string(&Failure {
+                    error: "bad address",
+                }),
+            }
+        }
+        _ => serde_json::to_string(&Failure {
+            error: "unknown command",
+        }),
+    };
+    reply.unwrap_or_else(|e| format!("{{\"error\":\"{}\"}}", e))
+}
+
+fn connections(ih: &InterfaceManager) -> Vec<ConnectionInfo> {
+    let mut infos = Vec::new();
+    for worker in 0..ih.shards.len() {
+        let shard = ih.shard(worker);
+        infos.extend(shard.connections.iter().map(|(quad, conn)| ConnectionInfo {
+            local: quad.local(),
+            remote: quad.remote(),
+            state: conn.state,
+            idle_for: conn.idle_for(),
+            orphaned: conn.orphaned_since().is_some(),
+            ingress: conn.ingress.len(),
+            unacked: conn.unacked.len(),
+        }));
+    }
+    infos.sort_by_key(|info| (info.local.port(), *info.remote.ip(), info.remote.port()));
+    infos
+}
+
+fn stats(ih: &InterfaceManager) -> Stats {
+    let mut listeners: Vec<u16> = ih.lock().listeners.keys().map(SocketAddrV4::port).collect();
+    listeners.sort_unstable();
+    listeners.dedup();
+    Stats {
+        connections: (0..ih.shards.len())
+            .map(|worker| ih.shard(worker).connections.len())
+            .sum(),
+        listeners,
+        orphans: ih.orphan_stats(),
+        memory: ih.memory_stats(),
+        drops: ih
+            .drop_stats()
+            .iter()
+            .map(|(reason, count)| (reason.as_str(), count))
+            .collect(),
+    }
+}
+
+fn details(ih: &InterfaceManager, local: SocketAddrV4, remote: SocketAddrV4) -> Option<Details> {
+    let quad = Tcp4Tuple {
+        src: (*remote.ip(), remote.port()),
+        dst: (*local.ip(), local.port()),
+    };
+    let shard = ih.shard_of(&quad);
+    shard.connections.get(&quad).map(|conn| Details {
+        local,
+        remote,
+        snapshot: conn.snapshot(),
+    })
+}
@@ -0,0 +1,211 @@
+//! Faults injected into chosen segments, for tests of retransmission and
+//! validation that need one particular segment lost, late, repeated or
+//! damaged rather than the random impairments of `Impairments`. Rules are
+//! matched in the order they were injected and the first one that fires
+//! decides what happens to a segment.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::tcp::history::Direction;
+
+/// Decides whether a segment is one a `FaultRule` is about. It runs on the
+/// threads sending and receiving packets and must not call back into the
+/// interface.
+pub type SegmentMatcher = Box<dyn Fn(&Ipv4HeaderSlice, &TcpHeaderSlice, &[u8]) -> bool + Send>;
+
+/// What happens to a segment a `FaultRule` fires on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Discard the segment
+    Drop,
+    /// Hold the segment back for this long, letting later ones overtake it
+    Delay(Duration),
+    /// Pass the segment on twice
+    Duplicate,
+    /// Flip a bit of the last byte of the segment, breaking its checksum
+    Corrupt,
+}
+
+/// Injects a fault into the segments going one way that a matcher picks:
+/// the `nth` of them, counting from 1, and the ones right after it up to
+/// `times` in all. See `Interface::inject_fault()`.
+pub struct FaultRule {
+    direction: Direction,
+    fault: Fault,
+    matcher: SegmentMatcher,
+    nth: usize,
+    times: usize,
+}
+
+impl FaultRule {
+    /// Inject `fault` into the first segment going `direction` that
+    /// `matcher` picks
+    pub fn new(
+        direction: Direction,
+        fault: Fault,
+        matcher: impl Fn(&Ipv4HeaderSlice, &TcpHeaderSlice, &[u8]) -> bool + Send + 'static,
+    ) -> Self {
+        Self {
+            direction,
+            fault,
+            matcher: Box::new(matcher),
+            nth: 1,
+            times: 1,
+        }
+    }
+
+    /// Skip the first `n - 1` segments matched
+    pub fn nth(mut self, n: usize) -> Self {
+        self.nth = n.max(1);
+        self
+    }
+
+    /// Fire on `n` segments in a row, `usize::MAX` for every one from the
+    /// `nth` on
+    pub fn times(mut self, n: usize) -> Self {
+        self.times = n;
+        self
+    }
+}
+
+/// Tells how often an injected fault fired
+#[derive(Debug, Clone)]
+pub struct FaultHandle {
+    applied: Arc<AtomicUsize>,
+}
+
+impl FaultHandle {
+    /// Segments the fault was injected into so far
+    pub fn applied(&self) -> usize {
+        self.applied.load(Ordering::Relaxed)
+    }
+}
+
+struct ActiveRule {
+    rule: FaultRule,
+    // Segments matched so far
+    matched: usize,
+    applied: Arc<AtomicUsize>,
+}
+
+impl ActiveRule {
+    /// Count a segment the rule matches and tell whether it fires on it
+    fn fires(&mut self, direction: Direction, packet: &[u8]) -> bool {
+        if self.rule.direction != direction
+            || !super::parse(packet)
+                .is_ok_and(|(ip, tcp, data)| (self.rule.matcher)(&ip, &tcp, data))
+        {
+            return false;
+        }
+        self.matched += 1;
+        let first = self.rule.nth;
+        let fires = self.matched >= first && self.matched - first < self.rule.times;
+        if fires {
+            self.applied.fetch_add(1, Ordering::Relaxed);
+        }
+        fires
+    }
+}
+
+/// Delayed segments by release time; the counter keeps the order of
+/// segments with the same release time
+type Delayed = BTreeMap<(Instant, u64), (Direction, Vec<u8>)>;
+
+/// The rules injected into an interface and the segments they hold back
+#[derive(Default)]
+pub(super) struct Faults {
+    rules: Mutex<Vec<ActiveRule>>,
+    delayed: Mutex<Delayed>,
+    counter: AtomicU64,
+}
+
+impl Faults {
+    pub fn inject(&self, rule: FaultRule) -> FaultHandle {
+        let applied = Arc::new(AtomicUsize::new(0));
+        self.rules.lock().unwrap().push(ActiveRule {
+            rule,
+            matched: 0,
+            applied: applied.clone(),
+        });
+        FaultHandle { applied }
+    }
+
+    /// Remove the rules; segments held back are still released
+    pub fn clear(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    /// No rules are injected, so segments pass untouched
+    pub fn is_empty(&self) -> bool {
+        self.rules.lock().unwrap().is_empty()
+    }
+
+    /// Pass a segment going `direction` to `deliver`, unless a rule drops
+    /// or delays it
+    pub fn apply(
+        &self,
+        direction: Direction,
+        mut packet: Vec<u8>,
+        now: Instant,
+        mut deliver: impl FnMut(Vec<u8>),
+    ) {
+        let fault = {
+            let mut rules = self.rules.lock().unwrap();
+            if rules.is_empty() {
+                drop(rules);
+                deliver(packet);
+                return;
+            }
+            // Every rule counts the segment, whichever fires
+            rules.iter_mut().fold(None, |fault, active| {
+                let fires = active.fires(direction, &packet);
+                fault.or(fires.then_some(active.rule.fault))
+            })
+        };
+        match fault {
+            None => deliver(packet),
+            Some(Fault::Drop) => {}
+            Some(Fault::Delay(delay)) => {
+                let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+                self.delayed
+                    .lock()
+                    .unwrap()
+                    .insert((now + delay, counter), (direction, packet));
+            }
+            Some(Fault::Duplicate) => {
+                deliver(packet.clone());
+                deliver(packet);
+            }
+            Some(Fault::Corrupt) => {
+                if let Some(last) = packet.last_mut() {
+                    *last ^= 1;
+                }
+                deliver(packet);
+            }
+        }
+    }
+
+    /// When the next delayed segment is due
+    pub fn release_at(&self) -> Option<Instant> {
+        let delayed = self.delayed.lock().unwrap();
+        delayed.keys().next().map(|(at, _)| *at)
+    }
+
+    /// Take the delayed segments that are due by `now`, in order
+    pub fn take_due(&self, now: Instant) -> Vec<(Direction, Vec<u8>)> {
+        let mut delayed = self.delayed.lock().unwrap();
+        let mut due = Vec::new();
+        while let Some(entry) = delayed.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            due.push(entry.remove());
+        }
+        due
+    }
+}
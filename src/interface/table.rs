@@ -0,0 +1,314 @@
+//! The connection table: connections live in a slab, a vector whose free
+//! slots are reused, and a map finds the slot of a quad. Streams keep the
+//! `ConnectionId` of their connection and reach it without hashing, and
+//! running the timers walks the slab in order.
+//!
+//! The table also counts the connections against the limits of the
+//! listeners that accepted them, so a SYN is checked without walking it.
+//! Connections stop counting in TIME-WAIT, which they reach while handed
+//! out mutably; the slots handed out are marked and looked at again before
+//! the counts are read.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::tcp::connection::{Connection, Tcp4Tuple};
+use crate::tcp::hash::QuadState;
+use crate::tcp::state::State;
+
+/// What a connection counts against: the listener that accepted it, and
+/// the network of the peer under the listener's per peer prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) struct LimitKey {
+    pub listener: SocketAddrV4,
+    pub network: Ipv4Addr,
+}
+
+/// Where a connection is stored. The generation tells a connection apart
+/// from later ones stored in the same slot, so a stale id finds nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ConnectionId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Default)]
+struct Slot {
+    generation: u32,
+    entry: Option<(Tcp4Tuple, Connection)>,
+    // What the connection is counted against, while it is
+    counted: Option<LimitKey>,
+    // Handed out mutably since it was last looked at
+    dirty: bool,
+}
+
+/// Connections counted against listener limits
+#[derive(Default)]
+struct LimitCounts {
+    per_listener: HashMap<SocketAddrV4, usize>,
+    per_network: HashMap<LimitKey, usize, QuadState>,
+}
+
+impl LimitCounts {
+    fn add(&mut self, key: LimitKey) {
+        *self.per_listener.entry(key.listener).or_default() += 1;
+        *self.per_network.entry(key).or_default() += 1;
+    }
+
+    fn subtract(&mut self, key: LimitKey) {
+        for count in [
+            self.per_listener.get_mut(&key.listener),
+            self.per_network.get_mut(&key),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *count -= 1;
+        }
+        if self.per_listener.get(&key.listener) == Some(&0) {
+            self.per_listener.remove(&key.listener);
+        }
+        if self.per_network.get(&key) == Some(&0) {
+            self.per_network.remove(&key);
+        }
+    }
+}
+
+/// Connections in TIME-WAIT or CLOSED don't count against limits, and
+/// never count again
+fn counts(conn: &Connection) -> bool {
+    !matches!(conn.state, State::TimeWait | State::Closed)
+}
+
+#[derive(Default)]
+pub(super) struct ConnectionTable {
+    slots: Vec<Slot>,
+    // Indexes of the empty slots
+    free: Vec<usize>,
+    ids: HashMap<Tcp4Tuple, ConnectionId, QuadState>,
+    limits: LimitCounts,
+    // Indexes of the dirty slots, unless all are
+    dirty: Vec<usize>,
+    all_dirty: bool,
+}
+
+impl ConnectionTable {
+    /// Store a connection, replacing the one with the same quad, counting
+    /// it against `limits` if given
+    pub fn insert(
+        &mut self,
+        quad: Tcp4Tuple,
+        conn: Connection,
+        limits: Option<LimitKey>,
+    ) -> ConnectionId {
+        self.remove(&quad);
+        let index = self.free.pop().unwrap_or_else(|| {
+            self.slots.push(Slot::default());
+            self.slots.len() - 1
+        });
+        let slot = &mut self.slots[index];
+        slot.counted = limits.filter(|_| counts(&conn));
+        if let Some(key) = slot.counted {
+            self.limits.add(key);
+        }
+        slot.entry = Some((quad.clone(), conn));
+        let id = ConnectionId {
+            index,
+            generation: slot.generation,
+        };
+        self.ids.insert(quad, id);
+        id
+    }
+
+    pub fn remove(&mut self, quad: &Tcp4Tuple) -> Option<Connection> {
+        let id = self.ids.remove(quad)?;
+        let slot = &mut self.slots[id.index];
+        slot.generation = slot.generation.wrapping_add(1);
+        if let Some(key) = slot.counted.take() {
+            self.limits.subtract(key);
+        }
+        self.free.push(id.index);
+        slot.entry.take().map(|(_, conn)| conn)
+    }
+
+    /// Connections counting against the limits of `key.listener`, in total
+    /// and from the peers in `key.network`
+    pub fn limit_counts(&mut self, key: &LimitKey) -> (usize, usize) {
+        self.settle();
+        (
+            self.limits
+                .per_listener
+                .get(&key.listener)
+                .copied()
+                .unwrap_or(0),
+            self.limits.per_network.get(key).copied().unwrap_or(0),
+        )
+    }
+
+    /// Count the connections of `listener` again, by the networks that
+    /// `network` maps their peers to
+    pub fn recount(&mut self, listener: SocketAddrV4, network: impl Fn(Ipv4Addr) -> Ipv4Addr) {
+        self.settle();
+        for slot in &mut self.slots {
+            let (Some((quad, _)), Some(key)) = (&slot.entry, &mut slot.counted) else {
+                continue;
+            };
+            if key.listener == listener {
+                self.limits.subtract(*key);
+                key.network = network(*quad.remote().ip());
+                self.limits.add(*key);
+            }
+        }
+    }
+
+    /// Stop counting the connections handed out since that reached
+    /// TIME-WAIT or CLOSED
+    fn settle(&mut self) {
+        if self.all_dirty {
+            self.all_dirty = false;
+            self.dirty.clear();
+            for index in 0..self.slots.len() {
+                self.settle_slot(index);
+            }
+        } else {
+            while let Some(index) = self.dirty.pop() {
+                self.settle_slot(index);
+            }
+        }
+    }
+
+    fn settle_slot(&mut self, index: usize) {
+        let slot = &mut self.slots[index];
+        slot.dirty = false;
+        if let Some((_, conn)) = &slot.entry {
+            if !counts(conn) {
+                if let Some(key) = slot.counted.take() {
+                    self.limits.subtract(key);
+                }
+            }
+        }
+    }
+
+    fn mark_dirty(&mut self, index: usize) {
+        let slot = &mut self.slots[index];
+        if !slot.dirty && !self.all_dirty && slot.counted.is_some() {
+            slot.dirty = true;
+            self.dirty.push(index);
+        }
+    }
+
+    pub fn id(&self, quad: &Tcp4Tuple) -> Option<ConnectionId> {
+        self.ids.get(quad).copied()
+    }
+
+    pub fn get(&self, quad: &Tcp4Tuple) -> Option<&Connection> {
+        self.by_id(self.id(quad)?)
+    }
+
+    pub fn get_mut(&mut self, quad: &Tcp4Tuple) -> Option<&mut Connection> {
+        self.by_id_mut(self.id(quad)?)
+    }
+
+    pub fn by_id(&self, id: ConnectionId) -> Option<&Connection> {
+        let slot = self.slots.get(id.index)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        slot.entry.as_ref().map(|(_, conn)| conn)
+    }
+
+    pub fn by_id_mut(&mut self, id: ConnectionId) -> Option<&mut Connection> {
+        if self.slots.get(id.index)?.generation != id.generation {
+            return None;
+        }
+        self.mark_dirty(id.index);
+        self.slots[id.index].entry.as_mut().map(|(_, conn)| conn)
+    }
+
+    /// Two different connections at once
+    pub fn pair_mut(
+        &mut self,
+        a: ConnectionId,
+        b: ConnectionId,
+    ) -> Option<(&mut Connection, &mut Connection)> {
+        if self.slots.get(a.index)?.generation != a.generation
+            || self.slots.get(b.index)?.generation != b.generation
+        {
+            return None;
+        }
+        self.mark_dirty(a.index);
+        self.mark_dirty(b.index);
+        let [sa, sb] = self.slots.get_disjoint_mut([a.index, b.index]).ok()?;
+        match (&mut sa.entry, &mut sb.entry) {
+            (Some((_, ca)), Some((_, cb))) => Some((ca, cb)),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "control")]
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Tcp4Tuple, &Connection)> {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.entry.as_ref().map(|(quad, conn)| (quad, conn)))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&Tcp4Tuple, &mut Connection)> {
+        self.all_dirty = true;
+        self.slots
+            .iter_mut()
+            .filter_map(|slot| slot.entry.as_mut().map(|(quad, conn)| (&*quad, conn)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Connection> {
+        self.iter().map(|(_, conn)| conn)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Connection> {
+        self.iter_mut().map(|(_, conn)| conn)
+    }
+
+    /// Keep the connections for which `f` returns true
+    pub fn retain(&mut self, mut f: impl FnMut(&Tcp4Tuple, &mut Connection) -> bool) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let Some((quad, conn)) = &mut slot.entry else {
+                continue;
+            };
+            let keep = f(quad, conn);
+            if !keep || !counts(conn) {
+                if let Some(key) = slot.counted.take() {
+                    self.limits.subtract(key);
+                }
+            }
+            if !keep {
+                self.ids.remove(quad);
+                slot.entry = None;
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(index);
+            }
+        }
+    }
+
+    /// Take all connections out of the table
+    pub fn drain(&mut self) -> Vec<(Tcp4Tuple, Connection)> {
+        self.ids.clear();
+        self.free.clear();
+        self.limits = LimitCounts::default();
+        self.dirty.clear();
+        self.all_dirty = false;
+        let mut drained = Vec::new();
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            slot.counted = None;
+            slot.dirty = false;
+            if let Some(entry) = slot.entry.take() {
+                slot.generation = slot.generation.wrapping_add(1);
+                drained.push(entry);
+            }
+            self.free.push(index);
+        }
+        drained
+    }
+}
@@ -1,34 +1,217 @@
-use std::io::Read;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Duration;
 
-use tcprs::Interface;
+use clap::{ArgAction, Parser, ValueEnum};
 
-fn main() -> io::Result<()> {
-    let mut iface = Interface::new()?;
-    let mut listener = iface.bind(6000)?;
-
-    while let Ok(mut stream) = listener.accept() {
-        eprintln!("Connected");
-        let jh1 = thread::spawn(move || {
-            if let Err(e) = stream.write(b"Hello\n") {
-                eprintln!("Error {:?}", e);
-            }
-            // Indicate CLOSE from our side
-            stream.shutdown(std::net::Shutdown::Write).unwrap();
-
-            loop {
-                let mut buf = [0u8; 512];
-                let n = stream.read(&mut buf).unwrap();
-                if n == 0 {
-                    println!("EOF");
-                    break;
-                }
-                println!("[{}]{}", n, std::string::String::from_utf8_lossy(&buf[..n]));
+use tcprs::bench::{self, RetransmitCounter};
+use tcprs::{Interface, TcpStream};
+
+/// Test server running on the userspace stack
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Args {
+    /// Port to listen on
+    #[arg(short, long, default_value_t = 6000)]
+    port: u16,
+    /// What to do with connections
+    #[arg(short, long, value_enum, default_value_t = Mode::Echo)]
+    mode: Mode,
+    /// Name of the tun device
+    #[arg(short, long, default_value = "tun0")]
+    device: String,
+    /// Assign ADDR/PREFIX to the device, e.g. 192.168.0.1/24
+    #[arg(short, long, value_parser = parse_address)]
+    address: Option<(Ipv4Addr, u8)>,
+    /// Serve at most this many connections at once, 0 for no limit
+    #[arg(short = 'c', long, default_value_t = 0)]
+    max_connections: usize,
+    /// Serve one connection at a time, as `-c 1`
+    #[arg(short, long)]
+    sequential: bool,
+    /// Seconds the send benchmark runs for
+    #[arg(long, default_value = "10", value_parser = parse_seconds)]
+    duration: Duration,
+    /// Size of the messages of the ping benchmark
+    #[arg(long, default_value_t = 64)]
+    size: usize,
+    /// Number of round trips of the ping benchmark
+    #[arg(long, default_value_t = 1000)]
+    count: usize,
+    /// Log connections, and with -vv every segment
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Mode {
+    /// Send back what is received (RFC 862)
+    Echo,
+    /// Throw away what is received (RFC 863)
+    Discard,
+    /// Send lines of characters until the peer closes (RFC 864)
+    Chargen,
+    /// Throw away what is received and report the goodput
+    Sink,
+    /// Send for --duration seconds and report the goodput
+    Send,
+    /// Time round trips of --count messages the peer echoes back
+    Ping,
+}
+
+fn parse_address(arg: &str) -> Result<(Ipv4Addr, u8), String> {
+    let (addr, prefix_len) = arg
+        .split_once('/')
+        .ok_or_else(|| "expected ADDR/PREFIX".to_string())?;
+    let addr = addr.parse().map_err(|e| format!("{}", e))?;
+    let prefix_len = prefix_len.parse().map_err(|e| format!("{}", e))?;
+    Ok((addr, prefix_len))
+}
+
+fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    let secs: f64 = arg.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("{}", e))
+}
+
+/// Counts the connections being served, making new ones wait for a slot
+struct Slots {
+    max: usize,
+    busy: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    fn acquire(&self) {
+        let mut busy = self.busy.lock().unwrap();
+        while self.max > 0 && *busy >= self.max {
+            busy = self.freed.wait(busy).unwrap();
+        }
+        *busy += 1;
+    }
+
+    fn release(&self) {
+        *self.busy.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+}
+
+fn echo(stream: &mut TcpStream) -> io::Result<u64> {
+    let mut buf = [0u8; 4096];
+    let mut total = 0;
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(total);
+        }
+        stream.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+fn discard(stream: &mut TcpStream) -> io::Result<u64> {
+    io::copy(stream, &mut io::sink())
+}
+
+/// The rotating 72-character lines of RFC 864
+fn chargen(stream: &mut TcpStream) -> io::Result<u64> {
+    const FIRST: u8 = b' ';
+    const COUNT: u8 = 95;
+    let mut line = [0u8; 74];
+    let mut total = 0;
+    for start in (0..COUNT).cycle() {
+        for (i, c) in line[..72].iter_mut().enumerate() {
+            *c = FIRST + (start + i as u8) % COUNT;
+        }
+        line[72..].copy_from_slice(b"\r\n");
+        match stream.write_all(&line) {
+            Ok(()) => total += line.len() as u64,
+            // The peer closing ends the stream
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+                ) =>
+            {
+                break
             }
-        });
-        let _ = jh1.join();
+            Err(e) => return Err(e),
+        }
     }
+    Ok(total)
+}
 
+fn serve(args: &Args, stream: &mut TcpStream, retransmits: &RetransmitCounter) -> io::Result<()> {
+    let peer = stream.peer_addr();
+    let bytes = match args.mode {
+        Mode::Echo => echo(stream)?,
+        Mode::Discard => discard(stream)?,
+        Mode::Chargen => chargen(stream)?,
+        Mode::Sink => {
+            println!("{}: {}", peer, bench::receive(stream, retransmits)?);
+            return Ok(());
+        }
+        Mode::Send => {
+            let report = bench::send(stream, args.duration, retransmits)?;
+            println!("{}: {}", peer, report);
+            return Ok(());
+        }
+        Mode::Ping => {
+            let report = bench::ping_pong(stream, args.size, args.count, retransmits)?;
+            println!("{}: {}", peer, report);
+            return Ok(());
+        }
+    };
+    // Chargen only stops once the peer reset the connection
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    if args.verbose > 0 {
+        eprintln!("{}: {} bytes", peer, bytes);
+    }
     Ok(())
 }
+
+fn main() -> io::Result<()> {
+    let args = Arc::new(Args::parse());
+    let retransmits = RetransmitCounter::default();
+    let observer = retransmits.clone();
+    let mut builder = Interface::builder()
+        .name(&args.device)
+        .trace(args.verbose > 1)
+        .on_event(move |e| observer.observe(e));
+    if let Some((addr, prefix_len)) = args.address {
+        builder = builder.address(addr, prefix_len);
+    }
+    let mut iface = builder.build()?;
+    let mut listener = iface.bind(args.port)?;
+    eprintln!(
+        "Serving {:?} on port {} of {}",
+        args.mode, args.port, args.device
+    );
+
+    let slots = Arc::new(Slots {
+        max: if args.sequential {
+            1
+        } else {
+            args.max_connections
+        },
+        busy: Mutex::new(0),
+        freed: Condvar::new(),
+    });
+    loop {
+        slots.acquire();
+        let mut stream = listener.accept()?;
+        if args.verbose > 0 {
+            eprintln!("{}: connected", stream.peer_addr());
+        }
+        let slots = slots.clone();
+        let args = args.clone();
+        let retransmits = retransmits.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(&args, &mut stream, &retransmits) {
+                eprintln!("{}: {}", stream.peer_addr(), e);
+            }
+            slots.release();
+        });
+    }
+}
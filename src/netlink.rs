@@ -0,0 +1,489 @@
+//! Minimal rtnetlink client used to configure the tun device.
+//!
+//! Only the handful of requests needed by the interface are implemented:
+//! adding/removing an IPv4 address, bringing a link up or down and
+//! installing/removing routes that point at the link. Requests
+//! are sent on a blocking `NETLINK_ROUTE` socket and each one waits for the
+//! kernel's acknowledgement so errors can be reported to the caller.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+const NLMSG_HDR_LEN: usize = 16;
+const NLMSG_ALIGNTO: usize = 4;
+const RECV_BUFFER_SIZE: usize = 8192;
+
+/// Round `len` up to the netlink alignment boundary
+fn align(len: usize) -> usize {
+    (len + NLMSG_ALIGNTO - 1) & !(NLMSG_ALIGNTO - 1)
+}
+
+/// A netlink request under construction.
+///
+/// The message starts with a `nlmsghdr` whose length is patched in when the
+/// message is finished. The family specific header and route attributes are
+/// appended in native byte order.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(msg_type: u16, flags: u16) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // nlmsg_len, patched later
+        buf.extend_from_slice(&msg_type.to_ne_bytes());
+        buf.extend_from_slice(
+            &(flags | libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16).to_ne_bytes(),
+        );
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // nlmsg_seq, set on send
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // nlmsg_pid, kernel fills in
+        Self { buf }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    /// Append a route attribute (`rtattr`) carrying `data`
+    fn push_attr(&mut self, attr_type: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.push(data);
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// A blocking `NETLINK_ROUTE` socket
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, seq: 0 })
+    }
+
+    /// Resolve the kernel index of the link called `name`
+    pub fn link_index(name: &str) -> io::Result<u32> {
+        let cname = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid link name"))?;
+        match unsafe { libc::if_nametoindex(cname.as_ptr()) } {
+            0 => Err(io::Error::last_os_error()),
+            index => Ok(index),
+        }
+    }
+
+    /// Assign `addr/prefix_len` to the link (`ip addr add`)
+    pub fn add_address(&mut self, index: u32, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        let msg = Self::address_message(
+            libc::RTM_NEWADDR,
+            (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+            index,
+            addr,
+            prefix_len,
+        );
+        self.request(msg)
+    }
+
+    /// Remove `addr/prefix_len` from the link (`ip addr del`)
+    pub fn del_address(&mut self, index: u32, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        let msg = Self::address_message(libc::RTM_DELADDR, 0, index, addr, prefix_len);
+        self.request(msg)
+    }
+
+    /// Bring the link up or down (`ip link set up/down`)
+    pub fn set_link_up(&mut self, index: u32, up: bool) -> io::Result<()> {
+        let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+        // struct ifinfomsg, pushed in one piece as its fields aren't aligned
+        let flags = if up { libc::IFF_UP as u32 } else { 0 };
+        let mut ifinfo = Vec::with_capacity(16);
+        ifinfo.extend_from_slice(&[libc::AF_UNSPEC as u8, 0]);
+        ifinfo.extend_from_slice(&0u16.to_ne_bytes()); // ifi_type
+        ifinfo.extend_from_slice(&(index as i32).to_ne_bytes());
+        ifinfo.extend_from_slice(&flags.to_ne_bytes());
+        ifinfo.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes()); // ifi_change
+        msg.push(&ifinfo);
+        self.request(msg)
+    }
+
+    /// Set the largest packet the link carries (`ip link set mtu`)
+    pub fn set_mtu(&mut self, index: u32, mtu: u32) -> io::Result<()> {
+        let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+        let mut ifinfo = Vec::with_capacity(16);
+        ifinfo.extend_from_slice(&[libc::AF_UNSPEC as u8, 0]);
+        ifinfo.extend_from_slice(&0u16.to_ne_bytes()); // ifi_type
+        ifinfo.extend_from_slice(&(index as i32).to_ne_bytes());
+        ifinfo.extend_from_slice(&0u32.to_ne_bytes()); // ifi_flags
+        ifinfo.extend_from_slice(&0u32.to_ne_bytes()); // ifi_change
+        msg.push(&ifinfo);
+        msg.push_attr(libc::IFLA_MTU, &mtu.to_ne_bytes());
+        self.request(msg)
+    }
+
+    /// Install a route to `dst/prefix_len` through the link (`ip route add`)
+    pub fn add_route(&mut self, index: u32, dst: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        let msg = Self::route_message(
+            libc::RTM_NEWROUTE,
+            (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+            index,
+            dst,
+            prefix_len,
+        );
+        self.request(msg)
+    }
+
+    /// Remove the route to `dst/prefix_len` through the link (`ip route del`)
+    pub fn del_route(&mut self, index: u32, dst: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        let msg = Self::route_message(libc::RTM_DELROUTE, 0, index, dst, prefix_len);
+        self.request(msg)
+    }
+
+    fn route_message(
+        msg_type: u16,
+        flags: u16,
+        index: u32,
+        dst: Ipv4Addr,
+        prefix_len: u8,
+    ) -> Message {
+        let mut msg = Message::new(msg_type, flags);
+        // struct rtmsg
+        msg.push(&[
+            libc::AF_INET as u8,
+            prefix_len,
+            0, // rtm_src_len
+            0, // rtm_tos
+            libc::RT_TABLE_MAIN,
+            libc::RTPROT_BOOT,
+            libc::RT_SCOPE_LINK,
+            libc::RTN_UNICAST,
+        ]);
+        msg.push(&0u32.to_ne_bytes()); // rtm_flags
+        msg.push_attr(libc::RTA_DST, &dst.octets());
+        msg.push_attr(libc::RTA_OIF, &index.to_ne_bytes());
+        msg
+    }
+
+    fn address_message(
+        msg_type: u16,
+        flags: u16,
+        index: u32,
+        addr: Ipv4Addr,
+        prefix_len: u8,
+    ) -> Message {
+        let mut msg = Message::new(msg_type, flags);
+        // struct ifaddrmsg
+        msg.push(&[libc::AF_INET as u8, prefix_len, 0, libc::RT_SCOPE_UNIVERSE]);
+        msg.push(&index.to_ne_bytes());
+        msg.push_attr(libc::IFA_LOCAL, &addr.octets());
+        msg.push_attr(libc::IFA_ADDRESS, &addr.octets());
+        msg
+    }
+
+    /// Send a request and wait for the kernel to acknowledge it
+    fn request(&mut self, msg: Message) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let buf = msg.finish(seq);
+        let n = unsafe { libc::send(self.fd.as_raw_fd(), buf.as_ptr().cast(), buf.len(), 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut resp = [0u8; RECV_BUFFER_SIZE];
+        loop {
+            let n =
+                unsafe { libc::recv(self.fd.as_raw_fd(), resp.as_mut_ptr().cast(), resp.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut msgs = &resp[..n as usize];
+            while msgs.len() >= NLMSG_HDR_LEN {
+                let len = u32::from_ne_bytes(msgs[0..4].try_into().unwrap()) as usize;
+                let msg_type = u16::from_ne_bytes(msgs[4..6].try_into().unwrap());
+                let msg_seq = u32::from_ne_bytes(msgs[8..12].try_into().unwrap());
+                if len < NLMSG_HDR_LEN || len > msgs.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Truncated netlink message",
+                    ));
+                }
+                if msg_seq == seq && msg_type == libc::NLMSG_ERROR as u16 {
+                    // struct nlmsgerr: a zero error code is an acknowledgement
+                    let errno = i32::from_ne_bytes(
+                        msgs[NLMSG_HDR_LEN..NLMSG_HDR_LEN + 4].try_into().unwrap(),
+                    );
+                    return match errno {
+                        0 => Ok(()),
+                        e => Err(io::Error::from_raw_os_error(-e)),
+                    };
+                }
+                msgs = &msgs[std::cmp::min(align(len), msgs.len())..];
+            }
+        }
+    }
+}
+
+/// Configuration applied to a link when the interface is created: the link
+/// is brought up, with an address assigned to it if one is configured.
+///
+/// The configuration is reverted when the value is dropped: the address is
+/// removed and the link is brought down again.
+pub struct LinkConfig {
+    netlink: Netlink,
+    index: u32,
+    address: Option<(Ipv4Addr, u8)>,
+}
+
+/// `addr` with the host bits beyond `prefix_len` cleared
+fn network(addr: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+    Ipv4Addr::from(u32::from(addr) & mask)
+}
+
+/// Make sure `prefix_len` is a valid IPv4 prefix length
+fn check_prefix_len(prefix_len: u8) -> io::Result<()> {
+    if prefix_len > 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid prefix length",
+        ));
+    }
+    Ok(())
+}
+
+impl LinkConfig {
+    /// Assign `address` to the link called `name`, if given, and bring it up
+    pub fn apply(name: &str, address: Option<(Ipv4Addr, u8)>) -> io::Result<Self> {
+        let mut netlink = Netlink::open()?;
+        let index = Netlink::link_index(name)?;
+        if let Some((addr, prefix_len)) = address {
+            check_prefix_len(prefix_len)?;
+            netlink.add_address(index, addr, prefix_len)?;
+        }
+        if let Err(e) = netlink.set_link_up(index, true) {
+            if let Some((addr, prefix_len)) = address {
+                let _ = netlink.del_address(index, addr, prefix_len);
+            }
+            return Err(e);
+        }
+        Ok(Self {
+            netlink,
+            index,
+            address,
+        })
+    }
+
+    /// Replace the address of the link, which stays up with its routes.
+    ///
+    /// Linux flushes the routes of a link once its last IPv4 address is
+    /// gone, and takes the other addresses of a subnet along with the first
+    /// one, so the new address goes in before the old one is removed, by
+    /// way of a host address when both are in the same subnet.
+    pub fn set_address(&mut self, address: Option<(Ipv4Addr, u8)>) -> io::Result<()> {
+        if address == self.address {
+            return Ok(());
+        }
+        let old = self.address;
+        let bridge = match (address, old) {
+            (Some((addr, prefix_len)), Some((old_addr, old_len)))
+                if prefix_len == old_len
+                    && network(addr, prefix_len) == network(old_addr, old_len) =>
+            {
+                self.netlink.add_address(self.index, addr, 32)?;
+                Some((addr, prefix_len))
+            }
+            (Some((addr, prefix_len)), _) => {
+                self.netlink.add_address(self.index, addr, prefix_len)?;
+                None
+            }
+            (None, _) => None,
+        };
+        if let Some((addr, prefix_len)) = old {
+            self.netlink.del_address(self.index, addr, prefix_len)?;
+        }
+        self.address = address;
+        if let Some((addr, prefix_len)) = bridge {
+            self.netlink.add_address(self.index, addr, prefix_len)?;
+            self.netlink.del_address(self.index, addr, 32)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LinkConfig {
+    fn drop(&mut self) {
+        if let Err(e) = self.netlink.set_link_up(self.index, false) {
+            // A tun device that isn't persistent disappears, together with
+            // its configuration, once its file descriptor is closed
+            if e.raw_os_error() == Some(libc::ENODEV) {
+                return;
+            }
+            eprintln!("Failed to bring link down: {:?}", e);
+        }
+        if let Some((addr, prefix_len)) = self.address {
+            if let Err(e) = self.netlink.del_address(self.index, addr, prefix_len) {
+                eprintln!("Failed to remove address {}: {:?}", addr, e);
+            }
+        }
+    }
+}
+
+/// A route pointing at a link, installed when the interface is created.
+///
+/// The route is removed again when the value is dropped.
+pub struct RouteConfig {
+    netlink: Netlink,
+    index: u32,
+    dst: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl RouteConfig {
+    /// Route `dst/prefix_len` through the link called `name`. Host bits of
+    /// `dst` beyond the prefix are cleared, since the kernel rejects them.
+    pub fn apply(name: &str, dst: Ipv4Addr, prefix_len: u8) -> io::Result<Self> {
+        check_prefix_len(prefix_len)?;
+        let dst = network(dst, prefix_len);
+
+        let mut netlink = Netlink::open()?;
+        let index = Netlink::link_index(name)?;
+        netlink.add_route(index, dst, prefix_len)?;
+        Ok(Self {
+            netlink,
+            index,
+            dst,
+            prefix_len,
+        })
+    }
+}
+
+impl Drop for RouteConfig {
+    fn drop(&mut self) {
+        if let Err(e) = self
+            .netlink
+            .del_route(self.index, self.dst, self.prefix_len)
+        {
+            // Routes through the link vanish along with it when it goes down
+            if !matches!(e.raw_os_error(), Some(libc::ESRCH | libc::ENODEV)) {
+                eprintln!(
+                    "Failed to remove route {}/{}: {:?}",
+                    self.dst, self.prefix_len, e
+                );
+            }
+        }
+    }
+}
+
+/// The address, routes and MTU of the interface's link, applied again when
+/// the device is reopened. The address and routes are reverted when the
+/// value is dropped, routes first.
+pub struct LinkSetup {
+    address: Option<(Ipv4Addr, u8)>,
+    routes: Vec<(Ipv4Addr, u8)>,
+    mtu: Option<u32>,
+    link: Option<LinkConfig>,
+    installed: Vec<RouteConfig>,
+}
+
+impl LinkSetup {
+    pub fn new(
+        address: Option<(Ipv4Addr, u8)>,
+        routes: Vec<(Ipv4Addr, u8)>,
+        mtu: Option<u32>,
+    ) -> Self {
+        Self {
+            address,
+            routes,
+            mtu,
+            link: None,
+            installed: Vec::new(),
+        }
+    }
+
+    /// Configure the link called `name`, reverting what was applied before
+    pub fn apply(&mut self, name: &str) -> io::Result<()> {
+        self.revert();
+        if let Some(mtu) = self.mtu {
+            Netlink::open()?.set_mtu(Netlink::link_index(name)?, mtu)?;
+        }
+        // Routes can only be installed on a link that is up, so it is
+        // brought up for them even without an address
+        if self.address.is_some() || !self.routes.is_empty() {
+            self.link = Some(LinkConfig::apply(name, self.address)?);
+        }
+        for &(dst, prefix_len) in &self.routes {
+            self.installed
+                .push(RouteConfig::apply(name, dst, prefix_len)?);
+        }
+        Ok(())
+    }
+
+    /// Replace the address of the link called `name`, leaving the link up
+    /// and its routes in place
+    pub fn set_address(&mut self, name: &str, address: Option<(Ipv4Addr, u8)>) -> io::Result<()> {
+        if let Some((_, prefix_len)) = address {
+            check_prefix_len(prefix_len)?;
+        }
+        match &mut self.link {
+            Some(link) => link.set_address(address)?,
+            None if address.is_some() => self.link = Some(LinkConfig::apply(name, address)?),
+            None => {}
+        }
+        self.address = address;
+        Ok(())
+    }
+
+    /// Set the MTU of the link called `name`
+    pub fn set_mtu(&mut self, name: &str, mtu: u32) -> io::Result<()> {
+        Netlink::open()?.set_mtu(Netlink::link_index(name)?, mtu)?;
+        self.mtu = Some(mtu);
+        Ok(())
+    }
+
+    pub fn revert(&mut self) {
+        self.installed.clear();
+        drop(self.link.take());
+    }
+}
+
+impl Drop for LinkSetup {
+    fn drop(&mut self) {
+        self.revert();
+    }
+}
@@ -0,0 +1,96 @@
+//! Descriptors that signal the readiness of streams and listeners, so event
+//! loops built on poll or epoll can wait on them along with other files,
+//! and the one that wakes up the packet loop.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// An eventfd that is readable while the object it belongs to is ready.
+/// The packet loop sets and clears it; reading it is never necessary.
+#[derive(Debug)]
+pub(crate) struct ReadinessFd {
+    fd: OwnedFd,
+    signaled: AtomicBool,
+}
+
+/// A non-blocking eventfd
+fn eventfd() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl ReadinessFd {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Self {
+            fd: eventfd()?,
+            signaled: AtomicBool::new(false),
+        })
+    }
+
+    /// Make the descriptor readable or not. Only transitions touch it.
+    pub(crate) fn set(&self, ready: bool) {
+        if self.signaled.swap(ready, Ordering::AcqRel) == ready {
+            return;
+        }
+        let mut value = 1u64;
+        let result = unsafe {
+            if ready {
+                libc::write(self.fd.as_raw_fd(), (&raw const value).cast(), 8)
+            } else {
+                libc::read(self.fd.as_raw_fd(), (&raw mut value).cast(), 8)
+            }
+        };
+        if result < 0 {
+            eprintln!(
+                "Error signaling readiness: {:?}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+impl AsRawFd for ReadinessFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// An eventfd that becomes readable when there is work for the packet loop
+/// besides arriving packets, e.g. data the application queued
+#[derive(Debug)]
+pub(crate) struct Waker {
+    fd: OwnedFd,
+}
+
+impl Waker {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Self { fd: eventfd()? })
+    }
+
+    /// Make the descriptor readable until it is drained
+    pub(crate) fn wake(&self) {
+        let value = 1u64;
+        if unsafe { libc::write(self.fd.as_raw_fd(), (&raw const value).cast(), 8) } < 0 {
+            eprintln!(
+                "Error waking the packet loop: {:?}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Consume the wake-ups so far
+    pub(crate) fn drain(&self) {
+        let mut value = 0u64;
+        unsafe { libc::read(self.fd.as_raw_fd(), (&raw mut value).cast(), 8) };
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
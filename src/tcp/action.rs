@@ -0,0 +1,71 @@
+use alloc::vec::Vec;
+
+use etherparse::{Ipv4Header, TcpHeader};
+
+use super::time::Instant;
+
+/// Room for the headers of a packet: IPv4 and TCP headers with the largest
+/// options each
+const MAX_HEADERS: usize = 2 * 60;
+
+/// Something a connection needs its owner to do. The protocol core does no
+/// I/O of its own: processing a segment, a timer or a user call queues
+/// actions, which the owner takes with `Connection::take_actions` and
+/// carries out on whatever device and event loop it uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Send an IPv4 packet carrying a segment
+    Transmit(Packet),
+    /// Bytes were appended to the receive queue
+    Deliver(usize),
+    /// Call `on_timer` again no later than this
+    Timer(Instant),
+}
+
+/// An IPv4 packet carrying a segment, with the headers kept apart from the
+/// payload so that the owner can hand both to the device in one vectored
+/// write without joining them. The payload is a buffer of the connection's
+/// pool, which goes back to it once the packet is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    headers: [u8; MAX_HEADERS],
+    headers_len: usize,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    pub(crate) fn new(ip: &Ipv4Header, tcp: &TcpHeader, payload: Vec<u8>) -> Self {
+        let ip = ip.to_bytes();
+        let tcp = tcp.to_bytes();
+        let mut headers = [0; MAX_HEADERS];
+        headers[..ip.len()].copy_from_slice(&ip);
+        headers[ip.len()..ip.len() + tcp.len()].copy_from_slice(&tcp);
+        Self {
+            headers,
+            headers_len: ip.len() + tcp.len(),
+            payload,
+        }
+    }
+
+    /// The IPv4 and TCP headers
+    pub fn headers(&self) -> &[u8] {
+        &self.headers[..self.headers_len]
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// The payload buffer, to give back to the pool once sent
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+
+    /// The whole packet in the payload buffer, for devices and tools that
+    /// need it in one piece
+    pub fn into_vec(self) -> Vec<u8> {
+        let mut packet = self.payload;
+        packet.splice(..0, self.headers[..self.headers_len].iter().copied());
+        packet
+    }
+}
@@ -0,0 +1,191 @@
+use super::time::{Duration, Instant};
+
+/// Largest window that can be advertised without window scaling
+pub const MAX_WINDOW: usize = u16::MAX as usize;
+/// Largest shift count of the Window Scale option RFC 7323 Section 2.3
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Smallest shift count that lets a window of `size` bytes be advertised
+pub fn window_scale(size: usize) -> u8 {
+    let mut shift = 0;
+    while shift < MAX_WINDOW_SCALE && size >> shift > MAX_WINDOW {
+        shift += 1;
+    }
+    shift
+}
+
+/// Receive buffer that sizes itself after the connection's bandwidth-delay
+/// product, modelled on Linux's dynamic right-sizing (DRS).
+///
+/// The receiver estimates the round trip time by timing how long it takes
+/// the sender to fill the window that was advertised: the sender cannot send
+/// beyond RCV.NXT + RCV.WND before it has seen the advertisement, so the
+/// edge being reached marks one round trip. Once per round trip the bytes
+/// received during it are compared against the buffer, and the buffer is
+/// grown to twice that amount (up to `max`) when the sender managed to fill
+/// more than the previous measurement, so the window never throttles a
+/// sender that could go faster.
+#[derive(Debug)]
+pub struct ReceiveBuffer {
+    /// current buffer size
+    size: usize,
+    /// limit for auto-tuning
+    max: usize,
+    /// grow the buffer with the measured bandwidth-delay product
+    autotune: bool,
+    /// smoothed receiver side round trip time
+    rtt: Option<Duration>,
+    /// window edge being timed for an rtt sample and when it was advertised
+    rtt_edge: Option<(u32, Instant)>,
+    /// start of the current measurement period
+    period_start: Instant,
+    /// bytes received during the current measurement period
+    period_bytes: usize,
+    /// most bytes received in a single round trip so far
+    space: usize,
+    /// don't grow for now
+    held: bool,
+    /// shift count of the advertised windows
+    scale: u8,
+}
+
+impl ReceiveBuffer {
+    pub fn new(size: usize, max: usize, autotune: bool, now: Instant) -> Self {
+        Self {
+            size,
+            max: core::cmp::max(size, max),
+            autotune,
+            rtt: None,
+            rtt_edge: None,
+            period_start: now,
+            period_bytes: 0,
+            space: size,
+            held: false,
+            scale: 0,
+        }
+    }
+
+    /// Advertise windows shifted by `scale`, as agreed with the peer
+    pub fn set_scale(&mut self, scale: u8) {
+        self.scale = scale;
+    }
+
+    /// Stop growing the buffer, or resume
+    pub fn hold(&mut self, held: bool) {
+        self.held = held;
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Window to advertise with `buffered` bytes waiting to be read, no
+    /// more than the window field shifted by the scale can carry
+    pub fn window(&self, buffered: usize) -> u32 {
+        core::cmp::min(self.size.saturating_sub(buffered), MAX_WINDOW << self.scale) as u32
+    }
+
+    /// Called when a window is advertised: start timing how long the
+    /// sender takes to reach its right edge, unless a sample is under way
+    pub fn on_advertise(&mut self, nxt: u32, wnd: u32, now: Instant) {
+        if self.autotune && self.rtt_edge.is_none() && wnd > 0 {
+            self.rtt_edge = Some((nxt.wrapping_add(wnd), now));
+        }
+    }
+
+    /// Called for `len` bytes of new data that moved RCV.NXT to `nxt`
+    pub fn on_data(&mut self, nxt: u32, len: usize, now: Instant) {
+        if !self.autotune {
+            return;
+        }
+
+        if let Some((edge, at)) = self.rtt_edge {
+            // RCV.NXT reached the edge we were timing: one round trip
+            if edge.wrapping_sub(nxt) as i32 <= 0 {
+                let sample = now - at;
+                self.rtt = Some(match self.rtt {
+                    Some(rtt) => (rtt * 7 + sample) / 8,
+                    None => sample,
+                });
+                self.rtt_edge = None;
+            }
+        }
+
+        self.period_bytes += len;
+        let Some(rtt) = self.rtt else {
+            return;
+        };
+        if now - self.period_start < rtt {
+            return;
+        }
+
+        // A round trip worth of data: grow if the sender filled more than before
+        if self.period_bytes > self.space {
+            self.space = self.period_bytes;
+            let size = core::cmp::min(2 * self.space, self.max);
+            if size > self.size && !self.held {
+                self.size = size;
+            }
+        }
+        self.period_start = now;
+        self.period_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn window_is_free_space_up_to_the_field() {
+        let buffer = ReceiveBuffer::new(1 << 20, 1 << 20, false, Instant::now());
+        assert_eq!(buffer.window(0), MAX_WINDOW as u32);
+        assert_eq!(buffer.window((1 << 20) - 100), 100);
+        assert_eq!(buffer.window(2 << 20), 0);
+    }
+
+    #[test]
+    fn scaled_windows_go_past_the_field() {
+        assert_eq!(window_scale(MAX_WINDOW), 0);
+        assert_eq!(window_scale(MAX_WINDOW + 1), 1);
+        assert_eq!(window_scale(6 << 20), 7);
+        assert_eq!(window_scale(usize::MAX), MAX_WINDOW_SCALE);
+
+        let mut buffer = ReceiveBuffer::new(1 << 20, 1 << 20, false, Instant::now());
+        buffer.set_scale(window_scale(1 << 20));
+        assert_eq!(buffer.window(0), 1 << 20);
+    }
+
+    #[test]
+    fn grows_to_twice_a_round_trip_of_data() {
+        let now = Instant::now();
+        let mut buffer = ReceiveBuffer::new(1000, 10_000, true, now);
+        buffer.on_advertise(0, 1000, now);
+        // Short of the advertised edge: no round trip measured yet
+        buffer.on_data(500, 500, now + RTT / 2);
+        assert_eq!(buffer.size(), 1000);
+        // The sender reached the edge one round trip later with 2000 bytes
+        buffer.on_data(2000, 1500, now + RTT);
+        assert_eq!(buffer.size(), 4000);
+    }
+
+    #[test]
+    fn stays_put_without_autotuning() {
+        let now = Instant::now();
+        let mut buffer = ReceiveBuffer::new(1000, 10_000, false, now);
+        buffer.on_advertise(0, 1000, now);
+        buffer.on_data(2000, 2000, now + RTT);
+        assert_eq!(buffer.size(), 1000);
+    }
+
+    #[test]
+    fn never_grows_past_the_limit() {
+        let now = Instant::now();
+        let mut buffer = ReceiveBuffer::new(1000, 3000, true, now);
+        buffer.on_advertise(0, 1000, now);
+        buffer.on_data(5000, 5000, now + RTT);
+        assert_eq!(buffer.size(), 3000);
+    }
+}
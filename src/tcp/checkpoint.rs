@@ -0,0 +1,43 @@
+use alloc::vec::Vec;
+use core::net::SocketAddrV4;
+
+use super::state::State;
+
+/// What a synchronized connection needs to carry on in another instance of
+/// the stack: its addresses, sequence spaces and buffered data. Timers and
+/// congestion state start over after a restore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SavedConnection {
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+    pub state: State,
+    /// send sequence space
+    pub snd_iss: u32,
+    pub snd_una: u32,
+    pub snd_wnd: u32,
+    pub snd_wl1: u32,
+    pub snd_wl2: u32,
+    /// receive sequence space
+    pub rcv_irs: u32,
+    pub rcv_nxt: u32,
+    /// size of the receive buffer
+    pub recv_buffer: usize,
+    /// bytes received and not read yet
+    pub ingress: Vec<u8>,
+    /// bytes queued for sending and not acknowledged, sent or not
+    pub unacked: Vec<u8>,
+    /// the application closed the connection
+    pub closed: bool,
+    /// sequence number of our FIN, once the peer acknowledged it
+    pub fin_acked_at: Option<u32>,
+    /// the Maximum Segment Size the peer announced on its SYN
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub peer_mss: Option<u16>,
+    /// shift counts of the windows received and sent, 0 without window
+    /// scaling
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub snd_wscale: u8,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rcv_wscale: u8,
+}
@@ -0,0 +1,27 @@
+//! TCP checksums from a sum of the payload computed once. The checksum is a
+//! one's complement sum (RFC 1071), so the sum of a segment's payload can be
+//! kept while it is unacknowledged and a retransmission only sums its
+//! headers again, whose acknowledgment number and window have changed.
+
+use etherparse::checksum::u64_16bit_word;
+use etherparse::{IpNumber, Ipv4Header, TcpHeader};
+
+/// One's complement sum of `payload`, folded to 16 bits
+pub fn payload_sum(payload: &[u8]) -> u16 {
+    !u64_16bit_word::ones_complement(u64_16bit_word::add_slice(0, payload))
+}
+
+/// Checksum of `tcp` sent in `ip` with a payload of `len` bytes whose
+/// `payload_sum()` is `sum`. The checksum field of `tcp` is ignored.
+pub fn tcp_checksum(ip: &Ipv4Header, tcp: &TcpHeader, len: usize, sum: u16) -> u16 {
+    let tcp_len = (tcp.header_len() + len) as u16;
+    let mut header = tcp.to_bytes();
+    header[16..18].fill(0);
+    let mut total = u64_16bit_word::add_4bytes(0, ip.source);
+    total = u64_16bit_word::add_4bytes(total, ip.destination);
+    total = u64_16bit_word::add_2bytes(total, [0, IpNumber::TCP.0]);
+    total = u64_16bit_word::add_2bytes(total, tcp_len.to_be_bytes());
+    total = u64_16bit_word::add_slice(total, &header);
+    total = u64_16bit_word::add_2bytes(total, sum.to_ne_bytes());
+    u64_16bit_word::ones_complement(total).to_be()
+}
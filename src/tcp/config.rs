@@ -0,0 +1,247 @@
+use alloc::sync::Arc;
+
+use super::congestion::{CongestionAlgorithm, INITIAL_WINDOW};
+use super::pool::BufferPool;
+use super::ratelimit::RateLimit;
+#[cfg(feature = "std")]
+use super::time::SystemClock;
+use super::time::{Clock, Duration, Instant};
+
+/// Linux default for `net.ipv4.tcp_fin_timeout`
+const FIN_WAIT2_TIMEOUT: Duration = Duration::from_secs(60);
+/// Linux default for `net.ipv4.tcp_synack_retries`
+const SYNACK_RETRIES: u32 = 5;
+/// RFC 1122 Section 4.2.3.5 recommends R1 of at least 3 retransmissions
+/// and R2 of at least 100 seconds
+const R1: Threshold = Threshold::Retransmissions(3);
+const R2: Threshold = Threshold::Time(Duration::from_secs(100));
+/// Send queue size at which writers block, and the level it has to drain to
+/// before they are woken up again
+const SEND_HIGH_WATERMARK: usize = 1024;
+const SEND_LOW_WATERMARK: usize = SEND_HIGH_WATERMARK / 2;
+/// Limit for send buffer auto-tuning
+const SEND_BUFFER_MAX: usize = 4 * 1024 * 1024;
+/// Initial receive buffer, the window advertised before auto-tuning kicks
+/// in
+const RECV_BUFFER: usize = 64 * 1024;
+/// Limit for receive buffer auto-tuning, which also sets the window scale
+/// announced to peers
+const RECV_BUFFER_MAX: usize = 6 * 1024 * 1024;
+/// Limit on connections left behind by dropped streams
+const MAX_ORPHANS: usize = 1024;
+const ORPHAN_TIMEOUT: Duration = Duration::from_secs(60);
+/// MTU of Ethernet, assumed where no device tells otherwise
+const MTU: usize = 1500;
+/// Linux default for `net.ipv4.ip_default_ttl`
+const TTL: u8 = 64;
+
+/// Retransmission threshold RFC 1122 Section 4.2.3.5, measured either as a
+/// number of retransmissions of the same segment or as the time spent
+/// retransmitting it
+#[derive(Debug, Clone, Copy)]
+pub enum Threshold {
+    Retransmissions(u32),
+    Time(Duration),
+}
+
+impl Threshold {
+    /// Has the threshold been reached after `retransmits` retransmissions
+    /// of data outstanding since `since`, at `now`
+    pub fn reached(&self, retransmits: u32, since: Option<Instant>, now: Instant) -> bool {
+        match *self {
+            Self::Retransmissions(count) => retransmits >= count,
+            Self::Time(limit) => {
+                since.is_some_and(|since| now.saturating_duration_since(since) >= limit)
+            }
+        }
+    }
+}
+
+/// Send queue thresholds used to apply backpressure to writers: writes block
+/// once `high` bytes are queued, and resume when the queue drained to `low`
+#[derive(Debug, Clone, Copy)]
+pub struct Watermarks {
+    pub low: usize,
+    pub high: usize,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Self {
+            low: SEND_LOW_WATERMARK,
+            high: SEND_HIGH_WATERMARK,
+        }
+    }
+}
+
+/// Limits on the bytes held in the send and receive queues of all the
+/// connections of an interface, like Linux's `net.ipv4.tcp_mem`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MemoryLimits {
+    /// Beyond this, queues stop growing and receive windows open by half
+    /// as much
+    pub soft: usize,
+    /// Beyond this, receive windows stop opening and new connections are
+    /// refused
+    pub hard: usize,
+}
+
+impl MemoryLimits {
+    /// The pressure `used` bytes put on the limits
+    pub fn pressure(&self, used: usize) -> MemoryPressure {
+        if used > self.hard {
+            MemoryPressure::Hard
+        } else if used > self.soft {
+            MemoryPressure::Soft
+        } else {
+            MemoryPressure::Normal
+        }
+    }
+}
+
+/// Which of the `MemoryLimits` the connections of an interface are over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MemoryPressure {
+    #[default]
+    Normal,
+    Soft,
+    Hard,
+}
+
+/// Tunables applied to the connections accepted on an interface
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long a connection may wait in FIN-WAIT-2 for the peer's FIN
+    /// before it is closed. `None` waits forever.
+    pub fin_wait2_timeout: Option<Duration>,
+    /// How many times a SYN-ACK is retransmitted before a connection that
+    /// never completed the handshake is dropped
+    pub synack_retries: u32,
+    /// Retransmissions after which a soft error is recorded and the
+    /// retransmission hook is invoked so routes can be re-evaluated (R1)
+    pub r1: Threshold,
+    /// Retransmissions after which the connection is aborted (R2)
+    pub r2: Threshold,
+    /// Send a reset to the peer when the FIN-WAIT-2 timer expires
+    pub fin_wait2_reset: bool,
+    /// Initial size of the receive buffer, which determines the advertised
+    /// window
+    pub recv_buffer: usize,
+    /// Size up to which the receive buffer may grow with auto-tuning
+    pub recv_buffer_max: usize,
+    /// Grow the receive buffer with the connection's bandwidth-delay product
+    pub recv_buffer_autotune: bool,
+    /// Offer to scale windows (RFC 7323 Section 2), so they can exceed
+    /// 65535 bytes
+    pub window_scaling: bool,
+    /// Detect spurious retransmission timeouts with F-RTO (RFC 5682)
+    pub frto: bool,
+    /// Send tail loss probes (RFC 8985 Section 7)
+    pub tlp: bool,
+    /// Detect losses with RACK (RFC 8985 Section 6)
+    pub rack: bool,
+    /// Spread transmissions over the round trip time
+    pub pacing: bool,
+    /// Congestion control algorithm
+    pub congestion: CongestionAlgorithm,
+    /// Log every segment sent and received in a tcpdump-like format
+    pub trace: bool,
+    /// Record every state transition with what triggered it
+    pub record_transitions: bool,
+    /// Segments each connection keeps a summary of, 0 for none
+    pub segment_history: usize,
+    /// Record the congestion window of new connections over time
+    pub record_cwnd: bool,
+    /// Record the inputs of new connections so they can be replayed
+    pub record: bool,
+    /// Initial congestion window in segments
+    pub initial_window: usize,
+    /// Default send queue watermarks
+    pub send_watermarks: Watermarks,
+    /// Size up to which the send queue may grow with auto-tuning
+    pub send_buffer_max: usize,
+    /// Grow the send queue with the congestion window
+    pub send_buffer_autotune: bool,
+    /// Maximum number of orphaned connections (whose stream was dropped
+    /// while the connection was still closing). Orphans beyond the limit
+    /// are reset immediately.
+    pub max_orphans: usize,
+    /// How long an orphaned connection may linger before it is reset
+    pub orphan_timeout: Duration,
+    /// How long a connection may go without receiving a segment or sending
+    /// data before it is reset. `None` keeps idle connections forever.
+    pub idle_timeout: Option<Duration>,
+    /// Let the User Timeout advertised by the peer adjust the local one
+    /// (RFC 5482 Section 3)
+    pub adopt_user_timeout: bool,
+    /// How many connections a single remote address may open on a
+    /// listener; SYNs beyond the limit are dropped. `None` is unlimited.
+    pub syn_rate_limit: Option<RateLimit>,
+    /// Limits on the memory the queues of all connections take. `None` is
+    /// unlimited.
+    pub memory_limits: Option<MemoryLimits>,
+    /// Source of the current time for timers and measurements
+    pub clock: Arc<dyn Clock>,
+    /// Largest IP packet sent and received, which sets the MSS. An
+    /// interface takes it from its device.
+    pub mtu: usize,
+    /// Time to live of the packets sent
+    pub ttl: u8,
+    /// Where the segments sent are built. With `std` it is the process-wide
+    /// `PACKETS` pool; without, buffers are allocated.
+    pub buffers: &'static dyn BufferPool,
+}
+
+impl Config {
+    /// The defaults, with the time read from `clock`
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            fin_wait2_timeout: Some(FIN_WAIT2_TIMEOUT),
+            fin_wait2_reset: false,
+            synack_retries: SYNACK_RETRIES,
+            r1: R1,
+            r2: R2,
+            recv_buffer: RECV_BUFFER,
+            recv_buffer_max: RECV_BUFFER_MAX,
+            recv_buffer_autotune: true,
+            window_scaling: true,
+            frto: true,
+            tlp: true,
+            rack: true,
+            pacing: false,
+            congestion: CongestionAlgorithm::default(),
+            trace: false,
+            record_transitions: false,
+            segment_history: 0,
+            record_cwnd: false,
+            record: false,
+            initial_window: INITIAL_WINDOW,
+            send_watermarks: Watermarks::default(),
+            send_buffer_max: SEND_BUFFER_MAX,
+            send_buffer_autotune: true,
+            max_orphans: MAX_ORPHANS,
+            orphan_timeout: ORPHAN_TIMEOUT,
+            idle_timeout: None,
+            adopt_user_timeout: false,
+            syn_rate_limit: None,
+            memory_limits: None,
+            mtu: MTU,
+            ttl: TTL,
+            clock,
+            #[cfg(feature = "std")]
+            buffers: &super::pool::PACKETS,
+            #[cfg(not(feature = "std"))]
+            buffers: &super::pool::Unpooled,
+        }
+    }
+}
+
+/// The defaults, with the time read from the system's monotonic clock
+#[cfg(feature = "std")]
+impl Default for Config {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
@@ -0,0 +1,1076 @@
+//! Conformance checks against the MUST-level requirements of RFC 793, RFC
+//! 1122 and RFC 9293. Every case drives a `Connection` and inspects the
+//! segments it emits. The test prints a pass/fail
+//! matrix; it fails when a requirement that is expected to hold regresses.
+
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use super::action::Action;
+use super::config::{Config, MemoryPressure};
+use super::congestion::DEFAULT_MSS;
+use super::connection::Connection;
+use super::drops::DropReason;
+use super::harness::{
+    parse, segment, segment_with_options, segment_with_window, syn_with_mss, syn_with_timestamp,
+    Harness, ACK, FIN_ACK, PEER_ISS, PEER_WINDOW, RST, SYN,
+};
+use super::options::SegmentOptions;
+use super::state::State;
+use super::time::{Duration, ManualClock};
+
+fn check(cond: bool, what: &str) -> Result<(), String> {
+    if cond {
+        Ok(())
+    } else {
+        Err(what.to_string())
+    }
+}
+
+/// A requirement and the check exercising it
+struct Case {
+    reference: &'static str,
+    requirement: &'static str,
+    check: fn() -> Result<(), String>,
+    /// the stack is known not to meet the requirement yet
+    known_failure: bool,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        reference: "RFC 9293 3.5",
+        requirement: "a SYN to a listening port is answered with SYN,ACK",
+        check: syn_answered_with_syn_ack,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.1",
+        requirement: "segments carry valid IP header and TCP checksums",
+        check: checksums_valid,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.5",
+        requirement: "an acceptable ACK completes the handshake",
+        check: ack_completes_handshake,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "a retransmitted SYN in SYN-RECEIVED is answered with the SYN,ACK again",
+        check: retransmitted_syn_answered,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.2",
+        requirement: "data on a SYN is acknowledged and delivered once the connection is established",
+        check: data_on_syn_delivered,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 6191 2",
+        requirement: "a SYN with a newer timestamp may end TIME-WAIT, an older one may not",
+        check: time_wait_reuse_by_timestamp,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 1122 4.2.2.13",
+        requirement: "without timestamps, a SYN beyond RCV.NXT may end TIME-WAIT",
+        check: time_wait_reuse_by_sequence,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 5961 4",
+        requirement: "a SYN in a synchronized state elicits a challenge ACK",
+        check: syn_in_established_challenged,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.3",
+        requirement: "an unacceptable ACK in SYN-RECEIVED elicits <SEQ=SEG.ACK><CTL=RST>",
+        check: unacceptable_ack_in_syn_received,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "an unacceptable segment elicits <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>",
+        check: unacceptable_segment_acked,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "a duplicate segment is acknowledged and not delivered again",
+        check: duplicate_segment_not_delivered,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "in-window data is delivered and acknowledged",
+        check: data_delivered_and_acked,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "a RST in the window aborts the connection",
+        check: rst_in_window_aborts,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "a RST outside the window is dropped silently",
+        check: rst_out_of_window_ignored,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.1",
+        requirement: "a segment with ACK for no connection elicits <SEQ=SEG.ACK><CTL=RST>",
+        check: reset_unknown_with_ack,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.1",
+        requirement: "a segment without ACK for no connection elicits <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>",
+        check: reset_unknown_without_ack,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.1",
+        requirement: "a RST is never answered with a RST",
+        check: rst_not_answered,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.6",
+        requirement: "closing sends FIN, the peer's FIN in FIN-WAIT-2 leads to TIME-WAIT",
+        check: active_close,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "a FIN in ESTABLISHED is acknowledged and moves to CLOSE-WAIT",
+        check: fin_in_established,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 1122 4.2.3.3",
+        requirement: "reading from a full receive buffer sends a window update",
+        check: window_update_after_read,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.8.6.2.2",
+        requirement: "memory pressure holds the window back without shrinking it, and its end reopens it",
+        check: memory_pressure_holds_window,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "data beyond the receive buffer is not queued or acknowledged",
+        check: receive_buffer_bounded,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "SND.WND is taken from segments newer than SND.WL1/SND.WL2",
+        check: send_window_updated,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.8.6.1",
+        requirement: "a zero window is probed with one byte and data resumes when it opens",
+        check: zero_window_probed,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 5681 3.1",
+        requirement: "data in flight is limited by min(cwnd, SND.WND), in segments of at most one MSS",
+        check: in_flight_limited_by_window,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.7.1",
+        requirement: "the SYN,ACK announces the MSS of the MTU, segments respect the peer's MSS or 536",
+        check: mss_negotiated,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.2",
+        requirement: "options that aren't implemented are skipped, a malformed option list is ignored",
+        check: unknown_options_ignored,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 1122 3.3.3",
+        requirement: "segments sent after the MTU was lowered fit the new MTU",
+        check: segments_fit_mtu,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.7.1",
+        requirement: "a jumbo MTU announces, sends and accepts segments above 1500 bytes",
+        check: jumbo_segments,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.10.7.4",
+        requirement: "an ACK of data not yet sent is answered with an ACK and the segment dropped",
+        check: ack_of_unsent_data_dropped,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 5681 2",
+        requirement: "duplicate ACKs are told apart from window updates",
+        check: duplicate_acks_counted,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 5681 4.2",
+        requirement: "an out-of-order segment is answered with an immediate duplicate ACK",
+        check: out_of_order_acked_at_once,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.9.1.2",
+        requirement: "the last segment of a write carries PSH",
+        check: push_on_last_segment,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 6298 5.4",
+        requirement: "the earliest unacknowledged segment is retransmitted when the RTO expires",
+        check: retransmit_on_rto,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 6298 2.1",
+        requirement: "until an RTT is measured, data is retransmitted after the initial RTO of 1s",
+        check: initial_rto,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 6298 2.2",
+        requirement: "the first RTT measurement replaces the initial SRTT",
+        check: first_rtt_sets_srtt,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 9293 3.1",
+        requirement: "retransmissions carry valid TCP checksums for their new headers",
+        check: retransmission_checksums_valid,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 7323 2",
+        requirement: "windows are scaled by the shift counts both SYNs announced, never on the SYN itself",
+        check: window_scale_negotiated,
+        known_failure: false,
+    },
+    Case {
+        reference: "RFC 5482 3",
+        requirement: "the peer's user timeout is adopted only when the application allows it",
+        check: user_timeout_adopted_on_request,
+        known_failure: false,
+    },
+];
+
+fn syn_answered_with_syn_ack() -> Result<(), String> {
+    let mut h = Harness::syn_received();
+    let synack = h.sent_one()?;
+    check(synack.tcp.syn && synack.tcp.ack, "SYN and ACK set")?;
+    check(
+        synack.tcp.acknowledgment_number == PEER_ISS + 1,
+        "SYN,ACK acknowledges the SYN",
+    )
+}
+
+fn checksums_valid() -> Result<(), String> {
+    let mut h = Harness::syn_received();
+    let synack = h.conn.take_actions();
+    let Some(Action::Transmit(packet)) = synack.into_iter().next() else {
+        return Err("nothing sent".to_string());
+    };
+    let packet = packet.into_vec();
+    let (ip, tcp, data) = parse(&packet);
+    check(
+        ip.to_header().calc_header_checksum() == ip.header_checksum(),
+        "IP header checksum",
+    )?;
+    check(
+        tcp.calc_checksum_ipv4(&ip, data).ok() == Some(tcp.checksum()),
+        "TCP checksum",
+    )
+}
+
+fn ack_completes_handshake() -> Result<(), String> {
+    let h = Harness::established();
+    check(h.conn.state == State::Established, "state is ESTABLISHED")
+}
+
+fn retransmitted_syn_answered() -> Result<(), String> {
+    let mut h = Harness::syn_received();
+    h.sent();
+    h.deliver(SYN, PEER_ISS, 0, &[]);
+    let synack = h.sent_one()?;
+    check(synack.tcp.syn && synack.tcp.ack, "SYN and ACK set")?;
+    check(synack.tcp.sequence_number == 0, "SEQ=ISS")?;
+    check(
+        synack.tcp.acknowledgment_number == PEER_ISS + 1,
+        "ACK=IRS+1",
+    )?;
+    check(h.conn.state == State::SynReceived, "state unchanged")?;
+    h.deliver(ACK, PEER_ISS + 1, 1, &[]);
+    check(
+        h.conn.state == State::Established,
+        "handshake completes afterwards",
+    )
+}
+
+fn data_on_syn_delivered() -> Result<(), String> {
+    let mut h = Harness::accept(&Config::default(), &segment(SYN, PEER_ISS, 0, b"early"));
+    let synack = h.sent_one()?;
+    check(
+        synack.tcp.acknowledgment_number == PEER_ISS + 6,
+        "SYN,ACK acknowledges the data",
+    )?;
+    check(h.conn.ingress.is_empty(), "data held back")?;
+    h.deliver(ACK, PEER_ISS + 6, 1, b" late");
+    check(h.conn.state == State::Established, "state is ESTABLISHED")?;
+    check(
+        h.conn.ingress.iter().eq(b"early late".iter()),
+        "data delivered in order",
+    )
+}
+
+/// Close the connection actively and take it to TIME-WAIT
+fn into_time_wait(h: &mut Harness) -> Result<(), String> {
+    h.deliver(ACK, PEER_ISS + 1, 1, &[]);
+    h.conn.close().map_err(|e| e.to_string())?;
+    let _ = h.conn.on_timer();
+    h.sent();
+    h.deliver(FIN_ACK, PEER_ISS + 1, 2, &[]);
+    check(h.conn.state == State::TimeWait, "state is TIME-WAIT")
+}
+
+fn new_incarnation(h: &Harness, packet: &[u8]) -> bool {
+    let (_, tcp, _) = parse(packet);
+    h.conn.accepts_new_incarnation(&tcp)
+}
+
+fn time_wait_reuse_by_timestamp() -> Result<(), String> {
+    let mut h = Harness::accept(&Config::default(), &syn_with_timestamp(PEER_ISS, 500));
+    into_time_wait(&mut h)?;
+    check(
+        new_incarnation(&h, &syn_with_timestamp(PEER_ISS + 10, 501)),
+        "newer timestamp accepted",
+    )?;
+    check(
+        !new_incarnation(&h, &syn_with_timestamp(PEER_ISS + 100_000, 500)),
+        "same timestamp refused, whatever the sequence number",
+    )?;
+    check(
+        h.conn.next_iss() > h.conn.snapshot().snd_nxt,
+        "next ISS beyond SND.NXT",
+    )
+}
+
+fn time_wait_reuse_by_sequence() -> Result<(), String> {
+    let mut h = Harness::syn_received();
+    into_time_wait(&mut h)?;
+    check(
+        new_incarnation(&h, &segment(SYN, PEER_ISS + 100, 0, &[])),
+        "SYN beyond RCV.NXT accepted",
+    )?;
+    check(
+        !new_incarnation(&h, &segment(SYN, PEER_ISS, 0, &[])),
+        "old SYN refused",
+    )
+}
+
+fn syn_in_established_challenged() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(SYN, PEER_ISS + 500, 0, &[]);
+    let ack = h.sent_one()?;
+    check(ack.tcp.ack && !ack.tcp.syn && !ack.tcp.rst, "ACK sent")?;
+    check(ack.tcp.sequence_number == 1, "SEQ=SND.NXT")?;
+    check(ack.tcp.acknowledgment_number == PEER_ISS + 1, "ACK=RCV.NXT")?;
+    check(h.conn.state == State::Established, "state unchanged")
+}
+
+fn unacceptable_ack_in_syn_received() -> Result<(), String> {
+    let mut h = Harness::syn_received();
+    h.sent();
+    h.deliver(ACK, PEER_ISS + 1, 5000, &[]);
+    let rst = h.sent_one()?;
+    check(rst.tcp.rst, "RST set")?;
+    check(rst.tcp.sequence_number == 5000, "SEQ=SEG.ACK")?;
+    check(h.conn.state == State::SynReceived, "state unchanged")
+}
+
+fn unacceptable_segment_acked() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(ACK, PEER_ISS + 100_000, 1, b"far");
+    let ack = h.sent_one()?;
+    check(ack.tcp.ack && !ack.tcp.rst, "ACK sent")?;
+    check(ack.tcp.sequence_number == 1, "SEQ=SND.NXT")?;
+    check(ack.tcp.acknowledgment_number == PEER_ISS + 1, "ACK=RCV.NXT")?;
+    check(h.conn.ingress.is_empty(), "data not delivered")
+}
+
+fn duplicate_segment_not_delivered() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(ACK, PEER_ISS + 1, 1, b"hello");
+    h.sent();
+    h.deliver(ACK, PEER_ISS + 1, 1, b"hello");
+    let ack = h.sent_one()?;
+    check(ack.tcp.acknowledgment_number == PEER_ISS + 6, "ACK=RCV.NXT")?;
+    check(h.conn.ingress.len() == 5, "data delivered once")
+}
+
+fn data_delivered_and_acked() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(ACK, PEER_ISS + 1, 1, b"hello");
+    let ack = h.sent_one()?;
+    check(ack.payload.is_empty(), "pure ACK")?;
+    check(
+        ack.tcp.acknowledgment_number == PEER_ISS + 6,
+        "data acknowledged",
+    )?;
+    check(h.conn.ingress.iter().eq(b"hello".iter()), "data delivered")
+}
+
+fn rst_in_window_aborts() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(RST, PEER_ISS + 1, 0, &[]);
+    check(h.conn.state == State::Closed, "state is CLOSED")?;
+    check(
+        h.conn.error == Some(io::ErrorKind::ConnectionReset),
+        "connection reset reported",
+    )?;
+    check(h.sent().is_empty(), "nothing sent")
+}
+
+fn rst_out_of_window_ignored() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(RST, PEER_ISS + 100_000, 0, &[]);
+    check(h.conn.state == State::Established, "state unchanged")?;
+    check(h.sent().is_empty(), "nothing sent")
+}
+
+fn reset_unknown_with_ack() -> Result<(), String> {
+    let packet = segment(ACK, 7, 4242, &[]);
+    let (ip, tcp, data) = parse(&packet);
+    let sent = Connection::reset_unknown(&Config::default(), &ip, &tcp, data)
+        .map_err(|e| e.to_string())?;
+    let sent = sent.ok_or("nothing sent")?.into_vec();
+    let (_, rst, _) = parse(&sent);
+    check(rst.rst() && !rst.ack(), "RST without ACK")?;
+    check(rst.sequence_number() == 4242, "SEQ=SEG.ACK")
+}
+
+fn reset_unknown_without_ack() -> Result<(), String> {
+    let packet = segment(SYN, 7, 0, b"data");
+    let (ip, tcp, data) = parse(&packet);
+    let sent = Connection::reset_unknown(&Config::default(), &ip, &tcp, data)
+        .map_err(|e| e.to_string())?;
+    let sent = sent.ok_or("nothing sent")?.into_vec();
+    let (_, rst, _) = parse(&sent);
+    check(rst.rst() && rst.ack(), "RST,ACK")?;
+    check(rst.sequence_number() == 0, "SEQ=0")?;
+    check(rst.acknowledgment_number() == 7 + 5, "ACK=SEG.SEQ+SEG.LEN")
+}
+
+fn rst_not_answered() -> Result<(), String> {
+    let packet = segment(RST, 7, 0, &[]);
+    let (ip, tcp, data) = parse(&packet);
+    let sent = Connection::reset_unknown(&Config::default(), &ip, &tcp, data)
+        .map_err(|e| e.to_string())?;
+    check(sent.is_none(), "nothing sent")
+}
+
+fn active_close() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.conn.close().map_err(|e| e.to_string())?;
+    let _ = h.conn.on_timer();
+    let fin = h.sent_one()?;
+    check(fin.tcp.fin, "FIN sent")?;
+    h.deliver(ACK, PEER_ISS + 1, fin.tcp.sequence_number + 1, &[]);
+    check(h.conn.state == State::FinWait2, "state is FIN-WAIT-2")?;
+    h.deliver(FIN_ACK, PEER_ISS + 1, fin.tcp.sequence_number + 1, &[]);
+    check(h.conn.state == State::TimeWait, "state is TIME-WAIT")?;
+    let ack = h.sent_one()?;
+    check(
+        ack.tcp.acknowledgment_number == PEER_ISS + 2,
+        "peer's FIN acknowledged",
+    )
+}
+
+fn fin_in_established() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(FIN_ACK, PEER_ISS + 1, 1, &[]);
+    let ack = h.sent_one()?;
+    check(
+        ack.tcp.acknowledgment_number == PEER_ISS + 2,
+        "FIN acknowledged",
+    )?;
+    check(h.conn.is_recv_closed(), "receive side closed")
+}
+
+fn window_update_after_read() -> Result<(), String> {
+    // A buffer that fills up with a single segment
+    let config = Config {
+        recv_buffer: 1000,
+        recv_buffer_autotune: false,
+        ..Config::default()
+    };
+    let mut h = Harness::established_with(&config);
+    let window = h.conn.snapshot().rcv_wnd as usize;
+    h.deliver(ACK, PEER_ISS + 1, 1, &vec![b'x'; window]);
+    let ack = h.sent_one()?;
+    check(ack.tcp.window_size == 0, "window closed")?;
+    h.conn.ingress.drain(..1);
+    h.conn.on_read().map_err(|e| e.to_string())?;
+    check(h.sent().is_empty(), "no update for a sliver of window")?;
+    h.conn.ingress.clear();
+    h.conn.on_read().map_err(|e| e.to_string())?;
+    let update = h.sent_one()?;
+    check(update.tcp.window_size as usize == window, "window reopened")?;
+    check(
+        update.tcp.acknowledgment_number == PEER_ISS + 1 + window as u32,
+        "ACK=RCV.NXT",
+    )
+}
+
+fn memory_pressure_holds_window() -> Result<(), String> {
+    let config = Config {
+        recv_buffer: 4000,
+        recv_buffer_autotune: false,
+        ..Config::default()
+    };
+    let mut h = Harness::established_with(&config);
+    h.conn
+        .set_memory_pressure(MemoryPressure::Hard)
+        .map_err(|e| e.to_string())?;
+    h.deliver(ACK, PEER_ISS + 1, 1, &[b'x'; 1000]);
+    check(
+        h.sent_one()?.tcp.window_size == 3000,
+        "what was offered stays offered",
+    )?;
+    h.conn.ingress.clear();
+    h.conn.on_read().map_err(|e| e.to_string())?;
+    check(h.sent().is_empty(), "no update under hard pressure")?;
+
+    h.conn
+        .set_memory_pressure(MemoryPressure::Soft)
+        .map_err(|e| e.to_string())?;
+    h.deliver(ACK, PEER_ISS + 1001, 1, &[b'x'; 2500]);
+    check(
+        h.sent_one()?.tcp.window_size == 750,
+        "half the free buffer under soft pressure",
+    )?;
+    h.conn
+        .set_memory_pressure(MemoryPressure::Normal)
+        .map_err(|e| e.to_string())?;
+    check(
+        h.sent_one()?.tcp.window_size == 1500,
+        "whole free buffer once the pressure is over",
+    )
+}
+
+fn receive_buffer_bounded() -> Result<(), String> {
+    let config = Config {
+        recv_buffer: 1000,
+        recv_buffer_autotune: false,
+        ..Config::default()
+    };
+    let mut h = Harness::established_with(&config);
+    // Starts inside the window, so it is acceptable, but ends beyond it
+    h.deliver(ACK, PEER_ISS + 1, 1, &[b'x'; 1500]);
+    let ack = h.sent_one()?;
+    check(h.conn.ingress.len() == 1000, "receive buffer filled")?;
+    check(
+        ack.tcp.acknowledgment_number == PEER_ISS + 1001,
+        "only the queued data acknowledged",
+    )?;
+    check(ack.tcp.window_size == 0, "window closed")?;
+    h.conn.ingress.clear();
+    h.conn.on_read().map_err(|e| e.to_string())?;
+    check(h.sent_one()?.tcp.window_size == 1000, "window reopened")?;
+    h.deliver(ACK, PEER_ISS + 1001, 1, &[b'x'; 500]);
+    let ack = h.sent_one()?;
+    check(h.conn.ingress.len() == 500, "rest queued once read")?;
+    check(
+        ack.tcp.acknowledgment_number == PEER_ISS + 1501,
+        "rest acknowledged",
+    )
+}
+
+fn send_window_updated() -> Result<(), String> {
+    let mut h = Harness::established();
+    check(
+        h.conn.snapshot().snd_wnd == PEER_WINDOW as u32,
+        "window of the handshake",
+    )?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 500, b"ab"));
+    check(h.conn.snapshot().snd_wnd == 500, "window shrunk")?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 3, 1, 700, &[]));
+    check(h.conn.snapshot().snd_wnd == 700, "window grown")?;
+    // An ACK from before the last update, overtaken on the way
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 100, &[]));
+    check(
+        h.conn.snapshot().snd_wnd == 700,
+        "older segment doesn't update the window",
+    )
+}
+
+fn zero_window_probed() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 0, &[]));
+    h.conn.unacked.extend(b"hello");
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let probe = h.sent_one()?;
+    check(probe.payload == b"h", "one byte probe")?;
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(
+        h.sent().is_empty(),
+        "nothing more while the window is closed",
+    )?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 2, PEER_WINDOW, &[]));
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let data = h.sent_one()?;
+    check(
+        data.tcp.sequence_number == 2 && data.payload == b"ello",
+        "rest sent once the window opened",
+    )
+}
+
+fn ack_of_unsent_data_dropped() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.conn.unacked.extend(b"hello");
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    h.sent();
+    h.deliver(ACK, PEER_ISS + 1, 100, b"data");
+    let ack = h.sent_one()?;
+    check(ack.payload.is_empty(), "pure ACK")?;
+    check(ack.tcp.sequence_number == 6, "SEQ=SND.NXT")?;
+    check(
+        ack.tcp.acknowledgment_number == PEER_ISS + 1,
+        "data not acknowledged",
+    )?;
+    check(h.conn.unacked.len() == 5, "send queue not drained")?;
+    check(h.conn.snapshot().snd_una == 1, "SND.UNA unchanged")?;
+    check(h.conn.ingress.is_empty(), "data not delivered")?;
+    check(
+        h.drops.get(DropReason::AckOfUnsentData) == 1,
+        "drop counted",
+    )
+}
+
+fn duplicate_acks_counted() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    check(h.conn.snapshot().dup_acks == 0, "nothing outstanding")?;
+    h.conn.unacked.extend(vec![b'x'; 1000]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    h.sent();
+    for _ in 0..3 {
+        h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    }
+    check(h.conn.snapshot().dup_acks == 3, "three duplicate ACKs")?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 50_000, &[]));
+    check(
+        h.conn.snapshot().dup_acks == 3,
+        "a window update is no duplicate",
+    )
+}
+
+fn out_of_order_acked_at_once() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver(ACK, PEER_ISS + 1, 1, b"first");
+    h.sent();
+    for _ in 0..2 {
+        h.deliver(ACK, PEER_ISS + 11, 1, b"beyond");
+        let ack = h.sent_one()?;
+        check(ack.payload.is_empty(), "pure ACK")?;
+        check(
+            ack.tcp.acknowledgment_number == PEER_ISS + 6,
+            "ACK of the data before the hole",
+        )?;
+    }
+    check(
+        h.conn.snapshot().dup_acks_sent == 2,
+        "duplicate ACKs counted",
+    )?;
+    h.deliver(ACK, PEER_ISS + 6, 1, b"fill");
+    h.sent();
+    check(
+        h.conn.snapshot().dup_acks_sent == 2,
+        "an in-order segment is no duplicate",
+    )
+}
+
+fn in_flight_limited_by_window() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    h.conn.unacked.extend(vec![b'x'; 5000]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let sent = h.sent();
+    check(sent.len() > 1, "one opportunity sends several segments")?;
+    check(
+        sent.iter().all(|s| s.payload.len() <= DEFAULT_MSS),
+        "segments of at most one MSS",
+    )?;
+    check(
+        sent.iter().map(|s| s.payload.len()).sum::<usize>() == 5000,
+        "all queued data sent",
+    )?;
+
+    let mut h = Harness::established();
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 2000, &[]));
+    h.conn.unacked.extend(vec![b'x'; 5000]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(
+        h.sent().iter().map(|s| s.payload.len()).sum::<usize>() == 2000,
+        "in flight limited by the peer's window",
+    )?;
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(h.sent().is_empty(), "nothing more until acknowledged")?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1001, 2000, &[]));
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(
+        h.sent().iter().map(|s| s.payload.len()).sum::<usize>() == 1000,
+        "acknowledged bytes make room",
+    )
+}
+
+fn mss_negotiated() -> Result<(), String> {
+    let mut h = Harness::accept(&Config::default(), &syn_with_mss(PEER_ISS, 1000));
+    let synack = h.sent_one()?;
+    check(
+        SegmentOptions::parse(synack.tcp.options.as_slice()).mss == Some(1460),
+        "MSS of a 1500 byte MTU announced",
+    )?;
+    check(h.conn.mss() == 1000, "peer's MSS taken")?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    h.conn.unacked.extend(vec![b'x'; 3000]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let sent = h.sent();
+    check(
+        sent.iter().all(|s| s.payload.len() <= 1000) && sent[0].payload.len() == 1000,
+        "segments of the peer's MSS",
+    )?;
+
+    let h = Harness::syn_received();
+    check(h.conn.mss() == 536, "536 without the option")?;
+    let config = Config {
+        mtu: 400,
+        ..Config::default()
+    };
+    let h = Harness::accept(&config, &syn_with_mss(PEER_ISS, 1460));
+    check(h.conn.mss() == 360, "no more than the MTU allows")
+}
+
+fn unknown_options_ignored() -> Result<(), String> {
+    // NOP, an unknown option, an experimental option (RFC 6994) and the MSS
+    let options = [
+        1, 30, 4, 0xab, 0xcd, 254, 6, 0x12, 0x34, 0, 0, 2, 4, 3, 232, 0,
+    ];
+    let syn = segment_with_options(SYN, PEER_ISS, 0, PEER_WINDOW, &options, &[]);
+    let mut h = Harness::accept(&Config::default(), &syn);
+    check(h.conn.mss() == 1000, "MSS found behind the other options")?;
+    let synack = h.sent_one()?;
+    check(
+        synack.tcp.options.as_slice().len() % 4 == 0,
+        "options padded to whole words",
+    )?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    h.conn.unacked.extend(vec![b'x'; 100]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(
+        h.sent_one()?.tcp.options.as_slice().is_empty(),
+        "no MSS after the SYN",
+    )?;
+
+    // An option whose length runs past the list hides the MSS behind it
+    let options = [30, 12, 0, 0, 2, 4, 3, 232];
+    let syn = segment_with_options(SYN, PEER_ISS, 0, PEER_WINDOW, &options, &[]);
+    let h = Harness::accept(&Config::default(), &syn);
+    check(h.conn.mss() == 536, "536 without a readable MSS")
+}
+
+fn segments_fit_mtu() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    h.conn.set_mtu(576);
+    h.conn.unacked.extend(vec![b'x'; 3000]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let sent = h.sent();
+    check(
+        sent.iter()
+            .all(|s| 20 + s.tcp.header_len() + s.payload.len() <= 576),
+        "packets of at most the MTU",
+    )?;
+    check(
+        sent.iter().map(|s| s.payload.len()).sum::<usize>() == 3000,
+        "all queued data sent",
+    )
+}
+
+fn jumbo_segments() -> Result<(), String> {
+    let config = Config {
+        mtu: 9000,
+        ..Config::default()
+    };
+    let mut h = Harness::accept(&config, &syn_with_mss(PEER_ISS, 8960));
+    let synack = h.sent_one()?;
+    check(
+        SegmentOptions::parse(synack.tcp.options.as_slice()).mss == Some(8960),
+        "MSS of a 9000 byte MTU announced",
+    )?;
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    h.conn.unacked.extend(vec![b'x'; 20_000]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let sent = h.sent();
+    check(
+        sent.first().is_some_and(|s| s.payload.len() == 8960),
+        "full-sized jumbo segments",
+    )?;
+    check(
+        sent.iter()
+            .all(|s| 20 + s.tcp.header_len() + s.payload.len() <= 9000),
+        "packets of at most the MTU",
+    )?;
+    h.deliver(ACK, PEER_ISS + 1, 1, &[b'y'; 8960]);
+    check(h.conn.ingress.len() == 8960, "jumbo segment received")
+}
+
+fn push_on_last_segment() -> Result<(), String> {
+    let mut h = Harness::established();
+    h.deliver_raw(&segment_with_window(ACK, PEER_ISS + 1, 1, 60_000, &[]));
+    h.conn.unacked.extend(vec![b'x'; 2 * DEFAULT_MSS + 10]);
+    h.conn.push();
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let sent = h.sent();
+    check(sent.len() == 3, "three segments")?;
+    check(
+        !sent[0].tcp.psh && !sent[1].tcp.psh,
+        "no PSH inside the write",
+    )?;
+    check(sent[2].tcp.psh, "PSH on the last segment")
+}
+
+fn retransmit_on_rto() -> Result<(), String> {
+    let clock = Arc::new(ManualClock::new());
+    // Tail loss probes would fire before the RTO
+    let config = Config {
+        clock: clock.clone(),
+        tlp: false,
+        ..Config::default()
+    };
+    let mut h = Harness::established_with(&config);
+    h.conn.unacked.extend(b"hello");
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let first = h.sent_one()?;
+    let rto = h.conn.snapshot().rto_in.ok_or("no retransmission timer")?;
+    clock.advance(rto - Duration::from_millis(100));
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(h.sent().is_empty(), "nothing before the RTO")?;
+    clock.advance(Duration::from_millis(200));
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let again = h.sent_one()?;
+    check(
+        again.tcp.sequence_number == first.tcp.sequence_number && again.payload == b"hello",
+        "same segment sent again",
+    )
+}
+
+fn initial_rto() -> Result<(), String> {
+    let clock = Arc::new(ManualClock::new());
+    let config = Config {
+        clock: clock.clone(),
+        tlp: false,
+        ..Config::default()
+    };
+    // The handshake carried no data, so nothing was timed yet
+    let mut h = Harness::established_with(&config);
+    h.conn.unacked.extend(b"first");
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    h.sent_one()?;
+    clock.advance(Duration::from_millis(900));
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(h.sent().is_empty(), "nothing before a second")?;
+    clock.advance(Duration::from_millis(200));
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(
+        h.sent_one()?.payload == b"first",
+        "retransmitted after a second",
+    )
+}
+
+fn first_rtt_sets_srtt() -> Result<(), String> {
+    let clock = Arc::new(ManualClock::new());
+    let config = Config {
+        clock: clock.clone(),
+        tlp: false,
+        ..Config::default()
+    };
+    let mut h = Harness::established_with(&config);
+    // Two segments, the second of which is timed
+    h.conn.unacked.extend([b'x'; DEFAULT_MSS + 1]);
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    check(h.sent().len() == 2, "two segments sent")?;
+    clock.advance(Duration::from_millis(100));
+    h.deliver(ACK, PEER_ISS + 1, DEFAULT_MSS as u32 + 2, &[]);
+    let srtt = h.conn.snapshot().srtt;
+    check(
+        srtt.abs_diff(Duration::from_millis(100)) < Duration::from_millis(1),
+        "SRTT is the first measurement",
+    )
+}
+
+fn retransmission_checksums_valid() -> Result<(), String> {
+    let clock = Arc::new(ManualClock::new());
+    let config = Config {
+        clock: clock.clone(),
+        tlp: false,
+        ..Config::default()
+    };
+    let mut h = Harness::established_with(&config);
+    h.conn.unacked.extend(b"odd length");
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    h.sent();
+    // The peer's data changes the acknowledgment number of the
+    // retransmission
+    h.deliver(ACK, PEER_ISS + 1, 1, b"data");
+    h.sent();
+    let rto = h.conn.snapshot().rto_in.ok_or("no retransmission timer")?;
+    clock.advance(rto + Duration::from_millis(100));
+    h.conn.on_timer().map_err(|e| e.to_string())?;
+    let actions = h.conn.take_actions();
+    let Some(Action::Transmit(packet)) = actions.into_iter().next() else {
+        return Err("nothing retransmitted".to_string());
+    };
+    let packet = packet.into_vec();
+    let (ip, tcp, data) = parse(&packet);
+    check(data == b"odd length", "same payload")?;
+    check(
+        tcp.acknowledgment_number() == PEER_ISS + 5,
+        "new acknowledgment number",
+    )?;
+    check(
+        tcp.calc_checksum_ipv4(&ip, data).ok() == Some(tcp.checksum()),
+        "TCP checksum",
+    )
+}
+
+fn window_scale_negotiated() -> Result<(), String> {
+    let config = Config {
+        recv_buffer: 256 * 1024,
+        ..Config::default()
+    };
+    // NOP and a Window Scale option with a shift count of 2
+    let syn = segment_with_options(SYN, PEER_ISS, 0, PEER_WINDOW, &[1, 3, 3, 2], &[]);
+    let mut h = Harness::accept(&config, &syn);
+    let synack = h.sent_one()?;
+    check(
+        SegmentOptions::parse(synack.tcp.options.as_slice()).window_scale == Some(7),
+        "shift count of the largest receive buffer announced",
+    )?;
+    check(
+        synack.tcp.window_size == u16::MAX,
+        "SYN,ACK window not scaled",
+    )?;
+    check(
+        h.conn.snapshot().snd_wnd == PEER_WINDOW as u32,
+        "peer's SYN window not scaled",
+    )?;
+
+    h.deliver(ACK, PEER_ISS + 1, 1, &[]);
+    check(
+        h.conn.snapshot().snd_wnd == 4 * PEER_WINDOW as u32,
+        "peer's window scaled",
+    )?;
+    h.deliver(ACK, PEER_ISS + 1, 1, b"data");
+    let ack = h.sent_one()?;
+    check(
+        ack.tcp.window_size == (256 * 1024 - 4u32).div_ceil(128) as u16,
+        "window advertised in units of 128 bytes, rounded up",
+    )?;
+    check(
+        h.conn.snapshot().rcv_wnd == u32::from(ack.tcp.window_size) << 7,
+        "RCV.WND is the window the peer was told",
+    )?;
+
+    // Without the option on the SYN, neither side scales
+    let mut h = Harness::syn_received_with(&config);
+    let synack = h.sent_one()?;
+    check(
+        SegmentOptions::parse(synack.tcp.options.as_slice())
+            .window_scale
+            .is_none(),
+        "no Window Scale option in reply to a SYN without one",
+    )?;
+    h.deliver(ACK, PEER_ISS + 1, 1, &[]);
+    check(
+        h.conn.snapshot().snd_wnd == PEER_WINDOW as u32,
+        "peer's window taken as is",
+    )?;
+    check(
+        h.conn.snapshot().rcv_wnd == u16::MAX as u32,
+        "window no larger than 65535",
+    )
+}
+
+fn user_timeout_adopted_on_request() -> Result<(), String> {
+    // A User Timeout of 600 seconds
+    let syn = segment_with_options(SYN, PEER_ISS, 0, PEER_WINDOW, &[28, 4, 0x02, 0x58], &[]);
+    let mut h = Harness::accept(&Config::default(), &syn);
+    check(h.conn.user_timeout().is_none(), "peer's timeout ignored")?;
+    h.conn.set_user_timeout(Duration::from_secs(200));
+    check(
+        h.conn.user_timeout() == Some(Duration::from_secs(200)),
+        "local timeout kept",
+    )?;
+
+    let config = Config {
+        adopt_user_timeout: true,
+        ..Config::default()
+    };
+    let mut h = Harness::accept(&config, &syn);
+    check(
+        h.conn.user_timeout() == Some(Duration::from_secs(600)),
+        "peer's timeout adopted",
+    )?;
+    h.conn.set_adopt_user_timeout(false);
+    check(h.conn.user_timeout().is_none(), "adoption turned off")
+}
+
+#[test]
+fn conformance_matrix() {
+    let mut regressions = Vec::new();
+    // Panics are reported in the matrix, their message is left to the
+    // process-wide hook
+    for case in CASES {
+        let result = panic::catch_unwind(AssertUnwindSafe(case.check))
+            .unwrap_or_else(|_| Err("panicked".to_string()));
+        let status = match (&result, case.known_failure) {
+            (Ok(()), false) => "PASS",
+            (Ok(()), true) => "PASS (was known to fail)",
+            (Err(_), true) => "XFAIL",
+            (Err(_), false) => "FAIL",
+        };
+        print!("[{}] {}: {}", status, case.reference, case.requirement);
+        if let Err(reason) = &result {
+            print!(" ({})", reason);
+            if !case.known_failure {
+                regressions.push(case.requirement);
+            }
+        }
+        println!();
+    }
+    assert!(
+        regressions.is_empty(),
+        "requirements not met: {:?}",
+        regressions
+    );
+}
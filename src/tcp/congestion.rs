@@ -0,0 +1,393 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::fmt;
+
+use super::time::{Duration, Instant};
+
+/// Sender maximum segment size assumed when the peer didn't announce one
+/// RFC 1122 Section 4.2.2.6
+pub const DEFAULT_MSS: usize = 536;
+
+/// Initial window in segments RFC 6928
+pub const INITIAL_WINDOW: usize = 10;
+
+/// Congestion control algorithms a connection can use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+    /// Loss based Reno, competing fairly with other flows
+    #[default]
+    Reno,
+    /// Delay based LEDBAT, yielding to other flows as soon as it sees
+    /// queues building up
+    Ledbat,
+}
+
+impl CongestionAlgorithm {
+    /// Create the controller starting with a window of `initial_window`
+    /// segments
+    pub fn build(self, mss: usize, initial_window: usize) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionAlgorithm::Reno => Box::new(Reno::new(mss, initial_window)),
+            CongestionAlgorithm::Ledbat => Box::new(Ledbat::new(mss, initial_window)),
+        }
+    }
+}
+
+/// A congestion control algorithm deciding how much data the sender may
+/// have in flight. Windows are counted in bytes.
+pub trait CongestionControl: fmt::Debug + Send {
+    /// Congestion window
+    fn cwnd(&self) -> usize;
+
+    /// The window is still growing exponentially
+    fn in_slow_start(&self) -> bool;
+
+    /// Slow start threshold, for algorithms that keep one and once it was
+    /// set
+    fn ssthresh(&self) -> Option<usize> {
+        None
+    }
+
+    /// `acked` bytes of new data were acknowledged while `in_flight` bytes
+    /// were outstanding, with `rtt` measured for the acknowledged data, at
+    /// `now`
+    fn on_ack(&mut self, acked: usize, in_flight: usize, rtt: Option<Duration>, now: Instant);
+
+    /// The retransmission timer expired with `in_flight` bytes outstanding
+    fn on_timeout(&mut self, in_flight: usize);
+
+    /// Loss was detected before the retransmission timer expired, with
+    /// `in_flight` bytes outstanding. Called once per recovery episode.
+    fn on_loss(&mut self, in_flight: usize);
+
+    /// The last retransmission timeout turned out to be spurious: return to
+    /// the state from before it
+    fn undo_timeout(&mut self);
+}
+
+/// Reno congestion control RFC 5681: slow start and congestion avoidance,
+/// collapsing to one segment after a retransmission timeout
+#[derive(Debug)]
+pub struct Reno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// bytes acknowledged towards the next increase in congestion avoidance
+    acked: usize,
+    /// cwnd and ssthresh from before the last timeout
+    prior: Option<(usize, usize)>,
+}
+
+impl Reno {
+    /// Start with a congestion window of `initial_window` segments
+    pub fn new(mss: usize, initial_window: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window * mss,
+            ssthresh: usize::MAX,
+            acked: 0,
+            prior: None,
+        }
+    }
+}
+
+impl CongestionControl for Reno {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+
+    fn ssthresh(&self) -> Option<usize> {
+        (self.ssthresh != usize::MAX).then_some(self.ssthresh)
+    }
+
+    fn on_ack(&mut self, acked: usize, _in_flight: usize, _rtt: Option<Duration>, _now: Instant) {
+        if self.cwnd < self.ssthresh {
+            // slow start: grow by at most one segment per ACK
+            self.cwnd += core::cmp::min(acked, self.mss);
+        } else {
+            // congestion avoidance: grow by one segment per window acknowledged
+            self.acked += acked;
+            if self.acked >= self.cwnd {
+                self.acked -= self.cwnd;
+                self.cwnd += self.mss;
+            }
+        }
+    }
+
+    fn on_timeout(&mut self, in_flight: usize) {
+        // ssthresh = max (FlightSize / 2, 2*SMSS), cwnd = 1 segment
+        self.prior = Some((self.cwnd, self.ssthresh));
+        self.ssthresh = core::cmp::max(in_flight / 2, 2 * self.mss);
+        self.cwnd = self.mss;
+        self.acked = 0;
+    }
+
+    fn on_loss(&mut self, in_flight: usize) {
+        // ssthresh = max (FlightSize / 2, 2*SMSS), cwnd = ssthresh
+        self.ssthresh = core::cmp::max(in_flight / 2, 2 * self.mss);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+    }
+
+    fn undo_timeout(&mut self) {
+        if let Some((cwnd, ssthresh)) = self.prior.take() {
+            self.cwnd = cwnd;
+            self.ssthresh = ssthresh;
+        }
+    }
+}
+
+/// Queuing delay LEDBAT aims for (the LEDBAT++ value, RFC 6817 allows up to
+/// 100ms)
+const LEDBAT_TARGET: Duration = Duration::from_millis(60);
+/// Delay samples the current delay is the minimum of
+const LEDBAT_CURRENT_FILTER: usize = 4;
+/// Minutes of base delay history
+const LEDBAT_BASE_HISTORY: usize = 10;
+const LEDBAT_BASE_INTERVAL: Duration = Duration::from_secs(60);
+/// Smallest congestion window in segments
+const LEDBAT_MIN_CWND: usize = 2;
+/// Segments the window may exceed the flight size by
+const LEDBAT_ALLOWED_INCREASE: usize = 1;
+
+/// LEDBAT low priority congestion control RFC 6817.
+///
+/// The window follows the queuing delay rather than losses: the delay over
+/// the lowest one seen on the path (the base delay) is kept at a small
+/// target, which means backing off as soon as other traffic fills the
+/// bottleneck queue. Without TCP timestamps there are no one-way delay
+/// measurements, so round trip times stand in for them, as in LEDBAT++.
+/// Losses and timeouts are handled like Reno.
+#[derive(Debug)]
+pub struct Ledbat {
+    mss: usize,
+    cwnd: usize,
+    /// slow start ends at the first sign of queuing or loss
+    slow_start: bool,
+    /// most recent delay samples
+    current: VecDeque<Duration>,
+    /// minimum delay per interval, newest last
+    base: VecDeque<Duration>,
+    /// start of the newest base delay interval
+    base_since: Option<Instant>,
+    /// cwnd and slow start from before the last timeout
+    prior: Option<(usize, bool)>,
+}
+
+impl Ledbat {
+    /// Start with a congestion window of `initial_window` segments
+    pub fn new(mss: usize, initial_window: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window * mss,
+            slow_start: true,
+            current: VecDeque::with_capacity(LEDBAT_CURRENT_FILTER),
+            base: VecDeque::with_capacity(LEDBAT_BASE_HISTORY),
+            base_since: None,
+            prior: None,
+        }
+    }
+
+    fn add_sample(&mut self, delay: Duration, now: Instant) {
+        if self.current.len() == LEDBAT_CURRENT_FILTER {
+            self.current.pop_front();
+        }
+        self.current.push_back(delay);
+
+        match self.base.back_mut() {
+            Some(base)
+                if self.base_since.is_some_and(|since| {
+                    now.saturating_duration_since(since) < LEDBAT_BASE_INTERVAL
+                }) =>
+            {
+                *base = (*base).min(delay);
+            }
+            _ => {
+                if self.base.len() == LEDBAT_BASE_HISTORY {
+                    self.base.pop_front();
+                }
+                self.base.push_back(delay);
+                self.base_since = Some(now);
+            }
+        }
+    }
+
+    /// Current delay over the base delay
+    fn queuing_delay(&self) -> Option<Duration> {
+        let current = self.current.iter().min()?;
+        let base = self.base.iter().min()?;
+        Some(current.saturating_sub(*base))
+    }
+
+    fn min_cwnd(&self) -> usize {
+        LEDBAT_MIN_CWND * self.mss
+    }
+}
+
+impl CongestionControl for Ledbat {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.slow_start
+    }
+
+    fn on_ack(&mut self, acked: usize, in_flight: usize, rtt: Option<Duration>, now: Instant) {
+        if let Some(rtt) = rtt {
+            self.add_sample(rtt, now);
+        }
+        let Some(queuing_delay) = self.queuing_delay() else {
+            return;
+        };
+        if self.slow_start && queuing_delay < LEDBAT_TARGET * 3 / 4 {
+            self.cwnd += core::cmp::min(acked, self.mss);
+            return;
+        }
+        self.slow_start = false;
+
+        // cwnd += GAIN * off_target * bytes_newly_acked * MSS / cwnd
+        let target = LEDBAT_TARGET.as_secs_f64();
+        let off_target = (target - queuing_delay.as_secs_f64()) / target;
+        let change = off_target * acked as f64 * self.mss as f64 / self.cwnd as f64;
+        let cwnd = (self.cwnd as f64 + change).max(0.) as usize;
+        let max_allowed = in_flight + LEDBAT_ALLOWED_INCREASE * self.mss;
+        self.cwnd = cwnd.min(max_allowed.max(self.cwnd)).max(self.min_cwnd());
+    }
+
+    fn on_timeout(&mut self, _in_flight: usize) {
+        self.prior = Some((self.cwnd, self.slow_start));
+        self.cwnd = self.mss;
+        self.slow_start = false;
+    }
+
+    fn on_loss(&mut self, _in_flight: usize) {
+        self.cwnd = core::cmp::max(self.cwnd / 2, self.min_cwnd());
+        self.slow_start = false;
+    }
+
+    fn undo_timeout(&mut self) {
+        if let Some((cwnd, slow_start)) = self.prior.take() {
+            self.cwnd = cwnd;
+            self.slow_start = slow_start;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    #[test]
+    fn starts_with_the_initial_window() {
+        assert_eq!(Reno::new(MSS, 3).cwnd(), 3 * MSS);
+        assert_eq!(Reno::new(MSS, INITIAL_WINDOW).cwnd(), INITIAL_WINDOW * MSS);
+    }
+
+    #[test]
+    fn slow_start_grows_by_at_most_a_segment_per_ack() {
+        let now = Instant::now();
+        let mut reno = Reno::new(MSS, INITIAL_WINDOW);
+        let cwnd = reno.cwnd();
+        reno.on_ack(3 * MSS, cwnd, None, now);
+        assert_eq!(reno.cwnd(), cwnd + MSS);
+        reno.on_ack(100, cwnd, None, now);
+        assert_eq!(reno.cwnd(), cwnd + MSS + 100);
+    }
+
+    #[test]
+    fn timeout_collapses_to_one_segment() {
+        let now = Instant::now();
+        let mut reno = Reno::new(MSS, INITIAL_WINDOW);
+        reno.on_timeout(10 * MSS);
+        assert_eq!(reno.cwnd(), MSS);
+        assert_eq!(reno.ssthresh, 5 * MSS);
+
+        // Back in slow start until ssthresh, then a segment per window
+        for _ in 0..4 {
+            reno.on_ack(MSS, MSS, None, now);
+        }
+        assert_eq!(reno.cwnd(), 5 * MSS);
+        reno.on_ack(4 * MSS, 5 * MSS, None, now);
+        assert_eq!(reno.cwnd(), 5 * MSS);
+        reno.on_ack(MSS, 5 * MSS, None, now);
+        assert_eq!(reno.cwnd(), 6 * MSS);
+    }
+
+    #[test]
+    fn ssthresh_is_at_least_two_segments() {
+        let mut reno = Reno::new(MSS, INITIAL_WINDOW);
+        reno.on_timeout(MSS);
+        assert_eq!(reno.ssthresh, 2 * MSS);
+    }
+
+    #[test]
+    fn undo_restores_the_window_from_before_the_timeout() {
+        let now = Instant::now();
+        let mut reno = Reno::new(MSS, INITIAL_WINDOW);
+        reno.on_timeout(20 * MSS);
+        reno.on_ack(MSS, MSS, None, now);
+        reno.undo_timeout();
+        assert_eq!(reno.cwnd(), INITIAL_WINDOW * MSS);
+        assert_eq!(reno.ssthresh, usize::MAX);
+
+        // Nothing to undo a second time
+        reno.on_ack(MSS, MSS, None, now);
+        reno.undo_timeout();
+        assert_eq!(reno.cwnd(), (INITIAL_WINDOW + 1) * MSS);
+    }
+
+    #[test]
+    fn ledbat_grows_while_there_is_no_queue() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(MSS, INITIAL_WINDOW);
+        for _ in 0..4 {
+            ledbat.on_ack(MSS, ledbat.cwnd(), Some(Duration::from_millis(50)), now);
+        }
+        assert!(ledbat.in_slow_start());
+        assert_eq!(ledbat.cwnd(), (INITIAL_WINDOW + 4) * MSS);
+    }
+
+    #[test]
+    fn ledbat_backs_off_above_the_target_delay() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(MSS, INITIAL_WINDOW);
+        ledbat.on_ack(MSS, ledbat.cwnd(), Some(Duration::from_millis(50)), now);
+
+        // Twice the target of queuing, seen once the older sample is
+        // filtered out
+        let queued = Duration::from_millis(50) + LEDBAT_TARGET * 2;
+        for _ in 1..LEDBAT_CURRENT_FILTER {
+            ledbat.on_ack(MSS, ledbat.cwnd(), Some(queued), now);
+        }
+        assert!(ledbat.in_slow_start());
+        let cwnd = ledbat.cwnd();
+        ledbat.on_ack(MSS, ledbat.cwnd(), Some(queued), now);
+        assert!(!ledbat.in_slow_start());
+        assert!(ledbat.cwnd() < cwnd);
+
+        for _ in 0..1000 {
+            ledbat.on_ack(MSS, ledbat.cwnd(), Some(queued), now);
+        }
+        assert_eq!(ledbat.cwnd(), LEDBAT_MIN_CWND * MSS);
+    }
+
+    #[test]
+    fn ledbat_halves_on_loss_and_undoes_timeouts() {
+        let mut ledbat = Ledbat::new(MSS, INITIAL_WINDOW);
+        ledbat.on_loss(INITIAL_WINDOW * MSS);
+        assert_eq!(ledbat.cwnd(), INITIAL_WINDOW * MSS / 2);
+        assert!(!ledbat.in_slow_start());
+
+        ledbat.on_timeout(ledbat.cwnd());
+        assert_eq!(ledbat.cwnd(), MSS);
+        ledbat.undo_timeout();
+        assert_eq!(ledbat.cwnd(), INITIAL_WINDOW * MSS / 2);
+    }
+}
@@ -1,23 +1,81 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use core::net::{Ipv4Addr, SocketAddrV4};
 use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
-use std::collections::{BTreeMap, VecDeque};
-use std::net::Ipv4Addr;
-use std::{io, io::Write, time};
 
+use super::action::{Action, Packet};
+use super::autotune::{window_scale, ReceiveBuffer, MAX_WINDOW_SCALE};
+use super::checkpoint::SavedConnection;
+use super::checksum;
+use super::config::{Config, MemoryPressure, Watermarks};
+use super::congestion::{CongestionAlgorithm, CongestionControl, DEFAULT_MSS};
+use super::cwnd_trace::{CongestionState, CwndTrace};
+use super::drops::{DropReason, DropStats};
+use super::event::ConnectionEvent;
+use super::history::{Direction, SegmentHistory};
+use super::io;
+use super::options::{OptionsBuilder, SegmentOptions, TcpOption};
+use super::pacing::Pacer;
+use super::rack::Rack;
+use super::ratelimit::{RateLimit, TokenBucket};
+use super::recording::{Record, Recorder, Recording};
 use super::sequence::ReceiveSequenceSpace;
 use super::sequence::SendSequenceSpace;
+use super::snapshot::TcbSnapshot;
 use super::state::{Available, State};
+use super::time;
+#[cfg(feature = "std")]
+use super::trace;
+use super::transitions::{Cause, SegmentSummary, TransitionLog};
 
-const MTU: usize = 1500;
-const TTL: u8 = 64;
 const ISS: u32 = 0; // Needs to change
-const WINDOW_SIZE: u16 = 10; // 4096;
+/// Length of IPv4 and TCP headers without options
+const IP_TCP_HEADERS: usize = 40;
+/// Distance between the last sequence number of a connection and the ISS of
+/// the next incarnation, beyond any window the old one advertised
+const INCARNATION_GAP: u32 = 1 << 16;
+/// Initial retransmission timeout RFC 6298 Section 2.1
+const INITIAL_RTO: time::Duration = time::Duration::from_secs(1);
+/// Worst case delayed ACK timer added to the probe timeout when a single
+/// segment is in flight RFC 8985 Section 7.2
+const WC_DEL_ACK: time::Duration = time::Duration::from_millis(200);
+/// Lower bound of the probe timeout
+const MIN_PTO: time::Duration = time::Duration::from_millis(10);
+/// Bounds applied to the user timeout when the peer's advertised value is
+/// taken into account (L_LIMIT and U_LIMIT in RFC 5482)
+const USER_TIMEOUT_LOWER_LIMIT: time::Duration = time::Duration::from_secs(100);
+const USER_TIMEOUT_UPPER_LIMIT: time::Duration = time::Duration::from_secs(60 * 60);
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Tcp4Tuple {
     pub src: (Ipv4Addr, u16),
     pub dst: (Ipv4Addr, u16),
 }
 
+/// The 12 bytes of the quad as two words, which `QuadHasher` takes in two
+/// steps
+impl Hash for Tcp4Tuple {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let addrs = u64::from(self.src.0.to_bits()) << 32 | u64::from(self.dst.0.to_bits());
+        state.write_u64(addrs);
+        state.write_u32(u32::from(self.src.1) << 16 | u32::from(self.dst.1));
+    }
+}
+
+impl Tcp4Tuple {
+    /// Our end of the connection
+    pub fn local(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.dst.0, self.dst.1)
+    }
+
+    /// The peer's end of the connection
+    pub fn remote(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.src.0, self.src.1)
+    }
+}
+
 #[derive(Debug)]
 struct Timers {
     /// when last segment was sent
@@ -25,44 +83,239 @@ struct Timers {
     /// segment sequence number and when it was sent
     // send_times: VecDeque<(u32, time::Instant)>,
     send_times: BTreeMap<u32, time::Instant>,
+    /// first sequence numbers of the segments in `send_times` that were sent
+    /// more than once, whose ACKs don't tell which transmission arrived
+    retransmitted: BTreeSet<u32>,
     /// round trip time
     srtt: f64,
+    /// when the oldest unacknowledged data was sent (or last made progress)
+    unacked_since: Option<time::Instant>,
+    /// when the connection entered FIN-WAIT-2
+    fin_wait2_since: Option<time::Instant>,
+    /// number of times the SYN-ACK was retransmitted
+    synack_retries: u32,
+    /// number of retransmissions since the peer last acknowledged new data
+    retransmits: u32,
+    /// R1 was reported for the current run of retransmissions
+    r1_reported: bool,
+    /// when to send a tail loss probe
+    pto: Option<time::Instant>,
+    /// SND.NXT when the outstanding tail loss probe was sent
+    tlp_high: Option<u32>,
+    /// `srtt` is based on a measurement rather than the initial value
+    rtt_measured: bool,
+    /// when a segment was last received or sent with new sequence space
+    last_activity: time::Instant,
 }
 
 impl Timers {
-    fn new() -> Self {
+    fn new(now: time::Instant) -> Self {
         Self {
             // last_send: time::Instant::now(),
             // send_times: VecDeque::default(),
             send_times: BTreeMap::default(),
+            retransmitted: BTreeSet::default(),
             srtt: time::Duration::from_secs(60).as_secs_f64(),
+            unacked_since: None,
+            fin_wait2_since: None,
+            synack_retries: 0,
+            retransmits: 0,
+            r1_reported: false,
+            pto: None,
+            tlp_high: None,
+            rtt_measured: false,
+            last_activity: now,
+        }
+    }
+}
+
+/// Shift counts agreed with the Window Scale option RFC 7323 Section 2
+#[derive(Debug, Default, Clone, Copy)]
+struct WindowScale {
+    /// applied to the windows the peer advertises
+    snd: u8,
+    /// applied to the windows advertised to the peer
+    rcv: u8,
+}
+
+/// TCP User Timeout state RFC 5482
+#[derive(Debug, Default)]
+struct UserTimeout {
+    /// timeout requested by the local application
+    local: Option<time::Duration>,
+    /// timeout advertised by the peer
+    remote: Option<time::Duration>,
+    /// the local timeout has not been delivered to the peer yet
+    advertise: bool,
+    /// end of the last segment that carried the option
+    advertised_through: Option<u32>,
+    /// the application lets the peer's timeout adjust the local one
+    adopt: bool,
+}
+
+impl UserTimeout {
+    /// The timeout in effect: the local value, adjusted by the peer's
+    /// advertised value when there is one and the application opted in
+    /// (RFC 5482 Section 3).
+    /// USER_TIMEOUT = min(U_LIMIT, max(LOCAL_UTO, REMOTE_UTO, L_LIMIT))
+    fn effective(&self) -> Option<time::Duration> {
+        match self.remote.filter(|_| self.adopt) {
+            Some(remote) => {
+                let local = self.local.unwrap_or_default();
+                let timeout = local.max(remote).max(USER_TIMEOUT_LOWER_LIMIT);
+                Some(timeout.min(USER_TIMEOUT_UPPER_LIMIT))
+            }
+            None => self.local,
         }
     }
 }
 
+/// What the connection observed about the path, for diagnosing it and
+/// tuning loss detection
+#[derive(Debug, Default)]
+struct PathMetrics {
+    /// duplicate ACKs received RFC 5681 Section 2
+    dup_acks: u64,
+    /// duplicate ACKs sent right away for segments beyond a hole RFC 5681
+    /// Section 4.2
+    dup_acks_sent: u64,
+    /// furthest a segment arrived ahead of RCV.NXT, in bytes
+    reordering: u32,
+    /// retransmissions found to be unnecessary
+    spurious_retransmits: u64,
+}
+
+/// Progress of forward RTO recovery (F-RTO) RFC 5682 Section 2.1 after a
+/// retransmission timeout. `recover` is SND.NXT at the time of the timeout.
+#[derive(Debug, Clone, Copy)]
+enum Frto {
+    /// The first unacknowledged segment was retransmitted, waiting for the
+    /// first acknowledgment (step 2)
+    Retransmitted { recover: u32 },
+    /// New data was sent in response to the first acknowledgment, waiting
+    /// for the second one (step 3)
+    SentNew,
+}
+
+/// What to transmit after an acknowledgment was processed by F-RTO
+#[derive(Debug, Clone, Copy)]
+enum FrtoResponse {
+    /// Send up to two new segments (step 2b)
+    SendNew,
+    /// The timeout was genuine: retransmit in slow start (steps 2a and 3a)
+    Conventional,
+}
+
+/// Buffer of a reader waiting for data, filled by `on_packet_into()`
+struct DirectRead<'a> {
+    buf: &'a mut [u8],
+    filled: usize,
+}
+
+impl DirectRead<'_> {
+    /// Copy as much of `data` as fits, returning the bytes copied
+    fn take(&mut self, data: &[u8]) -> usize {
+        let n = core::cmp::min(data.len(), self.buf.len() - self.filled);
+        self.buf[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+        self.filled += n;
+        n
+    }
+}
+
 #[derive(Debug)]
 pub struct Connection {
-    pub state: State,
+    pub(crate) state: State,
+    config: Config,
     send: SendSequenceSpace,
     receive: ReceiveSequenceSpace,
     timers: Timers,
     ip: Ipv4Header,
     tcp: TcpHeader,
-    pub ingress: VecDeque<u8>,
-    pub unacked: VecDeque<u8>,
-    pub closed: bool,
+    /// data received and not read yet, at most the size of the receive
+    /// buffer
+    pub(crate) ingress: VecDeque<u8>,
+    /// data received before the handshake completed, moved to `ingress`
+    /// once it does
+    early: Vec<u8>,
+    pub(crate) unacked: VecDeque<u8>,
+    /// data bytes the peer acknowledged
+    bytes_acked: u64,
+    /// payload length and `checksum::payload_sum()` of the segments sent
+    /// and not acknowledged yet, by sequence number, for retransmissions
+    payload_sums: BTreeMap<u32, (usize, u16)>,
+    pub(crate) closed: bool,
     closed_at: Option<u32>,
+    /// reset the connection after this long without activity
+    idle_timeout: Option<time::Duration>,
+    /// end of the data of the last write or flush, marked with PSH on the
+    /// segment that carries it
+    push_at: Option<u32>,
+    user_timeout: UserTimeout,
+    /// error to report to the user once the connection has been aborted
+    pub(crate) error: Option<io::ErrorKind>,
+    /// error observed on the connection that didn't abort it
+    soft_error: Option<io::ErrorKind>,
+    /// R1 was crossed and the interface hasn't been told about it yet
+    r1_crossed: bool,
+    /// when the stream owning the connection was dropped
+    orphaned_since: Option<time::Instant>,
+    /// receive buffer backing the advertised window
+    rcv_buffer: ReceiveBuffer,
+    /// right edge of the window advertised last, RCV.NXT + RCV.WND
+    rcv_edge: u32,
+    /// how the queues of all connections compare to the memory limits
+    memory_pressure: MemoryPressure,
+    /// congestion control algorithm
+    cc: Box<dyn CongestionControl>,
+    /// forward RTO recovery in progress
+    frto: Option<Frto>,
+    /// time-based loss detection
+    rack: Rack,
+    path: PathMetrics,
+    /// SND.NXT when loss recovery started, while it is in progress
+    recovery_end: Option<u32>,
+    /// release times of new data
+    pacer: Pacer,
+    /// caps the rate at which new data is sent, in bytes
+    rate_limit: Option<TokenBucket>,
+    /// state transitions, when they are recorded
+    transitions: Option<TransitionLog>,
+    /// the last segments sent and received, when they are kept
+    history: Option<SegmentHistory>,
+    /// congestion window samples, when they are recorded
+    cwnd_trace: Option<CwndTrace>,
+    /// latest timestamp (TSval) received from the peer
+    ts_recent: Option<u32>,
+    /// Maximum Segment Size announced by the peer
+    peer_mss: Option<u16>,
+    /// window scaling, when both sides sent the option on their SYN
+    window_scale: Option<WindowScale>,
+    /// inputs of the connection, when they are recorded
+    recorder: Option<Recorder>,
+    /// what is being processed, blamed for state transitions
+    cause: Cause,
+    /// events not delivered to the event handler yet
+    events: Vec<ConnectionEvent>,
+    /// actions the owner of the connection hasn't carried out yet
+    actions: Vec<Action>,
+    /// send queue watermarks
+    pub watermarks: Watermarks,
+    /// watermarks were set by the user, which disables auto-tuning
+    pub watermarks_locked: bool,
+    /// a writer found the send queue at the high watermark and waits for it
+    /// to drain to the low watermark
+    pub write_blocked: bool,
+    /// bytes received before readers are woken up, as SO_RCVLOWAT
+    pub recv_lowat: usize,
 }
 
 impl Connection {
     /// Any state after receiving FIN
     pub fn is_recv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO: CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
-            true
-        } else {
-            false
-        }
+        matches!(
+            self.state,
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed
+        )
     }
 
     /// Function to indicate read and write availability.
@@ -70,91 +323,408 @@ impl Connection {
     /// that are waiting for data to be available
     fn availability(&self) -> Available {
         let mut avail = Available::empty();
-        if self.is_recv_closed() || !self.ingress.is_empty() {
+        if self.is_recv_closed() || self.ingress.len() >= self.recv_lowat() {
             avail |= Available::READ;
         }
-        // TODO: set Available::WRITE
+        // Writers are woken up once the send queue drained to the low
+        // watermark, or when writing won't be possible anymore
+        if self.error.is_some() || (self.write_blocked && self.unacked.len() <= self.watermarks.low)
+        {
+            avail |= Available::WRITE;
+        }
         avail
     }
 
+    /// Operations that wouldn't block right now: reading when there is data,
+    /// the peer closed or the connection failed, and writing when there is
+    /// room in the send queue or the connection failed
+    pub fn readiness(&self) -> Available {
+        let mut ready = Available::empty();
+        if self.error.is_some() || self.is_recv_closed() || self.ingress.len() >= self.recv_lowat()
+        {
+            ready |= Available::READ;
+        }
+        let Watermarks { low, high } = self.watermarks;
+        let queued = self.unacked.len();
+        if self.error.is_some() || (queued < high && (!self.write_blocked || queued <= low)) {
+            ready |= Available::WRITE;
+        }
+        ready
+    }
+
     pub fn accept(
-        nic: &tun_tap::Iface,
+        config: &Config,
+        ip: Ipv4HeaderSlice,
+        tcp: TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<Self> {
+        Self::accept_with_iss(config, ip, tcp, data, ISS)
+    }
+
+    /// Accept a SYN, sending sequence numbers from `iss`
+    pub fn accept_with_iss(
+        config: &Config,
         ip: Ipv4HeaderSlice,
         tcp: TcpHeaderSlice,
         data: &[u8],
+        iss: u32,
     ) -> io::Result<Self> {
-        let tcp_len = tcp.slice().len();
-        let data_len = data.len();
-        let src = ip.source_addr();
-        let dst = ip.destination_addr();
+        let src = Ipv4Addr::from(ip.source());
+        let dst = Ipv4Addr::from(ip.destination());
         let srcp = tcp.source_port();
         let dstp = tcp.destination_port();
-        println!(
-            "TCP [{}:{}] {}:{} -> {}:{}",
-            tcp_len, data_len, src, srcp, dst, dstp,
-        );
-
         if !tcp.syn() {
             // non-syn unexpected
-            return Err(io::Error::new(io::ErrorKind::Other, "Unexpected SYN"));
+            return Err(io::Error::other("Unexpected SYN"));
         }
         // establish connection with the client we received SYN from
 
-        // Initialize receive sequence space
+        // Initialize receive sequence space, advertising the whole buffer
+        let now = config.clock.now();
+        let rcv_buffer = ReceiveBuffer::new(
+            config.recv_buffer,
+            config.recv_buffer_max,
+            config.recv_buffer_autotune,
+            now,
+        );
         let receive = ReceiveSequenceSpace {
             irs: tcp.sequence_number(),
-            nxt: tcp.sequence_number() + 1,
-            wnd: tcp.window_size(),
+            nxt: tcp.sequence_number().wrapping_add(1),
+            wnd: rcv_buffer.window(0),
             urgent: tcp.urgent_pointer(),
         };
 
         // Initialize send sequence space
-        let iss = ISS;
         let send = SendSequenceSpace {
             iss,
             una: iss,
             nxt: iss,
-            wnd: WINDOW_SIZE,
+            wnd: tcp.window_size() as u32,
             urgent: 0,
             wl1: tcp.sequence_number(),
-            wl2: iss + WINDOW_SIZE as u32,
+            wl2: iss,
         };
 
+        let opts = SegmentOptions::parse(tcp.options());
+
         // Flip source and destination in the response
-        let mut resp_tcp = TcpHeader::new(dstp, srcp, send.iss, send.wnd);
-        resp_tcp.syn = true;
-        resp_tcp.ack = true;
+        let local = SocketAddrV4::new(dst, dstp);
+        let remote = SocketAddrV4::new(src, srcp);
+        let mut conn = Self::new(config, local, remote, send, receive, rcv_buffer, now)?;
+        conn.set_peer_mss(opts.mss);
+        conn.set_window_scale(opts.window_scale);
+        conn.tcp.syn = true;
+        conn.tcp.ack = true;
+        conn.user_timeout.remote = opts.user_timeout;
+        conn.ts_recent = opts.timestamp();
+        if conn.config.trace {
+            conn.trace_received(&tcp, data.len());
+        }
+        conn.remember_received(&tcp, data.len());
+        conn.queue_early(tcp.sequence_number().wrapping_add(1), data);
+        if conn.config.record {
+            let mut recorder = Recorder::new(now);
+            recorder.record(
+                now,
+                Record::Segment([ip.slice(), tcp.slice(), data].concat()),
+            );
+            conn.recorder = Some(recorder);
+        }
+        conn.write(conn.send.nxt, 0)?;
+        Ok(conn)
+    }
 
-        let resp_ip = Ipv4Header::new(
-            resp_tcp.header_len() as u16,
-            TTL,
+    /// A connection in SYN-RECEIVED between `local` and `remote`
+    fn new(
+        config: &Config,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        send: SendSequenceSpace,
+        receive: ReceiveSequenceSpace,
+        rcv_buffer: ReceiveBuffer,
+        now: time::Instant,
+    ) -> io::Result<Self> {
+        let window = u16::try_from(receive.wnd).unwrap_or(u16::MAX);
+        let tcp = TcpHeader::new(local.port(), remote.port(), send.iss, window);
+        let ip = Ipv4Header::new(
+            tcp.header_len() as u16,
+            config.ttl,
             IpNumber::TCP,
-            dst.octets(),
-            src.octets(),
+            local.ip().octets(),
+            remote.ip().octets(),
         )
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        .map_err(io::Error::other)?;
+        let rcv_edge = receive.nxt.wrapping_add(receive.wnd);
 
-        let mut conn = Connection {
+        Ok(Connection {
             state: State::SynReceived,
+            config: config.clone(),
             send,
             receive,
-            timers: Timers::new(),
-            ip: resp_ip,
-            tcp: resp_tcp,
+            timers: Timers::new(now),
+            ip,
+            tcp,
             ingress: VecDeque::new(),
+            early: Vec::new(),
             unacked: VecDeque::new(),
+            bytes_acked: 0,
+            payload_sums: BTreeMap::new(),
             closed: false,
             closed_at: None,
+            idle_timeout: config.idle_timeout,
+            push_at: None,
+            user_timeout: UserTimeout {
+                adopt: config.adopt_user_timeout,
+                ..Default::default()
+            },
+            error: None,
+            soft_error: None,
+            r1_crossed: false,
+            orphaned_since: None,
+            rcv_buffer,
+            rcv_edge,
+            memory_pressure: MemoryPressure::Normal,
+            cc: config
+                .congestion
+                .build(Self::effective_mss(config.mtu, None), config.initial_window),
+            frto: None,
+            rack: Rack::default(),
+            path: PathMetrics::default(),
+            recovery_end: None,
+            pacer: Pacer::default(),
+            rate_limit: None,
+            transitions: config.record_transitions.then(|| TransitionLog::new(now)),
+            history: (config.segment_history > 0)
+                .then(|| SegmentHistory::new(config.segment_history, now)),
+            cwnd_trace: config.record_cwnd.then(|| CwndTrace::new(now)),
+            ts_recent: None,
+            peer_mss: None,
+            window_scale: None,
+            recorder: None,
+            cause: Cause::User,
+            events: Vec::new(),
+            actions: Vec::new(),
+            watermarks: config.send_watermarks,
+            watermarks_locked: false,
+            write_blocked: false,
+            recv_lowat: 1,
+        })
+    }
+
+    /// Capture what it takes to carry on with the connection in another
+    /// instance of the stack. Only synchronized connections that are still
+    /// open can be saved.
+    pub fn save(&self) -> Option<SavedConnection> {
+        if matches!(
+            self.state,
+            State::Closed | State::SynReceived | State::TimeWait
+        ) {
+            return None;
+        }
+        // The FIN counts once it was acknowledged; otherwise it is sent
+        // again along with the unacknowledged data
+        let fin_acked_at = self
+            .closed_at
+            .filter(|at| self.send.una == at.wrapping_add(1));
+        Some(SavedConnection {
+            local: self.local(),
+            remote: self.remote(),
+            state: self.state,
+            snd_iss: self.send.iss,
+            snd_una: self.send.una,
+            snd_wnd: self.send.wnd,
+            snd_wl1: self.send.wl1,
+            snd_wl2: self.send.wl2,
+            rcv_irs: self.receive.irs,
+            rcv_nxt: self.receive.nxt,
+            recv_buffer: self.rcv_buffer.size(),
+            ingress: self.ingress.iter().copied().collect(),
+            unacked: self.unacked.iter().copied().collect(),
+            closed: self.closed,
+            fin_acked_at,
+            peer_mss: self.peer_mss,
+            snd_wscale: self.window_scale.map_or(0, |scale| scale.snd),
+            rcv_wscale: self.window_scale.map_or(0, |scale| scale.rcv),
+        })
+    }
+
+    /// Carry on with a connection saved by another instance of the stack.
+    /// Everything that wasn't acknowledged is sent again, and an ACK tells
+    /// the peer the current window. A saved connection with inconsistent
+    /// fields fails with `InvalidData`.
+    pub fn restore(config: &Config, saved: &SavedConnection) -> io::Result<Self> {
+        Self::check_saved(saved)?;
+        let now = config.clock.now();
+        let rcv_buffer = ReceiveBuffer::new(
+            saved.recv_buffer,
+            config.recv_buffer_max,
+            config.recv_buffer_autotune,
+            now,
+        );
+        let receive = ReceiveSequenceSpace {
+            irs: saved.rcv_irs,
+            nxt: saved.rcv_nxt,
+            wnd: rcv_buffer.window(saved.ingress.len()),
+            urgent: 0,
+        };
+        let send = SendSequenceSpace {
+            iss: saved.snd_iss,
+            una: saved.snd_una,
+            nxt: saved.snd_una,
+            wnd: saved.snd_wnd,
+            urgent: 0,
+            wl1: saved.snd_wl1,
+            wl2: saved.snd_wl2,
         };
-        conn.write(nic, conn.send.nxt, 0)?;
+        let mut conn = Self::new(
+            config,
+            saved.local,
+            saved.remote,
+            send,
+            receive,
+            rcv_buffer,
+            now,
+        )?;
+        conn.set_peer_mss(saved.peer_mss);
+        conn.window_scale = Some(WindowScale {
+            snd: saved.snd_wscale,
+            rcv: saved.rcv_wscale,
+        });
+        conn.rcv_buffer.set_scale(saved.rcv_wscale);
+        conn.state = saved.state;
+        conn.tcp.ack = true;
+        conn.ingress.extend(&saved.ingress);
+        conn.unacked.extend(&saved.unacked);
+        conn.closed = saved.closed;
+        conn.closed_at = saved.fin_acked_at;
+        conn.push();
+        conn.write(conn.send.nxt, 0)?;
         Ok(conn)
     }
 
-    fn write(&mut self, nic: &tun_tap::Iface, seq: u32, mut limit: usize) -> io::Result<usize> {
-        let mut buf = [0u8; MTU];
+    /// Reject a saved connection that `save()` couldn't have produced, such
+    /// as one read from a corrupted checkpoint
+    fn check_saved(saved: &SavedConnection) -> io::Result<()> {
+        let invalid = |what| Err(io::Error::new(io::ErrorKind::InvalidData, what));
+        if matches!(
+            saved.state,
+            State::Closed | State::SynReceived | State::TimeWait
+        ) {
+            return invalid("Saved connection isn't synchronized");
+        }
+        if saved.snd_wscale > MAX_WINDOW_SCALE || saved.rcv_wscale > MAX_WINDOW_SCALE {
+            return invalid("Saved window scale too large");
+        }
+        if saved.ingress.len() > saved.recv_buffer {
+            return invalid("Saved data exceeds the receive buffer");
+        }
+        // Our SYN was acknowledged, the peer's received, and what is in
+        // flight fits in half the sequence space
+        if !Self::wrapping_lt(saved.snd_iss, saved.snd_una)
+            || !Self::wrapping_lt(saved.rcv_irs, saved.rcv_nxt)
+            || saved.unacked.len() >= (u32::MAX >> 1) as usize
+        {
+            return invalid("Saved sequence numbers are inconsistent");
+        }
+        if let Some(at) = saved.fin_acked_at {
+            if !saved.closed || !saved.unacked.is_empty() || saved.snd_una != at.wrapping_add(1) {
+                return invalid("Saved FIN is inconsistent");
+            }
+        }
+        Ok(())
+    }
+
+    /// Our end of the connection
+    pub fn local(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.ip.source.into(), self.tcp.source_port)
+    }
+
+    /// The peer's end of the connection
+    pub fn remote(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.ip.destination.into(), self.tcp.destination_port)
+    }
+
+    /// Traces go to stderr: without std there is nowhere to write them
+    fn trace_received(&self, tcp: &TcpHeaderSlice, len: usize) {
+        #[cfg(not(feature = "std"))]
+        let _ = (tcp, len);
+        #[cfg(feature = "std")]
+        eprintln!(
+            "{}",
+            trace::segment(
+                self.remote(),
+                self.local(),
+                &tcp.to_header(),
+                len,
+                self.receive.irs,
+                self.send.iss,
+            )
+        );
+    }
+
+    /// Add a received segment to the history, if it is kept
+    fn remember_received(&mut self, tcp: &TcpHeaderSlice, len: usize) {
+        let now = self.now();
+        if let Some(history) = &mut self.history {
+            history.record(now, Direction::Received, &tcp.to_header(), len);
+        }
+    }
+
+    /// Dump the history of an aborted connection to stderr, if it is kept
+    fn dump_history(&self, kind: io::ErrorKind) {
+        #[cfg(not(feature = "std"))]
+        let _ = kind;
+        #[cfg(feature = "std")]
+        if let Some(history) = &self.history {
+            eprintln!(
+                "{} > {}: aborted ({:?}), last segments:\n{}",
+                self.local(),
+                self.remote(),
+                kind,
+                history
+            );
+        }
+    }
+
+    fn trace_sent(&self, tcp: &TcpHeader, len: usize) {
+        #[cfg(not(feature = "std"))]
+        let _ = (tcp, len);
+        #[cfg(feature = "std")]
+        eprintln!(
+            "{}",
+            trace::segment(
+                self.local(),
+                self.remote(),
+                tcp,
+                len,
+                self.send.iss,
+                self.receive.irs,
+            )
+        );
+    }
+
+    fn write(&mut self, seq: u32, mut limit: usize) -> io::Result<usize> {
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.receive.nxt;
-        let mut offset = seq.wrapping_sub(self.send.una) as usize;
+        // The window of a SYN is never scaled (RFC 7323 Section 2.2). Like
+        // Linux, a window that isn't a multiple of the scale is rounded up,
+        // so that a buffer smaller than the unit isn't advertised as closed.
+        let shift = match self.window_scale {
+            Some(scale) if !self.tcp.syn => scale.rcv,
+            _ => 0,
+        };
+        let window = core::cmp::min(
+            self.advertised_window().div_ceil(1 << shift),
+            u16::MAX as u32,
+        );
+        self.tcp.window_size = window as u16;
+        self.receive.wnd = window << shift;
+        self.rcv_edge = self.receive.nxt.wrapping_add(self.receive.wnd);
+        let now = self.now();
+        self.rcv_buffer
+            .on_advertise(self.receive.nxt, self.receive.wnd, now);
+        let data_start = self.data_start();
+        let mut offset = core::cmp::min(seq.wrapping_sub(data_start) as usize, self.unacked.len());
 
         // Handle special cases of SYN and FIN
         // If asked to send bytes starting at after SYN/FIN, do not read any data
@@ -175,51 +745,84 @@ impl Connection {
             t = &t[(offset - skipped)..];
         }
 
-        let max_data = std::cmp::min(limit, h.len() + t.len());
-        let size = std::cmp::min(
-            buf.len(),
-            self.tcp.header_len() as usize + self.ip.header_len() as usize + max_data,
+        let max_data = core::cmp::min(limit, h.len() + t.len());
+
+        // The options go in before sizing the segment, as they take room
+        // from its payload
+        let mut opts = OptionsBuilder::new();
+        // SYNs announce the largest segment that fits the MTU
+        if self.tcp.syn {
+            let mss = self.config.mtu.saturating_sub(IP_TCP_HEADERS);
+            opts.push(&TcpOption::Mss(u16::try_from(mss).unwrap_or(u16::MAX)));
+            if let Some(scale) = self.window_scale {
+                opts.push(&TcpOption::WindowScale(scale.rcv));
+            }
+        }
+        // Keep advertising the user timeout until the peer acknowledges a
+        // segment that carried it
+        let mut uto_sent = false;
+        if self.user_timeout.advertise {
+            if let Some(timeout) = self.user_timeout.local {
+                uto_sent = opts.push(&TcpOption::UserTimeout(timeout));
+            }
+        }
+        let _ = self.tcp.set_options_raw(opts.as_slice());
+
+        let size = core::cmp::min(
+            self.config.mtu,
+            self.tcp.header_len() + self.ip.header_len() + max_data,
         );
-        let _ = self
-            .ip
-            .set_payload_len(size - self.ip.header_len() as usize);
-
-        // write out the headers and the payload
-        let buf_len = buf.len();
-        let mut unwritten = &mut buf[..];
-
-        self.ip.write(&mut unwritten)?;
-        let ip_header_ends_at = buf_len - unwritten.len();
-
-        // postpone writing the tcp header because we need the payload as one contiguous slice to calculate the tcp checksum
-        unwritten = &mut unwritten[self.tcp.header_len() as usize..];
-        let tcp_hdr_end_off = buf_len - unwritten.len();
-
-        // write out the payload
-        let payload_bytes = {
-            let mut written = 0;
-            let mut limit = max_data;
-
-            // Write as much as we can from head
-            let p1len = std::cmp::min(limit, h.len());
-            written += unwritten.write(&h[..p1len])?;
-            limit -= written;
-
-            // Write more from tail
-            let p2len = std::cmp::min(limit, t.len());
-            written += unwritten.write(&t[..p2len])?;
-            written
-        };
-        let payload_end_off = buf_len - unwritten.len();
+        let _ = self.ip.set_payload_len(size - self.ip.header_len());
 
-        // Calculate checksum
-        self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &buf[tcp_hdr_end_off..payload_end_off])
-            .expect("failed to compute checksum");
+        // Gather the payload as one contiguous slice to calculate the tcp
+        // checksum: as much as we can from head, then more from tail. The
+        // headers are kept apart from it.
+        let header_len = self.ip.header_len() + self.tcp.header_len();
+        let payload_max = size - header_len;
+        let mut payload = self.config.buffers.take();
+        let p1len = core::cmp::min(payload_max, h.len());
+        payload.extend_from_slice(&h[..p1len]);
+        let p2len = core::cmp::min(payload_max - p1len, t.len());
+        payload.extend_from_slice(&t[..p2len]);
+        let payload_bytes = payload.len();
+        let end = seq.wrapping_add(payload_bytes as u32);
+        self.tcp.psh = self
+            .push_at
+            .is_some_and(|at| Self::wrapping_lt(seq, at) && !Self::wrapping_lt(end, at));
+
+        // The FIN goes out with the last byte of data only. If it didn't fit
+        // and was never sent before, it is queued again with the next data.
+        if let (true, Some(closed_at)) = (self.tcp.fin, self.closed_at) {
+            if seq.wrapping_add(payload_bytes as u32) != closed_at {
+                self.tcp.fin = false;
+                if !Self::wrapping_lt(closed_at, self.send.nxt) {
+                    self.closed_at = None;
+                }
+            }
+        }
 
-        let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_hdr_end_off];
-        self.tcp.write(&mut tcp_header_buf)?;
+        // Calculate checksum, summing the payload of a segment only the
+        // first time it is sent
+        let sum = match self.payload_sums.get(&seq) {
+            Some(&(len, sum)) if len == payload_bytes => sum,
+            _ => {
+                let sum = checksum::payload_sum(&payload);
+                if payload_bytes > 0 {
+                    self.payload_sums.insert(seq, (payload_bytes, sum));
+                }
+                sum
+            }
+        };
+        self.tcp.checksum = checksum::tcp_checksum(&self.ip, &self.tcp, payload_bytes, sum);
+
+        self.ip.header_checksum = self.ip.calc_header_checksum();
+        let packet = Packet::new(&self.ip, &self.tcp, payload);
+        if self.config.trace {
+            self.trace_sent(&self.tcp, payload_bytes);
+        }
+        if let Some(history) = &mut self.history {
+            history.record(now, Direction::Sent, &self.tcp, payload_bytes);
+        }
 
         // Adjust send sequence space
         let mut next_seq = seq.wrapping_add(payload_bytes as u32);
@@ -232,22 +835,107 @@ impl Connection {
             next_seq = next_seq.wrapping_add(1);
             self.tcp.fin = false;
         }
+        // Sequence space below SND.NXT went out before
+        let retransmission = Self::wrapping_lt(seq, self.send.nxt);
         if Self::wrapping_lt(self.send.nxt, next_seq) {
             self.send.nxt = next_seq;
         }
-        self.timers.send_times.insert(seq, time::Instant::now());
+        if next_seq != seq {
+            self.timers.last_activity = self.now();
+            if self.timers.unacked_since.is_none() {
+                self.timers.unacked_since = Some(self.now());
+            }
+            if uto_sent {
+                self.user_timeout.advertised_through = Some(next_seq);
+            }
+        }
+        let _ = self.tcp.set_options_raw(&[]);
+        self.timers.send_times.insert(seq, self.now());
+        if retransmission {
+            self.timers.retransmitted.insert(seq);
+        }
 
-        nic.send(&buf[..payload_end_off])?;
+        self.actions.push(Action::Transmit(packet));
         Ok(payload_bytes)
     }
 
     pub fn on_packet(
         &mut self,
-        nic: &tun_tap::Iface,
+        drops: &mut DropStats,
+        ip: Ipv4HeaderSlice,
+        tcp: TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<Available> {
+        self.on_packet_into(drops, ip, tcp, data, &mut [])
+            .map(|(avail, _)| avail)
+    }
+
+    /// `on_packet()` for a connection whose reader waits with an empty
+    /// receive queue: in-order data goes straight into `buf`, as if it was
+    /// queued and read, and only what doesn't fit is queued. Returns the
+    /// availability and the bytes copied into `buf`.
+    pub fn on_packet_into(
+        &mut self,
+        drops: &mut DropStats,
+        ip: Ipv4HeaderSlice,
+        tcp: TcpHeaderSlice,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<(Available, usize)> {
+        self.record_with(|| Record::Segment([ip.slice(), tcp.slice(), data].concat()));
+        self.cause = Cause::Segment(SegmentSummary {
+            syn: tcp.syn(),
+            ack: tcp.ack(),
+            fin: tcp.fin(),
+            rst: tcp.rst(),
+            seq: tcp.sequence_number(),
+            ack_number: tcp.acknowledgment_number(),
+            len: data.len(),
+        });
+        let mut reader = DirectRead { buf, filled: 0 };
+        let result = self.process_segment(drops, ip, tcp, data, &mut reader);
+        self.cause = Cause::User;
+        result.map(|avail| (avail, reader.filled))
+    }
+
+    fn process_segment(
+        &mut self,
+        drops: &mut DropStats,
         _ip: Ipv4HeaderSlice,
         tcp: TcpHeaderSlice,
         data: &[u8],
+        reader: &mut DirectRead,
     ) -> io::Result<Available> {
+        if self.config.trace {
+            self.trace_received(&tcp, data.len());
+        }
+        self.remember_received(&tcp, data.len());
+        if let State::Closed = self.state {
+            // Connection was aborted, nothing more to process
+            drops.record(DropReason::ConnectionClosed);
+            return Ok(self.availability());
+        }
+        let opts = SegmentOptions::parse(tcp.options());
+        if let Some(timeout) = opts.user_timeout {
+            self.user_timeout.remote = Some(timeout);
+        }
+
+        // A SYN for a connection that exists is never processed as data
+        if tcp.syn() && !tcp.rst() {
+            drops.record(DropReason::DuplicateSyn);
+            if let State::SynReceived = self.state {
+                // The peer retransmitted its SYN, so our SYN,ACK was lost;
+                // if it started over with another ISN, the SYN,ACK makes
+                // it reset the stale connection
+                self.tcp.syn = true;
+                self.write(self.send.iss, 0)?;
+            } else {
+                // Challenge ACK (RFC 5961 Section 4)
+                self.write(self.send.nxt, 0)?;
+            }
+            return Ok(self.availability());
+        }
+
         // First check if sequence numbers are valid
         let seq = tcp.sequence_number();
         let mut slen = data.len() as u32;
@@ -262,35 +950,55 @@ impl Connection {
         // RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
         // or
         // RCV.NXT =< SEG.SEQ + SEG.LEN-1 < RCV.NXT+RCV.WND
-        let wend = self.receive.nxt.wrapping_add(self.receive.wnd as u32);
+        let wend = self.receive.nxt.wrapping_add(self.receive.wnd);
         let okay = if slen == 0 {
             // zero length segment
             if self.receive.wnd == 0 {
                 seq == self.receive.nxt
-            } else if !Self::is_between_wrapped(self.receive.nxt.wrapping_sub(1), seq, wend) {
-                false
             } else {
-                true
+                Self::is_between_wrapped(self.receive.nxt.wrapping_sub(1), seq, wend)
             }
         } else if self.receive.wnd == 0 {
             false
-        } else if !Self::is_between_wrapped(self.receive.nxt.wrapping_sub(1), seq, wend)
-            && !Self::is_between_wrapped(
-                self.receive.nxt.wrapping_sub(1),
-                seq.wrapping_add(slen - 1),
-                wend,
-            )
-        {
-            false
         } else {
-            true
+            Self::is_between_wrapped(self.receive.nxt.wrapping_sub(1), seq, wend)
+                || Self::is_between_wrapped(
+                    self.receive.nxt.wrapping_sub(1),
+                    seq.wrapping_add(slen - 1),
+                    wend,
+                )
         };
 
         if !okay {
-            // Not acceptable
-            self.write(nic, self.send.nxt, 0)?;
+            // Not acceptable: ACK it, unless it is a reset which is dropped
+            drops.record(if slen > 0 && self.receive.wnd == 0 {
+                DropReason::ZeroWindow
+            } else {
+                DropReason::OutOfWindow
+            });
+            if !tcp.rst() {
+                self.write(self.send.nxt, 0)?;
+            }
+            return Ok(self.availability());
+        }
+
+        if let Some(ts) = opts.timestamp() {
+            if self
+                .ts_recent
+                .is_none_or(|recent| !Self::wrapping_lt(ts, recent))
+            {
+                self.ts_recent = Some(ts);
+            }
+        }
+
+        if tcp.rst() {
+            // A reset is valid if its sequence number is in the window
+            // RFC 793 Section 3.4: abort the connection
+            self.events.push(ConnectionEvent::ResetReceived);
+            self.abort(io::ErrorKind::ConnectionReset);
             return Ok(self.availability());
         }
+        self.timers.last_activity = self.now();
         // Adjust receive sequence space: we have accepted the segment
         // self.receive.nxt = seq.wrapping_add(slen);
 
@@ -298,9 +1006,10 @@ impl Connection {
         // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
 
         if !tcp.ack() {
-            if tcp.syn() {
-                // SYN as part of initial handshake
-                self.receive.nxt = seq.wrapping_add(1);
+            if let State::SynReceived = self.state {
+                if self.queue_early(seq, data) > 0 {
+                    self.write(self.send.nxt, 0)?;
+                }
             }
             return Ok(self.availability());
         }
@@ -315,53 +1024,199 @@ impl Connection {
             ) {
                 // The peer must have ACK-ed out SYN, since we detected atleast
                 // one ACK-ed byte which was for the to SYN
-                self.state = State::Established;
+                self.set_state(State::Established);
             } else {
-                //TODO: Form a RST segment: SEQ=SEG.ACK><CTL=RST>
+                // Unacceptable ACK in a non-synchronized state:
+                // form a reset segment <SEQ=SEG.ACK><CTL=RST> and drop the segment
+                drops.record(DropReason::UnacceptableAck);
+                self.send_rst(ack, None)?;
+                return Ok(self.availability());
             }
         }
 
-        if let State::Established | State::FinWait1 | State::FinWait2 = self.state {
+        // RFC 9293 3.10.7.4: an ACK for something not yet sent
+        // (SEG.ACK > SND.NXT) is answered with an ACK and the segment is
+        // dropped, leaving SND.UNA and the send queue alone
+        if Self::wrapping_lt(self.send.nxt, ack) {
+            drops.record(DropReason::AckOfUnsentData);
+            self.write(self.send.nxt, 0)?;
+            return Ok(self.availability());
+        }
+
+        // Duplicate ACK RFC 5681 Section 2: no data, no change to the window
+        // or SND.UNA, while data is outstanding
+        if ack == self.send.una
+            && slen == 0
+            && self.peer_window(&tcp) == self.send.wnd
+            && self.send.una != self.send.nxt
+        {
+            self.path.dup_acks += 1;
+        }
+
+        let frto = self.frto_on_ack(ack, data.is_empty());
+
+        if let State::Established
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait
+        | State::Closing
+        | State::LastAck = self.state
+        {
+            // Window update RFC 9293 3.10.7.4: if SND.UNA =< SEG.ACK =<
+            // SND.NXT, take the window unless the segment is older than the
+            // one that last updated it, as told by SND.WL1 and SND.WL2
+            if Self::is_between_wrapped(
+                self.send.una.wrapping_sub(1),
+                ack,
+                self.send.nxt.wrapping_add(1),
+            ) && (Self::wrapping_lt(self.send.wl1, seq)
+                || (self.send.wl1 == seq && !Self::wrapping_lt(ack, self.send.wl2)))
+            {
+                self.send.wnd = self.peer_window(&tcp);
+                self.send.wl1 = seq;
+                self.send.wl2 = ack;
+            }
             if Self::is_between_wrapped(self.send.una, ack, self.send.nxt.wrapping_add(1)) {
+                let mut rtt = None;
+                let mut delivered: Option<(time::Instant, bool)> = None;
                 // Remove ACK-ed bytes from retransmission queue
                 if !self.unacked.is_empty() {
-                    let data_start = if self.send.una == self.send.iss {
-                        // send.una hasn't been updated yet with ACK for SYN
-                        self.send.una.wrapping_add(1)
-                    } else {
-                        self.send.una
-                    };
-                    let acked_data_end =
-                        std::cmp::min(ack.wrapping_sub(data_start) as usize, self.unacked.len());
+                    // SND.UNA isn't updated with the ACK for our SYN yet
+                    let acked_data_end = core::cmp::min(
+                        ack.wrapping_sub(self.data_start()) as usize,
+                        self.unacked.len(),
+                    );
                     self.unacked.drain(..acked_data_end);
+                    self.bytes_acked += acked_data_end as u64;
 
+                    let now = self.now();
+                    // Segments are keyed by their first sequence number, so
+                    // the one at SND.UNA is acknowledged too
                     self.timers.send_times.retain(|seq, sent| {
-                        if Self::is_between_wrapped(self.send.una, *seq, ack) {
-                            let rtt = sent.elapsed().as_secs_f64();
-                            self.timers.srtt = 0.8 * self.timers.srtt + (1. - 0.8) * rtt;
+                        if !Self::wrapping_lt(*seq, self.send.una) && Self::wrapping_lt(*seq, ack) {
+                            let retransmitted = self.timers.retransmitted.remove(seq);
+                            if delivered.is_none_or(|(at, _)| at <= *sent) {
+                                delivered = Some((*sent, retransmitted));
+                            }
+                            // Karn's algorithm: the ACK of a retransmitted
+                            // segment may be for any of its transmissions
+                            // (RFC 6298 Section 3)
+                            if retransmitted {
+                                return false;
+                            }
+                            let sample = now.saturating_duration_since(*sent);
+                            rtt = Some(sample);
+                            let sample = sample.as_secs_f64();
+                            // The first measurement replaces the initial
+                            // value (RFC 6298 Section 2.2)
+                            self.timers.srtt = if self.timers.rtt_measured {
+                                0.8 * self.timers.srtt + (1. - 0.8) * sample
+                            } else {
+                                sample
+                            };
+                            self.timers.rtt_measured = true;
                             false
                         } else {
                             true
                         }
                     });
+                    self.payload_sums.retain(|seq, (len, _)| {
+                        Self::wrapping_lt(ack, seq.wrapping_add(*len as u32))
+                    });
+                }
+
+                if let Some((sent, retransmitted)) = delivered {
+                    self.rack.on_delivered(sent, ack, retransmitted, self.now());
                 }
 
+                let acked = ack.wrapping_sub(self.send.una) as usize;
+                let in_flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+                self.cc.on_ack(acked, in_flight, rtt, self.now());
+                self.tune_send_buffer();
+
                 self.send.una = ack;
+                self.timers.retransmits = 0;
+                self.timers.r1_reported = false;
+                self.timers.unacked_since = if self.send.una == self.send.nxt {
+                    None
+                } else {
+                    Some(self.now())
+                };
+                if self.push_at.is_some_and(|at| !Self::wrapping_lt(ack, at)) {
+                    self.push_at = None;
+                }
+                if let Some(end) = self.recovery_end {
+                    if !Self::wrapping_lt(ack, end) {
+                        self.recovery_end = None;
+                        self.rack.on_recovery_done();
+                    }
+                }
+                self.sample_cwnd();
+                if let Some(high) = self.timers.tlp_high {
+                    if !Self::wrapping_lt(ack, high) {
+                        // Everything up to the probe got through
+                        self.timers.tlp_high = None;
+                    }
+                }
+                self.arm_probe();
+                if let Some(through) = self.user_timeout.advertised_through {
+                    if !Self::wrapping_lt(ack, through) {
+                        // Peer received our user timeout
+                        self.user_timeout.advertise = false;
+                        self.user_timeout.advertised_through = None;
+                    }
+                }
             }
         }
 
-        if let State::FinWait1 = self.state {
-            if let Some(closed_at) = self.closed_at {
-                if self.send.una == closed_at.wrapping_add(1) {
-                    // Sender would have ACK-ed our FIN.
-                    self.state = State::FinWait2;
+        match frto {
+            Some(FrtoResponse::SendNew) => {
+                // Probe with new data: only an ACK for it can tell whether
+                // the retransmission was needed
+                let send = core::cmp::min(self.unsent(), 2 * self.mss() as u32);
+                let window = self.send.wnd;
+                let in_flight = self.send.nxt.wrapping_sub(self.send.una);
+                let send = core::cmp::min(send, window.saturating_sub(in_flight));
+                if send == 0 {
+                    self.frto = None;
+                    self.retransmit_conventional()?;
+                } else {
+                    self.write(self.send.nxt, send as usize)?;
+                }
+            }
+            Some(FrtoResponse::Conventional) => self.retransmit_conventional()?,
+            None => self.rack_detect_loss()?,
+        }
+
+        let fin_acked = self
+            .closed_at
+            .is_some_and(|closed_at| self.send.una == closed_at.wrapping_add(1));
+        if fin_acked {
+            // Sender would have ACK-ed our FIN.
+            match self.state {
+                State::FinWait1 => {
+                    self.set_state(State::FinWait2);
+                    self.timers.fin_wait2_since = Some(self.now());
                 }
+                State::Closing => self.set_state(State::TimeWait),
+                State::LastAck => self.set_state(State::Closed),
+                _ => {}
             }
         }
 
         // Handle reads
         if !data.is_empty() {
             if let State::Established | State::FinWait1 | State::FinWait2 = self.state {
+                if Self::wrapping_lt(self.receive.nxt, seq) {
+                    let distance = seq.wrapping_sub(self.receive.nxt);
+                    self.path.reordering = self.path.reordering.max(distance);
+                    // Data beyond a hole isn't queued: ACK what we have
+                    // right away, a duplicate ACK that lets the peer fast
+                    // retransmit (RFC 5681 Section 4.2)
+                    self.write(self.send.nxt, 0)?;
+                    self.path.dup_acks_sent += 1;
+                    return Ok(self.availability());
+                }
                 // offset to unread data
                 let mut data_off = self.receive.nxt.wrapping_sub(seq) as usize;
                 if data_off > data.len() {
@@ -370,30 +1225,62 @@ impl Connection {
                     assert_eq!(data_off, data.len() + 1);
                     data_off = 0;
                 }
-                self.ingress.extend(&data[data_off..]);
+                // In-order data is delivered right away, which is all PSH
+                // asks of the receiver (RFC 9293 3.9.1.2): to a waiting
+                // reader while nothing is queued before it, else to the
+                // receive queue. What doesn't fit in the receive buffer is
+                // left for the peer to send again.
+                let data = &data[data_off..];
+                let direct = if self.ingress.is_empty() {
+                    reader.take(data)
+                } else {
+                    0
+                };
+                if direct > 0 {
+                    self.record_with(|| Record::Consume(direct));
+                }
+                let queued = core::cmp::min(data.len() - direct, self.ingress_room());
+                self.ingress.extend(&data[direct..direct + queued]);
+                let len = direct + queued;
+                self.actions.push(Action::Deliver(len));
 
                 // Adjust receive sequence space: we have accepted the segment
                 // Once the TCP takes responsibility for the data it advances
                 // RCV.NXT over the data accepted, and adjusts RCV.WND as
                 // appropriate to the current buffer availability.  The total of
                 // RCV.NXT and RCV.WND should not be reduced.
-                self.receive.nxt = seq.wrapping_add(data.len() as u32);
+                self.receive.nxt = seq.wrapping_add((data_off + len) as u32);
+                let now = self.now();
+                self.rcv_buffer.on_data(self.receive.nxt, len, now);
 
                 // Send ACK: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
-                self.write(nic, self.send.nxt, 0)?;
+                self.write(self.send.nxt, 0)?;
             }
         }
 
         if tcp.fin() {
+            let fin_seq = seq.wrapping_add(data.len() as u32);
             match self.state {
-                State::FinWait2 => {
-                    // Connection terminated
+                State::Established | State::FinWait1 | State::FinWait2
+                    if fin_seq == self.receive.nxt =>
+                {
+                    // Peer is done sending: ACK its FIN
                     self.receive.nxt = self.receive.nxt.wrapping_add(1);
-                    // Sender would have ACK-ed our FIN - ACK sender's FIN
-                    self.write(nic, self.send.nxt, 0)?;
-                    self.state = State::TimeWait;
+                    self.write(self.send.nxt, 0)?;
+                    self.set_state(match self.state {
+                        State::Established => State::CloseWait,
+                        // Our FIN is not ACK-ed yet, or we'd be in FIN-WAIT-2
+                        State::FinWait1 => State::Closing,
+                        // Connection terminated
+                        _ => State::TimeWait,
+                    });
                 }
-                _ => unimplemented!(),
+                State::CloseWait | State::Closing | State::LastAck | State::TimeWait => {
+                    // Retransmitted FIN: our ACK must have been lost
+                    self.write(self.send.nxt, 0)?;
+                }
+                // FIN beyond a hole, handled once the data before it arrives
+                _ => {}
             }
         }
 
@@ -402,23 +1289,67 @@ impl Connection {
 
     /// Decide if something needs to be transmitted. Check if we have
     /// space in the window. If so, transmit it.
-    pub fn on_timer(&mut self, nic: &tun_tap::Iface) -> io::Result<()> {
-        if let State::FinWait2 | State::TimeWait = self.state {
+    pub fn on_timer(&mut self) -> io::Result<Available> {
+        self.record_with(|| Record::Timer);
+        self.cause = Cause::Timer;
+        let result = self.expire_timers();
+        self.cause = Cause::User;
+        result
+    }
+
+    fn expire_timers(&mut self) -> io::Result<Available> {
+        let now = self.now();
+        if let State::FinWait2 = self.state {
+            // Don't wait forever for a peer that never sends its FIN
+            if let (Some(timeout), Some(since)) =
+                (self.config.fin_wait2_timeout, self.timers.fin_wait2_since)
+            {
+                if now.saturating_duration_since(since) > timeout {
+                    if self.config.fin_wait2_reset {
+                        self.send_rst(self.send.nxt, None)?;
+                    }
+                    self.set_state(State::Closed);
+                }
+            }
+        }
+        if let State::FinWait2 | State::TimeWait | State::Closed = self.state {
             // Shutdown write from our side and the peer ACKed, no need to (re)transmit anything
-            return Ok(());
+            return Ok(self.availability());
+        }
+        // Reset connections that have been idle for too long
+        if self.idle_timeout.is_some_and(|timeout| {
+            now.saturating_duration_since(self.timers.last_activity) > timeout
+        }) {
+            self.send_rst(self.send.nxt, None)?;
+            self.abort(io::ErrorKind::TimedOut);
+            return Ok(self.availability());
+        }
+        if let State::SynReceived = self.state {
+            self.retransmit_synack()?;
+            return Ok(self.availability());
         }
 
-        // bytes sent but not ACK-ed
+        // Give up if data stayed unacknowledged for longer than the user timeout
+        if let (Some(timeout), Some(since)) =
+            (self.user_timeout.effective(), self.timers.unacked_since)
+        {
+            if now.saturating_duration_since(since) > timeout {
+                self.abort(io::ErrorKind::TimedOut);
+                return Ok(self.availability());
+            }
+        }
+
+        // Segments that were waiting out the reordering window
+        if self.rack.timeout_due(self.now()) {
+            self.rack_detect_loss()?;
+        }
 
-        // let unacked = self.send.nxt.wrapping_sub(self.send.una);
+        // bytes sent but not ACK-ed
         let unacked = self
             .closed_at
             .unwrap_or(self.send.nxt)
             .wrapping_sub(self.send.una);
-
-        // bytes not sent yet
-        // let unsent = self.unacked.len() as u32 - unacked;
-        let unsent = self.unacked.len() as u32 - unacked;
+        let unsent = self.unsent();
 
         // Get the elapsed time of the first unacked send time
         let waited_for = self
@@ -426,70 +1357,409 @@ impl Connection {
             .send_times
             .range(self.send.una..)
             .next()
-            .map(|t| t.1.elapsed());
+            .map(|t| now.saturating_duration_since(*t.1));
 
         let should_restransmit = if let Some(waited_for) = waited_for {
-            waited_for > time::Duration::from_secs(1)
-                && waited_for.as_secs_f64() > 1.5 * self.timers.srtt
+            waited_for > self.rto()
         } else {
             false // no timers
         };
 
         if should_restransmit {
-            // retransmit
-            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
+            // Give up on a peer that doesn't acknowledge anything anymore (R2)
+            if self
+                .config
+                .r2
+                .reached(self.timers.retransmits, self.timers.unacked_since, now)
+            {
+                self.abort(io::ErrorKind::TimedOut);
+                return Ok(self.availability());
+            }
+            self.timers.retransmits += 1;
+            // Past R1 the path may be broken: let the user know (R1)
+            if !self.timers.r1_reported
+                && self
+                    .config
+                    .r1
+                    .reached(self.timers.retransmits, self.timers.unacked_since, now)
+            {
+                self.timers.r1_reported = true;
+                self.soft_error = Some(io::ErrorKind::TimedOut);
+                self.r1_crossed = true;
+            }
+            self.cc.on_timeout(unacked as usize);
+            self.timers.pto = None;
+            self.recovery_end = None;
+            self.sample_cwnd();
+            // Only the first timeout of a run of retransmissions can be
+            // recovered from with F-RTO
+            self.frto = if self.config.frto && self.timers.retransmits == 1 {
+                Some(Frto::Retransmitted {
+                    recover: self.send.nxt,
+                })
+            } else {
+                None
+            };
+
+            // retransmit as much as the collapsed congestion window allows,
+            // which is just the first unacknowledged segment, or the byte
+            // probing a zero window
+            let window = core::cmp::min(self.send.wnd, self.cc.cwnd() as u32).max(1);
+            let resend = core::cmp::min(self.unacked.len() as u32, window);
             // Also check 'self.unacked.len() == 0' if FIN shouldn't be piggybacked to data
-            if resend < self.send.wnd as u32 && self.closed_at.is_some() {
+            if resend < window && self.closed_at.is_some() {
                 // If no data to send and connection was closed, do nothing
                 self.tcp.fin = true;
-                self.closed_at = Some(self.send.nxt.wrapping_add(self.unacked.len() as u32));
+                self.closed_at = Some(self.data_start().wrapping_add(self.unacked.len() as u32));
             }
 
-            self.write(nic, self.send.una, resend as usize)?;
+            self.on_retransmit(self.send.una, resend as usize);
+            self.write(self.send.una, resend as usize)?;
+        } else if self.timers.pto.is_some_and(|pto| pto <= self.now()) {
+            self.send_probe(unsent)?;
         } else {
-            // send new data if available and there is space in the window
-            if unsent == 0 && !self.closed {
+            self.send_new_data()?;
+        }
+
+        Ok(self.availability())
+    }
+
+    /// Send queued data as segments of up to one MSS while the bytes in
+    /// flight stay within both the peer's window and the congestion window,
+    /// with the FIN on the last one once the user closed
+    fn send_new_data(&mut self) -> io::Result<()> {
+        if self.closed_at.is_some() {
+            // Everything up to the FIN went out already
+            return Ok(());
+        }
+        let window = core::cmp::min(self.send.wnd, self.cc.cwnd() as u32);
+        let now = self.now();
+        // Paced senders wait for the release time and send small bursts
+        let paced = self.config.pacing && self.timers.rtt_measured;
+        let mut budget = u32::MAX;
+        if paced {
+            if !self.pacer.ready(now) {
                 return Ok(());
             }
-            let allowed = self.send.wnd as u32 - unacked;
-            if allowed == 0 {
+            budget = 2 * self.mss() as u32;
+        }
+        if let Some(bucket) = &mut self.rate_limit {
+            // Wait until a full segment may go out rather than sending
+            // small ones as the tokens trickle in
+            let tokens = bucket.available(now);
+            if tokens < self.unsent().min(self.mss() as u32) {
                 return Ok(());
             }
-            let send = std::cmp::min(unsent, allowed);
-            // Also check 'unsent == 0' if FIN shouldn't be piggybacked to data
-            if send < allowed && self.closed && self.closed_at.is_none() {
+            budget = budget.min(tokens);
+        }
+
+        let mut sent = 0;
+        loop {
+            let unsent = self.unsent();
+            if (unsent == 0 && !self.closed) || self.closed_at.is_some() || budget == 0 {
+                break;
+            }
+            let in_flight = self.send.nxt.wrapping_sub(self.send.una);
+            // A zero window is probed with one byte while nothing is in
+            // flight; retransmitting it keeps probing until the window opens
+            // RFC 9293 3.8.6.1
+            let allowed = if self.send.wnd == 0 && in_flight == 0 {
+                core::cmp::min(unsent, 1)
+            } else {
+                window.saturating_sub(in_flight)
+            };
+            if allowed == 0 {
+                break;
+            }
+            let send = unsent.min(allowed).min(budget).min(self.mss() as u32);
+            if send == unsent && send < allowed && self.closed {
                 // Send FIN
                 self.tcp.fin = true;
-                self.closed_at = Some(self.send.nxt.wrapping_add(self.unacked.len() as u32));
+                self.closed_at = Some(self.data_start().wrapping_add(self.unacked.len() as u32));
             }
-            self.write(nic, self.send.nxt, send as usize)?;
+            let nxt = self.send.nxt;
+            self.write(nxt, send as usize)?;
+            let len = self.send.nxt.wrapping_sub(nxt);
+            if len == 0 {
+                break;
+            }
+            sent += len;
+            budget = budget.saturating_sub(len);
         }
 
+        if sent > 0 {
+            if let Some(bucket) = &mut self.rate_limit {
+                bucket.take(sent);
+            }
+            if paced {
+                self.pacer.on_send(
+                    sent as usize,
+                    self.cc.cwnd(),
+                    time::Duration::from_secs_f64(self.timers.srtt),
+                    self.cc.in_slow_start(),
+                    self.now(),
+                );
+            }
+            self.arm_probe();
+        }
         Ok(())
     }
 
-    /// TCP half-domain wrapping
-    ///
-    /// It is essential to remember that the actual sequence number space is
-    /// finite, though very large.  This space ranges from 0 to 2**32 - 1.
-    /// Since the space is finite, all arithmetic dealing with sequence
-    /// numbers must be performed modulo 2**32.  This unsigned arithmetic
-    /// preserves the relationship of sequence numbers as they cycle from
-    /// 2**32 - 1 to 0 again. The symbol "=<" means "less than or equal"
-    /// [modulo 2**32].
-    ///
-    /// RFC 1323:
-    /// TCP determines if a data segment is "old" or "new" by testing
-    /// whether its sequence number is within 2**31 bytes of the left edge
-    /// of the window, and if it is not, discarding the data as "old".  To
-    /// insure that new data is never mistakenly considered old and vice-
-    /// versa, the left edge of the sender's window has to be at most
-    /// 2**31 away from the right edge of the receiver's window.
-    ///
-    /// This function checks if a sequence number `x` is between `start` and `end`
-    /// in a circular space, considering the wrapping behavior of TCP sequence numbers.
-    ///
-    /// # Arguments
+    /// Retransmission timeout: 1.5*SRTT but at least a second, and the
+    /// initial RTO until a round trip was measured RFC 6298 Section 2.1
+    fn rto(&self) -> time::Duration {
+        if !self.timers.rtt_measured {
+            return INITIAL_RTO;
+        }
+        core::cmp::max(
+            time::Duration::from_secs(1),
+            time::Duration::from_secs_f64(1.5 * self.timers.srtt),
+        )
+    }
+
+    /// Probe timeout RFC 8985 Section 7.2: PTO = 2*SRTT, plus the worst case
+    /// delayed ACK time when only one segment is in flight. Without an RTT
+    /// sample it is the initial RTO.
+    fn probe_timeout(&self, in_flight: u32) -> time::Duration {
+        if !self.timers.rtt_measured {
+            return INITIAL_RTO;
+        }
+        let mut pto = time::Duration::from_secs_f64(2. * self.timers.srtt);
+        if in_flight <= self.mss() as u32 {
+            pto += WC_DEL_ACK;
+        }
+        core::cmp::max(pto, MIN_PTO)
+    }
+
+    /// (Re)schedule the tail loss probe after new data was sent or
+    /// acknowledged, unless a probe is already waiting for its ACK
+    fn arm_probe(&mut self) {
+        let in_flight = self.send.nxt.wrapping_sub(self.send.una);
+        self.timers.pto = if self.config.tlp
+            && in_flight > 0
+            && self.timers.retransmits == 0
+            && self.timers.tlp_high.is_none()
+        {
+            Some(self.now() + self.probe_timeout(in_flight))
+        } else {
+            None
+        };
+    }
+
+    /// Send a tail loss probe RFC 8985 Section 7.3: new data when there is
+    /// some and the peer's window allows it, otherwise the last segment
+    /// sent. Whatever the peer acknowledges in response reveals the loss
+    /// without waiting for the retransmission timeout.
+    fn send_probe(&mut self, unsent: u32) -> io::Result<()> {
+        self.timers.pto = None;
+        let in_flight = self.send.nxt.wrapping_sub(self.send.una);
+        let window = self.send.wnd;
+        if unsent > 0 && in_flight < window {
+            let send = core::cmp::min(unsent, self.mss() as u32).min(window - in_flight);
+            self.write(self.send.nxt, send as usize)?;
+        } else {
+            let data_end = self.closed_at.unwrap_or(self.send.nxt);
+            let len = core::cmp::min(data_end.wrapping_sub(self.send.una), self.mss() as u32);
+            if self.closed_at.is_some() {
+                self.tcp.fin = true;
+            }
+            self.on_retransmit(data_end.wrapping_sub(len), len as usize);
+            self.write(data_end.wrapping_sub(len), len as usize)?;
+        }
+        self.timers.tlp_high = Some(self.send.nxt);
+        Ok(())
+    }
+
+    /// Sequence number of the first unacknowledged byte of data: data
+    /// starts after our SYN until it is acknowledged
+    fn data_start(&self) -> u32 {
+        if self.send.una == self.send.iss {
+            self.send.una.wrapping_add(1)
+        } else {
+            self.send.una
+        }
+    }
+
+    /// Bytes queued by the application that were not sent yet
+    fn unsent(&self) -> u32 {
+        let in_flight = self
+            .closed_at
+            .unwrap_or(self.send.nxt)
+            .wrapping_sub(self.data_start());
+        (self.unacked.len() as u32).saturating_sub(in_flight)
+    }
+
+    /// Run an acknowledgment through F-RTO RFC 5682 Section 2.1, before it
+    /// updates SND.UNA. A spurious timeout is undone right away; any
+    /// transmission to make in response is returned.
+    fn frto_on_ack(&mut self, ack: u32, no_data: bool) -> Option<FrtoResponse> {
+        let frto = self.frto.take()?;
+        let advances = Self::is_between_wrapped(self.send.una, ack, self.send.nxt.wrapping_add(1));
+        let duplicate = ack == self.send.una && no_data;
+
+        match frto {
+            Frto::Retransmitted { recover } => {
+                if advances && Self::wrapping_lt(ack, recover) {
+                    // Part of the outstanding data was acknowledged
+                    self.frto = Some(Frto::SentNew);
+                    Some(FrtoResponse::SendNew)
+                } else if advances || duplicate {
+                    Some(FrtoResponse::Conventional)
+                } else {
+                    self.frto = Some(frto);
+                    None
+                }
+            }
+            Frto::SentNew => {
+                if advances {
+                    // Data sent before the timeout was acknowledged without
+                    // needing the retransmission: the timeout was spurious
+                    self.cc.undo_timeout();
+                    self.rack.on_reordering();
+                    self.path.spurious_retransmits += 1;
+                    self.sample_cwnd();
+                    None
+                } else if duplicate {
+                    Some(FrtoResponse::Conventional)
+                } else {
+                    self.frto = Some(frto);
+                    None
+                }
+            }
+        }
+    }
+
+    /// Retransmit the segments RACK considers lost, entering loss recovery
+    /// if it isn't in progress yet. Retransmissions are limited to the
+    /// congestion window.
+    fn rack_detect_loss(&mut self) -> io::Result<()> {
+        if !self.config.rack || self.frto.is_some() {
+            return Ok(());
+        }
+        if let State::SynReceived | State::FinWait2 | State::TimeWait | State::Closed = self.state {
+            return Ok(());
+        }
+        let (una, nxt) = (self.send.una, self.send.nxt);
+        // Outstanding segments: each send time entry covers the sequence
+        // space up to the next one
+        let sent: Vec<(u32, time::Instant)> = self
+            .timers
+            .send_times
+            .iter()
+            .filter(|(seq, _)| Self::is_between_wrapped(una.wrapping_sub(1), **seq, nxt))
+            .map(|(seq, at)| (*seq, *at))
+            .collect();
+        let segments = sent
+            .iter()
+            .enumerate()
+            .map(|(i, (seq, at))| (*seq, sent.get(i + 1).map_or(nxt, |next| next.0), *at));
+        let srtt = time::Duration::from_secs_f64(self.timers.srtt);
+        let lost = self.rack.detect_loss(segments, srtt, self.now());
+        if lost.is_empty() {
+            return Ok(());
+        }
+
+        if self.recovery_end.is_none() {
+            self.cc.on_loss(nxt.wrapping_sub(una) as usize);
+            self.recovery_end = Some(nxt);
+            self.sample_cwnd();
+        }
+        let mut budget = self.cc.cwnd() as u32;
+        for (start, end) in lost {
+            if budget == 0 {
+                break;
+            }
+            let mut len = end.wrapping_sub(start);
+            if let Some(closed_at) = self.closed_at {
+                if end == closed_at.wrapping_add(1) {
+                    // the segment carried the FIN
+                    len -= 1;
+                    self.tcp.fin = len <= budget;
+                }
+            }
+            let len = core::cmp::min(len, budget);
+            budget -= len;
+            self.on_retransmit(start, len as usize);
+            self.write(start, len as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Retransmit outstanding data from SND.UNA, as far as the (collapsed)
+    /// congestion window allows
+    fn retransmit_conventional(&mut self) -> io::Result<()> {
+        let in_flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        let resend = core::cmp::min(in_flight, self.cc.cwnd());
+        if resend > 0 {
+            self.on_retransmit(self.send.una, resend);
+            self.write(self.send.una, resend)?;
+        }
+        Ok(())
+    }
+
+    /// Grow the send queue so that it can hold twice the congestion window:
+    /// one window in flight and one ready to go when it is acknowledged.
+    /// The queue never shrinks, and user supplied watermarks are left alone.
+    fn tune_send_buffer(&mut self) {
+        if !self.config.send_buffer_autotune
+            || self.watermarks_locked
+            || self.memory_pressure != MemoryPressure::Normal
+        {
+            return;
+        }
+        let target = core::cmp::min(2 * self.cc.cwnd(), self.config.send_buffer_max);
+        if target > self.watermarks.high {
+            self.watermarks = Watermarks {
+                low: target / 2,
+                high: target,
+            };
+        }
+    }
+
+    /// Retransmit the SYN-ACK if the handshake didn't complete in time,
+    /// doubling the timeout after every attempt. Once the retries are used up
+    /// the embryonic connection is closed.
+    fn retransmit_synack(&mut self) -> io::Result<()> {
+        let Some(sent) = self.timers.send_times.get(&self.send.iss) else {
+            return Ok(());
+        };
+        let timeout = INITIAL_RTO * 2u32.saturating_pow(self.timers.synack_retries);
+        if self.now().saturating_duration_since(*sent) <= timeout {
+            return Ok(());
+        }
+        if self.timers.synack_retries >= self.config.synack_retries {
+            self.abort(io::ErrorKind::TimedOut);
+            return Ok(());
+        }
+        self.timers.synack_retries += 1;
+        self.on_retransmit(self.send.iss, 0);
+        self.tcp.syn = true;
+        self.write(self.send.iss, 0)?;
+        Ok(())
+    }
+
+    /// TCP half-domain wrapping
+    ///
+    /// It is essential to remember that the actual sequence number space is
+    /// finite, though very large.  This space ranges from 0 to 2**32 - 1.
+    /// Since the space is finite, all arithmetic dealing with sequence
+    /// numbers must be performed modulo 2**32.  This unsigned arithmetic
+    /// preserves the relationship of sequence numbers as they cycle from
+    /// 2**32 - 1 to 0 again. The symbol "=<" means "less than or equal"
+    /// [modulo 2**32].
+    ///
+    /// RFC 1323:
+    /// TCP determines if a data segment is "old" or "new" by testing
+    /// whether its sequence number is within 2**31 bytes of the left edge
+    /// of the window, and if it is not, discarding the data as "old".  To
+    /// insure that new data is never mistakenly considered old and vice-
+    /// versa, the left edge of the sender's window has to be at most
+    /// 2**31 away from the right edge of the receiver's window.
+    ///
+    /// This function checks if a sequence number `x` is between `start` and `end`
+    /// in a circular space, considering the wrapping behavior of TCP sequence numbers.
+    ///
+    /// # Arguments
     ///
     /// * `start` - The starting sequence number of the range.
     /// * `x` - The sequence number to check.
@@ -518,14 +1788,604 @@ impl Connection {
     /// * `true` if `lhs` is less than `rhs` in the circular sequence space.
     /// * `false` otherwise.
     fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
-        lhs.wrapping_sub(rhs) > u32::max_value() >> 1
+        lhs.wrapping_sub(rhs) > u32::MAX >> 1
+    }
+
+    /// Send new data at no more than `bytes_per_sec` on average, regardless
+    /// of the congestion window; `None` removes the limit. Retransmissions
+    /// are not limited.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.rate_limit = bytes_per_sec.map(|rate| {
+            // Allow bursts of 100ms worth of data, but at least a segment
+            let burst = u32::try_from(rate / 10)
+                .unwrap_or(u32::MAX)
+                .max(self.mss() as u32);
+            let limit = RateLimit {
+                per_second: rate as f64,
+                burst,
+            };
+            TokenBucket::new(limit, self.now())
+        });
+    }
+
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.rate_limit
+            .as_ref()
+            .map(|bucket| bucket.limit().per_second as u64)
+    }
+
+    /// Reset the connection once it went `timeout` without receiving a
+    /// segment or sending data; `None` exempts it from the idle timeout
+    pub fn set_idle_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    pub fn idle_timeout(&self) -> Option<time::Duration> {
+        self.idle_timeout
+    }
+
+    /// Time since a segment was last received or data last sent
+    pub fn idle_for(&self) -> time::Duration {
+        self.now()
+            .saturating_duration_since(self.timers.last_activity)
+    }
+
+    /// Bound how long data may remain unacknowledged before the connection is
+    /// aborted. The value is advertised to the peer with the User Timeout
+    /// option until a segment carrying it is acknowledged.
+    pub fn set_user_timeout(&mut self, timeout: time::Duration) {
+        self.user_timeout.local = Some(timeout);
+        self.user_timeout.advertise = true;
+        self.user_timeout.advertised_through = None;
+    }
+
+    /// Let a timeout advertised by the peer raise the local one
+    pub fn set_adopt_user_timeout(&mut self, enable: bool) {
+        self.user_timeout.adopt = enable;
+    }
+
+    /// The user timeout in effect for the connection, if any
+    pub fn user_timeout(&self) -> Option<time::Duration> {
+        self.user_timeout.effective()
+    }
+
+    /// What the congestion controller is doing
+    fn congestion_state(&self) -> CongestionState {
+        if self.timers.retransmits > 0 {
+            CongestionState::Loss
+        } else if self.recovery_end.is_some() {
+            CongestionState::Recovery
+        } else if self.cc.in_slow_start() {
+            CongestionState::SlowStart
+        } else {
+            CongestionState::CongestionAvoidance
+        }
+    }
+
+    /// Add the congestion window to its trace, if it is recorded
+    fn sample_cwnd(&mut self) {
+        let now = self.now();
+        let state = self.congestion_state();
+        let in_flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        if let Some(trace) = &mut self.cwnd_trace {
+            trace.record(now, self.cc.cwnd(), self.cc.ssthresh(), in_flight, state);
+        }
+    }
+
+    /// Move to another state, recording the transition for the event handler
+    fn set_state(&mut self, state: State) {
+        if self.state == state {
+            return;
+        }
+        let from = core::mem::replace(&mut self.state, state);
+        let now = self.now();
+        if let Some(log) = &mut self.transitions {
+            log.record(now, from, state, self.cause);
+        }
+        self.events
+            .push(ConnectionEvent::StateChanged { from, to: state });
+        if !self.early.is_empty() && state != State::Closed {
+            // The handshake completed: deliver what arrived along with it
+            self.actions.push(Action::Deliver(self.early.len()));
+            self.ingress.extend(self.early.drain(..));
+        }
+        match state {
+            State::Established => self.events.push(ConnectionEvent::Established),
+            State::Closed => self.events.push(ConnectionEvent::Closed),
+            _ => {}
+        }
+    }
+
+    /// Record that data is being sent again
+    fn on_retransmit(&mut self, seq: u32, len: usize) {
+        self.events
+            .push(ConnectionEvent::Retransmission { seq, len });
+    }
+
+    /// Take the events recorded since the last call
+    pub fn take_events(&mut self) -> Vec<ConnectionEvent> {
+        core::mem::take(&mut self.events)
+    }
+
+    /// Take the actions queued since the last call, followed by the timer
+    /// to arm for the next call to `on_timer`
+    pub fn take_actions(&mut self) -> Vec<Action> {
+        let mut actions = core::mem::take(&mut self.actions);
+        if let Some(at) = self.poll_at() {
+            actions.push(Action::Timer(at));
+        }
+        actions
+    }
+
+    /// When `on_timer` has something to do next: the earliest of the
+    /// running timers, or now if queued data or a FIN can be sent
+    pub fn poll_at(&self) -> Option<time::Instant> {
+        let now = self.now();
+        match self.state {
+            State::Closed | State::TimeWait => return None,
+            State::FinWait2 => {
+                return self
+                    .config
+                    .fin_wait2_timeout
+                    .zip(self.timers.fin_wait2_since)
+                    .map(|(timeout, since)| since + timeout);
+            }
+            State::SynReceived => {
+                let timeout = INITIAL_RTO * 2u32.saturating_pow(self.timers.synack_retries);
+                return [
+                    self.timers
+                        .send_times
+                        .get(&self.send.iss)
+                        .map(|sent| *sent + timeout),
+                    self.idle_deadline(),
+                ]
+                .into_iter()
+                .flatten()
+                .min();
+            }
+            _ => {}
+        }
+
+        let unacked = self
+            .closed_at
+            .unwrap_or(self.send.nxt)
+            .wrapping_sub(self.send.una);
+        let window = core::cmp::min(self.send.wnd, self.cc.cwnd() as u32);
+        let can_send =
+            (self.unsent() > 0 || (self.closed && self.closed_at.is_none())) && window > unacked;
+        let send_at = can_send.then(|| {
+            let release = self
+                .pacer
+                .release()
+                .filter(|_| self.config.pacing && self.timers.rtt_measured);
+            let limited = self
+                .rate_limit
+                .as_ref()
+                .map(|bucket| bucket.ready_at(self.unsent().min(self.mss() as u32)));
+            [Some(now), release, limited]
+                .into_iter()
+                .flatten()
+                .max()
+                .unwrap()
+        });
+
+        // Data held back by a zero window is probed once the RTO expires
+        // (persist timer)
+        let persist = self.send.wnd == 0 && self.unsent() > 0;
+        let rto = self
+            .timers
+            .send_times
+            .range(self.send.una..)
+            .next()
+            .filter(|_| self.send.nxt != self.send.una || persist)
+            .map(|(_, sent)| *sent + self.rto());
+        let user_timeout = self
+            .user_timeout
+            .effective()
+            .zip(self.timers.unacked_since)
+            .map(|(timeout, since)| since + timeout);
+
+        [
+            send_at,
+            rto,
+            self.timers.pto,
+            self.rack.timeout(),
+            user_timeout,
+            self.idle_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// The current time of the configured clock
+    fn now(&self) -> time::Instant {
+        self.config.clock.now()
+    }
+
+    /// When the idle timeout expires unless something happens before
+    fn idle_deadline(&self) -> Option<time::Instant> {
+        self.idle_timeout
+            .map(|timeout| self.timers.last_activity + timeout)
+    }
+
+    /// Abort the connection: flush all queues, record the error to signal to
+    /// the user and enter the CLOSED state (RFC 793 USER TIMEOUT event)
+    fn abort(&mut self, kind: io::ErrorKind) {
+        self.ingress.clear();
+        self.unacked.clear();
+        self.payload_sums.clear();
+        self.timers.send_times.clear();
+        self.timers.retransmitted.clear();
+        self.timers.unacked_since = None;
+        self.error = Some(kind);
+        self.set_state(State::Closed);
+        self.dump_history(kind);
+    }
+
+    /// Take the soft error recorded on the connection, if any
+    pub fn take_error(&mut self) -> Option<io::ErrorKind> {
+        self.soft_error.take()
+    }
+
+    /// Has the connection crossed the R1 retransmission threshold since the
+    /// last call
+    pub fn take_r1_crossed(&mut self) -> bool {
+        core::mem::take(&mut self.r1_crossed)
+    }
+
+    /// Mark the connection as orphaned: its stream is gone and it is left to
+    /// finish closing on its own
+    pub fn orphan(&mut self) {
+        if self.orphaned_since.is_none() {
+            self.orphaned_since = Some(self.now());
+        }
+    }
+
+    /// When the connection was orphaned, if it is an orphan
+    pub fn orphaned_since(&self) -> Option<time::Instant> {
+        self.orphaned_since
+    }
+
+    /// Set the initial congestion window in segments. This is only possible
+    /// as long as no data was sent on the connection.
+    pub fn set_initial_window(&mut self, segments: usize) -> io::Result<()> {
+        // Only the SYN has been sent so far
+        if self.send.nxt.wrapping_sub(self.send.iss) > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Data already sent",
+            ));
+        }
+        self.config.initial_window = segments;
+        self.cc = self.config.congestion.build(self.mss(), segments);
+        Ok(())
+    }
+
+    /// Replace the congestion controller, carrying over the current window
+    pub fn set_congestion_control(&mut self, algorithm: CongestionAlgorithm) {
+        let mss = self.mss();
+        let segments = core::cmp::max(self.cc.cwnd() / mss, 1);
+        self.config.congestion = algorithm;
+        self.cc = algorithm.build(mss, segments);
+    }
+
+    /// Largest payload of a segment that fits `mtu`, and no more than the
+    /// peer announced: 536 bytes if it didn't (RFC 9293 3.7.1)
+    fn effective_mss(mtu: usize, peer_mss: Option<u16>) -> usize {
+        let peer = peer_mss.map_or(DEFAULT_MSS, usize::from);
+        mtu.saturating_sub(IP_TCP_HEADERS).min(peer).max(1)
+    }
+
+    /// Agree on window scaling with a peer whose SYN carried the shift
+    /// count `peer`. Windows are scaled in both directions only when both
+    /// sides send the option, ours announcing the shift that lets the
+    /// largest receive buffer be advertised (RFC 7323 Section 2).
+    fn set_window_scale(&mut self, peer: Option<u8>) {
+        self.window_scale = peer
+            .filter(|_| self.config.window_scaling)
+            .map(|shift| WindowScale {
+                snd: shift.min(MAX_WINDOW_SCALE),
+                rcv: window_scale(self.config.recv_buffer.max(self.config.recv_buffer_max)),
+            });
+        self.rcv_buffer
+            .set_scale(self.window_scale.map_or(0, |scale| scale.rcv));
+    }
+
+    /// The window the peer advertises on a segment other than its SYN
+    fn peer_window(&self, tcp: &TcpHeaderSlice) -> u32 {
+        let shift = self.window_scale.map_or(0, |scale| scale.snd);
+        (tcp.window_size() as u32) << shift
+    }
+
+    /// Take the MSS the peer announced, before anything was sent
+    fn set_peer_mss(&mut self, mss: Option<u16>) {
+        self.peer_mss = mss;
+        self.cc = self
+            .config
+            .congestion
+            .build(self.mss(), self.config.initial_window);
+    }
+
+    /// Largest payload of the segments sent
+    pub fn mss(&self) -> usize {
+        Self::effective_mss(self.config.mtu, self.peer_mss)
+    }
+
+    /// Limit the packets sent from now on to `mtu` bytes
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.config.mtu = mtu;
+    }
+
+    /// Send the packets from now on with a time to live of `ttl`
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.config.ttl = ttl;
+        self.ip.time_to_live = ttl;
+    }
+
+    /// Log the segments of this connection in a tcpdump-like format
+    pub fn set_trace(&mut self, enable: bool) {
+        self.config.trace = enable;
+    }
+
+    /// May the SYN `tcp` end TIME-WAIT and open a new incarnation of the
+    /// connection: its timestamp is newer than the last one from the peer
+    /// (RFC 6191), or without timestamps, its sequence number is beyond the
+    /// old connection's (RFC 1122 4.2.2.13)
+    pub fn accepts_new_incarnation(&self, tcp: &TcpHeaderSlice) -> bool {
+        if self.state != State::TimeWait || !tcp.syn() || tcp.ack() || tcp.rst() {
+            return false;
+        }
+        match (
+            SegmentOptions::parse(tcp.options()).timestamp(),
+            self.ts_recent,
+        ) {
+            (Some(ts), Some(recent)) => Self::wrapping_lt(recent, ts),
+            _ => Self::wrapping_lt(self.receive.nxt, tcp.sequence_number()),
+        }
+    }
+
+    /// Initial sequence number for the next incarnation of the connection,
+    /// so its segments can't be mistaken for old duplicates
+    pub fn next_iss(&self) -> u32 {
+        self.send.nxt.wrapping_add(INCARNATION_GAP)
+    }
+
+    /// Record the state transitions of the connection from now on, or stop
+    /// and forget the ones recorded
+    pub fn set_record_transitions(&mut self, enable: bool) {
+        if enable != self.transitions.is_some() {
+            self.transitions = enable.then(|| TransitionLog::new(self.now()));
+        }
+    }
+
+    /// The state transitions recorded so far
+    pub fn transitions(&self) -> Option<&TransitionLog> {
+        self.transitions.as_ref()
+    }
+
+    /// Record the congestion window whenever the congestion controller
+    /// acts from now on, or stop and forget the samples
+    pub fn set_record_cwnd(&mut self, enable: bool) {
+        if enable != self.cwnd_trace.is_some() {
+            self.cwnd_trace = enable.then(|| CwndTrace::new(self.now()));
+        }
+    }
+
+    /// The congestion window samples recorded so far
+    pub fn cwnd_trace(&self) -> Option<&CwndTrace> {
+        self.cwnd_trace.as_ref()
+    }
+
+    /// Keep the last `capacity` segments sent and received from now on, or
+    /// with 0 stop and forget the ones kept
+    pub fn set_segment_history(&mut self, capacity: usize) {
+        let current = self.history.as_ref().map_or(0, SegmentHistory::capacity);
+        if capacity != current {
+            let now = self.now();
+            self.history = (capacity > 0).then(|| SegmentHistory::new(capacity, now));
+        }
+    }
+
+    /// The last segments sent and received, if they are kept
+    pub fn segment_history(&self) -> Option<&SegmentHistory> {
+        self.history.as_ref()
+    }
+
+    /// Add an input to the recording, if the connection is recorded. The
+    /// owner records the changes it makes to `ingress` and `unacked`.
+    pub fn record_with(&mut self, record: impl FnOnce() -> Record) {
+        let now = self.now();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(now, record());
+        }
+    }
+
+    /// The inputs recorded so far, if the connection is recorded
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recorder.as_ref().map(Recorder::recording)
+    }
+
+    /// Capture the state of the TCB
+    pub fn snapshot(&self) -> TcbSnapshot {
+        let now = self.now();
+        let left = |deadline: time::Instant| deadline.saturating_duration_since(now);
+        let in_flight = self.send.nxt != self.send.una;
+        let rto_in = self
+            .timers
+            .send_times
+            .range(self.send.una..)
+            .next()
+            .filter(|_| in_flight)
+            .map(|(_, sent)| left(*sent + self.rto()));
+        let fin_wait2_in = match (self.state, self.config.fin_wait2_timeout) {
+            (State::FinWait2, Some(timeout)) => {
+                self.timers.fin_wait2_since.map(|s| left(s + timeout))
+            }
+            _ => None,
+        };
+        let user_timeout_in = self
+            .user_timeout
+            .effective()
+            .zip(self.timers.unacked_since)
+            .map(|(timeout, since)| left(since + timeout));
+
+        TcbSnapshot {
+            state: self.state,
+            snd_iss: self.send.iss,
+            snd_una: self.send.una,
+            snd_nxt: self.send.nxt,
+            snd_wnd: self.send.wnd,
+            rcv_irs: self.receive.irs,
+            rcv_nxt: self.receive.nxt,
+            rcv_wnd: self.receive.wnd,
+            cwnd: self.cc.cwnd(),
+            mss: self.mss(),
+            srtt: time::Duration::from_secs_f64(self.timers.srtt),
+            retransmits: self.timers.retransmits,
+            rto_in,
+            probe_in: self.timers.pto.map(left),
+            reorder_in: self.rack.timeout().map(left),
+            fin_wait2_in,
+            user_timeout_in,
+            ingress: self.ingress.len(),
+            unacked: self.unacked.len(),
+            unsent: self.unsent() as usize,
+            closed: self.closed,
+            closed_at: self.closed_at,
+            error: self.error,
+            dup_acks: self.path.dup_acks,
+            dup_acks_sent: self.path.dup_acks_sent,
+            reordering: self.path.reordering,
+            spurious_retransmits: self.path.spurious_retransmits,
+        }
+    }
+
+    /// Current size of the (auto-tuned) receive buffer
+    pub fn recv_buffer_size(&self) -> usize {
+        self.rcv_buffer.size()
+    }
+
+    /// Bytes a blocking read waits for: the receive low watermark, but no
+    /// more than the receive buffer holds and at least one
+    pub fn recv_lowat(&self) -> usize {
+        self.recv_lowat.min(self.recv_buffer_size()).max(1)
+    }
+
+    /// The space left in the receive buffer, of which only half is offered
+    /// under soft memory pressure and none under hard pressure. Pressure
+    /// doesn't take back what was offered before (RFC 9293 3.8.6.2.2).
+    fn advertised_window(&self) -> u32 {
+        let window = self.rcv_buffer.window(self.received());
+        let offered = self.rcv_edge.wrapping_sub(self.receive.nxt);
+        let offered = if (offered as i32) > 0 { offered } else { 0 };
+        let allowed = match self.memory_pressure {
+            MemoryPressure::Normal => window,
+            MemoryPressure::Soft => window / 2,
+            MemoryPressure::Hard => 0,
+        };
+        core::cmp::min(window, core::cmp::max(allowed, offered))
+    }
+
+    /// Tell the connection which of the memory limits of its interface the
+    /// queues of all connections are over. Under pressure its buffers stop
+    /// growing and its receive window opens less. Once the pressure eases,
+    /// the window it held back is advertised.
+    pub fn set_memory_pressure(&mut self, pressure: MemoryPressure) -> io::Result<()> {
+        let eased = pressure < self.memory_pressure;
+        self.memory_pressure = pressure;
+        self.rcv_buffer.hold(pressure != MemoryPressure::Normal);
+        if eased {
+            self.update_window()?;
+        }
+        Ok(())
+    }
+
+    /// Data bytes the peer acknowledged so far
+    pub fn bytes_acked(&self) -> u64 {
+        self.bytes_acked
+    }
+
+    /// Current state of the connection
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Error the connection was aborted with, if it was
+    pub fn error(&self) -> Option<io::ErrorKind> {
+        self.error
+    }
+
+    /// Data received and not read yet
+    pub fn ingress(&self) -> &VecDeque<u8> {
+        &self.ingress
+    }
+
+    /// Data queued for sending and not acknowledged yet, sent or not
+    pub fn unacked(&self) -> &VecDeque<u8> {
+        &self.unacked
+    }
+
+    /// Take `n` bytes off the front of the received data, as the
+    /// application read them, and update the window
+    pub fn consume(&mut self, n: usize) -> io::Result<()> {
+        let n = n.min(self.ingress.len());
+        drop(self.ingress.drain(..n));
+        self.record_with(|| Record::Consume(n));
+        self.on_read()
+    }
+
+    /// Queue `data` for sending, to go out with the next timer run
+    pub fn queue(&mut self, data: &[u8]) {
+        self.unacked.extend(data);
+        self.record_with(|| Record::Queue(data.to_vec()));
+    }
+
+    /// Bytes held in the connection's send and receive buffers
+    pub fn buffered(&self) -> usize {
+        self.received() + self.unacked.len()
+    }
+
+    /// Bytes received and not read yet, including those held back until
+    /// the handshake completes
+    fn received(&self) -> usize {
+        self.ingress.len() + self.early.len()
+    }
+
+    /// Space left in the receive buffer
+    fn ingress_room(&self) -> usize {
+        self.rcv_buffer.size().saturating_sub(self.received())
+    }
+
+    /// Hold back in-order data that arrived before the handshake completed,
+    /// e.g. on the SYN, as far as the window allows. Returns the bytes
+    /// taken.
+    fn queue_early(&mut self, seq: u32, data: &[u8]) -> usize {
+        if seq != self.receive.nxt {
+            return 0;
+        }
+        let len = data
+            .len()
+            .min(self.receive.wnd as usize)
+            .min(self.ingress_room());
+        self.early.extend_from_slice(&data[..len]);
+        self.receive.nxt = self.receive.nxt.wrapping_add(len as u32);
+        len
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
     }
 
     pub fn close(&mut self) -> io::Result<()> {
+        self.record_with(|| Record::Close);
         self.closed = true;
         match self.state {
             State::SynReceived | State::Established => {
-                self.state = State::FinWait1;
+                self.set_state(State::FinWait1);
+            }
+            State::CloseWait => {
+                self.set_state(State::LastAck);
             }
             State::FinWait1 | State::FinWait2 => {}
             _ => {
@@ -537,12 +2397,295 @@ impl Connection {
         }
         Ok(())
     }
-    pub fn _send_rst(&mut self, nic: &tun_tap::Iface) -> io::Result<()> {
-        // TODO: fix seq numbers and handle synchronized RST
-        self.tcp.rst = true;
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, self.send.nxt, 0)?;
+
+    /// Mark the end of the send queue as the end of a write: the segment
+    /// carrying it is sent with PSH (RFC 9293 3.9.1.2). Marks not sent yet
+    /// collapse into the last one.
+    pub fn push(&mut self) {
+        self.record_with(|| Record::Push);
+        if self.unacked.is_empty() {
+            return;
+        }
+        self.push_at = Some(self.data_start().wrapping_add(self.unacked.len() as u32));
+    }
+
+    /// Called after the user took data from the receive queue. Once the
+    /// space freed moves the right edge of the window by min(RCV.BUFF / 2,
+    /// MSS) past the one advertised last, a window update is sent, so a peer
+    /// stalled on a full window doesn't wait for its persist timer (RFC 1122
+    /// 4.2.3.3).
+    pub fn on_read(&mut self) -> io::Result<()> {
+        self.record_with(|| Record::Read);
+        self.update_window()
+    }
+
+    /// Send a window update if the window opened by enough since it was
+    /// advertised
+    fn update_window(&mut self) -> io::Result<()> {
+        if !matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        ) {
+            return Ok(());
+        }
+        let advertised = self
+            .tcp
+            .acknowledgment_number
+            .wrapping_add(self.receive.wnd);
+        let edge = self.receive.nxt.wrapping_add(self.advertised_window());
+        let threshold = core::cmp::min(self.rcv_buffer.size() / 2, self.mss());
+        if edge.wrapping_sub(advertised) as i32 >= core::cmp::max(threshold, 1) as i32 {
+            self.write(self.send.nxt, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Abort the connection at the user's request (RFC 793 ABORT call).
+    ///
+    /// In SYN-RECEIVED and the synchronized states a reset segment
+    /// <SEQ=SND.NXT><CTL=RST> is sent. All queued data is discarded and the
+    /// connection enters the CLOSED state. In TIME-WAIT the connection is
+    /// simply closed, since the peer has nothing more to say.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.record_with(|| Record::Reset);
+        match self.state {
+            State::Closed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "connection does not exist",
+                ));
+            }
+            State::SynReceived
+            | State::Established
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait
+            | State::Closing
+            | State::LastAck => {
+                self.send_rst(self.send.nxt, None)?;
+            }
+            State::TimeWait => {}
+        }
+        self.abort(io::ErrorKind::ConnectionAborted);
+        Ok(())
+    }
+
+    /// Reply with a reset to a segment that doesn't belong to any connection
+    /// (RFC 793 CLOSED state). If the segment has an ACK the reset takes its
+    /// sequence number from the acknowledgment: <SEQ=SEG.ACK><CTL=RST>,
+    /// otherwise <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>. Resets are never
+    /// answered with resets. Returns the packet to send, if any.
+    pub fn reset_unknown(
+        config: &Config,
+        ip: &Ipv4HeaderSlice,
+        tcp: &TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<Option<Packet>> {
+        if tcp.rst() {
+            return Ok(None);
+        }
+        let (seq, ack) = if tcp.ack() {
+            (tcp.acknowledgment_number(), None)
+        } else {
+            let mut slen = data.len() as u32;
+            if tcp.syn() {
+                slen += 1;
+            }
+            if tcp.fin() {
+                slen += 1;
+            }
+            (0, Some(tcp.sequence_number().wrapping_add(slen)))
+        };
+        let resp_ip = Ipv4Header::new(0, config.ttl, IpNumber::TCP, ip.destination(), ip.source())
+            .map_err(io::Error::other)?;
+        let (packet, _) = Self::build_rst(
+            resp_ip,
+            (tcp.destination_port(), tcp.source_port()),
+            seq,
+            ack,
+        )?;
+        Ok(Some(packet))
+    }
+
+    /// Send a reset segment carrying sequence number `seq`.
+    ///
+    /// A reset sent in a synchronized state (or for a user ABORT) uses
+    /// SND.NXT. A reset sent in reply to an unacceptable segment in a
+    /// non-synchronized state uses the segment's acknowledgment number, or
+    /// sequence number zero with `ack` set to SEG.SEQ+SEG.LEN when the
+    /// offending segment had no ACK. Resets don't occupy sequence space, so
+    /// the send sequence space is left untouched.
+    pub fn send_rst(&mut self, seq: u32, ack: Option<u32>) -> io::Result<()> {
+        let (packet, tcp) = Self::build_rst(
+            self.ip.clone(),
+            (self.tcp.source_port, self.tcp.destination_port),
+            seq,
+            ack,
+        )?;
+        if self.config.trace {
+            self.trace_sent(&tcp, 0);
+        }
+        let now = self.now();
+        if let Some(history) = &mut self.history {
+            history.record(now, Direction::Sent, &tcp, 0);
+        }
+        self.actions.push(Action::Transmit(packet));
         Ok(())
     }
+
+    fn build_rst(
+        mut ip: Ipv4Header,
+        (src_port, dst_port): (u16, u16),
+        seq: u32,
+        ack: Option<u32>,
+    ) -> io::Result<(Packet, TcpHeader)> {
+        let mut tcp = TcpHeader::new(src_port, dst_port, seq, 0);
+        tcp.rst = true;
+        if let Some(ack) = ack {
+            tcp.ack = true;
+            tcp.acknowledgment_number = ack;
+        }
+
+        ip.set_payload_len(tcp.header_len())
+            .map_err(io::Error::other)?;
+        tcp.checksum = tcp
+            .calc_checksum_ipv4(&ip, &[])
+            .expect("failed to compute checksum");
+
+        ip.header_checksum = ip.calc_header_checksum();
+        Ok((Packet::new(&ip, &tcp, Vec::new()), tcp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::super::harness::{Harness, ACK, PEER_ISS};
+    use super::super::time::{Duration, ManualClock};
+    use super::*;
+
+    #[test]
+    fn probe_timeout_follows_the_first_rtt_sample() {
+        let clock = Arc::new(ManualClock::new());
+        let config = Config {
+            clock: clock.clone(),
+            ..Config::default()
+        };
+        let mut h = Harness::established_with(&config);
+        let mss = h.conn.mss() as u32;
+        // The handshake carried no data, so nothing was timed yet
+        assert_eq!(h.conn.probe_timeout(2 * mss), INITIAL_RTO);
+
+        h.conn.unacked.extend(b"timed");
+        h.conn.on_timer().unwrap();
+        h.sent();
+        clock.advance(Duration::from_millis(100));
+        h.deliver(ACK, PEER_ISS + 1, h.conn.send.nxt, &[]);
+        assert_eq!(h.conn.probe_timeout(2 * mss), Duration::from_millis(200));
+        // A lone segment may wait for a delayed ACK
+        assert_eq!(
+            h.conn.probe_timeout(mss),
+            Duration::from_millis(200) + WC_DEL_ACK
+        );
+    }
+
+    #[test]
+    fn retransmitted_segments_are_not_timed() {
+        let clock = Arc::new(ManualClock::new());
+        let config = Config {
+            clock: clock.clone(),
+            tlp: false,
+            ..Config::default()
+        };
+        let mut h = Harness::established_with(&config);
+        h.conn.unacked.extend(b"timed");
+        h.conn.on_timer().unwrap();
+        h.sent();
+        clock.advance(Duration::from_millis(100));
+        h.deliver(ACK, PEER_ISS + 1, h.conn.send.nxt, &[]);
+        assert_eq!(h.conn.timers.srtt, 0.1);
+
+        h.conn.unacked.extend(b"resent");
+        h.conn.on_timer().unwrap();
+        h.sent();
+        clock.advance(h.conn.rto() + Duration::from_millis(1));
+        h.conn.on_timer().unwrap();
+        assert_eq!(h.sent_one().unwrap().payload, b"resent");
+        // Too soon to be for the retransmission, but nothing tells
+        clock.advance(Duration::from_millis(1));
+        h.deliver(ACK, PEER_ISS + 1, h.conn.send.nxt, &[]);
+        assert_eq!(h.conn.timers.srtt, 0.1);
+        assert!(h.conn.timers.retransmitted.is_empty());
+    }
+
+    #[test]
+    fn ack_of_a_retransmission_does_not_shrink_the_ledbat_window() {
+        let clock = Arc::new(ManualClock::new());
+        let config = Config {
+            clock: clock.clone(),
+            congestion: CongestionAlgorithm::Ledbat,
+            ..Config::default()
+        };
+        let mut h = Harness::established_with(&config);
+        let exchange = |h: &mut Harness, data: &[u8]| {
+            h.conn.unacked.extend(data);
+            h.conn.on_timer().unwrap();
+            h.sent();
+            clock.advance(Duration::from_millis(100));
+            h.deliver(ACK, PEER_ISS + 1, h.conn.send.nxt, &[]);
+        };
+        exchange(&mut h, b"base");
+
+        // A tail loss probe retransmits the segment without touching cwnd
+        h.conn.unacked.extend(b"probed");
+        h.conn.on_timer().unwrap();
+        h.sent();
+        clock.advance(h.conn.probe_timeout(6) + Duration::from_millis(1));
+        h.conn.on_timer().unwrap();
+        assert_eq!(h.sent_one().unwrap().payload, b"probed");
+        // The ACK of the original transmission, right after the probe,
+        // would make the base delay look much lower than it is
+        clock.advance(Duration::from_millis(1));
+        h.deliver(ACK, PEER_ISS + 1, h.conn.send.nxt, &[]);
+
+        // The path's delay didn't change, so there's no queue to back off from
+        for _ in 0..8 {
+            let cwnd = h.conn.cc.cwnd();
+            exchange(&mut h, b"more");
+            assert!(h.conn.cc.cwnd() >= cwnd);
+        }
+    }
+
+    #[test]
+    fn corrupted_checkpoints_are_rejected() {
+        let config = Config::default();
+        let mut h = Harness::established_with(&config);
+        h.deliver(ACK, PEER_ISS + 1, h.conn.send.nxt, b"unread");
+        h.conn.unacked.extend(b"unacked");
+        let saved = h.conn.save().unwrap();
+        Connection::restore(&config, &saved).unwrap();
+
+        let corruptions: [fn(&mut SavedConnection); 8] = [
+            |saved| saved.state = State::TimeWait,
+            |saved| saved.snd_wscale = MAX_WINDOW_SCALE + 1,
+            |saved| saved.rcv_wscale = u8::MAX,
+            |saved| saved.recv_buffer = saved.ingress.len() - 1,
+            |saved| saved.snd_una = saved.snd_iss,
+            |saved| saved.rcv_nxt = saved.rcv_irs.wrapping_sub(1),
+            |saved| saved.fin_acked_at = Some(saved.snd_una.wrapping_sub(1)),
+            |saved| {
+                saved.closed = true;
+                saved.fin_acked_at = Some(saved.snd_una.wrapping_add(1));
+            },
+        ];
+        for (i, corrupt) in corruptions.iter().enumerate() {
+            let mut saved = saved.clone();
+            corrupt(&mut saved);
+            let err = Connection::restore(&config, &saved)
+                .map(|_| ())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "corruption {}", i);
+        }
+    }
 }
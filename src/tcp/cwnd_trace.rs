@@ -0,0 +1,140 @@
+//! Time series of the congestion window of a connection, sampled whenever
+//! the congestion controller acts on an acknowledgment, a loss or a
+//! timeout, exportable as CSV or JSON to plot sawtooths and compare the
+//! algorithms implementing `CongestionControl`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use super::time::{Duration, Instant};
+
+/// What the congestion controller of a connection is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum CongestionState {
+    /// the window grows exponentially
+    SlowStart,
+    /// the window grows by about a segment per round trip, or follows the
+    /// queuing delay
+    CongestionAvoidance,
+    /// losses detected by acknowledgments are being repaired
+    Recovery,
+    /// the retransmission timer expired and nothing was acknowledged since
+    Loss,
+}
+
+impl CongestionState {
+    fn name(self) -> &'static str {
+        match self {
+            Self::SlowStart => "slow-start",
+            Self::CongestionAvoidance => "congestion-avoidance",
+            Self::Recovery => "recovery",
+            Self::Loss => "loss",
+        }
+    }
+}
+
+impl fmt::Display for CongestionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CwndSample {
+    /// time since tracing started
+    pub at: Duration,
+    /// congestion window in bytes
+    pub cwnd: usize,
+    /// slow start threshold in bytes, if the algorithm set one
+    pub ssthresh: Option<usize>,
+    /// bytes sent and not acknowledged
+    pub in_flight: usize,
+    pub state: CongestionState,
+}
+
+/// The samples of one connection in the order they were taken
+#[derive(Debug, Clone)]
+pub struct CwndTrace {
+    started: Instant,
+    samples: Vec<CwndSample>,
+}
+
+impl CwndTrace {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        now: Instant,
+        cwnd: usize,
+        ssthresh: Option<usize>,
+        in_flight: usize,
+        state: CongestionState,
+    ) {
+        self.samples.push(CwndSample {
+            at: now.saturating_duration_since(self.started),
+            cwnd,
+            ssthresh,
+            in_flight,
+            state,
+        });
+    }
+
+    pub fn samples(&self) -> &[CwndSample] {
+        &self.samples
+    }
+
+    /// One line per sample under a header, times in seconds and an unset
+    /// ssthresh left empty
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("time,cwnd,ssthresh,in_flight,state\n");
+        for s in &self.samples {
+            let _ = write!(out, "{:.6},{},", s.at.as_secs_f64(), s.cwnd);
+            if let Some(ssthresh) = s.ssthresh {
+                let _ = write!(out, "{}", ssthresh);
+            }
+            let _ = writeln!(out, ",{},{}", s.in_flight, s.state);
+        }
+        out
+    }
+
+    /// An array with one object per sample, fields named like the CSV
+    /// columns and an unset ssthresh `null`
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[");
+        for (i, s) in self.samples.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "\n  {{\"time\": {:.6}, \"cwnd\": {}, \"ssthresh\": ",
+                s.at.as_secs_f64(),
+                s.cwnd
+            );
+            match s.ssthresh {
+                Some(ssthresh) => {
+                    let _ = write!(out, "{}", ssthresh);
+                }
+                None => out.push_str("null"),
+            }
+            let _ = write!(
+                out,
+                ", \"in_flight\": {}, \"state\": \"{}\"}}",
+                s.in_flight, s.state
+            );
+        }
+        if !self.samples.is_empty() {
+            out.push('\n');
+        }
+        out.push_str("]\n");
+        out
+    }
+}
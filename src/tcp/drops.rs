@@ -0,0 +1,148 @@
+use core::fmt;
+
+/// Why a received segment was discarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// Not an IPv4 packet
+    NotIpv4,
+    /// The IPv4 header couldn't be parsed
+    MalformedIp,
+    /// Not a TCP segment
+    NotTcp,
+    /// The TCP header couldn't be parsed
+    MalformedTcp,
+    /// The TCP checksum didn't match
+    BadChecksum,
+    /// The interface's packet filter rejected the segment
+    Filtered,
+    /// No connection and nothing listening on the port
+    NoListener,
+    /// The listener on the port is paused
+    ListenerPaused,
+    /// The listener doesn't accept connections from the peer's address
+    PeerRejected,
+    /// The peer opened connections faster than the listener's rate limit
+    SynRateLimited,
+    /// The listener has as many connections as its limits allow, in total
+    /// or from the peer
+    ConnectionLimit,
+    /// The listener's accept queue was full
+    BacklogFull,
+    /// A segment other than a SYN for a connection that doesn't exist
+    NotSyn,
+    /// The connection was already closed
+    ConnectionClosed,
+    /// The segment was outside of the receive window
+    OutOfWindow,
+    /// The segment carried data while the receive window was closed
+    ZeroWindow,
+    /// The segment acknowledged something that wasn't sent
+    UnacceptableAck,
+    /// The segment acknowledged data beyond SND.NXT, which wasn't sent yet
+    AckOfUnsentData,
+    /// A SYN for a connection that already exists
+    DuplicateSyn,
+    /// A SYN while the connections hold more memory than the hard limit
+    MemoryPressure,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 20] = [
+        DropReason::NotIpv4,
+        DropReason::MalformedIp,
+        DropReason::NotTcp,
+        DropReason::MalformedTcp,
+        DropReason::BadChecksum,
+        DropReason::Filtered,
+        DropReason::NoListener,
+        DropReason::ListenerPaused,
+        DropReason::PeerRejected,
+        DropReason::SynRateLimited,
+        DropReason::ConnectionLimit,
+        DropReason::BacklogFull,
+        DropReason::NotSyn,
+        DropReason::ConnectionClosed,
+        DropReason::OutOfWindow,
+        DropReason::ZeroWindow,
+        DropReason::UnacceptableAck,
+        DropReason::AckOfUnsentData,
+        DropReason::DuplicateSyn,
+        DropReason::MemoryPressure,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::NotIpv4 => "not-ipv4",
+            DropReason::MalformedIp => "malformed-ip",
+            DropReason::NotTcp => "not-tcp",
+            DropReason::MalformedTcp => "malformed-tcp",
+            DropReason::BadChecksum => "bad-checksum",
+            DropReason::Filtered => "filtered",
+            DropReason::NoListener => "no-listener",
+            DropReason::ListenerPaused => "listener-paused",
+            DropReason::PeerRejected => "peer-rejected",
+            DropReason::SynRateLimited => "syn-rate-limited",
+            DropReason::ConnectionLimit => "connection-limit",
+            DropReason::BacklogFull => "backlog-full",
+            DropReason::NotSyn => "not-syn",
+            DropReason::ConnectionClosed => "connection-closed",
+            DropReason::OutOfWindow => "out-of-window",
+            DropReason::ZeroWindow => "zero-window",
+            DropReason::UnacceptableAck => "unacceptable-ack",
+            DropReason::AckOfUnsentData => "ack-of-unsent-data",
+            DropReason::DuplicateSyn => "duplicate-syn",
+            DropReason::MemoryPressure => "memory-pressure",
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Number of segments discarded for each reason
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DropStats {
+    counts: [u64; DropReason::ALL.len()],
+}
+
+impl DropStats {
+    pub fn record(&mut self, reason: DropReason) {
+        self.counts[reason as usize] += 1;
+    }
+
+    /// Segments discarded for `reason`
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize]
+    }
+
+    /// Add the counts of `other`
+    pub fn merge(&mut self, other: &DropStats) {
+        for (count, more) in self.counts.iter_mut().zip(other.counts) {
+            *count += more;
+        }
+    }
+
+    /// Segments discarded for any reason
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Reasons with their counts
+    pub fn iter(&self) -> impl Iterator<Item = (DropReason, u64)> + '_ {
+        DropReason::ALL
+            .iter()
+            .map(|reason| (*reason, self.get(*reason)))
+    }
+}
+
+impl fmt::Display for DropStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (reason, count) in self.iter().filter(|(_, count)| *count > 0) {
+            writeln!(f, "{}: {}", reason, count)?;
+        }
+        Ok(())
+    }
+}
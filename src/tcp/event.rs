@@ -0,0 +1,26 @@
+use core::net::SocketAddrV4;
+
+use super::state::State;
+
+/// Something that happened on a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The three-way handshake completed
+    Established,
+    /// The connection moved from one state to another
+    StateChanged { from: State, to: State },
+    /// `len` bytes starting at sequence number `seq` were sent again
+    Retransmission { seq: u32, len: usize },
+    /// The peer reset the connection
+    ResetReceived,
+    /// The connection reached the CLOSED state and is going away
+    Closed,
+}
+
+/// An event together with the connection it happened on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+    pub kind: ConnectionEvent,
+}
@@ -0,0 +1,207 @@
+//! A simulated peer for driving a `Connection` and collecting the segments
+//! it emits, shared by the conformance checks and the model-based tests.
+
+use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use std::net::Ipv4Addr;
+
+use super::action::Action;
+use super::config::Config;
+use super::connection::Connection;
+use super::drops::DropStats;
+
+pub const LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 80);
+pub const REMOTE: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 2), 40000);
+/// Initial sequence number of the simulated peer
+pub const PEER_ISS: u32 = 1000;
+
+/// Flags of a segment sent by the simulated peer
+#[derive(Default, Clone, Copy)]
+pub struct Flags {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+}
+
+pub const SYN: Flags = Flags {
+    syn: true,
+    ack: false,
+    fin: false,
+    rst: false,
+};
+pub const ACK: Flags = Flags {
+    syn: false,
+    ack: true,
+    fin: false,
+    rst: false,
+};
+pub const FIN_ACK: Flags = Flags {
+    syn: false,
+    ack: true,
+    fin: true,
+    rst: false,
+};
+pub const RST: Flags = Flags {
+    syn: false,
+    ack: false,
+    fin: false,
+    rst: true,
+};
+
+/// A segment emitted by the stack
+pub struct Sent {
+    pub tcp: TcpHeader,
+    pub payload: Vec<u8>,
+}
+
+/// Window the peer advertises unless told otherwise
+pub const PEER_WINDOW: u16 = 1024;
+
+/// Serialize a segment from the peer
+pub fn segment(flags: Flags, seq: u32, ack: u32, data: &[u8]) -> Vec<u8> {
+    segment_with_window(flags, seq, ack, PEER_WINDOW, data)
+}
+
+/// Serialize a segment from the peer advertising `window`
+pub fn segment_with_window(flags: Flags, seq: u32, ack: u32, window: u16, data: &[u8]) -> Vec<u8> {
+    segment_with_options(flags, seq, ack, window, &[], data)
+}
+
+/// Serialize a SYN from the peer carrying the timestamp `tsval`
+pub fn syn_with_timestamp(seq: u32, tsval: u32) -> Vec<u8> {
+    let [a, b, c, d] = tsval.to_be_bytes();
+    let options = [1, 1, 8, 10, a, b, c, d, 0, 0, 0, 0];
+    segment_with_options(SYN, seq, 0, PEER_WINDOW, &options, &[])
+}
+
+/// Serialize a SYN from the peer announcing a Maximum Segment Size
+pub fn syn_with_mss(seq: u32, mss: u16) -> Vec<u8> {
+    let [hi, lo] = mss.to_be_bytes();
+    segment_with_options(SYN, seq, 0, PEER_WINDOW, &[2, 4, hi, lo], &[])
+}
+
+/// Serialize a segment from the peer with raw `options`
+pub fn segment_with_options(
+    flags: Flags,
+    seq: u32,
+    ack: u32,
+    window: u16,
+    options: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut tcp = TcpHeader::new(REMOTE.1, LOCAL.1, seq, window);
+    tcp.set_options_raw(options).unwrap();
+    tcp.syn = flags.syn;
+    tcp.ack = flags.ack;
+    tcp.fin = flags.fin;
+    tcp.rst = flags.rst;
+    tcp.acknowledgment_number = ack;
+    let ip = Ipv4Header::new(
+        (tcp.header_len() + data.len()) as u16,
+        64,
+        IpNumber::TCP,
+        REMOTE.0.octets(),
+        LOCAL.0.octets(),
+    )
+    .unwrap();
+    tcp.checksum = tcp.calc_checksum_ipv4(&ip, data).unwrap();
+
+    let mut buf = Vec::new();
+    ip.write(&mut buf).unwrap();
+    tcp.write(&mut buf).unwrap();
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Split a packet into its headers and payload
+pub fn parse(packet: &[u8]) -> (Ipv4HeaderSlice<'_>, TcpHeaderSlice<'_>, &[u8]) {
+    let ip = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let tcp = TcpHeaderSlice::from_slice(&packet[ip.slice().len()..]).unwrap();
+    let data = &packet[ip.slice().len() + tcp.slice().len()..];
+    (ip, tcp, data)
+}
+
+/// A connection under test
+pub struct Harness {
+    pub conn: Connection,
+    pub drops: DropStats,
+}
+
+impl Harness {
+    /// Passive open: the peer's SYN is accepted
+    pub fn syn_received() -> Self {
+        Self::syn_received_with(&Config::default())
+    }
+
+    /// Passive open of a connection configured with `config`
+    pub fn syn_received_with(config: &Config) -> Self {
+        Self::accept(config, &segment(SYN, PEER_ISS, 0, &[]))
+    }
+
+    /// Passive open with the serialized SYN `syn`
+    pub fn accept(config: &Config, syn: &[u8]) -> Self {
+        let (ip, tcp, data) = parse(syn);
+        let conn = Connection::accept(config, ip, tcp, data).unwrap();
+        Self {
+            conn,
+            drops: DropStats::default(),
+        }
+    }
+
+    /// Connection in ESTABLISHED, with the handshake segments consumed
+    pub fn established() -> Self {
+        Self::established_with(&Config::default())
+    }
+
+    /// Connection configured with `config` in ESTABLISHED
+    pub fn established_with(config: &Config) -> Self {
+        Self::established_from(config, PEER_ISS)
+    }
+
+    /// Connection in ESTABLISHED with a peer whose initial sequence number
+    /// is `iss`
+    pub fn established_from(config: &Config, iss: u32) -> Self {
+        let mut h = Self::accept(config, &segment(SYN, iss, 0, &[]));
+        h.deliver(ACK, iss.wrapping_add(1), 1, &[]);
+        h.sent();
+        h
+    }
+
+    pub fn deliver(&mut self, flags: Flags, seq: u32, ack: u32, data: &[u8]) {
+        self.deliver_raw(&segment(flags, seq, ack, data));
+    }
+
+    /// Hand a serialized segment to the connection
+    pub fn deliver_raw(&mut self, packet: &[u8]) {
+        let (ip, tcp, data) = parse(packet);
+        let _ = self.conn.on_packet(&mut self.drops, ip, tcp, data);
+    }
+
+    /// Segments sent since the last call
+    pub fn sent(&mut self) -> Vec<Sent> {
+        self.conn
+            .take_actions()
+            .into_iter()
+            .filter_map(|action| match action {
+                Action::Transmit(packet) => Some(packet.into_vec()),
+                _ => None,
+            })
+            .map(|packet| {
+                let (_, tcp, data) = parse(&packet);
+                Sent {
+                    tcp: tcp.to_header(),
+                    payload: data.to_vec(),
+                }
+            })
+            .collect()
+    }
+
+    /// The one segment sent since the last call
+    pub fn sent_one(&mut self) -> Result<Sent, String> {
+        let mut sent = self.sent();
+        match sent.len() {
+            1 => Ok(sent.remove(0)),
+            n => Err(format!("expected one segment, {} were sent", n)),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Hashing of connection quads for the connection table. A quad is hashed
+//! as two words with a folded multiply, far cheaper than SipHash for 12
+//! bytes. The hasher is keyed, and with `std` the key is random for every
+//! process, so peers can't pick quads that collide to slow down lookups.
+
+use core::hash::{BuildHasher, Hasher};
+
+/// Multiplier of the PCG generator, odd and with well-mixed bits
+const MULTIPLE: u64 = 0x5851_f42d_4c95_7f2d;
+
+/// Full 128-bit product of `a` and `b` with both halves xor-ed together
+fn folded_multiply(a: u64, b: u64) -> u64 {
+    let full = u128::from(a) * u128::from(b);
+    full as u64 ^ (full >> 64) as u64
+}
+
+/// A keyed word-at-a-time hasher
+#[derive(Debug, Clone)]
+pub struct QuadHasher {
+    buffer: u64,
+    pad: u64,
+}
+
+impl QuadHasher {
+    pub fn with_keys(keys: [u64; 2]) -> Self {
+        Self {
+            buffer: keys[0],
+            pad: keys[1],
+        }
+    }
+
+    fn update(&mut self, word: u64) {
+        self.buffer = folded_multiply(self.buffer ^ word, MULTIPLE);
+    }
+}
+
+impl Hasher for QuadHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.update(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let mut rest = [0; 8];
+        rest[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+        self.update(u64::from_le_bytes(rest) ^ ((bytes.len() as u64) << 56));
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.update(u64::from(i));
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.update(u64::from(i));
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.update(u64::from(i));
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.update(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.update(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let rot = (self.buffer & 63) as u32;
+        folded_multiply(self.buffer, self.pad).rotate_left(rot)
+    }
+}
+
+/// Builds `QuadHasher`s with the same keys, for a `HashMap`
+#[derive(Debug, Clone, Copy)]
+pub struct QuadState {
+    keys: [u64; 2],
+}
+
+impl QuadState {
+    pub const fn with_keys(keys: [u64; 2]) -> Self {
+        Self { keys }
+    }
+}
+
+/// The key of the process, drawn on first use
+#[cfg(feature = "std")]
+impl Default for QuadState {
+    fn default() -> Self {
+        static KEYS: std::sync::OnceLock<[u64; 2]> = std::sync::OnceLock::new();
+        let keys = KEYS.get_or_init(|| {
+            // The standard library seeds SipHash from the OS
+            let random = std::collections::hash_map::RandomState::new();
+            [random.hash_one(0u8), random.hash_one(1u8)]
+        });
+        Self::with_keys(*keys)
+    }
+}
+
+impl BuildHasher for QuadState {
+    type Hasher = QuadHasher;
+
+    fn build_hasher(&self) -> QuadHasher {
+        QuadHasher::with_keys(self.keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: ([u8; 4], u16, [u8; 4], u16) = ([10, 0, 0, 1], 80, [10, 0, 0, 2], 40000);
+
+    #[test]
+    fn same_keys_hash_alike() {
+        let a = QuadState::with_keys([1, 2]);
+        let b = QuadState::with_keys([1, 2]);
+        assert_eq!(a.hash_one(QUAD), b.hash_one(QUAD));
+    }
+
+    #[test]
+    fn keys_change_every_hash() {
+        let a = QuadState::with_keys([1, 2]);
+        let b = QuadState::with_keys([3, 4]);
+        assert_ne!(a.hash_one(QUAD), b.hash_one(QUAD));
+    }
+
+    #[test]
+    fn ports_and_addresses_are_told_apart() {
+        let state = QuadState::with_keys([1, 2]);
+        let (src, sport, dst, dport) = QUAD;
+        let hash = state.hash_one(QUAD);
+        assert_ne!(hash, state.hash_one((src, sport + 1, dst, dport)));
+        assert_ne!(hash, state.hash_one((dst, sport, src, dport)));
+        assert_ne!(hash, state.hash_one((src, dport, dst, sport)));
+    }
+
+    #[test]
+    fn trailing_zeros_change_the_hash() {
+        let state = QuadState::with_keys([1, 2]);
+        let hash = |bytes: &[u8]| {
+            let mut hasher = state.build_hasher();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_ne!(hash(b"quad"), hash(b"quad\0"));
+        assert_ne!(hash(&[0; 8]), hash(&[0; 16]));
+    }
+}
@@ -0,0 +1,104 @@
+//! The last segments a connection sent and received, kept in a ring of
+//! fixed size so that "why did this connection reset" can be answered after
+//! the fact. The ring is dumped when the connection aborts and can be taken
+//! at any time.
+
+use alloc::collections::VecDeque;
+use core::fmt;
+
+use etherparse::TcpHeader;
+
+use super::time::{Duration, Instant};
+use super::transitions::SegmentSummary;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HistoryEntry {
+    /// time since the history started
+    pub at: Duration,
+    pub direction: Direction,
+    pub segment: SegmentSummary,
+    /// window advertised by the sender of the segment
+    pub window: u16,
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        write!(
+            f,
+            "+{:.6}s {} {} win {}",
+            self.at.as_secs_f64(),
+            arrow,
+            self.segment,
+            self.window
+        )
+    }
+}
+
+/// The last segments of one connection, oldest first
+#[derive(Debug, Clone)]
+pub struct SegmentHistory {
+    started: Instant,
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl SegmentHistory {
+    /// A history of the last `capacity` segments, which must be above 0
+    pub fn new(capacity: usize, now: Instant) -> Self {
+        Self {
+            started: now,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, direction: Direction, tcp: &TcpHeader, len: usize) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry {
+            at: now.saturating_duration_since(self.started),
+            direction,
+            segment: SegmentSummary {
+                syn: tcp.syn,
+                ack: tcp.ack,
+                fin: tcp.fin,
+                rst: tcp.rst,
+                seq: tcp.sequence_number,
+                ack_number: tcp.acknowledgment_number,
+                len,
+            },
+            window: tcp.window_size,
+        });
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+}
+
+impl fmt::Display for SegmentHistory {
+    /// One segment per line, `>` for those sent and `<` for those received
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! Errors of the protocol core. With `std` these are the standard library's
+//! I/O errors, so they pass through the socket API unchanged; without it a
+//! minimal stand-in with the kinds the core reports.
+
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind, Result};
+
+#[cfg(not(feature = "std"))]
+pub use self::core_io::{Error, ErrorKind, Result};
+
+#[cfg(not(feature = "std"))]
+mod core_io {
+    use core::fmt;
+
+    /// Kinds of errors, named after their `std::io::ErrorKind` counterparts
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum ErrorKind {
+        NotConnected,
+        ConnectionReset,
+        ConnectionAborted,
+        TimedOut,
+        InvalidInput,
+        InvalidData,
+        Other,
+    }
+
+    #[derive(Debug)]
+    pub struct Error {
+        kind: ErrorKind,
+        message: &'static str,
+    }
+
+    impl Error {
+        pub fn new(kind: ErrorKind, message: &'static str) -> Self {
+            Self { kind, message }
+        }
+
+        /// An error of kind `Other`. The cause is not kept, there is
+        /// nowhere to allocate it.
+        pub fn other<E: fmt::Debug>(_error: E) -> Self {
+            Self::new(ErrorKind::Other, "other error")
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Self::new(kind, "")
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{:?}: {}", self.kind, self.message)
+        }
+    }
+
+    pub type Result<T> = core::result::Result<T, Error>;
+}
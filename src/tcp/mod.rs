@@ -1,3 +1,32 @@
+pub mod action;
+pub mod autotune;
+pub mod checkpoint;
+pub mod checksum;
+pub mod config;
+#[cfg(test)]
+mod conformance;
+pub mod congestion;
 pub mod connection;
+pub mod cwnd_trace;
+pub mod drops;
+pub mod event;
+#[cfg(test)]
+mod harness;
+pub mod hash;
+pub mod history;
+pub mod io;
+#[cfg(test)]
+mod model;
+pub mod options;
+pub mod pacing;
+pub mod pool;
+pub mod rack;
+pub mod ratelimit;
+pub mod recording;
 pub mod sequence;
+pub mod snapshot;
 pub mod state;
+pub mod time;
+#[cfg(feature = "std")]
+pub mod trace;
+pub mod transitions;
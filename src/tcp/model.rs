@@ -0,0 +1,271 @@
+//! Model-based tests of the state machine. Proptest generates random
+//! sequences of valid peer segments and local calls; a reference model of
+//! the RFC 9293 transitions predicts where each one leaves the connection.
+//! The connection must never panic, must end up in the predicted state and
+//! must deliver exactly the in-order data the peer sent.
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use super::config::Config;
+use super::harness::{segment, Flags, Harness, ACK, FIN_ACK, RST};
+use super::state::State;
+
+/// Something the peer or the local user does to the connection
+#[derive(Debug, Clone)]
+enum Op {
+    /// Data following whatever the peer sent before, as much as the window
+    /// allows, or a window probe when it is closed
+    Data(Vec<u8>),
+    /// The last data segment once more
+    Duplicate,
+    /// Data after a hole of `gap` bytes in the sequence space, if it fits
+    /// in the window
+    Ahead(u32, Vec<u8>),
+    /// Acknowledge everything the connection sent, which every new segment
+    /// but a reset does
+    Ack,
+    /// Close the peer's side once the window is open, or send its FIN again
+    Fin,
+    /// Reset, with a sequence number in the window or far outside
+    Rst { in_window: bool },
+    /// The local user queues data
+    Write(Vec<u8>),
+    /// The local user reads everything received
+    Read,
+    /// The local user closes
+    Close,
+    /// The local user aborts
+    Abort,
+    /// A timer tick
+    Timer,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let data = || vec(any::<u8>(), 1..16);
+    prop_oneof![
+        3 => data().prop_map(Op::Data),
+        1 => Just(Op::Duplicate),
+        1 => (1u32..64, data()).prop_map(|(gap, data)| Op::Ahead(gap, data)),
+        3 => Just(Op::Ack),
+        1 => Just(Op::Fin),
+        1 => any::<bool>().prop_map(|in_window| Op::Rst { in_window }),
+        2 => data().prop_map(Op::Write),
+        2 => Just(Op::Read),
+        1 => Just(Op::Close),
+        1 => Just(Op::Abort),
+        3 => Just(Op::Timer),
+    ]
+}
+
+/// What the connection is expected to look like
+struct Model {
+    state: State,
+    /// the connection's FIN went out
+    fin_sent: bool,
+    /// in-order data waiting in the receive queue
+    delivered: Vec<u8>,
+}
+
+impl Model {
+    /// Everything sent was acknowledged, including a FIN that went out
+    fn on_ack(&mut self) {
+        if !self.fin_sent {
+            return;
+        }
+        self.state = match self.state {
+            State::FinWait1 => State::FinWait2,
+            State::Closing => State::TimeWait,
+            State::LastAck => State::Closed,
+            state => state,
+        };
+    }
+
+    /// The peer's FIN arrived in order
+    fn on_fin(&mut self) {
+        self.state = match self.state {
+            State::Established => State::CloseWait,
+            State::FinWait1 => State::Closing,
+            State::FinWait2 => State::TimeWait,
+            state => state,
+        };
+    }
+
+    fn on_data(&mut self, data: &[u8]) {
+        if let State::Established | State::FinWait1 | State::FinWait2 = self.state {
+            self.delivered.extend_from_slice(data);
+        }
+    }
+
+    fn on_close(&mut self) {
+        self.state = match self.state {
+            State::Established => State::FinWait1,
+            State::CloseWait => State::LastAck,
+            state => state,
+        };
+    }
+
+    fn can_write(&self) -> bool {
+        matches!(self.state, State::Established | State::CloseWait)
+    }
+}
+
+/// The simulated peer's view of the connection
+struct Peer {
+    /// next sequence number to send
+    nxt: u32,
+    /// end of the sequence space the connection sent
+    acked: u32,
+    /// right edge of the window the connection advertised
+    edge: u32,
+    /// the peer sent its FIN
+    fin: bool,
+    /// last data segment sent
+    last: Option<Vec<u8>>,
+}
+
+impl Peer {
+    /// Take note of the segments the connection emitted
+    fn observe(&mut self, h: &mut Harness, model: &mut Model) {
+        for sent in h.sent() {
+            let tcp = &sent.tcp;
+            let len = sent.payload.len() as u32 + tcp.syn as u32 + tcp.fin as u32;
+            let end = tcp.sequence_number.wrapping_add(len);
+            if (end.wrapping_sub(self.acked) as i32) > 0 {
+                self.acked = end;
+            }
+            if tcp.fin {
+                model.fin_sent = true;
+            }
+            self.edge = tcp
+                .acknowledgment_number
+                .wrapping_add(tcp.window_size as u32);
+        }
+    }
+
+    /// Bytes the peer may send
+    fn room(&self) -> u32 {
+        std::cmp::max(self.edge.wrapping_sub(self.nxt) as i32, 0) as u32
+    }
+}
+
+/// Initial sequence numbers of the peer, close to wrapping around more
+/// often than chance would
+fn peer_iss() -> impl Strategy<Value = u32> {
+    prop_oneof![
+        1 => any::<u32>(),
+        1 => (u32::MAX - 64)..=u32::MAX,
+    ]
+}
+
+fn run(iss: u32, ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let mut h = Harness::established_from(&Config::default(), iss);
+    let mut model = Model {
+        state: State::Established,
+        fin_sent: false,
+        delivered: Vec::new(),
+    };
+    let mut peer = Peer {
+        nxt: iss.wrapping_add(1),
+        acked: 1,
+        edge: iss.wrapping_add(1 + h.conn.snapshot().rcv_wnd),
+        fin: false,
+        last: None,
+    };
+
+    for op in ops {
+        let deliver = |h: &mut Harness, flags: Flags, seq: u32, data: &[u8]| {
+            h.deliver(flags, seq, peer.acked, data);
+        };
+        match &op {
+            Op::Data(data) if !peer.fin && peer.room() == 0 => {
+                // Zero window probe: not accepted, but answered with the
+                // current window
+                deliver(&mut h, ACK, peer.nxt, &data[..1]);
+            }
+            Op::Data(data) if !peer.fin => {
+                let data = &data[..std::cmp::min(data.len(), peer.room() as usize)];
+                deliver(&mut h, ACK, peer.nxt, data);
+                model.on_ack();
+                model.on_data(data);
+                peer.last = Some(segment(ACK, peer.nxt, peer.acked, data));
+                peer.nxt = peer.nxt.wrapping_add(data.len() as u32);
+            }
+            Op::Duplicate => {
+                if let Some(packet) = &peer.last {
+                    h.deliver_raw(packet);
+                }
+            }
+            Op::Ahead(gap, data) if !peer.fin && gap + data.len() as u32 <= peer.room() => {
+                deliver(&mut h, ACK, peer.nxt.wrapping_add(*gap), data);
+                model.on_ack();
+            }
+            Op::Ack => {
+                deliver(&mut h, ACK, peer.nxt, &[]);
+                model.on_ack();
+            }
+            Op::Fin if peer.fin => {
+                // Retransmission of the FIN already sent: it is outside
+                // the window, so it's only answered with an ACK
+                deliver(&mut h, FIN_ACK, peer.nxt.wrapping_sub(1), &[]);
+            }
+            Op::Fin if peer.room() > 0 => {
+                deliver(&mut h, FIN_ACK, peer.nxt, &[]);
+                model.on_ack();
+                model.on_fin();
+                peer.fin = true;
+                peer.nxt = peer.nxt.wrapping_add(1);
+            }
+            Op::Rst { in_window } => {
+                let seq = if *in_window {
+                    peer.nxt
+                } else {
+                    peer.nxt.wrapping_add(100_000)
+                };
+                deliver(&mut h, RST, seq, &[]);
+                if *in_window {
+                    model.state = State::Closed;
+                }
+            }
+            Op::Write(data) if model.can_write() => {
+                h.conn.unacked.extend(data);
+            }
+            Op::Read => {
+                h.conn.ingress.clear();
+                let _ = h.conn.on_read();
+                model.delivered.clear();
+            }
+            Op::Close => {
+                let _ = h.conn.close();
+                model.on_close();
+            }
+            Op::Abort => {
+                let _ = h.conn.reset();
+                model.state = State::Closed;
+            }
+            Op::Timer => {
+                let _ = h.conn.on_timer();
+            }
+            // Not something a well-behaved peer or user does
+            Op::Data(_) | Op::Ahead(..) | Op::Fin | Op::Write(_) => {}
+        }
+        peer.observe(&mut h, &mut model);
+
+        prop_assert_eq!(h.conn.state, model.state, "after {:?}", op);
+        if model.state != State::Closed {
+            prop_assert!(
+                h.conn.ingress.iter().eq(model.delivered.iter()),
+                "received data differs after {:?}",
+                op
+            );
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn state_machine_follows_model(iss in peer_iss(), ops in vec(op(), 1..40)) {
+        run(iss, ops)?;
+    }
+}
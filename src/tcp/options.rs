@@ -0,0 +1,473 @@
+//! TCP options: `OptionsBuilder` lays out the options of an outgoing
+//! segment within the 40 bytes the header has room for, and `parse()` walks
+//! the options of a received one. `SegmentOptions` collects the options a
+//! connection acts on in a single walk.
+
+use super::time::Duration;
+
+/// Room for options in a TCP header: the data offset counts at most 15
+/// words, 5 of which are the fixed header
+pub const MAX_LEN: usize = 40;
+
+/// End of option list
+const KIND_END: u8 = 0;
+/// No-operation (padding)
+const KIND_NOP: u8 = 1;
+/// Maximum Segment Size RFC 9293 3.2
+const KIND_MSS: u8 = 2;
+const MSS_LEN: u8 = 4;
+/// Window Scale RFC 7323 Section 2
+const KIND_WINDOW_SCALE: u8 = 3;
+const WINDOW_SCALE_LEN: u8 = 3;
+/// SACK-Permitted RFC 2018 Section 2
+const KIND_SACK_PERMITTED: u8 = 4;
+const SACK_PERMITTED_LEN: u8 = 2;
+/// SACK RFC 2018 Section 3
+const KIND_SACK: u8 = 5;
+/// Timestamps RFC 7323
+const KIND_TIMESTAMPS: u8 = 8;
+const TIMESTAMPS_LEN: u8 = 10;
+/// TCP User Timeout RFC 5482
+const KIND_USER_TIMEOUT: u8 = 28;
+const USER_TIMEOUT_LEN: u8 = 4;
+/// Shared experimental options RFC 6994
+const KIND_EXPERIMENTAL: [u8; 2] = [253, 254];
+
+/// Granularity bit of the User Timeout option: set when the timeout is
+/// expressed in minutes rather than seconds
+const USER_TIMEOUT_GRANULARITY: u16 = 0x8000;
+const USER_TIMEOUT_MAX: u16 = 0x7fff;
+
+/// Most blocks a SACK option carries, filling the options on its own
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+/// The blocks of a SACK option, each the left and right edge of data
+/// received beyond RCV.NXT
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SackBlocks {
+    blocks: [(u32, u32); MAX_SACK_BLOCKS],
+    len: usize,
+}
+
+impl SackBlocks {
+    /// The first `MAX_SACK_BLOCKS` of `blocks`
+    pub fn new(blocks: &[(u32, u32)]) -> Self {
+        let len = core::cmp::min(blocks.len(), MAX_SACK_BLOCKS);
+        let mut sack = Self {
+            len,
+            ..Self::default()
+        };
+        sack.blocks[..len].copy_from_slice(&blocks[..len]);
+        sack
+    }
+
+    pub fn as_slice(&self) -> &[(u32, u32)] {
+        &self.blocks[..self.len]
+    }
+}
+
+/// A TCP option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpOption<'a> {
+    /// Largest segment the sender receives, sent on SYNs only
+    Mss(u16),
+    /// Shift count of the windows of the sender, sent on SYNs only
+    WindowScale(u8),
+    /// The sender takes SACK options, sent on SYNs only
+    SackPermitted,
+    Sack(SackBlocks),
+    /// TSval and TSecr
+    Timestamps {
+        value: u32,
+        echo: u32,
+    },
+    UserTimeout(Duration),
+    /// An option of one of the shared experimental kinds, told apart by its
+    /// 16-bit ExID RFC 6994 Section 3
+    Experimental {
+        kind: u8,
+        exid: u16,
+        data: &'a [u8],
+    },
+    /// An option this stack doesn't know, or a known one of the wrong
+    /// length
+    Unknown {
+        kind: u8,
+        data: &'a [u8],
+    },
+}
+
+impl<'a> TcpOption<'a> {
+    /// Bytes the option takes in the header, kind and length included
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Self::Mss(_) => MSS_LEN as usize,
+            Self::WindowScale(_) => WINDOW_SCALE_LEN as usize,
+            Self::SackPermitted => SACK_PERMITTED_LEN as usize,
+            Self::Sack(blocks) => 2 + 8 * blocks.len,
+            Self::Timestamps { .. } => TIMESTAMPS_LEN as usize,
+            Self::UserTimeout(_) => USER_TIMEOUT_LEN as usize,
+            Self::Experimental { data, .. } => 4 + data.len(),
+            Self::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Self::Mss(_) => KIND_MSS,
+            Self::WindowScale(_) => KIND_WINDOW_SCALE,
+            Self::SackPermitted => KIND_SACK_PERMITTED,
+            Self::Sack(_) => KIND_SACK,
+            Self::Timestamps { .. } => KIND_TIMESTAMPS,
+            Self::UserTimeout(_) => KIND_USER_TIMEOUT,
+            Self::Experimental { kind, .. } | Self::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    /// Write the option to the start of `out`, which has room for it
+    fn encode(&self, out: &mut [u8]) {
+        out[0] = self.kind();
+        out[1] = self.encoded_len() as u8;
+        let body = &mut out[2..self.encoded_len()];
+        match self {
+            Self::Mss(mss) => body.copy_from_slice(&mss.to_be_bytes()),
+            Self::WindowScale(shift) => body[0] = *shift,
+            Self::SackPermitted => {}
+            Self::Sack(blocks) => {
+                for (chunk, (left, right)) in body.chunks_mut(8).zip(blocks.as_slice()) {
+                    chunk[..4].copy_from_slice(&left.to_be_bytes());
+                    chunk[4..].copy_from_slice(&right.to_be_bytes());
+                }
+            }
+            Self::Timestamps { value, echo } => {
+                body[..4].copy_from_slice(&value.to_be_bytes());
+                body[4..].copy_from_slice(&echo.to_be_bytes());
+            }
+            Self::UserTimeout(timeout) => {
+                body.copy_from_slice(&encode_user_timeout(*timeout).to_be_bytes())
+            }
+            Self::Experimental { exid, data, .. } => {
+                body[..2].copy_from_slice(&exid.to_be_bytes());
+                body[2..].copy_from_slice(data);
+            }
+            Self::Unknown { data, .. } => body.copy_from_slice(data),
+        }
+    }
+
+    /// Decode an option from its kind and the data behind its length
+    fn decode(kind: u8, data: &'a [u8]) -> Self {
+        let len = data.len() + 2;
+        match kind {
+            KIND_MSS if len == MSS_LEN as usize => {
+                Self::Mss(u16::from_be_bytes([data[0], data[1]]))
+            }
+            KIND_WINDOW_SCALE if len == WINDOW_SCALE_LEN as usize => Self::WindowScale(data[0]),
+            KIND_SACK_PERMITTED if len == SACK_PERMITTED_LEN as usize => Self::SackPermitted,
+            KIND_SACK if !data.is_empty() && data.len().is_multiple_of(8) => {
+                let mut blocks = [(0, 0); MAX_SACK_BLOCKS];
+                let mut count = 0;
+                for (block, chunk) in blocks.iter_mut().zip(data.chunks(8)) {
+                    *block = (be_u32(&chunk[..4]), be_u32(&chunk[4..]));
+                    count += 1;
+                }
+                Self::Sack(SackBlocks::new(&blocks[..count]))
+            }
+            KIND_TIMESTAMPS if len == TIMESTAMPS_LEN as usize => Self::Timestamps {
+                value: be_u32(&data[..4]),
+                echo: be_u32(&data[4..]),
+            },
+            KIND_USER_TIMEOUT if len == USER_TIMEOUT_LEN as usize => {
+                Self::UserTimeout(decode_user_timeout(u16::from_be_bytes([data[0], data[1]])))
+            }
+            _ if KIND_EXPERIMENTAL.contains(&kind) && data.len() >= 2 => Self::Experimental {
+                kind,
+                exid: u16::from_be_bytes([data[0], data[1]]),
+                data: &data[2..],
+            },
+            _ => Self::Unknown { kind, data },
+        }
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Value of the User Timeout option RFC 5482 Section 2
+///
+///  0                   1                   2                   3
+///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |   Kind = 28   |   Length = 4  |G|        User Timeout         |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///
+/// Timeouts that don't fit in 15 bits of seconds are sent in minutes
+/// (rounded up), saturating at the largest value the option can carry.
+fn encode_user_timeout(timeout: Duration) -> u16 {
+    let secs = timeout.as_secs();
+    if secs <= USER_TIMEOUT_MAX as u64 {
+        secs as u16
+    } else {
+        let mins = core::cmp::min(secs.div_ceil(60), USER_TIMEOUT_MAX as u64);
+        USER_TIMEOUT_GRANULARITY | mins as u16
+    }
+}
+
+fn decode_user_timeout(value: u16) -> Duration {
+    let timeout = (value & USER_TIMEOUT_MAX) as u64;
+    if value & USER_TIMEOUT_GRANULARITY != 0 {
+        Duration::from_secs(timeout * 60)
+    } else {
+        Duration::from_secs(timeout)
+    }
+}
+
+/// The options of an outgoing segment. Each option is preceded by the NOPs
+/// that keep the options after it aligned on 32 bits, so the list always
+/// fills whole words of the header.
+#[derive(Debug, Clone, Copy)]
+pub struct OptionsBuilder {
+    buf: [u8; MAX_LEN],
+    len: usize,
+}
+
+impl Default for OptionsBuilder {
+    fn default() -> Self {
+        Self {
+            buf: [KIND_END; MAX_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl OptionsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `option`, or leave the options as they are and return false
+    /// if it doesn't fit in what is left of the header
+    pub fn push(&mut self, option: &TcpOption) -> bool {
+        let len = option.encoded_len();
+        let pad = (4 - len % 4) % 4;
+        if len > u8::MAX as usize || self.len + pad + len > MAX_LEN {
+            return false;
+        }
+        self.buf[self.len..self.len + pad].fill(KIND_NOP);
+        self.len += pad;
+        option.encode(&mut self.buf[self.len..]);
+        self.len += len;
+        true
+    }
+
+    /// Bytes left for more options
+    pub fn room(&self) -> usize {
+        MAX_LEN - self.len
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Iterator over the options of a received segment, see `parse()`
+#[derive(Debug, Clone)]
+pub struct Options<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = TcpOption<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match *self.rest.first()? {
+                KIND_END => {
+                    self.rest = &[];
+                    return None;
+                }
+                KIND_NOP => self.rest = &self.rest[1..],
+                kind => {
+                    let len = *self.rest.get(1)? as usize;
+                    if len < 2 || len > self.rest.len() {
+                        // malformed option list
+                        self.rest = &[];
+                        return None;
+                    }
+                    let data = &self.rest[2..len];
+                    self.rest = &self.rest[len..];
+                    return Some(TcpOption::decode(kind, data));
+                }
+            }
+        }
+    }
+}
+
+/// The options in the raw options of a segment, up to the end of the list
+/// or the first malformed option
+pub fn parse(options: &[u8]) -> Options<'_> {
+    Options { rest: options }
+}
+
+/// The options of a received segment that the connection acts on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub sack: Option<SackBlocks>,
+    /// TSval and TSecr
+    pub timestamps: Option<(u32, u32)>,
+    pub user_timeout: Option<Duration>,
+}
+
+impl SegmentOptions {
+    /// Collect the options from the raw options of a segment. The first
+    /// option of each kind counts.
+    pub fn parse(options: &[u8]) -> Self {
+        let mut opts = Self::default();
+        for option in parse(options) {
+            match option {
+                TcpOption::Mss(mss) => {
+                    opts.mss.get_or_insert(mss);
+                }
+                TcpOption::WindowScale(shift) => {
+                    opts.window_scale.get_or_insert(shift);
+                }
+                TcpOption::SackPermitted => opts.sack_permitted = true,
+                TcpOption::Sack(blocks) => {
+                    opts.sack.get_or_insert(blocks);
+                }
+                TcpOption::Timestamps { value, echo } => {
+                    opts.timestamps.get_or_insert((value, echo));
+                }
+                TcpOption::UserTimeout(timeout) => {
+                    opts.user_timeout.get_or_insert(timeout);
+                }
+                TcpOption::Experimental { .. } | TcpOption::Unknown { .. } => {}
+            }
+        }
+        opts
+    }
+
+    /// The TSval of the Timestamps option RFC 7323 Section 3
+    pub fn timestamp(&self) -> Option<u32> {
+        self.timestamps.map(|(value, _)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_option() {
+        let sack = SackBlocks::new(&[(1000, 2000), (3000, 4000)]);
+        let options = [
+            TcpOption::Mss(1460),
+            TcpOption::WindowScale(7),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps { value: 1, echo: 2 },
+            TcpOption::Sack(sack),
+        ];
+        for option in &options {
+            let mut builder = OptionsBuilder::new();
+            assert!(builder.push(option));
+            assert_eq!(builder.len() % 4, 0);
+            assert_eq!(parse(builder.as_slice()).collect::<Vec<_>>(), [*option]);
+        }
+    }
+
+    #[test]
+    fn fills_the_header_and_refuses_what_does_not_fit() {
+        let mut builder = OptionsBuilder::new();
+        assert!(builder.push(&TcpOption::Mss(1460)));
+        assert!(builder.push(&TcpOption::SackPermitted));
+        assert!(builder.push(&TcpOption::WindowScale(7)));
+        assert!(builder.push(&TcpOption::Timestamps { value: 1, echo: 2 }));
+        assert_eq!(builder.room(), 16);
+        let sack = SackBlocks::new(&[(0, 1), (2, 3)]);
+        assert!(!builder.push(&TcpOption::Sack(sack)));
+        assert_eq!(builder.room(), 16);
+        assert!(builder.push(&TcpOption::Sack(SackBlocks::new(&[(0, 1)]))));
+        assert_eq!(builder.room(), 4);
+    }
+
+    #[test]
+    fn user_timeout_switches_to_minutes() {
+        let encoded = |timeout| {
+            let mut builder = OptionsBuilder::new();
+            builder.push(&TcpOption::UserTimeout(timeout));
+            SegmentOptions::parse(builder.as_slice()).user_timeout
+        };
+        let secs = Duration::from_secs(u64::from(USER_TIMEOUT_MAX));
+        assert_eq!(encoded(secs), Some(secs));
+        // Rounded up to whole minutes
+        assert_eq!(
+            encoded(secs + Duration::from_secs(1)),
+            Some(Duration::from_secs(32_820))
+        );
+        // Saturating at the largest value
+        let max = Duration::from_secs(u64::from(USER_TIMEOUT_MAX) * 60);
+        assert_eq!(encoded(Duration::MAX), Some(max));
+    }
+
+    #[test]
+    fn parse_stops_at_the_end_of_the_list_or_malformed_options() {
+        let mut raw = vec![KIND_NOP, KIND_MSS, MSS_LEN, 0x05, 0xb4, KIND_END, KIND_NOP];
+        raw.extend_from_slice(&[KIND_WINDOW_SCALE, WINDOW_SCALE_LEN, 7]);
+        assert_eq!(parse(&raw).collect::<Vec<_>>(), [TcpOption::Mss(1460)]);
+
+        let raw = [KIND_MSS, MSS_LEN, 0x05, 0xb4, KIND_WINDOW_SCALE, 10, 7];
+        assert_eq!(parse(&raw).collect::<Vec<_>>(), [TcpOption::Mss(1460)]);
+        let raw = [KIND_WINDOW_SCALE, 1, 7];
+        assert_eq!(parse(&raw).count(), 0);
+    }
+
+    #[test]
+    fn keeps_unknown_and_experimental_options() {
+        // An MSS option of the wrong length, then an experimental one
+        let mut raw = vec![KIND_MSS, 3, 0x05];
+        raw.extend_from_slice(&[KIND_EXPERIMENTAL[0], 6, 0xab, 0xcd, 1, 2]);
+        let options: Vec<_> = parse(&raw).collect();
+        assert_eq!(
+            options,
+            [
+                TcpOption::Unknown {
+                    kind: KIND_MSS,
+                    data: &[0x05],
+                },
+                TcpOption::Experimental {
+                    kind: KIND_EXPERIMENTAL[0],
+                    exid: 0xabcd,
+                    data: &[1, 2],
+                },
+            ]
+        );
+        let mut builder = OptionsBuilder::new();
+        assert!(builder.push(&options[1]));
+        assert_eq!(builder.as_slice()[..2], [KIND_NOP, KIND_NOP]);
+        assert_eq!(builder.as_slice()[2..], raw[3..]);
+    }
+
+    #[test]
+    fn segment_options_keep_the_first_of_each_kind() {
+        let mut builder = OptionsBuilder::new();
+        builder.push(&TcpOption::Mss(1460));
+        builder.push(&TcpOption::Mss(536));
+        builder.push(&TcpOption::Timestamps { value: 7, echo: 3 });
+        let opts = SegmentOptions::parse(builder.as_slice());
+        assert_eq!(opts.mss, Some(1460));
+        assert_eq!(opts.timestamp(), Some(7));
+        assert_eq!(opts.window_scale, None);
+        assert!(!opts.sack_permitted);
+    }
+}
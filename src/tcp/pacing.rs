@@ -0,0 +1,103 @@
+use super::time::{Duration, Instant};
+
+/// Pacing rate multiplier in slow start, so the window can still double
+/// every round trip
+const SLOW_START_GAIN: f64 = 2.0;
+/// Pacing rate multiplier in congestion avoidance
+const CONGESTION_AVOIDANCE_GAIN: f64 = 1.2;
+
+/// Spreads transmissions over the round trip time instead of sending a
+/// whole congestion window in one burst, like Linux's fq qdisc does for TCP.
+///
+/// Every transmission pushes the release time of the next one out by the
+/// time it takes to send it at the pacing rate: cwnd / SRTT, scaled up by a
+/// gain so the sender doesn't fall behind the window.
+#[derive(Debug, Default)]
+pub struct Pacer {
+    /// earliest time the next segment may leave
+    release: Option<Instant>,
+}
+
+impl Pacer {
+    /// The next segment may be sent at `now`
+    pub fn ready(&self, now: Instant) -> bool {
+        self.release.is_none_or(|release| release <= now)
+    }
+
+    /// Earliest time the next segment may leave, if it is held back
+    pub fn release(&self) -> Option<Instant> {
+        self.release
+    }
+
+    /// `bytes` were sent at `now`: hold back the next segment accordingly
+    pub fn on_send(
+        &mut self,
+        bytes: usize,
+        cwnd: usize,
+        srtt: Duration,
+        slow_start: bool,
+        now: Instant,
+    ) {
+        let gain = if slow_start {
+            SLOW_START_GAIN
+        } else {
+            CONGESTION_AVOIDANCE_GAIN
+        };
+        // bytes per second
+        let rate = gain * cwnd as f64 / srtt.as_secs_f64().max(f64::EPSILON);
+        let delay = Duration::from_secs_f64(bytes as f64 / rate);
+        // Don't let an idle period build up credit for a burst
+        let from = self.release.map_or(now, |release| release.max(now));
+        self.release = Some(from + delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_a_window_over_the_round_trip() {
+        let now = Instant::now();
+        let srtt = Duration::from_millis(100);
+        let mut pacer = Pacer::default();
+        assert!(pacer.ready(now));
+
+        // 1000 bytes of a 10000 byte window take a tenth of SRTT over the gain
+        pacer.on_send(1000, 10_000, srtt, false, now);
+        let delay = Duration::from_secs_f64(0.01 / CONGESTION_AVOIDANCE_GAIN);
+        assert!(!pacer.ready(now));
+        assert!(pacer.ready(now + delay));
+
+        // Slow start paces at twice the rate
+        let mut pacer = Pacer::default();
+        pacer.on_send(1000, 10_000, srtt, true, now);
+        assert!(pacer.ready(now + Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn idle_time_builds_no_credit() {
+        let now = Instant::now();
+        let srtt = Duration::from_millis(100);
+        let mut pacer = Pacer::default();
+        pacer.on_send(1000, 10_000, srtt, true, now);
+
+        // After a second of silence the next segment is paced from then on
+        let later = now + Duration::from_secs(1);
+        pacer.on_send(1000, 10_000, srtt, true, later);
+        assert!(!pacer.ready(later));
+        assert!(pacer.ready(later + Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn back_to_back_sends_queue_up() {
+        let now = Instant::now();
+        let srtt = Duration::from_millis(100);
+        let mut pacer = Pacer::default();
+        for _ in 0..4 {
+            pacer.on_send(1000, 10_000, srtt, true, now);
+        }
+        assert!(!pacer.ready(now + Duration::from_millis(19)));
+        assert!(pacer.ready(now + Duration::from_millis(20)));
+    }
+}
@@ -0,0 +1,129 @@
+//! Reusable packet buffers. Connections build the segments they send in
+//! buffers taken from the pool of their configuration, and the owner hands
+//! them back once they are on the wire; received packets can be read into
+//! the same buffers. In a steady state no packet allocates.
+
+use alloc::vec::Vec;
+
+/// Capacity of a new buffer: a packet of an Ethernet MTU with room to
+/// spare. Buffers grow for jumbo frames, and pools keep them grown.
+pub const BUFFER_SIZE: usize = 2048;
+
+/// Where connections get packet buffers from
+pub trait BufferPool: Send + Sync + core::fmt::Debug {
+    /// An empty buffer with a capacity of at least `BUFFER_SIZE`
+    fn take(&self) -> Vec<u8>;
+
+    /// Return a buffer that is no longer needed
+    fn give(&self, buf: Vec<u8>);
+}
+
+/// No pooling: every buffer is allocated and freed again
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Unpooled;
+
+impl BufferPool for Unpooled {
+    fn take(&self) -> Vec<u8> {
+        Vec::with_capacity(BUFFER_SIZE)
+    }
+
+    fn give(&self, _buf: Vec<u8>) {}
+}
+
+#[cfg(feature = "std")]
+pub use self::shared::{PacketPool, PACKETS};
+
+#[cfg(feature = "std")]
+mod shared {
+    use alloc::vec::Vec;
+    use std::sync::Mutex;
+
+    use super::{BufferPool, BUFFER_SIZE};
+
+    /// Buffers kept for reuse by the process-wide pool
+    const PACKETS_FREE: usize = 512;
+
+    /// The pool connections use unless configured otherwise
+    pub static PACKETS: PacketPool = PacketPool::new(PACKETS_FREE);
+
+    /// A free list of buffers shared between threads. Buffers returned
+    /// while `max` are kept already are freed, which bounds the memory the
+    /// pool holds on to after a burst.
+    #[derive(Debug)]
+    pub struct PacketPool {
+        free: Mutex<Vec<Vec<u8>>>,
+        max: usize,
+    }
+
+    impl PacketPool {
+        pub const fn new(max: usize) -> Self {
+            Self {
+                free: Mutex::new(Vec::new()),
+                max,
+            }
+        }
+
+        /// Buffers waiting to be reused
+        pub fn available(&self) -> usize {
+            self.free.lock().unwrap().len()
+        }
+    }
+
+    impl BufferPool for PacketPool {
+        fn take(&self) -> Vec<u8> {
+            self.free
+                .lock()
+                .unwrap()
+                .pop()
+                .unwrap_or_else(|| Vec::with_capacity(BUFFER_SIZE))
+        }
+
+        fn give(&self, mut buf: Vec<u8>) {
+            if buf.capacity() < BUFFER_SIZE {
+                return;
+            }
+            let mut free = self.free.lock().unwrap();
+            if free.len() < self.max {
+                buf.clear();
+                free.push(buf);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_returned_buffers() {
+        let pool = PacketPool::new(4);
+        let mut buf = pool.take();
+        assert!(buf.capacity() >= BUFFER_SIZE);
+        buf.extend_from_slice(b"packet");
+        let ptr = buf.as_ptr();
+        pool.give(buf);
+        assert_eq!(pool.available(), 1);
+
+        let buf = pool.take();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.is_empty());
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn keeps_at_most_max_buffers() {
+        let pool = PacketPool::new(2);
+        for _ in 0..3 {
+            pool.give(Vec::with_capacity(BUFFER_SIZE));
+        }
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn drops_buffers_too_small_to_reuse() {
+        let pool = PacketPool::new(2);
+        pool.give(Vec::with_capacity(BUFFER_SIZE / 2));
+        assert_eq!(pool.available(), 0);
+    }
+}
@@ -0,0 +1,263 @@
+use alloc::vec::Vec;
+
+use super::time::{Duration, Instant};
+
+/// Recoveries without reordering after which the reordering window shrinks
+/// back to its initial size RFC 8985 Section 6.2 step 4
+const REO_WND_PERSIST: u32 = 16;
+
+/// Largest multiplier of the reordering window
+const REO_WND_MULT_MAX: u32 = 16;
+
+/// RACK time-based loss detection RFC 8985.
+///
+/// Instead of counting duplicate ACKs, a segment is considered lost once a
+/// segment sent after it was delivered and it wasn't acknowledged within
+/// one round trip plus a reordering window. Retransmissions are timed too,
+/// so a lost retransmission is detected like any other loss.
+///
+/// The reordering window starts at a quarter of the minimum RTT. It grows
+/// every time reordering shows up (as a spurious retransmission) and shrinks
+/// back after 16 recoveries without any, so connections on reordering paths
+/// stop retransmitting prematurely.
+#[derive(Debug)]
+pub struct Rack {
+    /// send time of the most recently sent segment that was delivered
+    xmit_ts: Option<Instant>,
+    /// end of that segment
+    end_seq: u32,
+    /// round trip time measured on that segment
+    rtt: Duration,
+    /// lowest round trip time seen on the connection
+    min_rtt: Option<Duration>,
+    /// reordering window in quarters of `min_rtt`
+    reo_wnd_mult: u32,
+    /// recoveries left before `reo_wnd_mult` is reset
+    reo_wnd_persist: u32,
+    /// when to check the segments that are still within the window again
+    timeout: Option<Instant>,
+}
+
+impl Default for Rack {
+    fn default() -> Self {
+        Self {
+            xmit_ts: None,
+            end_seq: 0,
+            rtt: Duration::ZERO,
+            min_rtt: None,
+            reo_wnd_mult: 1,
+            reo_wnd_persist: REO_WND_PERSIST,
+            timeout: None,
+        }
+    }
+}
+
+impl Rack {
+    /// A segment ending at `end_seq` and last sent at `xmit_ts` was
+    /// acknowledged. If it was `retransmitted`, a round trip shorter than
+    /// the minimum is taken to acknowledge an earlier transmission and is
+    /// ignored (RFC 8985 Section 6.2 step 2).
+    pub fn on_delivered(
+        &mut self,
+        xmit_ts: Instant,
+        end_seq: u32,
+        retransmitted: bool,
+        now: Instant,
+    ) {
+        let rtt = now - xmit_ts;
+        if retransmitted && self.min_rtt.is_some_and(|min| rtt < min) {
+            return;
+        }
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        if self.sent_after(xmit_ts, end_seq) {
+            self.xmit_ts = Some(xmit_ts);
+            self.end_seq = end_seq;
+            self.rtt = rtt;
+        }
+    }
+
+    /// Reordering was observed: widen the reordering window
+    pub fn on_reordering(&mut self) {
+        self.reo_wnd_mult = core::cmp::min(self.reo_wnd_mult + 1, REO_WND_MULT_MAX);
+        self.reo_wnd_persist = REO_WND_PERSIST;
+    }
+
+    /// A loss recovery episode ended
+    pub fn on_recovery_done(&mut self) {
+        self.reo_wnd_persist = self.reo_wnd_persist.saturating_sub(1);
+        if self.reo_wnd_persist == 0 {
+            self.reo_wnd_mult = 1;
+            self.reo_wnd_persist = REO_WND_PERSIST;
+        }
+    }
+
+    /// When the reordering timer fires, if it is running
+    pub fn timeout(&self) -> Option<Instant> {
+        self.timeout
+    }
+
+    /// The reordering timer expired
+    pub fn timeout_due(&self, now: Instant) -> bool {
+        self.timeout.is_some_and(|timeout| timeout <= now)
+    }
+
+    /// Reordering window, bounded by the smoothed round trip time
+    fn reo_wnd(&self, srtt: Duration) -> Duration {
+        let Some(min_rtt) = self.min_rtt else {
+            return Duration::ZERO;
+        };
+        core::cmp::min(min_rtt / 4 * self.reo_wnd_mult, srtt)
+    }
+
+    /// A segment sent at `xmit_ts` and ending at `end_seq` was sent after
+    /// the most recently delivered one
+    fn sent_after(&self, xmit_ts: Instant, end_seq: u32) -> bool {
+        match self.xmit_ts {
+            None => true,
+            Some(ts) if xmit_ts != ts => xmit_ts > ts,
+            Some(_) => (end_seq.wrapping_sub(self.end_seq) as i32) > 0,
+        }
+    }
+
+    /// Go through the outstanding `segments` (start, end, send time) and
+    /// return the ones that are lost RFC 8985 Section 6.2 step 5. Segments
+    /// that could still be reordered arm the reordering timer.
+    pub fn detect_loss(
+        &mut self,
+        segments: impl Iterator<Item = (u32, u32, Instant)>,
+        srtt: Duration,
+        now: Instant,
+    ) -> Vec<(u32, u32)> {
+        self.timeout = None;
+        let Some(rack_ts) = self.xmit_ts else {
+            return Vec::new();
+        };
+        let reo_wnd = self.reo_wnd(srtt);
+        let mut lost = Vec::new();
+        let mut wait = Duration::ZERO;
+
+        for (start, end, xmit_ts) in segments {
+            // Only segments sent before the delivered one can be lost
+            let before = xmit_ts < rack_ts
+                || (xmit_ts == rack_ts && (self.end_seq.wrapping_sub(end) as i32) > 0);
+            if !before {
+                continue;
+            }
+            let deadline = xmit_ts + self.rtt + reo_wnd;
+            if deadline <= now {
+                lost.push((start, end));
+            } else {
+                wait = wait.max(deadline - now);
+            }
+        }
+        if !wait.is_zero() {
+            self.timeout = Some(now + wait);
+        }
+        lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: Duration = Duration::from_millis(100);
+
+    /// Segments of 100 bytes sent 1ms apart from `start`
+    fn sent(start: Instant, count: u32) -> Vec<(u32, u32, Instant)> {
+        (0..count)
+            .map(|i| {
+                (
+                    i * 100,
+                    (i + 1) * 100,
+                    start + Duration::from_millis(i.into()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nothing_is_lost_before_a_delivery() {
+        let start = Instant::now();
+        let mut rack = Rack::default();
+        let lost = rack.detect_loss(sent(start, 3).into_iter(), RTT, start + RTT * 10);
+        assert!(lost.is_empty());
+        assert_eq!(rack.timeout, None);
+    }
+
+    #[test]
+    fn earlier_segments_are_lost_after_rtt_and_reordering_window() {
+        let start = Instant::now();
+        let segments = sent(start, 3);
+        let mut rack = Rack::default();
+        // The last segment arrives after one round trip, the others don't
+        let (_, end, xmit_ts) = segments[2];
+        let now = xmit_ts + RTT;
+        rack.on_delivered(xmit_ts, end, false, now);
+        let reo_wnd = RTT / 4;
+
+        // Still within RTT + reo_wnd of their transmission: wait
+        let lost = rack.detect_loss(segments[..2].iter().copied(), RTT, now);
+        assert!(lost.is_empty());
+        assert_eq!(rack.timeout, Some(segments[1].2 + RTT + reo_wnd));
+        assert!(!rack.timeout_due(now));
+
+        // The first one is overdue, the second one isn't yet
+        let now = segments[0].2 + RTT + reo_wnd;
+        assert!(rack.timeout_due(segments[1].2 + RTT + reo_wnd));
+        let lost = rack.detect_loss(segments[..2].iter().copied(), RTT, now);
+        assert_eq!(lost, vec![(0, 100)]);
+        assert_eq!(rack.timeout, Some(segments[1].2 + RTT + reo_wnd));
+    }
+
+    #[test]
+    fn segments_sent_after_the_delivered_one_are_not_lost() {
+        let start = Instant::now();
+        let segments = sent(start, 3);
+        let mut rack = Rack::default();
+        let (_, end, xmit_ts) = segments[0];
+        rack.on_delivered(xmit_ts, end, false, xmit_ts + RTT);
+        let lost = rack.detect_loss(segments[1..].iter().copied(), RTT, start + RTT * 10);
+        assert!(lost.is_empty());
+    }
+
+    #[test]
+    fn short_samples_of_retransmitted_segments_are_ignored() {
+        let start = Instant::now();
+        let mut rack = Rack::default();
+        rack.on_delivered(start, 100, false, start + RTT);
+
+        // Acknowledged right after the retransmission: the ACK is for the
+        // original transmission
+        let resent = start + RTT * 3;
+        rack.on_delivered(resent, 200, true, resent + RTT / 10);
+        assert_eq!(rack.min_rtt, Some(RTT));
+        assert_eq!(rack.xmit_ts, Some(start));
+
+        rack.on_delivered(resent, 200, true, resent + RTT * 2);
+        assert_eq!(rack.xmit_ts, Some(resent));
+        assert_eq!(rack.rtt, RTT * 2);
+    }
+
+    #[test]
+    fn reordering_window_grows_and_resets() {
+        let start = Instant::now();
+        let mut rack = Rack::default();
+        rack.on_delivered(start, 100, false, start + RTT);
+        assert_eq!(rack.reo_wnd(RTT), RTT / 4);
+
+        rack.on_reordering();
+        rack.on_reordering();
+        assert_eq!(rack.reo_wnd(RTT), RTT / 4 * 3);
+        // Never more than SRTT
+        for _ in 0..REO_WND_MULT_MAX {
+            rack.on_reordering();
+        }
+        assert_eq!(rack.reo_wnd(RTT), RTT);
+
+        for _ in 0..REO_WND_PERSIST {
+            rack.on_recovery_done();
+        }
+        assert_eq!(rack.reo_wnd(RTT), RTT / 4);
+    }
+}
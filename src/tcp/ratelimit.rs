@@ -0,0 +1,229 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use core::net::Ipv4Addr;
+
+use super::hash::QuadState;
+use super::time::{Duration, Instant};
+
+/// Number of peers tracked at most
+const SLOTS: usize = 4096;
+/// Slots a peer's bucket may take, evicting the fullest bucket among them
+/// once all are taken
+const WAYS: usize = 4;
+
+/// Token bucket parameters: `per_second` tokens are added every second, up
+/// to `burst`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// `count` tokens per `period`, in bursts of up to `count`
+    pub fn new(count: u32, period: Duration) -> Self {
+        Self {
+            per_second: f64::from(count) / period.as_secs_f64().max(f64::EPSILON),
+            burst: count,
+        }
+    }
+}
+
+/// Token bucket that starts out full
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.per_second)
+            .min(f64::from(self.limit.burst));
+        self.updated = now;
+    }
+
+    /// Whole tokens available at `now`
+    pub fn available(&mut self, now: Instant) -> u32 {
+        self.refill(now);
+        self.tokens as u32
+    }
+
+    /// Use up `count` tokens
+    pub fn take(&mut self, count: u32) {
+        self.tokens -= f64::from(count);
+    }
+
+    /// When `count` tokens will be available, or as many as the bucket holds
+    pub fn ready_at(&self, count: u32) -> Instant {
+        let missing = f64::from(count.min(self.limit.burst)) - self.tokens;
+        if missing <= 0.0 {
+            return self.updated;
+        }
+        self.updated + Duration::from_secs_f64(missing / self.limit.per_second.max(f64::EPSILON))
+    }
+}
+
+/// Limits how many connections each remote address may open per second,
+/// with a token bucket per address. Buckets live in a fixed number of slots
+/// picked by a keyed hash of the address, so a flood of SYNs from many
+/// addresses costs neither memory nor time per SYN beyond the slots. A new
+/// peer takes the slot of the bucket with the most tokens, whose peer has
+/// been quiet the longest.
+#[derive(Debug)]
+pub struct SynLimiter {
+    limit: Option<RateLimit>,
+    /// `WAYS` slots per hash value, allocated with the first bucket
+    slots: Vec<Option<(Ipv4Addr, TokenBucket)>>,
+    hasher: QuadState,
+}
+
+impl Default for SynLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl SynLimiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        #[cfg(feature = "std")]
+        let hasher = QuadState::default();
+        // Without a source of randomness, peers could find addresses that
+        // share slots, but that only makes them evict each other
+        #[cfg(not(feature = "std"))]
+        let hasher = QuadState::with_keys([0x243f_6a88_85a3_08d3, 0x1319_8a2e_0370_7344]);
+        Self {
+            limit,
+            slots: Vec::new(),
+            hasher,
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: Option<RateLimit>) {
+        self.limit = limit;
+        self.slots.clear();
+    }
+
+    /// Take a token for a SYN from `src`. Returns false if the peer ran out
+    /// of tokens and the SYN should be dropped.
+    pub fn allow(&mut self, src: Ipv4Addr, now: Instant) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        if self.slots.is_empty() {
+            self.slots = vec![None; SLOTS];
+        }
+        let first = (self.hasher.hash_one(src) as usize % (SLOTS / WAYS)) * WAYS;
+        let set = &mut self.slots[first..first + WAYS];
+        let way = match set
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(|(addr, _)| *addr == src))
+        {
+            Some(way) => way,
+            None => {
+                let mut victim = 0;
+                let mut most = f64::NEG_INFINITY;
+                for (way, slot) in set.iter_mut().enumerate() {
+                    let tokens = match slot {
+                        Some((_, bucket)) => {
+                            bucket.refill(now);
+                            bucket.tokens
+                        }
+                        None => f64::INFINITY,
+                    };
+                    if tokens > most {
+                        victim = way;
+                        most = tokens;
+                    }
+                }
+                set[victim] = Some((src, TokenBucket::new(limit, now)));
+                victim
+            }
+        };
+        let (_, bucket) = set[way].as_mut().expect("slot was just found or filled");
+        if bucket.available(now) >= 1 {
+            bucket.take(1);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    #[test]
+    fn allows_a_burst_then_refills_over_time() {
+        let now = Instant::now();
+        let mut limiter = SynLimiter::new(Some(RateLimit::new(2, Duration::from_secs(1))));
+        assert!(limiter.allow(PEER, now));
+        assert!(limiter.allow(PEER, now));
+        assert!(!limiter.allow(PEER, now));
+        // Other peers have buckets of their own
+        assert!(limiter.allow(Ipv4Addr::new(10, 0, 0, 3), now));
+
+        assert!(!limiter.allow(PEER, now + Duration::from_millis(400)));
+        assert!(limiter.allow(PEER, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn unlimited_without_a_limit() {
+        let now = Instant::now();
+        let mut limiter = SynLimiter::default();
+        assert!((0..100).all(|_| limiter.allow(PEER, now)));
+    }
+
+    #[test]
+    fn tracks_a_fixed_number_of_peers() {
+        let now = Instant::now();
+        let mut limiter = SynLimiter::new(Some(RateLimit::new(1, Duration::from_secs(1))));
+        for i in 0..2 * SLOTS as u32 {
+            assert!(limiter.allow(Ipv4Addr::from(i), now));
+        }
+        assert_eq!(limiter.slots.len(), SLOTS);
+    }
+
+    #[test]
+    fn a_new_peer_evicts_the_fullest_bucket_of_its_slots() {
+        let start = Instant::now();
+        let mut limiter = SynLimiter::new(Some(RateLimit::new(1, Duration::from_secs(1))));
+        limiter.hasher = QuadState::with_keys([1, 2]);
+        let set = |addr: Ipv4Addr| limiter.hasher.hash_one(addr) as usize % (SLOTS / WAYS);
+        let peers: Vec<Ipv4Addr> = (0..)
+            .map(Ipv4Addr::from)
+            .filter(|addr| set(*addr) == set(PEER))
+            .take(WAYS)
+            .collect();
+
+        // The first peer used its token longest ago
+        for (i, peer) in peers.iter().enumerate() {
+            assert!(limiter.allow(*peer, start + Duration::from_millis(100 * i as u64)));
+        }
+        let now = start + Duration::from_millis(500);
+        assert!(limiter.allow(PEER, now));
+        // The others' buckets are still there, and empty
+        for peer in &peers[1..] {
+            assert!(!limiter.allow(*peer, now));
+        }
+        assert!(!limiter.allow(PEER, now));
+    }
+}
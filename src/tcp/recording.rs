@@ -0,0 +1,300 @@
+//! Recording of everything that drives a connection: the segments it
+//! receives, its timers and the calls of the application. A recording is
+//! saved in a compact binary format and replayed into a fresh `Connection`
+//! to reproduce a bug deterministically.
+
+use alloc::vec::Vec;
+
+use super::io;
+use super::time::{Duration, Instant};
+
+/// Start of an encoded recording, including the format version
+const MAGIC: &[u8] = b"TCPRSREC1";
+
+/// One input of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    /// A segment from the peer, IP header included. The first record of a
+    /// recording is the SYN that opened the connection.
+    Segment(Vec<u8>),
+    /// `Connection::on_timer`
+    Timer,
+    /// The application took this many bytes from the receive queue
+    Consume(usize),
+    /// The application appended data to the send queue
+    Queue(Vec<u8>),
+    /// `Connection::on_read`
+    Read,
+    /// `Connection::push`
+    Push,
+    /// `Connection::close`
+    Close,
+    /// `Connection::reset`
+    Reset,
+}
+
+impl Record {
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Segment(_) => 0,
+            Self::Timer => 1,
+            Self::Consume(_) => 2,
+            Self::Queue(_) => 3,
+            Self::Read => 4,
+            Self::Push => 5,
+            Self::Close => 6,
+            Self::Reset => 7,
+        }
+    }
+}
+
+/// A record and when it happened, relative to the first one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedRecord {
+    pub at: Duration,
+    pub record: Record,
+}
+
+/// The inputs of a connection in the order they happened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub entries: Vec<TimedRecord>,
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Truncated recording")
+}
+
+fn get_varint(input: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first().ok_or_else(truncated)?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Invalid number in recording",
+    ))
+}
+
+fn get_bytes(input: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = get_varint(input)? as usize;
+    if input.len() < len {
+        return Err(truncated());
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes.to_vec())
+}
+
+impl Recording {
+    /// Encode as a tag, the time in nanoseconds since the previous record
+    /// and the payload of every record, with variable-length integers
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        let mut last = Duration::ZERO;
+        for entry in &self.entries {
+            out.push(entry.record.tag());
+            put_varint(&mut out, entry.at.saturating_sub(last).as_nanos() as u64);
+            last = entry.at;
+            match &entry.record {
+                Record::Segment(bytes) | Record::Queue(bytes) => {
+                    put_varint(&mut out, bytes.len() as u64);
+                    out.extend_from_slice(bytes);
+                }
+                Record::Consume(n) => put_varint(&mut out, *n as u64),
+                Record::Timer | Record::Read | Record::Push | Record::Close | Record::Reset => {}
+            }
+        }
+        out
+    }
+
+    pub fn decode(mut input: &[u8]) -> io::Result<Self> {
+        input = input
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not a recording"))?;
+        let mut entries = Vec::new();
+        let mut at = Duration::ZERO;
+        while let Some((&tag, rest)) = input.split_first() {
+            input = rest;
+            at += Duration::from_nanos(get_varint(&mut input)?);
+            let record = match tag {
+                0 => Record::Segment(get_bytes(&mut input)?),
+                1 => Record::Timer,
+                2 => Record::Consume(get_varint(&mut input)? as usize),
+                3 => Record::Queue(get_bytes(&mut input)?),
+                4 => Record::Read,
+                5 => Record::Push,
+                6 => Record::Close,
+                7 => Record::Reset,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unknown record in recording",
+                    ))
+                }
+            };
+            entries.push(TimedRecord { at, record });
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Records the inputs of a live connection
+#[derive(Debug, Clone)]
+pub(crate) struct Recorder {
+    started: Instant,
+    recording: Recording,
+}
+
+impl Recorder {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            recording: Recording::default(),
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, record: Record) {
+        self.recording.entries.push(TimedRecord {
+            at: now.saturating_duration_since(self.started),
+            record,
+        });
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::replay::Replayer;
+
+#[cfg(feature = "std")]
+mod replay {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+
+    use alloc::sync::Arc;
+
+    use super::super::action::Action;
+    use super::super::config::Config;
+    use super::super::connection::Connection;
+    use super::super::drops::DropStats;
+    use super::super::io;
+    use super::super::time::{Duration, ManualClock};
+    use super::{Record, Recording, TimedRecord};
+
+    /// A segment split into its headers and payload
+    fn parse_segment(
+        packet: &[u8],
+    ) -> io::Result<(Ipv4HeaderSlice<'_>, TcpHeaderSlice<'_>, &[u8])> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid recorded segment");
+        let ip = Ipv4HeaderSlice::from_slice(packet).map_err(|_| invalid())?;
+        let rest = &packet[ip.slice().len()..];
+        let tcp = TcpHeaderSlice::from_slice(rest).map_err(|_| invalid())?;
+        let data = &rest[tcp.slice().len()..];
+        Ok((ip, tcp, data))
+    }
+
+    /// Feeds a recording into a fresh connection, moving a manual clock to
+    /// the time of every record. The connection needs the configuration of
+    /// the one recorded to behave the same.
+    #[derive(Debug)]
+    pub struct Replayer {
+        entries: vec::IntoIter<TimedRecord>,
+        config: Config,
+        clock: Arc<ManualClock>,
+        elapsed: Duration,
+        drops: DropStats,
+        conn: Option<Connection>,
+    }
+
+    impl Replayer {
+        pub fn new(recording: Recording, mut config: Config, clock: Arc<ManualClock>) -> Self {
+            config.clock = clock.clone();
+            config.record = false;
+            Self {
+                entries: recording.entries.into_iter(),
+                config,
+                clock,
+                elapsed: Duration::ZERO,
+                drops: DropStats::default(),
+                conn: None,
+            }
+        }
+
+        /// Apply the next record and return the actions it caused, or
+        /// `None` at the end of the recording
+        pub fn step(&mut self) -> Option<io::Result<Vec<Action>>> {
+            let entry = self.entries.next()?;
+            self.clock.advance(entry.at.saturating_sub(self.elapsed));
+            self.elapsed = self.elapsed.max(entry.at);
+            Some(self.apply(entry.record))
+        }
+
+        fn apply(&mut self, record: Record) -> io::Result<Vec<Action>> {
+            let Some(conn) = &mut self.conn else {
+                let Record::Segment(packet) = record else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Recording doesn't start with a segment",
+                    ));
+                };
+                let (ip, tcp, data) = parse_segment(&packet)?;
+                let conn = self
+                    .conn
+                    .insert(Connection::accept(&self.config, ip, tcp, data)?);
+                return Ok(conn.take_actions());
+            };
+            match record {
+                Record::Segment(packet) => {
+                    let (ip, tcp, data) = parse_segment(&packet)?;
+                    conn.on_packet(&mut self.drops, ip, tcp, data)?;
+                }
+                Record::Timer => {
+                    conn.on_timer()?;
+                }
+                Record::Consume(n) => {
+                    let n = n.min(conn.ingress.len());
+                    drop(conn.ingress.drain(..n));
+                }
+                Record::Queue(data) => conn.unacked.extend(data),
+                Record::Read => conn.on_read()?,
+                Record::Push => conn.push(),
+                Record::Close => conn.close()?,
+                Record::Reset => conn.reset()?,
+            }
+            Ok(conn.take_actions())
+        }
+
+        /// Apply the rest of the recording, ignoring errors as the
+        /// interface does
+        pub fn run(&mut self) {
+            while self.step().is_some() {}
+        }
+
+        /// The connection being replayed, once the first record opened it
+        pub fn connection(&mut self) -> Option<&mut Connection> {
+            self.conn.as_mut()
+        }
+
+        /// Segments discarded by the connection so far
+        pub fn drops(&self) -> &DropStats {
+            &self.drops
+        }
+    }
+}
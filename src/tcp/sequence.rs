@@ -6,16 +6,17 @@
 /// - `iss`: Initial send sequence number.
 /// - `una`: The unacknowledged sequence number.
 /// - `nxt`: The next sequence number to be sent.
-/// - `wnd`: The window size.
+/// - `wnd`: The window size in bytes, after scaling.
 /// - `urgent`: Indicates whether urgent data is present.
 /// - `wl1`: Sequence number used for the last window update.
 /// - `wl2`: Acknowledgment number used for the last window update.
+#[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct SendSequenceSpace {
     pub iss: u32,
     pub una: u32,
     pub nxt: u32,
-    pub wnd: u16,
+    pub wnd: u32,
     pub urgent: u16,
     pub wl1: u32,
     pub wl2: u32,
@@ -28,12 +29,13 @@ pub struct SendSequenceSpace {
 /// Fields:
 /// - `irs`: Initial receive sequence number.
 /// - `nxt`: The next expected sequence number to receive.
-/// - `wnd`: The window size.
+/// - `wnd`: The window size in bytes, after scaling.
 /// - `urgent`: Indicates whether urgent data is present.
+#[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct ReceiveSequenceSpace {
     pub irs: u32,
     pub nxt: u32,
-    pub wnd: u16,
+    pub wnd: u32,
     pub urgent: u16,
 }
@@ -0,0 +1,137 @@
+use core::fmt;
+
+use super::time::Duration;
+
+use super::state::State;
+
+/// Point in time view of a connection's transmission control block, for
+/// bug reports and runtime inspection. Timer fields hold the time left
+/// until the timer fires, when it is running.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TcbSnapshot {
+    pub state: State,
+    /// send sequence space
+    pub snd_iss: u32,
+    pub snd_una: u32,
+    pub snd_nxt: u32,
+    pub snd_wnd: u32,
+    /// receive sequence space
+    pub rcv_irs: u32,
+    pub rcv_nxt: u32,
+    pub rcv_wnd: u32,
+    /// congestion window in bytes
+    pub cwnd: usize,
+    /// largest payload of the segments sent
+    pub mss: usize,
+    /// smoothed round trip time
+    pub srtt: Duration,
+    /// retransmissions since the peer last acknowledged new data
+    pub retransmits: u32,
+    /// retransmission timer
+    pub rto_in: Option<Duration>,
+    /// tail loss probe timer
+    pub probe_in: Option<Duration>,
+    /// RACK reordering timer
+    pub reorder_in: Option<Duration>,
+    /// FIN-WAIT-2 timer
+    pub fin_wait2_in: Option<Duration>,
+    /// user timeout
+    pub user_timeout_in: Option<Duration>,
+    /// bytes received and not read yet
+    pub ingress: usize,
+    /// bytes queued for sending, sent or not
+    pub unacked: usize,
+    /// bytes queued and not sent yet
+    pub unsent: usize,
+    /// the application closed the connection
+    pub closed: bool,
+    /// sequence number of our FIN
+    pub closed_at: Option<u32>,
+    /// error the connection was aborted with
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_error"))]
+    pub error: Option<super::io::ErrorKind>,
+    /// duplicate ACKs received
+    pub dup_acks: u64,
+    /// duplicate ACKs sent for segments beyond a hole
+    pub dup_acks_sent: u64,
+    /// furthest a segment arrived ahead of the next expected byte
+    pub reordering: u32,
+    /// retransmission timeouts found to be spurious by F-RTO
+    pub spurious_retransmits: u64,
+}
+
+/// Sequence numbers are shown relative to the initial ones
+fn rel(seq: u32, initial: u32) -> u32 {
+    seq.wrapping_sub(initial)
+}
+
+fn timer(f: &mut fmt::Formatter<'_>, name: &str, left: Option<Duration>) -> fmt::Result {
+    match left {
+        Some(left) => write!(f, " {}={:?}", name, left),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for TcbSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "state {:?}", self.state)?;
+        writeln!(
+            f,
+            "snd iss={} una={} nxt={} wnd={}",
+            self.snd_iss,
+            rel(self.snd_una, self.snd_iss),
+            rel(self.snd_nxt, self.snd_iss),
+            self.snd_wnd
+        )?;
+        writeln!(
+            f,
+            "rcv irs={} nxt={} wnd={}",
+            self.rcv_irs,
+            rel(self.rcv_nxt, self.rcv_irs),
+            self.rcv_wnd
+        )?;
+        writeln!(
+            f,
+            "cwnd={} mss={} srtt={:?} retransmits={}",
+            self.cwnd, self.mss, self.srtt, self.retransmits
+        )?;
+        writeln!(
+            f,
+            "path dup-acks={} dup-acks-sent={} reordering={} spurious-retransmits={}",
+            self.dup_acks, self.dup_acks_sent, self.reordering, self.spurious_retransmits
+        )?;
+        write!(f, "timers")?;
+        timer(f, "rto", self.rto_in)?;
+        timer(f, "probe", self.probe_in)?;
+        timer(f, "reorder", self.reorder_in)?;
+        timer(f, "fin-wait-2", self.fin_wait2_in)?;
+        timer(f, "user-timeout", self.user_timeout_in)?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "queues ingress={} unacked={} unsent={}",
+            self.ingress, self.unacked, self.unsent
+        )?;
+        write!(f, "closed={}", self.closed)?;
+        if let Some(closed_at) = self.closed_at {
+            write!(f, " closed_at={}", rel(closed_at, self.snd_iss))?;
+        }
+        if let Some(error) = self.error {
+            write!(f, " error={:?}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error kinds are written by name, the std ones aren't serializable
+#[cfg(feature = "serde")]
+fn serialize_error<S: serde::Serializer>(
+    error: &Option<super::io::ErrorKind>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match error {
+        Some(kind) => serializer.serialize_some(&format_args!("{:?}", kind)),
+        None => serializer.serialize_none(),
+    }
+}
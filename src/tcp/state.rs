@@ -37,26 +37,33 @@ use bitflags::bitflags;
 ///   connection termination request previously sent to the remote TCP
 ///   (which includes an acknowledgment of its connection termination
 ///   request).
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum State {
     #[default]
-    // Closed,
+    Closed,
     // Listen,
     SynReceived,
     Established,
     FinWait1,
     FinWait2,
-    // CloseWait,
-    // Closing,
+    CloseWait,
+    Closing,
     TimeWait,
-    // LastAck,
+    LastAck,
 }
 
 impl State {
     pub fn _is_sync(&self) -> bool {
         matches!(
             self,
-            Self::Established | Self::FinWait1 | Self::FinWait2 | Self::TimeWait
+            Self::Established
+                | Self::FinWait1
+                | Self::FinWait2
+                | Self::CloseWait
+                | Self::Closing
+                | Self::TimeWait
+                | Self::LastAck
         )
     }
 }
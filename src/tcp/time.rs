@@ -0,0 +1,125 @@
+//! Time source of the protocol core. Connections read the time through the
+//! `Clock` of their configuration: with `std` the standard library's
+//! monotonic clock by default, which tests replace with a `ManualClock` to
+//! control time explicitly. Embedded targets have no clock the core could
+//! call, so they implement `Clock` for the clock of the platform and hand
+//! it to every connection with `Config::with_clock()`.
+
+pub use core::time::Duration;
+
+/// Where connections get the current time from
+pub trait Clock: Send + Sync + core::fmt::Debug {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the platform, `Instant::now()`
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, starting at the time it is
+/// created
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    /// nanoseconds since `start`
+    offset: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(feature = "std")]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            offset: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.offset
+            .fetch_add(by.as_nanos() as u64, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        let offset = self.offset.load(std::sync::atomic::Ordering::SeqCst);
+        self.start + Duration::from_nanos(offset)
+    }
+}
+
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+
+#[cfg(not(feature = "std"))]
+pub use self::clock::Instant;
+
+#[cfg(not(feature = "std"))]
+mod clock {
+    use core::ops::{Add, AddAssign, Sub};
+
+    use super::Duration;
+
+    /// A point in time of the platform's monotonic clock
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Instant(Duration);
+
+    impl Instant {
+        /// The time `since_epoch` after an arbitrary epoch, like the uptime
+        /// of the system, for a `Clock` to return
+        pub const fn from_epoch(since_epoch: Duration) -> Self {
+            Self(since_epoch)
+        }
+
+        pub fn saturating_duration_since(&self, earlier: Self) -> Duration {
+            self.0.saturating_sub(earlier.0)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Self;
+
+        fn add(self, rhs: Duration) -> Self {
+            Self(self.0 + rhs)
+        }
+    }
+
+    impl AddAssign<Duration> for Instant {
+        fn add_assign(&mut self, rhs: Duration) {
+            self.0 += rhs;
+        }
+    }
+
+    impl Sub<Duration> for Instant {
+        type Output = Self;
+
+        fn sub(self, rhs: Duration) -> Self {
+            Self(self.0 - rhs)
+        }
+    }
+
+    impl Sub for Instant {
+        type Output = Duration;
+
+        fn sub(self, rhs: Self) -> Duration {
+            self.saturating_duration_since(rhs)
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use core::net::SocketAddrV4;
+use etherparse::TcpHeader;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::options::{self, TcpOption};
+
+/// An option as tcpdump prints it
+fn option(opt: &TcpOption) -> String {
+    match opt {
+        TcpOption::Mss(mss) => format!("mss {}", mss),
+        TcpOption::WindowScale(shift) => format!("wscale {}", shift),
+        TcpOption::SackPermitted => "sackOK".to_string(),
+        TcpOption::Sack(blocks) => {
+            let edges: Vec<String> = blocks
+                .as_slice()
+                .iter()
+                .map(|(left, right)| format!("{{{}:{}}}", left, right))
+                .collect();
+            format!("sack {} {}", edges.len(), edges.join(""))
+        }
+        TcpOption::Timestamps { value, echo } => format!("TS val {} ecr {}", value, echo),
+        TcpOption::UserTimeout(timeout) => format!("uto {}s", timeout.as_secs()),
+        TcpOption::Experimental { kind, exid, .. } => format!("exp-{} {:#06x}", kind, exid),
+        TcpOption::Unknown { kind, .. } => format!("unknown-{}", kind),
+    }
+}
+
+/// Wall clock time of day (UTC) as tcpdump prints it: HH:MM:SS.ffffff
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_micros()
+    )
+}
+
+/// Flags the way tcpdump abbreviates them, with '.' standing for ACK
+fn flags(tcp: &TcpHeader) -> String {
+    let mut flags = String::new();
+    for (set, c) in [
+        (tcp.syn, 'S'),
+        (tcp.fin, 'F'),
+        (tcp.psh, 'P'),
+        (tcp.rst, 'R'),
+        (tcp.urg, 'U'),
+        (tcp.ack, '.'),
+    ] {
+        if set {
+            flags.push(c);
+        }
+    }
+    if flags.is_empty() {
+        flags.push_str("none");
+    }
+    flags
+}
+
+/// Describe a segment in a tcpdump-like line. Sequence numbers are
+/// relative to `seq_base`, the initial sequence number of the sender, and
+/// acknowledgment numbers to `ack_base`, the one of the receiver.
+pub fn segment(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    tcp: &TcpHeader,
+    len: usize,
+    seq_base: u32,
+    ack_base: u32,
+) -> String {
+    let mut line = format!(
+        "{} {}.{} > {}.{}: Flags [{}]",
+        timestamp(),
+        src.ip(),
+        src.port(),
+        dst.ip(),
+        dst.port(),
+        flags(tcp)
+    );
+    let seq = tcp.sequence_number.wrapping_sub(seq_base);
+    let slen = len as u32 + tcp.syn as u32 + tcp.fin as u32;
+    if slen > 0 {
+        let _ = write!(line, ", seq {}:{}", seq, seq.wrapping_add(slen));
+    } else {
+        let _ = write!(line, ", seq {}", seq);
+    }
+    if tcp.ack {
+        let _ = write!(
+            line,
+            ", ack {}",
+            tcp.acknowledgment_number.wrapping_sub(ack_base)
+        );
+    }
+    let _ = write!(line, ", win {}", tcp.window_size);
+    let mut opts = options::parse(tcp.options.as_slice()).peekable();
+    if opts.peek().is_some() {
+        let names: Vec<String> = opts.map(|opt| option(&opt)).collect();
+        let _ = write!(line, ", options [{}]", names.join(","));
+    }
+    let _ = write!(line, ", length {}", len);
+    line
+}
@@ -0,0 +1,149 @@
+//! Record of the state transitions of a connection, with what triggered
+//! each of them, exportable as a Graphviz or Mermaid state diagram for
+//! teaching and bug reports about the handshake and close.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use super::state::State;
+use super::time::{Duration, Instant};
+
+/// The parts of a segment that explain a state transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SegmentSummary {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub seq: u32,
+    pub ack_number: u32,
+    /// payload length
+    pub len: usize,
+}
+
+impl fmt::Display for SegmentSummary {
+    /// Flags the way tcpdump abbreviates them, then the sequence numbers
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, c) in [
+            (self.syn, 'S'),
+            (self.fin, 'F'),
+            (self.rst, 'R'),
+            (self.ack, '.'),
+        ] {
+            if set {
+                f.write_char(c)?;
+            }
+        }
+        write!(f, " seq {}", self.seq)?;
+        if self.ack {
+            write!(f, " ack {}", self.ack_number)?;
+        }
+        write!(f, " len {}", self.len)
+    }
+}
+
+/// What made a connection change state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Cause {
+    /// a segment from the peer
+    Segment(SegmentSummary),
+    /// a timer expired
+    Timer,
+    /// a call by the application, e.g. close
+    User,
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Segment(segment) => write!(f, "{}", segment),
+            Self::Timer => f.write_str("timer"),
+            Self::User => f.write_str("user"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Transition {
+    /// time since recording started
+    pub at: Duration,
+    pub from: State,
+    pub to: State,
+    pub cause: Cause,
+}
+
+impl Transition {
+    fn label(&self) -> String {
+        alloc::format!("+{:.6}s {}", self.at.as_secs_f64(), self.cause)
+    }
+}
+
+/// The transitions of one connection in the order they happened
+#[derive(Debug, Clone)]
+pub struct TransitionLog {
+    started: Instant,
+    transitions: Vec<Transition>,
+}
+
+impl TransitionLog {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            transitions: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, from: State, to: State, cause: Cause) {
+        self.transitions.push(Transition {
+            at: now.saturating_duration_since(self.started),
+            from,
+            to,
+            cause,
+        });
+    }
+
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    /// A Graphviz digraph with one numbered edge per transition
+    pub fn to_graphviz(&self) -> String {
+        let mut out = String::from("digraph connection {\n    rankdir=LR;\n");
+        for (i, t) in self.transitions.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "    {:?} -> {:?} [label=\"{}. {}\"];",
+                t.from,
+                t.to,
+                i + 1,
+                t.label()
+            );
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// A Mermaid state diagram with one numbered edge per transition
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("stateDiagram-v2\n");
+        if let Some(first) = self.transitions.first() {
+            let _ = writeln!(out, "    [*] --> {:?}", first.from);
+        }
+        for (i, t) in self.transitions.iter().enumerate() {
+            // A colon would end the label early
+            let _ = writeln!(
+                out,
+                "    {:?} --> {:?}: {}. {}",
+                t.from,
+                t.to,
+                i + 1,
+                t.label().replace(':', " ")
+            );
+        }
+        out
+    }
+}
@@ -0,0 +1,216 @@
+//! Support for end-to-end tests against the kernel's TCP stack.
+//!
+//! A test moves its thread into a throwaway network namespace, creates the
+//! tun device there and talks to the userspace stack with `std::net`
+//! sockets, which go through the kernel on the other side of the device.
+//! Nothing leaks into the host's network configuration, and tests running
+//! in parallel don't see each other's devices. Creating the namespace
+//! requires CAP_SYS_ADMIN and the device CAP_NET_ADMIN; tests are expected
+//! to skip themselves when `unavailable()` says they can't run.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::thread;
+use std::time::Duration;
+
+use crate::netlink::Netlink;
+use crate::{Interface, InterfaceBuilder};
+
+/// How long kernel sockets wait for the stack before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The error means the environment lacks the privileges or the tun device
+/// needed to run the test, rather than the stack failing
+pub fn unavailable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound
+    )
+}
+
+/// The calling thread's membership in a new, empty network namespace with
+/// only the loopback device up. Threads spawned by the thread afterwards
+/// start in the namespace too. Dropping it returns the thread to the
+/// namespace it came from.
+pub struct NetNs {
+    previous: File,
+}
+
+impl NetNs {
+    pub fn enter() -> io::Result<Self> {
+        let previous = File::open("/proc/thread-self/ns/net")?;
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ns = Self { previous };
+        let mut nl = Netlink::open()?;
+        nl.set_link_up(Netlink::link_index("lo")?, true)?;
+        Ok(ns)
+    }
+}
+
+impl Drop for NetNs {
+    fn drop(&mut self) {
+        if unsafe { libc::setns(self.previous.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+            eprintln!(
+                "Failed to leave network namespace: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// The stack running on a tun device in its own network namespace
+pub struct TestBed {
+    // The interface must go before the namespace is left; it is only
+    // missing while it is restarted
+    iface: Option<Interface>,
+    _ns: NetNs,
+}
+
+impl TestBed {
+    /// Address of the kernel's end of the device
+    pub const LINK_ADDR: Ipv4Addr = Ipv4Addr::new(10, 11, 0, 1);
+    /// Address the kernel reaches the stack at
+    pub const STACK_ADDR: Ipv4Addr = Ipv4Addr::new(10, 11, 0, 2);
+    pub const PREFIX_LEN: u8 = 24;
+
+    /// Set up the stack with the default configuration
+    pub fn new() -> io::Result<Self> {
+        Self::with(Interface::builder())
+    }
+
+    /// Set up the stack configured by `builder`; the device address is
+    /// set by the test bed
+    pub fn with(builder: InterfaceBuilder) -> io::Result<Self> {
+        let ns = NetNs::enter()?;
+        let iface = builder.address(Self::LINK_ADDR, Self::PREFIX_LEN).build()?;
+        Ok(Self {
+            iface: Some(iface),
+            _ns: ns,
+        })
+    }
+
+    /// Set up the stack on a device opened beforehand and handed in as a
+    /// descriptor, as a privileged helper would; with packet information if
+    /// `packet_info` is set
+    pub fn with_device_fd(builder: InterfaceBuilder, packet_info: bool) -> io::Result<Self> {
+        let ns = NetNs::enter()?;
+        let tun = if packet_info {
+            tun_tap::Iface::new("tun0", tun_tap::Mode::Tun)?
+        } else {
+            tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?
+        };
+        let fd = unsafe { OwnedFd::from_raw_fd(tun.into_raw_fd()) };
+        let iface = builder
+            .device_fd(fd)
+            .packet_info(packet_info)
+            .address(Self::LINK_ADDR, Self::PREFIX_LEN)
+            .build()?;
+        Ok(Self {
+            iface: Some(iface),
+            _ns: ns,
+        })
+    }
+
+    pub fn interface(&mut self) -> &mut Interface {
+        self.iface.as_mut().expect("interface is running")
+    }
+
+    /// Replace the stack with a new one configured by `builder` on the same
+    /// device, as a restarted process would. Streams and listeners of the
+    /// old interface must be dropped first so the device is released.
+    pub fn restart(&mut self, builder: InterfaceBuilder) -> io::Result<&mut Interface> {
+        drop(self.iface.take());
+        let iface = builder.address(Self::LINK_ADDR, Self::PREFIX_LEN).build()?;
+        Ok(self.iface.insert(iface))
+    }
+
+    /// Address of a port on the stack
+    pub fn stack_addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Self::STACK_ADDR, port)
+    }
+
+    /// Connect a kernel socket to `port` on the stack
+    pub fn connect(port: u16) -> io::Result<std::net::TcpStream> {
+        let stream = std::net::TcpStream::connect_timeout(&Self::stack_addr(port).into(), TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(stream)
+    }
+
+    /// Connect a kernel socket bound to `local_port` to `port` on the stack.
+    /// The port can be bound again as soon as the connection closed.
+    pub fn connect_from(local_port: u16, port: u16) -> io::Result<std::net::TcpStream> {
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let sockaddr = |addr: SocketAddrV4| libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: addr.port().to_be(),
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(*addr.ip()).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        let len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        let one: libc::c_int = 1;
+        let local = sockaddr(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port));
+        let remote = sockaddr(Self::stack_addr(port));
+        let result = unsafe {
+            if libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                (&raw const one).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            ) < 0
+                || libc::bind(fd.as_raw_fd(), (&raw const local).cast(), len) < 0
+            {
+                -1
+            } else {
+                libc::connect(fd.as_raw_fd(), (&raw const remote).cast(), len)
+            }
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stream = std::net::TcpStream::from(fd);
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(stream)
+    }
+
+    /// Have a kernel client send `data` to a server on the stack listening
+    /// on `port`, which echoes it back, and check that both directions
+    /// arrived intact and the connection closed cleanly
+    pub fn assert_echo(&mut self, port: u16, data: &[u8]) {
+        let mut listener = self.interface().bind(port).expect("bind");
+        let sent = data.to_vec();
+        let client = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut stream = Self::connect(port)?;
+            stream.write_all(&sent)?;
+            let mut echoed = Vec::new();
+            // The stack closes first once it echoed everything
+            stream.read_to_end(&mut echoed)?;
+            Ok(echoed)
+        });
+
+        let mut stream = listener.accept().expect("accept");
+        let mut received = vec![0; data.len()];
+        stream.read_exact(&mut received).expect("read on the stack");
+        assert_eq!(received, data, "data received by the stack");
+        stream.write_all(&received).expect("write on the stack");
+        drop(stream);
+
+        let echoed = client
+            .join()
+            .expect("client panicked")
+            .expect("client failed");
+        assert_eq!(echoed, data, "data echoed to the kernel");
+    }
+}
@@ -0,0 +1,85 @@
+//! Placement of the threads that run the stack: their names, the cores they
+//! may run on and their scheduling priority, for low-latency deployments
+//! and benchmarks that need the stack off the cores of the application.
+
+use std::sync::mpsc;
+use std::{io, mem, thread};
+
+/// How a thread of the stack runs. The default is an unpinned thread with
+/// the default name and scheduling policy.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadOptions {
+    /// Name shown by top and ps, at most 15 bytes of it on Linux
+    pub name: Option<String>,
+    /// Cores the thread may run on; empty leaves the choice to the
+    /// scheduler
+    pub cpus: Vec<usize>,
+    /// Run under SCHED_FIFO with this priority, from 1 to 99. Needs
+    /// CAP_SYS_NICE.
+    pub realtime_priority: Option<u8>,
+}
+
+impl ThreadOptions {
+    /// Apply the affinity and priority to the calling thread
+    fn apply(&self) -> io::Result<()> {
+        if !self.cpus.is_empty() {
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            for &cpu in &self.cpus {
+                if cpu >= libc::CPU_SETSIZE as usize {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "CPU number out of range",
+                    ));
+                }
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            if unsafe { libc::sched_setaffinity(0, mem::size_of_val(&set), &set) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(priority) = self.realtime_priority {
+            let param = libc::sched_param {
+                sched_priority: i32::from(priority),
+            };
+            let err = unsafe {
+                libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param)
+            };
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Start a thread running `f` with `options`, named `default_name` unless
+/// they name it. Fails without running `f` if the options can't be
+/// applied.
+pub(crate) fn spawn(
+    options: &ThreadOptions,
+    default_name: String,
+    f: impl FnOnce() -> io::Result<()> + Send + 'static,
+) -> io::Result<thread::JoinHandle<io::Result<()>>> {
+    let (applied_tx, applied_rx) = mpsc::sync_channel(1);
+    let thread_options = options.clone();
+    let jh = thread::Builder::new()
+        .name(options.name.clone().unwrap_or(default_name))
+        .spawn(move || {
+            let applied = thread_options.apply();
+            let ok = applied.is_ok();
+            let _ = applied_tx.send(applied);
+            if ok {
+                f()
+            } else {
+                Ok(())
+            }
+        })?;
+    match applied_rx.recv() {
+        Ok(Ok(())) => Ok(jh),
+        Ok(Err(e)) => {
+            let _ = jh.join();
+            Err(e)
+        }
+        Err(_) => Err(io::Error::other("Thread exited before it started")),
+    }
+}
@@ -0,0 +1,1637 @@
+//! End-to-end tests against the kernel TCP stack. They need CAP_SYS_ADMIN
+//! and CAP_NET_ADMIN and skip themselves without.
+
+use std::io::{BufRead, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use tcprs::testing::{self, TestBed};
+use tcprs::{
+    Cause, Config, CongestionState, ConnectionFilter, ConnectionLimits, DeviceEvent, Direction,
+    DropReason, Fault, FaultRule, Health, Interface, InterfaceBuilder, ManualClock, MemoryLimits,
+    MemoryPressure, MsgFlags, PacketFilter, PacketPool, Recording, Replayer, State, ThreadOptions,
+    Verdict,
+};
+
+fn test_bed() -> Option<TestBed> {
+    test_bed_with(Interface::builder())
+}
+
+fn test_bed_with(builder: InterfaceBuilder) -> Option<TestBed> {
+    match TestBed::with(builder) {
+        Ok(bed) => Some(bed),
+        Err(e) if testing::unavailable(&e) => {
+            eprintln!("skipping: {}", e);
+            None
+        }
+        Err(e) => panic!("setting up the test bed: {}", e),
+    }
+}
+
+#[test]
+fn echo() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    bed.assert_echo(7000, b"hello from the kernel");
+}
+
+#[test]
+fn read_lines() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7001).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<()> {
+        let mut stream = TestBed::connect(7001)?;
+        stream.write_all(b"first\nsecond\nthird")?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        // Wait for the stack to close
+        stream.read_to_end(&mut Vec::new())?;
+        Ok(())
+    });
+
+    let mut stream = listener.accept().expect("accept");
+    let lines = (&mut stream)
+        .lines()
+        .collect::<std::io::Result<Vec<_>>>()
+        .expect("read lines");
+    assert_eq!(lines, ["first", "second", "third"]);
+    drop(stream);
+    client
+        .join()
+        .expect("client panicked")
+        .expect("client failed");
+}
+
+#[test]
+fn recv_flags() {
+    let Some(mut bed) = test_bed_with(Interface::builder().recv_buffer(10)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7002).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<Vec<u8>> {
+        let mut stream = TestBed::connect(7002)?;
+        stream.write_all(b"hello ")?;
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        stream.write_all(b"world")?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed)?;
+        Ok(echoed)
+    });
+
+    let mut stream = listener.accept().expect("accept");
+    // Waits for the second segment, which fits the 10 byte receive buffer
+    let mut buf = [0; 8];
+    let n = stream
+        .recv_with_flags(&mut buf, MsgFlags::PEEK | MsgFlags::WAITALL)
+        .expect("peek");
+    assert_eq!(&buf[..n], b"hello wo");
+    let mut buf = [0; 11];
+    let n = stream
+        .recv_with_flags(&mut buf, MsgFlags::WAITALL)
+        .expect("recv");
+    assert_eq!(&buf[..n], b"hello world");
+    let n = stream
+        .recv_with_flags(&mut buf, MsgFlags::DONTWAIT)
+        .expect("recv after close");
+    assert_eq!(n, 0);
+    let n = stream
+        .send_with_flags(b"bye", MsgFlags::DONTWAIT)
+        .expect("send");
+    assert_eq!(n, 3);
+    drop(stream);
+    let echoed = client
+        .join()
+        .expect("client panicked")
+        .expect("client failed");
+    assert_eq!(echoed, b"bye");
+}
+
+#[test]
+fn send_file() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let content: Vec<u8> = (0..8_000u32).map(|i| i as u8).collect();
+    let path = std::env::temp_dir().join(format!("tcprs-send-file-{}", std::process::id()));
+    std::fs::write(&path, &content).expect("write file");
+    let file = std::fs::File::open(&path).expect("open file");
+    std::fs::remove_file(&path).expect("remove file");
+
+    let mut listener = bed.interface().bind(7003).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<Vec<u8>> {
+        let mut stream = TestBed::connect(7003)?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received)?;
+        Ok(received)
+    });
+
+    let mut stream = listener.accept().expect("accept");
+    let sent = stream.send_file(&file, 100..50_000).expect("send file");
+    assert_eq!(sent, 7_900, "stops at the end of the file");
+    drop(stream);
+    let received = client
+        .join()
+        .expect("client panicked")
+        .expect("client failed");
+    assert!(received == content[100..], "file content received");
+}
+
+#[test]
+fn proxy() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut front = bed.interface().bind(7004).expect("bind");
+    let mut back = bed.interface().bind(7005).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<Vec<u8>> {
+        let mut stream = TestBed::connect(7004)?;
+        stream.write_all(b"ping")?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply)?;
+        Ok(reply)
+    });
+    let server = std::thread::spawn(|| -> std::io::Result<Vec<u8>> {
+        let mut stream = TestBed::connect(7005)?;
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request)?;
+        stream.write_all(b"pong")?;
+        Ok(request)
+    });
+
+    let mut a = front.accept().expect("accept client");
+    let mut b = back.accept().expect("accept server");
+    let copied = tcprs::copy_bidirectional(&mut a, &mut b).expect("copy");
+    assert_eq!(copied, (4, 4));
+    drop((a, b));
+    let request = server
+        .join()
+        .expect("server panicked")
+        .expect("server failed");
+    assert_eq!(request, b"ping");
+    let reply = client
+        .join()
+        .expect("client panicked")
+        .expect("client failed");
+    assert_eq!(reply, b"pong");
+}
+
+#[test]
+fn listener_drop_resets_pending() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7006).expect("bind");
+    let mut kept = TestBed::connect(7006).expect("connect");
+    let mut accepted = listener.accept().expect("accept");
+    let mut pending = TestBed::connect(7006).expect("connect");
+    // Let the handshake of the second connection complete
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    drop(listener);
+
+    let err = pending
+        .read(&mut [0; 1])
+        .expect_err("pending connection is reset");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+    kept.write_all(b"still open").expect("write");
+    let mut buf = [0; 10];
+    accepted
+        .read_exact(&mut buf)
+        .expect("read on accepted stream");
+    assert_eq!(&buf, b"still open");
+}
+
+#[test]
+fn idle_timeout() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7007).expect("bind");
+    listener.set_idle_timeout(Some(std::time::Duration::from_millis(200)));
+    let mut idle = TestBed::connect(7007).expect("connect");
+    let _idle_stream = listener.accept().expect("accept");
+    let mut exempt = TestBed::connect(7007).expect("connect");
+    let mut exempt_stream = listener.accept().expect("accept");
+    exempt_stream.set_idle_timeout(None).expect("exempt");
+    std::thread::sleep(std::time::Duration::from_millis(600));
+
+    let err = idle
+        .read(&mut [0; 1])
+        .expect_err("idle connection is reset");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+    exempt.write_all(b"still open").expect("write");
+    let mut buf = [0; 10];
+    exempt_stream
+        .read_exact(&mut buf)
+        .expect("read on exempt stream");
+    assert_eq!(&buf, b"still open");
+}
+
+#[test]
+fn syn_rate_limit() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7008).expect("bind");
+    listener.set_syn_rate_limit(Some(tcprs::RateLimit::new(
+        1,
+        std::time::Duration::from_secs(60),
+    )));
+    let _first = TestBed::connect(7008).expect("connect");
+    let _stream = listener.accept().expect("accept");
+    let err = std::net::TcpStream::connect_timeout(
+        &TestBed::stack_addr(7008).into(),
+        std::time::Duration::from_secs(2),
+    )
+    .expect_err("second connection is rate limited");
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(
+        bed.interface()
+            .drop_stats()
+            .get(tcprs::DropReason::SynRateLimited)
+            > 0
+    );
+}
+
+#[test]
+fn memory_pressure() {
+    let limits = MemoryLimits {
+        soft: 1000,
+        hard: 2000,
+    };
+    let Some(mut bed) = test_bed_with(Interface::builder().memory_limits(Some(limits))) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7039).expect("bind");
+    let mut client = TestBed::connect(7039).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    client.write_all(&[b'x'; 3000]).expect("write");
+    assert!(wait_until(|| {
+        bed.interface().memory_stats().pressure == MemoryPressure::Hard
+    }));
+    assert!(bed.interface().memory_stats().buffered_bytes >= 3000);
+    let err = std::net::TcpStream::connect_timeout(
+        &TestBed::stack_addr(7039).into(),
+        std::time::Duration::from_secs(1),
+    )
+    .expect_err("connection refused under hard pressure");
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(bed.interface().drop_stats().get(DropReason::MemoryPressure) > 0);
+
+    // Reading the data relieves the pressure
+    let mut buf = [0; 3000];
+    stream.read_exact(&mut buf).expect("read");
+    assert!(wait_until(|| {
+        bed.interface().memory_stats().pressure == MemoryPressure::Normal
+    }));
+    let _second = TestBed::connect(7039).expect("connect once the pressure is over");
+}
+
+#[test]
+fn connection_limits() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7040).expect("bind");
+    listener.set_connection_limits(ConnectionLimits {
+        per_peer: Some(1),
+        excess: tcprs::PauseMode::Reset,
+        ..Default::default()
+    });
+    let _first = TestBed::connect(7040).expect("connect");
+    let _stream = listener.accept().expect("accept");
+    let err = TestBed::connect(7040).expect_err("second connection from the peer refused");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+    // The whole peer network counts against a total limit as well
+    listener.set_connection_limits(ConnectionLimits {
+        total: Some(2),
+        per_peer_prefix: 24,
+        ..Default::default()
+    });
+    let mut second = TestBed::connect(7040).expect("connect");
+    let stream = listener.accept().expect("accept");
+    let err = std::net::TcpStream::connect_timeout(
+        &TestBed::stack_addr(7040).into(),
+        std::time::Duration::from_secs(1),
+    )
+    .expect_err("third connection dropped");
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(
+        bed.interface()
+            .drop_stats()
+            .get(DropReason::ConnectionLimit)
+            >= 2
+    );
+
+    // A connection stops counting once it is in TIME-WAIT
+    drop(stream);
+    assert_eq!(second.read(&mut [0; 1]).expect("read EOF"), 0);
+    drop(second);
+    let time_wait = ConnectionFilter {
+        state: Some(State::TimeWait),
+        local_port: Some(7040),
+        ..Default::default()
+    };
+    assert!(wait_until(|| {
+        let mut found = false;
+        bed.interface()
+            .for_each_connection(&time_wait, |_| found = true);
+        found
+    }));
+    let _third = TestBed::connect(7040).expect("connect once one is in TIME-WAIT");
+    listener.accept().expect("accept");
+}
+
+#[test]
+fn backlog_full() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7041).expect("bind");
+    listener.set_backlog(Some(1), tcprs::PauseMode::Reset);
+    let _first = TestBed::connect(7041).expect("connect");
+    let err = TestBed::connect(7041).expect_err("backlog full");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    assert_eq!(listener.backlog_stats().reset, 1);
+
+    listener.set_backlog(Some(1), tcprs::PauseMode::Drop);
+    let err = std::net::TcpStream::connect_timeout(
+        &TestBed::stack_addr(7041).into(),
+        std::time::Duration::from_secs(1),
+    )
+    .expect_err("backlog still full");
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(listener.backlog_stats().dropped >= 1);
+    assert!(bed.interface().drop_stats().get(DropReason::BacklogFull) >= 2);
+
+    // Accepting makes room
+    let _stream = listener.accept().expect("accept");
+    let _second = TestBed::connect(7041).expect("connect once accepted");
+}
+
+#[test]
+fn accept_with_data() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7042).expect("bind");
+    let mut client = TestBed::connect(7042).expect("connect");
+    client.write_all(b"GET / HTTP/1.1\r\n").expect("write");
+    let (mut stream, first) = listener
+        .accept_with_data(4, std::time::Duration::from_secs(1))
+        .expect("accept");
+    assert_eq!(first, b"GET ");
+    // The sniffed bytes are still there to read
+    let mut buf = [0u8; 16];
+    stream.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf, b"GET / HTTP/1.1\r\n");
+
+    // A client that sends nothing yields no data once the timeout passes
+    let _silent = TestBed::connect(7042).expect("connect");
+    let (_stream, first) = listener
+        .accept_with_data(4, std::time::Duration::from_millis(100))
+        .expect("accept");
+    assert!(first.is_empty());
+}
+
+#[test]
+fn peer_filter() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7009).expect("bind");
+    listener.allow(TestBed::STACK_ADDR, TestBed::PREFIX_LEN);
+    listener.reject_with(tcprs::PauseMode::Reset);
+    let _allowed = TestBed::connect(7009).expect("connect");
+    let _stream = listener.accept().expect("accept");
+
+    listener.deny(TestBed::LINK_ADDR, 32);
+    let err = TestBed::connect(7009).expect_err("denied peer is refused");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    assert!(
+        bed.interface()
+            .drop_stats()
+            .get(tcprs::DropReason::PeerRejected)
+            > 0
+    );
+}
+
+/// Drops connection requests to port 7011 and resets connections that send
+/// "reset me"
+struct Firewall;
+
+impl PacketFilter for Firewall {
+    fn filter(&self, _ip: &Ipv4HeaderSlice, tcp: &TcpHeaderSlice, data: &[u8]) -> Verdict {
+        if tcp.destination_port() == 7011 {
+            Verdict::Drop
+        } else if data == b"reset me" {
+            Verdict::Reset
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+#[test]
+fn packet_filter() {
+    let Some(mut bed) = test_bed_with(Interface::builder().packet_filter(Firewall)) else {
+        return;
+    };
+    let _dropped = bed.interface().bind(7011).expect("bind");
+    let err = std::net::TcpStream::connect_timeout(
+        &TestBed::stack_addr(7011).into(),
+        std::time::Duration::from_secs(1),
+    )
+    .expect_err("filtered connection request");
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+    let mut listener = bed.interface().bind(7010).expect("bind");
+    let mut client = TestBed::connect(7010).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    client.write_all(b"reset me").expect("write");
+    let err = client.read(&mut [0; 1]).expect_err("connection is reset");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    let err = stream.read(&mut [0; 1]).expect_err("connection is aborted");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+    assert!(
+        bed.interface()
+            .drop_stats()
+            .get(tcprs::DropReason::Filtered)
+            > 0
+    );
+}
+
+/// Drops the segments to port 7043 while set, so data sent there goes
+/// unacknowledged
+struct AckBlackhole(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+impl PacketFilter for AckBlackhole {
+    fn filter(&self, _ip: &Ipv4HeaderSlice, tcp: &TcpHeaderSlice, _data: &[u8]) -> Verdict {
+        if tcp.destination_port() == 7043 && self.0.load(std::sync::atomic::Ordering::Relaxed) {
+            Verdict::Drop
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+#[test]
+fn flush_deadline() {
+    let blackhole = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let filter = AckBlackhole(blackhole.clone());
+    let Some(mut bed) = test_bed_with(Interface::builder().packet_filter(filter)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7043).expect("bind");
+    let mut client = TestBed::connect(7043).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    stream.write_all(b"first").expect("write");
+    stream
+        .flush_deadline(std::time::Instant::now() + std::time::Duration::from_secs(1))
+        .expect("acknowledged");
+    assert_eq!(stream.bytes_unacked().expect("unacked"), 0);
+
+    blackhole.store(true, std::sync::atomic::Ordering::Relaxed);
+    stream.write_all(b"second").expect("write");
+    let err = stream
+        .flush_deadline(std::time::Instant::now() + std::time::Duration::from_millis(200))
+        .expect_err("ACKs are dropped");
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert_eq!(stream.bytes_unacked().expect("unacked"), 6);
+
+    // The retransmission gets through once the ACKs do
+    blackhole.store(false, std::sync::atomic::Ordering::Relaxed);
+    stream
+        .flush_deadline(std::time::Instant::now() + std::time::Duration::from_secs(5))
+        .expect("acknowledged after all");
+    let mut buf = [0u8; 11];
+    client.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf, b"firstsecond");
+}
+
+#[test]
+fn deliveries() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7044).expect("bind");
+    let mut client = TestBed::connect(7044).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    let deliveries = stream.deliveries().expect("deliveries");
+    let reader = std::thread::spawn(move || {
+        let mut sink = Vec::new();
+        client.read_to_end(&mut sink).expect("read");
+        sink.len()
+    });
+    let data = vec![b'x'; 256 * 1024];
+    stream.write_all(&data).expect("write");
+    stream
+        .flush_deadline(std::time::Instant::now() + std::time::Duration::from_secs(5))
+        .expect("acknowledged");
+    drop(stream);
+    assert_eq!(reader.join().unwrap(), data.len());
+
+    let reports: Vec<_> = deliveries.try_iter().collect();
+    assert!(reports.len() > 1);
+    assert!(reports.windows(2).all(|w| w[0].acked < w[1].acked));
+    assert_eq!(reports.last().unwrap().acked, data.len() as u64);
+    assert!(reports.iter().all(|report| report.rate > 0));
+}
+
+#[test]
+fn rate_limit() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7012).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<usize> {
+        let mut stream = TestBed::connect(7012)?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received)?;
+        Ok(received.len())
+    });
+
+    let mut stream = listener.accept().expect("accept");
+    stream.set_rate_limit(Some(10_000)).expect("rate limit");
+    let start = std::time::Instant::now();
+    stream.write_all(&[b'x'; 20_000]).expect("write");
+    drop(stream);
+    let received = client
+        .join()
+        .expect("client panicked")
+        .expect("client failed");
+    assert_eq!(received, 20_000);
+    // The first 1000 bytes may go out in a burst
+    assert!(start.elapsed() >= std::time::Duration::from_millis(1800));
+}
+
+#[test]
+fn egress_rate_limit() {
+    let limit = tcprs::RateLimit {
+        per_second: 10_000.0,
+        burst: 2000,
+    };
+    let Some(mut bed) = test_bed_with(Interface::builder().egress_rate_limit(Some(limit))) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7013).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<usize> {
+        let mut stream = TestBed::connect(7013)?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received)?;
+        Ok(received.len())
+    });
+
+    let mut stream = listener.accept().expect("accept");
+    let start = std::time::Instant::now();
+    stream.write_all(&[b'x'; 20_000]).expect("write");
+    drop(stream);
+    let received = client
+        .join()
+        .expect("client panicked")
+        .expect("client failed");
+    assert_eq!(received, 20_000);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(1800));
+}
+
+#[test]
+fn impaired_echo() {
+    let impairments = tcprs::Impairments {
+        loss: 0.05,
+        duplicate: 0.05,
+        corrupt: 0.05,
+        delay: std::time::Duration::from_millis(5),
+        jitter: std::time::Duration::from_millis(5),
+        ..Default::default()
+    };
+    let Some(mut bed) = test_bed_with(Interface::builder().impairments(impairments)) else {
+        return;
+    };
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    bed.assert_echo(7014, &data);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn checkpoint_restore() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7015).expect("bind");
+    let mut client = TestBed::connect(7015).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    client.write_all(b"ping").expect("write");
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).expect("read");
+    stream.write_all(b"before").expect("write");
+
+    // Hand the connection over to a new stack through its serialized form
+    let checkpoint = bed.interface().checkpoint();
+    drop(stream);
+    drop(listener);
+    let json = serde_json::to_string(&checkpoint).expect("serialize");
+    let checkpoint: tcprs::Checkpoint = serde_json::from_str(&json).expect("deserialize");
+    let mut restored = bed
+        .restart(Interface::builder())
+        .expect("restart")
+        .restore(&checkpoint)
+        .expect("restore");
+    assert_eq!(restored.listeners.len(), 1);
+    let mut stream = restored.streams.pop().expect("restored stream");
+
+    let mut buf = [0; 6];
+    client.read_exact(&mut buf).expect("read before restart");
+    assert_eq!(&buf, b"before");
+    client.write_all(b"after").expect("write");
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).expect("read after restart");
+    assert_eq!(&buf, b"after");
+    stream.write_all(b"done").expect("write");
+    let mut buf = [0; 4];
+    client.read_exact(&mut buf).expect("read after restart");
+    assert_eq!(&buf, b"done");
+}
+
+/// Poll `ready` until it holds, for up to a second
+fn wait_until(mut ready: impl FnMut() -> bool) -> bool {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+    while !ready() {
+        if std::time::Instant::now() > deadline {
+            return false;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    true
+}
+
+#[test]
+fn readiness() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7016).expect("bind");
+    assert!(!listener.has_pending());
+    let mut client = TestBed::connect(7016).expect("connect");
+    assert!(wait_until(|| listener.has_pending()));
+    let mut stream = listener.accept().expect("accept");
+    assert!(!stream.is_read_ready());
+    assert!(stream.is_write_ready());
+
+    client.write_all(b"ready").expect("write");
+    assert!(wait_until(|| stream.is_read_ready()));
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).expect("read");
+    assert!(!stream.is_read_ready());
+}
+
+#[test]
+fn read_to_end() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7037).expect("bind");
+    let sent: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    let expected = sent.clone();
+    let client = std::thread::spawn(move || -> std::io::Result<()> {
+        let mut stream = TestBed::connect(7037)?;
+        stream.write_all(&sent)?;
+        drop(stream);
+        let mut stream = TestBed::connect(7037)?;
+        stream.write_all("héllo".as_bytes())?;
+        Ok(())
+    });
+    let mut stream = listener.accept().expect("accept");
+    let mut received = b"prefix".to_vec();
+    let n = stream.read_to_end(&mut received).expect("read to end");
+    assert_eq!(n, expected.len());
+    assert_eq!(&received[6..], expected);
+
+    let mut stream = listener.accept().expect("accept");
+    let mut text = String::new();
+    stream.read_to_string(&mut text).expect("read to string");
+    assert_eq!(text, "héllo");
+    client.join().unwrap().expect("client");
+}
+
+#[test]
+fn blocked_read() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7038).expect("bind");
+    let mut client = TestBed::connect(7038).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    let sent: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    let expected = sent.clone();
+    let reader = std::thread::spawn(move || {
+        // Smaller than a segment, so segments also go partly to the queue
+        let mut buf = [0; 1000];
+        let mut received = Vec::new();
+        loop {
+            let n = stream.read(&mut buf).expect("read");
+            if n == 0 {
+                return received;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+    });
+    // Let the reader block on the empty queue first
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    for chunk in sent.chunks(10_000) {
+        client.write_all(chunk).expect("write");
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    drop(client);
+    assert_eq!(reader.join().unwrap(), expected);
+}
+
+#[test]
+fn recv_lowat() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7036).expect("bind");
+    let mut client = TestBed::connect(7036).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    stream.set_recv_lowat(8).expect("set low watermark");
+    assert_eq!(stream.recv_lowat().expect("low watermark"), 8);
+
+    client.write_all(b"half").expect("write");
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(!stream.is_read_ready());
+    // A non-blocking read takes what is there
+    let mut buf = [0; 16];
+    let n = stream
+        .recv_with_flags(&mut buf, MsgFlags::PEEK | MsgFlags::DONTWAIT)
+        .expect("peek");
+    assert_eq!(&buf[..n], b"half");
+
+    let reader = std::thread::spawn(move || {
+        let mut buf = [0; 16];
+        let n = stream.read(&mut buf).expect("read");
+        buf[..n].to_vec()
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    client.write_all(b"full").expect("write");
+    assert_eq!(reader.join().unwrap(), b"halffull");
+}
+
+/// Wait up to a second for `fd` to become readable
+fn poll_readable(fd: &impl std::os::fd::AsFd) -> bool {
+    let mut pfd = [nix::poll::PollFd::new(
+        fd.as_fd(),
+        nix::poll::PollFlags::POLLIN,
+    )];
+    nix::poll::poll(&mut pfd, 1000u16).expect("poll") == 1
+}
+
+#[test]
+fn readiness_fd() {
+    use std::os::fd::{AsRawFd, BorrowedFd};
+
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7017).expect("bind");
+    let listener_fd = unsafe { BorrowedFd::borrow_raw(listener.as_raw_fd()) };
+    let mut client = TestBed::connect(7017).expect("connect");
+    assert!(poll_readable(&listener_fd));
+    let mut stream = listener.accept().expect("accept");
+    assert!(!poll_readable(&listener_fd));
+
+    let stream_fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    assert!(!poll_readable(&stream_fd));
+    client.write_all(b"ready").expect("write");
+    assert!(poll_readable(&stream_fd));
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).expect("read");
+    assert!(!poll_readable(&stream_fd));
+}
+
+#[test]
+fn for_each_connection() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7018).expect("bind");
+    let closing = TestBed::connect(7018).expect("connect");
+    let _first = listener.accept().expect("accept");
+    let mut open = TestBed::connect(7018).expect("connect");
+    let _second = listener.accept().expect("accept");
+
+    let closing_addr = match closing.local_addr().expect("local address") {
+        std::net::SocketAddr::V4(addr) => addr,
+        addr => panic!("unexpected address {}", addr),
+    };
+    drop(closing);
+    let close_wait = ConnectionFilter {
+        state: Some(State::CloseWait),
+        local_port: Some(7018),
+        ..Default::default()
+    };
+    let mut listed = Vec::new();
+    assert!(wait_until(|| {
+        listed.clear();
+        bed.interface()
+            .for_each_connection(&close_wait, |conn| listed.push(conn.remote()));
+        !listed.is_empty()
+    }));
+    assert_eq!(listed, [closing_addr]);
+
+    let everything = ConnectionFilter {
+        local_port: Some(7018),
+        ..Default::default()
+    };
+    assert_eq!(bed.interface().abort_connections(&everything), 2);
+    let mut buf = [0; 1];
+    let err = open.read(&mut buf).expect_err("read after abort");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn state_transitions() {
+    let Some(mut bed) = test_bed_with(Interface::builder().record_transitions(true)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7020).expect("bind");
+    let client = TestBed::connect(7020).expect("connect");
+    let stream = listener.accept().expect("accept");
+    drop(client);
+
+    let mut log = None;
+    assert!(wait_until(|| {
+        log = stream.transitions().expect("transitions");
+        log.as_ref().is_some_and(|log| log.transitions().len() == 2)
+    }));
+    let log = log.unwrap();
+    let [established, close_wait] = log.transitions() else {
+        unreachable!();
+    };
+    assert_eq!(
+        (established.from, established.to),
+        (State::SynReceived, State::Established)
+    );
+    assert!(matches!(established.cause, Cause::Segment(s) if s.ack && !s.syn));
+    assert_eq!(
+        (close_wait.from, close_wait.to),
+        (State::Established, State::CloseWait)
+    );
+    assert!(matches!(close_wait.cause, Cause::Segment(s) if s.fin));
+    assert!(log.to_graphviz().contains("Established -> CloseWait"));
+    assert!(log.to_mermaid().contains("Established --> CloseWait"));
+}
+
+#[test]
+fn cwnd_trace() {
+    let Some(mut bed) = test_bed_with(Interface::builder().record_cwnd(true)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7047).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<usize> {
+        let mut stream = TestBed::connect(7047)?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received)?;
+        Ok(received.len())
+    });
+    let mut stream = listener.accept().expect("accept");
+    stream.write_all(&[7u8; 100_000]).expect("write");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    stream.flush_deadline(deadline).expect("acknowledged");
+
+    let trace = stream.cwnd_trace().expect("trace").expect("recorded");
+    let samples = trace.samples();
+    assert!(!samples.is_empty());
+    assert!(samples.windows(2).all(|w| w[0].at <= w[1].at));
+    assert_eq!(samples[0].state, CongestionState::SlowStart);
+    assert!(samples.last().unwrap().cwnd > samples[0].cwnd);
+    assert_eq!(samples.last().unwrap().in_flight, 0);
+
+    let csv = trace.to_csv();
+    assert_eq!(csv.lines().count(), samples.len() + 1);
+    assert!(csv.starts_with("time,cwnd,ssthresh,in_flight,state\n"));
+    let json: serde_json::Value = serde_json::from_str(&trace.to_json()).expect("json");
+    assert_eq!(json.as_array().map(Vec::len), Some(samples.len()));
+    assert_eq!(json[0]["state"], "slow-start");
+
+    stream.set_record_cwnd(false).expect("stop");
+    assert!(stream.cwnd_trace().expect("trace").is_none());
+    drop(stream);
+    assert_eq!(
+        client.join().expect("client panicked").expect("client"),
+        100_000
+    );
+}
+
+#[test]
+fn segment_history() {
+    let Some(mut bed) = test_bed_with(Interface::builder().segment_history(4)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7045).expect("bind");
+    let mut client = TestBed::connect(7045).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    for _ in 0..3 {
+        client.write_all(b"hello").expect("write");
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).expect("read");
+    }
+    stream.write_all(b"bye").expect("write");
+    client.read_exact(&mut [0u8; 3]).expect("read");
+
+    let history = stream.segment_history().expect("history").expect("kept");
+    let entries: Vec<_> = history.entries().copied().collect();
+    assert_eq!(entries.len(), 4, "{}", history);
+    assert!(entries.windows(2).all(|w| w[0].at <= w[1].at));
+    assert!(entries
+        .iter()
+        .any(|e| e.direction == Direction::Sent && e.segment.len == 3));
+    assert!(entries
+        .iter()
+        .any(|e| e.direction == Direction::Received && e.segment.len == 5));
+
+    stream.set_segment_history(0).expect("stop");
+    assert!(stream.segment_history().expect("history").is_none());
+}
+
+#[test]
+fn injected_faults() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let corrupted = bed.interface().inject_fault(FaultRule::new(
+        Direction::Received,
+        Fault::Corrupt,
+        |_, _, data| !data.is_empty(),
+    ));
+    let duplicated = bed.interface().inject_fault(
+        FaultRule::new(Direction::Received, Fault::Duplicate, |_, tcp, _| tcp.fin()).times(2),
+    );
+    let mut listener = bed.interface().bind(7046).expect("bind");
+    let mut client = TestBed::connect(7046).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+
+    // The kernel retransmits the segment whose checksum broke
+    client.write_all(b"hello").expect("write");
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf, b"hello");
+    assert_eq!(corrupted.applied(), 1);
+    assert!(bed.interface().drop_stats().get(DropReason::BadChecksum) >= 1);
+
+    // And the stack the segment that was lost, once it measured the RTT
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    stream.write_all(b"first").expect("write");
+    stream.flush_deadline(deadline).expect("acknowledged");
+    let lost = bed.interface().inject_fault(FaultRule::new(
+        Direction::Sent,
+        Fault::Drop,
+        |_, _, data| !data.is_empty(),
+    ));
+    stream.write_all(b"again").expect("write");
+    let mut buf = [0u8; 10];
+    client.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf, b"firstagain");
+    assert_eq!(lost.applied(), 1);
+
+    // A duplicated FIN is acknowledged again without harm
+    client
+        .shutdown(std::net::Shutdown::Write)
+        .expect("shutdown");
+    assert_eq!(stream.read(&mut [0u8; 1]).expect("read"), 0);
+    assert_eq!(duplicated.applied(), 1);
+    bed.interface().clear_faults();
+    drop(stream);
+    assert_eq!(client.read(&mut [0u8; 1]).expect("read"), 0);
+}
+
+#[test]
+fn record_replay() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7021).expect("bind");
+    listener.set_record(true);
+    let mut client = TestBed::connect(7021).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+
+    client.write_all(b"hello").expect("client write");
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).expect("read");
+    stream.write_all(b"world").expect("write");
+    std::thread::sleep(std::time::Duration::from_millis(3000));
+    eprintln!("{}", stream.debug_snapshot().unwrap());
+    client.read_exact(&mut buf).expect("client read");
+    drop(client);
+    assert!(wait_until(|| {
+        let snapshot = stream.debug_snapshot().expect("snapshot");
+        snapshot.state == State::CloseWait && snapshot.snd_una == snapshot.snd_nxt
+    }));
+    let live = stream.debug_snapshot().expect("snapshot");
+
+    let recording = stream.recording().expect("recording").expect("recorded");
+    let decoded = Recording::decode(&recording.encode()).expect("decode");
+    assert_eq!(decoded, recording);
+
+    let clock = Arc::new(ManualClock::new());
+    let mut replayer = Replayer::new(decoded, Config::default(), clock);
+    replayer.run();
+    let replayed = replayer.connection().expect("replayed").snapshot();
+    assert_eq!(
+        (
+            replayed.state,
+            replayed.snd_una,
+            replayed.snd_nxt,
+            replayed.rcv_nxt
+        ),
+        (live.state, live.snd_una, live.snd_nxt, live.rcv_nxt)
+    );
+}
+
+#[test]
+fn time_wait_reuse() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7022).expect("bind");
+    let mut client = TestBed::connect_from(47022, 7022).expect("connect");
+    let stream = listener.accept().expect("accept");
+    // The stack closes first and ends up in TIME-WAIT
+    drop(stream);
+    let mut buf = [0; 1];
+    assert_eq!(client.read(&mut buf).expect("read EOF"), 0);
+    drop(client);
+    let time_wait = ConnectionFilter {
+        state: Some(State::TimeWait),
+        local_port: Some(7022),
+        ..Default::default()
+    };
+    assert!(wait_until(|| {
+        let mut found = false;
+        bed.interface()
+            .for_each_connection(&time_wait, |_| found = true);
+        found
+    }));
+
+    // The kernel's SYN carries a newer timestamp: a new incarnation
+    let mut client = TestBed::connect_from(47022, 7022).expect("reconnect");
+    let mut stream = listener.accept().expect("accept again");
+    client.write_all(b"again").expect("client write");
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf, b"again");
+    assert_eq!(
+        bed.interface().drop_stats().get(DropReason::DuplicateSyn),
+        0
+    );
+}
+
+#[test]
+fn specific_listener_precedence() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let any = bed.interface().bind(7024).expect("bind any address");
+    let other = bed
+        .interface()
+        .bind_to(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 7024))
+        .expect("bind another address");
+    let mut specific = bed
+        .interface()
+        .bind_to(TestBed::stack_addr(7024))
+        .expect("bind the stack's address");
+    let err = bed
+        .interface()
+        .bind_to(TestBed::stack_addr(7024))
+        .err()
+        .expect("address bound twice");
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+    let _client = TestBed::connect(7024).expect("connect");
+    specific.accept().expect("accept");
+    assert!(!any.has_pending());
+    assert!(!other.has_pending());
+
+    // Without it, the listener on any address takes over
+    drop(specific);
+    let _client = TestBed::connect(7024).expect("connect again");
+    assert!(wait_until(|| any.has_pending()));
+}
+
+#[test]
+fn any_port_listener() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut any_port = bed.interface().bind(0).expect("bind port 0");
+    let mut own = bed.interface().bind(7026).expect("bind");
+
+    let mut client = TestBed::connect(7025).expect("connect");
+    let mut stream = any_port.accept().expect("accept");
+    assert_eq!(stream.local_addr(), TestBed::stack_addr(7025));
+    assert_eq!(
+        std::net::SocketAddr::V4(stream.peer_addr()),
+        client.local_addr().expect("client address")
+    );
+    client.write_all(b"proxied").expect("client write");
+    let mut buf = [0; 7];
+    stream.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf, b"proxied");
+
+    // A port with a listener of its own isn't taken over
+    let _client = TestBed::connect(7026).expect("connect to a bound port");
+    own.accept().expect("accept on the bound port");
+    assert!(!any_port.has_pending());
+}
+
+/// Contents of `field` in the status of this process's thread named `name`
+fn thread_status(name: &str, field: &str) -> Option<String> {
+    std::fs::read_dir("/proc/self/task")
+        .ok()?
+        .flatten()
+        .find(|task| {
+            std::fs::read_to_string(task.path().join("comm")).is_ok_and(|comm| comm.trim() == name)
+        })
+        .and_then(|task| std::fs::read_to_string(task.path().join("status")).ok())?
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .map(|value| value.trim().to_string())
+}
+
+#[test]
+fn thread_options() {
+    let pinned = ThreadOptions {
+        name: Some("tcprs-pinned".to_string()),
+        cpus: vec![0],
+        ..Default::default()
+    };
+    let Some(_bed) = test_bed_with(Interface::builder().protocol_thread(pinned)) else {
+        return;
+    };
+    assert_eq!(
+        thread_status("tcprs-pinned", "Cpus_allowed_list").as_deref(),
+        Some("0")
+    );
+
+    let nowhere = ThreadOptions {
+        cpus: vec![1 << 20],
+        ..Default::default()
+    };
+    let err = TestBed::with(Interface::builder().receive_thread(nowhere))
+        .err()
+        .expect("pinned to a CPU that doesn't exist");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn protocol_workers() {
+    let workers = ThreadOptions {
+        name: Some("tcprs-worker".to_string()),
+        ..Default::default()
+    };
+    let builder = Interface::builder()
+        .protocol_workers(4)
+        .protocol_thread(workers);
+    let Some(mut bed) = test_bed_with(builder) else {
+        return;
+    };
+    for name in ["tcprs-worker", "tcprs-worker1", "tcprs-worker3"] {
+        assert!(thread_status(name, "Name").is_some(), "no thread {}", name);
+    }
+    let mut listener = bed.interface().bind(7035).expect("bind");
+    // Enough connections for every worker to run some
+    let clients: Vec<_> = (0..16u8)
+        .map(|i| {
+            std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+                let mut stream = TestBed::connect(7035)?;
+                stream.write_all(&[i; 4096])?;
+                stream.shutdown(std::net::Shutdown::Write)?;
+                let mut echoed = Vec::new();
+                stream.read_to_end(&mut echoed)?;
+                Ok(echoed)
+            })
+        })
+        .collect();
+    let servers: Vec<_> = (0..clients.len())
+        .map(|_| {
+            let mut stream = listener.accept().expect("accept");
+            std::thread::spawn(move || {
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).expect("read");
+                stream.write_all(&data).expect("write");
+            })
+        })
+        .collect();
+    for server in servers {
+        server.join().unwrap();
+    }
+    for (i, client) in clients.into_iter().enumerate() {
+        assert_eq!(client.join().unwrap().expect("client"), vec![i as u8; 4096]);
+    }
+
+    let err = TestBed::with(Interface::builder().protocol_workers(0))
+        .err()
+        .expect("no protocol workers");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn proxy_across_workers() {
+    let Some(mut bed) = test_bed_with(Interface::builder().protocol_workers(4)) else {
+        return;
+    };
+    let mut front = bed.interface().bind(7048).expect("bind");
+    let mut back = bed.interface().bind(7049).expect("bind");
+    // The two sides of a proxied pair mostly land on different workers
+    for i in 0..4u8 {
+        let client = std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+            let mut stream = TestBed::connect(7048)?;
+            stream.write_all(&[i; 2048])?;
+            stream.shutdown(std::net::Shutdown::Write)?;
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply)?;
+            Ok(reply)
+        });
+        let server = std::thread::spawn(|| -> std::io::Result<Vec<u8>> {
+            let mut stream = TestBed::connect(7049)?;
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request)?;
+            stream.write_all(&request)?;
+            Ok(request)
+        });
+
+        let mut a = front.accept().expect("accept client");
+        let mut b = back.accept().expect("accept server");
+        let copied = tcprs::copy_bidirectional(&mut a, &mut b).expect("copy");
+        assert_eq!(copied, (2048, 2048));
+        drop((a, b));
+        let request = server.join().unwrap().expect("server failed");
+        assert_eq!(request, vec![i; 2048]);
+        let reply = client.join().unwrap().expect("client failed");
+        assert_eq!(reply, vec![i; 2048]);
+    }
+}
+
+#[test]
+fn buffer_pool() {
+    static POOL: PacketPool = PacketPool::new(16);
+    let Some(mut bed) = test_bed_with(Interface::builder().buffer_pool(&POOL)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7023).expect("bind");
+    let mut client = TestBed::connect(7023).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+
+    let data = vec![b'x'; 64 * 1024];
+    client.write_all(&data).expect("client write");
+    let mut buf = vec![0; data.len()];
+    stream.read_exact(&mut buf).expect("read");
+    stream.write_all(&data).expect("write");
+    client.read_exact(&mut buf).expect("client read");
+    // Segments sent and received went back to the pool, which keeps no
+    // more than its limit
+    assert!(POOL.available() > 0);
+    assert!(POOL.available() <= 16);
+}
+
+/// Panics on segments carrying "panic"
+struct Crash;
+
+impl PacketFilter for Crash {
+    fn filter(&self, _ip: &Ipv4HeaderSlice, _tcp: &TcpHeaderSlice, data: &[u8]) -> Verdict {
+        assert_ne!(data, b"panic", "filter crashed");
+        Verdict::Accept
+    }
+}
+
+#[test]
+fn packet_loop_failure() {
+    let Some(mut bed) = test_bed_with(Interface::builder().packet_filter(Crash)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7027).expect("bind");
+    let mut client = TestBed::connect(7027).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    assert_eq!(bed.interface().health(), Health::Running);
+
+    // The blocked read fails once the packet loop died, as do later calls
+    client.write_all(b"panic").expect("client write");
+    let err = stream.read(&mut [0; 8]).expect_err("packet loop failed");
+    assert!(err.to_string().contains("filter crashed"), "{}", err);
+    stream.write(b"late").expect_err("packet loop failed");
+    assert!(listener.accept().is_err());
+
+    assert_eq!(bed.interface().health(), Health::Failed);
+    let err = bed.interface().last_error().expect("failure recorded");
+    assert!(err.to_string().contains("filter crashed"), "{}", err);
+}
+
+#[test]
+fn device_reopen() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let builder = Interface::builder().on_device_event(move |event| {
+        let _ = tx.send(event.clone());
+    });
+    let Some(mut bed) = test_bed_with(builder) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7028).expect("bind");
+    let mut client = TestBed::connect(7028).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+
+    let deleted = std::process::Command::new("ip")
+        .args(["link", "delete", "tun0"])
+        .status();
+    if !deleted.is_ok_and(|status| status.success()) {
+        eprintln!("skipping: can't delete the device");
+        return;
+    }
+    let timeout = std::time::Duration::from_secs(5);
+    assert!(matches!(
+        rx.recv_timeout(timeout),
+        Ok(DeviceEvent::Failed { attempt: 1, .. })
+    ));
+    assert_eq!(rx.recv_timeout(timeout), Ok(DeviceEvent::Reopened));
+
+    // The connection carries on over the new device
+    client.write_all(b"still here").expect("client write");
+    let mut buf = [0; 10];
+    stream.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf, b"still here");
+    assert_eq!(bed.interface().health(), Health::Running);
+}
+
+#[test]
+fn hot_reconfiguration() {
+    let Some(mut bed) = test_bed() else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7029).expect("bind");
+    let mut client = TestBed::connect(7029).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+
+    // Established connections take the lower MTU
+    bed.interface().set_mtu(576).expect("set MTU");
+    if let Ok(link) = std::process::Command::new("ip")
+        .args(["-o", "link", "show", "tun0"])
+        .output()
+    {
+        assert!(String::from_utf8_lossy(&link.stdout).contains("mtu 576"));
+    }
+    let data = vec![b'x'; 8 * 1024];
+    stream.write_all(&data).expect("write");
+    let mut buf = vec![0; data.len()];
+    client.read_exact(&mut buf).expect("client read");
+    assert_eq!(buf, data);
+    bed.interface().set_ttl(0).expect_err("zero TTL");
+    bed.interface().set_ttl(32).expect("set TTL");
+
+    // The stack is reached on the new subnet, and the link keeps its
+    // routes, even one added by hand
+    let routed = std::process::Command::new("ip")
+        .args(["route", "add", "10.15.0.0/24", "dev", "tun0"])
+        .status()
+        .is_ok_and(|status| status.success());
+    let route_kept = || {
+        let routes = std::process::Command::new("ip")
+            .args(["-o", "route", "show", "dev", "tun0"])
+            .output()
+            .expect("list routes");
+        !routed || String::from_utf8_lossy(&routes.stdout).contains("10.15.0.0/24")
+    };
+    bed.interface()
+        .set_address(Ipv4Addr::new(10, 12, 0, 3), 24)
+        .expect("set address");
+    assert!(route_kept());
+    // Within the same subnet too
+    bed.interface()
+        .set_address(Ipv4Addr::new(10, 12, 0, 1), 24)
+        .expect("set address");
+    assert!(route_kept());
+    let moved = SocketAddrV4::new(Ipv4Addr::new(10, 12, 0, 2), 7029);
+    let _client =
+        std::net::TcpStream::connect_timeout(&moved.into(), std::time::Duration::from_secs(5))
+            .expect("connect to the new address");
+    let stream = listener.accept().expect("accept");
+    assert_eq!(stream.local_addr(), moved);
+}
+
+#[test]
+fn device_mtu() {
+    let Some(mut bed) = test_bed_with(Interface::builder().mtu(1400)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7030).expect("bind");
+    let _client = TestBed::connect(7030).expect("connect");
+    let stream = listener.accept().expect("accept");
+    // The kernel announced the MSS of the device's MTU
+    let snapshot = stream.debug_snapshot().expect("snapshot");
+    assert_eq!(snapshot.mss, 1360);
+
+    let changed = std::process::Command::new("ip")
+        .args(["link", "set", "tun0", "mtu", "1280"])
+        .status();
+    if !changed.is_ok_and(|status| status.success()) {
+        eprintln!("skipping: can't change the MTU");
+        return;
+    }
+    // The stack looks at the MTU once a second
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(3);
+    while stream.debug_snapshot().expect("snapshot").mss != 1240 {
+        assert!(std::time::Instant::now() < deadline, "MTU change missed");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn jumbo_frames() {
+    let Some(mut bed) = test_bed_with(Interface::builder().mtu(9000)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7031).expect("bind");
+    let data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+    let sent = data.clone();
+    // The client reads while it writes, so neither end waits on the other
+    let client = std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+        let mut stream = TestBed::connect(7031)?;
+        let mut reader = stream.try_clone()?;
+        let echo = std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+            let mut buf = vec![0; 256 * 1024];
+            reader.read_exact(&mut buf)?;
+            Ok(buf)
+        });
+        stream.write_all(&sent)?;
+        echo.join().unwrap()
+    });
+    let mut stream = listener.accept().expect("accept");
+    assert_eq!(stream.debug_snapshot().expect("snapshot").mss, 8960);
+
+    let mut buf = vec![0; data.len()];
+    stream.read_exact(&mut buf).expect("read");
+    assert_eq!(buf, data);
+    stream.write_all(&data).expect("write");
+    assert_eq!(client.join().unwrap().expect("client"), data);
+}
+
+#[test]
+fn packet_info() {
+    let Some(mut bed) = test_bed_with(Interface::builder().packet_info(true)) else {
+        return;
+    };
+    bed.assert_echo(7032, b"hello through the packet information prefix");
+}
+
+#[test]
+fn device_fd() {
+    let mut bed = match TestBed::with_device_fd(Interface::builder(), true) {
+        Ok(bed) => bed,
+        Err(e) if testing::unavailable(&e) => {
+            eprintln!("skipping: {}", e);
+            return;
+        }
+        Err(e) => panic!("setting up the test bed: {}", e),
+    };
+    bed.assert_echo(7033, b"hello over a device opened by someone else");
+
+    let not_tun = std::fs::File::open("/dev/null").expect("open /dev/null");
+    let error = Interface::from_fd(not_tun.into())
+        .err()
+        .expect("not a tun device");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn benchmark() {
+    use tcprs::bench::{self, RetransmitCounter};
+
+    let retransmits = RetransmitCounter::default();
+    let observer = retransmits.clone();
+    let Some(mut bed) = test_bed_with(Interface::builder().on_event(move |e| observer.observe(e)))
+    else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7034).expect("bind");
+    let client = std::thread::spawn(|| -> std::io::Result<()> {
+        // Sink the bulk transfer
+        let mut stream = TestBed::connect(7034)?;
+        std::io::copy(&mut stream, &mut std::io::sink())?;
+        drop(stream);
+        // Source one
+        let mut stream = TestBed::connect(7034)?;
+        stream.write_all(&[0; 512 * 1024])?;
+        drop(stream);
+        // Echo the pings
+        let mut stream = TestBed::connect(7034)?;
+        let mut buf = [0; 64];
+        while stream.read_exact(&mut buf).is_ok() {
+            stream.write_all(&buf)?;
+        }
+        Ok(())
+    });
+
+    let mut stream = listener.accept().expect("accept");
+    let sent = bench::send(
+        &mut stream,
+        std::time::Duration::from_millis(200),
+        &retransmits,
+    )
+    .expect("send");
+    assert!(sent.bytes > 0 && sent.goodput() > 0.0);
+    let mut stream = listener.accept().expect("accept");
+    let received = bench::receive(&mut stream, &retransmits).expect("receive");
+    assert_eq!(received.bytes, 512 * 1024);
+    let mut stream = listener.accept().expect("accept");
+    let latency = bench::ping_pong(&mut stream, 64, 100, &retransmits).expect("ping-pong");
+    assert_eq!(latency.samples.len(), 100);
+    assert!(latency.percentile(50.0) <= latency.percentile(99.0));
+    assert_eq!(latency.percentile(100.0), latency.samples[99]);
+    drop(stream);
+    client.join().unwrap().expect("client");
+}
+
+#[cfg(feature = "control")]
+#[test]
+fn control_socket() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    let path = std::env::temp_dir().join(format!("tcprs-control-{}.sock", std::process::id()));
+    let Some(mut bed) = test_bed_with(Interface::builder().control_socket(&path)) else {
+        return;
+    };
+    let mut listener = bed.interface().bind(7019).expect("bind");
+    let mut client = TestBed::connect(7019).expect("connect");
+    let mut stream = listener.accept().expect("accept");
+    // Once data arrived the handshake is complete on both ends
+    client.write_all(b"hi").expect("write");
+    stream.read_exact(&mut [0; 2]).expect("read");
+
+    let mode = std::fs::metadata(&path)
+        .expect("socket")
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // A client that stays connected doesn't keep others waiting
+    let _idle = UnixStream::connect(&path).expect("connect to the control socket");
+    let control = UnixStream::connect(&path).expect("connect to the control socket");
+    control
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .expect("read timeout");
+    let mut replies = std::io::BufReader::new(control.try_clone().expect("clone"));
+    let mut query = |command: &str| -> serde_json::Value {
+        writeln!(&control, "{}", command).expect("send command");
+        let mut reply = String::new();
+        replies.read_line(&mut reply).expect("read reply");
+        serde_json::from_str(&reply).expect("parse reply")
+    };
+
+    let remote = client.local_addr().expect("local address").to_string();
+    let connections = query("connections");
+    let conn = &connections.as_array().expect("connection list")[0];
+    assert_eq!(conn["remote"], remote.as_str());
+    assert_eq!(conn["state"], "Established");
+
+    let stats = query("stats");
+    assert_eq!(stats["connections"], 1);
+    assert_eq!(stats["listeners"], serde_json::json!([7019]));
+
+    let local = conn["local"].as_str().expect("local address").to_string();
+    let details = query(&format!("connection {} {}", local, remote));
+    assert_eq!(details["snapshot"]["state"], "Established");
+    assert!(query("bogus")["error"].is_string());
+
+    drop(bed);
+    assert!(!path.exists());
+}
+
+#[test]
+fn route_without_address() {
+    let ns = match testing::NetNs::enter() {
+        Ok(ns) => ns,
+        Err(e) if testing::unavailable(&e) => {
+            eprintln!("skipping: {}", e);
+            return;
+        }
+        Err(e) => panic!("entering a namespace: {}", e),
+    };
+    // The link has to come up for the route to be accepted
+    let iface = Interface::builder()
+        .route(Ipv4Addr::new(10, 13, 0, 0), 24)
+        .build()
+        .expect("route without an address");
+    if let Ok(routes) = std::process::Command::new("ip")
+        .args(["-o", "route", "show", "dev", "tun0"])
+        .output()
+    {
+        assert!(String::from_utf8_lossy(&routes.stdout).contains("10.13.0.0/24"));
+    }
+    drop(iface);
+    drop(ns);
+}